@@ -32,7 +32,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     // Create output to contain the clone of the archive's source
-    let mut output = CloneOutput::new(output_file, archive.build_source_index());
+    let mut output = CloneOutput::new(output_file, archive.build_source_index())
+        .size_limit(archive.total_source_size());
 
     // Reorder chunks in the output
     let reused_bytes = output.reorder_in_place(output_index).await?;
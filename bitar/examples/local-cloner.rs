@@ -21,7 +21,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .expect("open output"),
         // Get a list of all chunks needed to create the clone
         archive.build_source_index(),
-    );
+    )
+    // Don't let the archive make us write past the end of its source
+    .size_limit(archive.total_source_size());
 
     // Use as much data as possible from the example seed
     let mut read_seed_bytes = 0;
@@ -0,0 +1,110 @@
+//! Feeds arbitrary sources through every chunker, read using arbitrary I/O patterns, and
+//! checks that the chunker doesn't panic and gives the same chunks as when reading the whole
+//! source at once.
+//!
+//! Run using `cargo fuzz run chunker` from the bitar directory.
+#![no_main]
+use bitar::chunker::{Config, FilterBits, FilterConfig};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::StreamExt;
+use libfuzzer_sys::fuzz_target;
+use std::io;
+use tokio::io::{AsyncRead, ReadBuf};
+
+// Number of input bytes used to pick the chunker config and read pattern.
+const PARAMS_SIZE: usize = 12;
+
+// Source which gives a repeated pattern of read sizes and returns Pending after every
+// pending_cadence reads.
+struct MockSource<'a> {
+    data: &'a [u8],
+    read_pattern: Vec<usize>,
+    pending_cadence: usize,
+    reads: usize,
+    pending: bool,
+}
+
+impl AsyncRead for MockSource<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        if self.data.is_empty() {
+            Poll::Ready(Ok(()))
+        } else if self.pending {
+            self.pending = false;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        } else {
+            let bytes_per_read = self.read_pattern[self.reads % self.read_pattern.len()];
+            let read = bytes_per_read
+                .max(1)
+                .min(buf.remaining())
+                .min(self.data.len());
+            buf.put_slice(&self.data[..read]);
+            self.data = &self.data[read..];
+            self.reads += 1;
+            self.pending =
+                self.pending_cadence > 0 && self.reads.is_multiple_of(self.pending_cadence);
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+fn config(params: &[u8]) -> Config {
+    let size = |hi: u8, lo: u8| usize::from(u16::from_be_bytes([hi, lo]));
+    let filter_config = FilterConfig {
+        filter_bits: FilterBits(u32::from(params[1] % 34)),
+        min_chunk_size: size(params[2], params[3]),
+        max_chunk_size: size(params[4], params[5]),
+        window_size: size(params[6], params[7]) % 4096,
+        buzhash_table: None,
+    };
+    match params[0] % 3 {
+        0 => Config::BuzHash(filter_config),
+        1 => Config::RollSum(filter_config),
+        _ => Config::FixedSize(size(params[2], params[3])),
+    }
+}
+
+async fn chunks<R: AsyncRead + Unpin + Send>(config: &Config, source: R) -> Vec<(u64, Vec<u8>)> {
+    config
+        .new_chunker(source)
+        .map(|result| {
+            let (offset, chunk) = result.unwrap();
+            assert!(!chunk.data().is_empty());
+            (offset, chunk.data().to_vec())
+        })
+        .collect()
+        .await
+}
+
+fuzz_target!(|input: &[u8]| {
+    if input.len() < PARAMS_SIZE {
+        return;
+    }
+    let (params, data) = input.split_at(PARAMS_SIZE);
+    let config = config(params);
+    let source = MockSource {
+        data,
+        read_pattern: params[8..11].iter().map(|&v| usize::from(v)).collect(),
+        pending_cadence: usize::from(params[11] % 4),
+        reads: 0,
+        pending: false,
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let expected = chunks(&config, data).await;
+        let mut offset = 0;
+        for (chunk_offset, chunk) in &expected {
+            assert_eq!(*chunk_offset, offset);
+            offset += chunk.len() as u64;
+        }
+        assert_eq!(offset, data.len() as u64);
+        assert_eq!(chunks(&config, source).await, expected);
+    });
+});
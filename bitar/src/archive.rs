@@ -1,20 +1,39 @@
 use blake2::{Blake2b512, Digest};
+use bytes::Bytes;
 use futures_util::{stream::Stream, StreamExt};
-use std::{convert::TryInto, fmt};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::{convert::TryInto, fmt, io};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
 
 use crate::{
-    archive_reader::ArchiveReader, chunk_dictionary as dict, chunker,
-    compression::CompressionAlgorithm, header, ChunkIndex, ChunkOffset, CompressedArchiveChunk,
-    CompressedChunk, Compression, HashSum,
+    archive_reader::{ArchiveReader, SplitReader, StoreReader},
+    chunk_dictionary as dict, chunker,
+    compression::CompressionAlgorithm,
+    header::{self, DictionaryKey},
+    ChunkHasher, ChunkIndex, ChunkOffset, CloneOutput, CompressedArchiveChunk, CompressedChunk,
+    Compression, CompressionError, HashFunction, HashSum, HashSumMismatchError, SourceCheckpoints,
 };
 
 #[derive(Debug)]
 pub enum ArchiveError<R> {
+    /// Source doesn't start with the archive file magic.
+    NotAnArchive,
+    /// The dictionary of the archive is encrypted and no key was given.
+    EncryptedDictionary,
+    /// The number of part readers doesn't match the number of parts of the archive.
+    PartCountMismatch {
+        expected: usize,
+        got: usize,
+    },
     InvalidArchive(Box<dyn std::error::Error + Send + Sync>),
     ReaderError(R),
 }
 impl<R> ArchiveError<R> {
-    fn invalid_archive<T: Into<Box<dyn std::error::Error + Send + Sync>>>(err: T) -> Self {
+    pub(crate) fn invalid_archive<T: Into<Box<dyn std::error::Error + Send + Sync>>>(
+        err: T,
+    ) -> Self {
         Self::InvalidArchive(err.into())
     }
 }
@@ -24,6 +43,9 @@ where
 {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
+            ArchiveError::NotAnArchive
+            | ArchiveError::EncryptedDictionary
+            | ArchiveError::PartCountMismatch { .. } => None,
             ArchiveError::InvalidArchive(err) => Some(err.as_ref()),
             ArchiveError::ReaderError(err) => Some(err),
         }
@@ -35,6 +57,15 @@ where
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::NotAnArchive => write!(f, "not an archive"),
+            Self::EncryptedDictionary => {
+                write!(f, "archive dictionary is encrypted, a key is required")
+            }
+            Self::PartCountMismatch { expected, got } => write!(
+                f,
+                "archive has {} parts but {} part readers were given",
+                expected, got
+            ),
             Self::InvalidArchive(_) => write!(f, "invalid archive"),
             Self::ReaderError(_) => write!(f, "reader error"),
         }
@@ -46,6 +77,61 @@ impl<R> From<prost::DecodeError> for ArchiveError<R> {
     }
 }
 
+#[derive(Debug)]
+pub enum UnpackError<R> {
+    /// Archive has no source of the given name.
+    UnknownSource(String),
+    ReaderError(R),
+    CompressionError(CompressionError),
+    HashSumMismatch(Box<HashSumMismatchError>),
+    /// Failed to read a seed or write the output.
+    IoError(io::Error),
+}
+impl<R> std::error::Error for UnpackError<R>
+where
+    R: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            UnpackError::UnknownSource(_) => None,
+            UnpackError::ReaderError(err) => Some(err),
+            UnpackError::CompressionError(err) => Some(err),
+            UnpackError::HashSumMismatch(err) => Some(err.as_ref()),
+            UnpackError::IoError(err) => Some(err),
+        }
+    }
+}
+impl<R> fmt::Display for UnpackError<R>
+where
+    R: std::error::Error,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownSource(name) => write!(f, "no source named '{}' in archive", name),
+            Self::ReaderError(_) => write!(f, "reader error"),
+            Self::CompressionError(_) => write!(f, "failed to decompress chunk"),
+            Self::HashSumMismatch(_) => write!(f, "chunk hash mismatch"),
+            Self::IoError(_) => write!(f, "i/o error"),
+        }
+    }
+}
+impl<R> From<io::Error> for UnpackError<R> {
+    fn from(err: io::Error) -> Self {
+        UnpackError::IoError(err)
+    }
+}
+
+/// A source stored in an archive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceInfo {
+    /// Name of the source, empty for the source of a single source archive.
+    pub name: String,
+    /// Size of the source.
+    pub size: u64,
+    /// Checksum of the source (Blake2).
+    pub checksum: HashSum,
+}
+
 /// Description of a chunk within an archive.
 #[derive(Clone, Debug, PartialEq)]
 pub struct ChunkDescriptor {
@@ -57,6 +143,8 @@ pub struct ChunkDescriptor {
     pub archive_offset: u64,
     /// Size of the chunk data in source (uncompressed).
     pub source_size: u32,
+    /// CRC32C of the chunk data in source (uncompressed), if stored in the archive.
+    pub crc32c: Option<u32>,
 }
 
 impl ChunkDescriptor {
@@ -65,6 +153,34 @@ impl ChunkDescriptor {
     }
 }
 
+/// Kind of file system entry the source of an archive was made from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SourceEntry {
+    /// Regular file, device or stream, rebuilt from the archive chunks.
+    File,
+    /// Symbolic link pointing at the target path, stored without chunks.
+    Symlink(String),
+    /// Hard link of the source with the given file name, stored without chunks.
+    HardLink(String),
+}
+
+impl From<SourceEntry> for Option<dict::SourceEntry> {
+    fn from(entry: SourceEntry) -> Self {
+        use dict::source_entry::EntryType;
+        match entry {
+            SourceEntry::File => None,
+            SourceEntry::Symlink(target) => Some(dict::SourceEntry {
+                entry_type: EntryType::Symlink as i32,
+                link_target: target,
+            }),
+            SourceEntry::HardLink(name) => Some(dict::SourceEntry {
+                entry_type: EntryType::HardLink as i32,
+                link_target: name,
+            }),
+        }
+    }
+}
+
 /// A readable archive.
 pub struct Archive<R> {
     reader: R,
@@ -81,75 +197,64 @@ pub struct Archive<R> {
     chunk_data_offset: u64,
     source_total_size: u64,
     source_checksum: HashSum,
+    source_entry: SourceEntry,
+    source_checkpoints: Option<SourceCheckpoints>,
+    // Size of each part if the chunk data is split across multiple files.
+    chunk_data_part_sizes: Vec<u64>,
     chunker_config: chunker::Config,
     chunk_hash_length: usize,
+    chunk_hasher: ChunkHasher,
+    source_name: String,
+    additional_sources: Vec<dict::Source>,
+    // The main source followed by the additional sources.
+    sources: Vec<SourceInfo>,
+    chunk_data_checksum: Option<HashSum>,
+    chunk_data_alignment: Option<u64>,
+    content_type: Option<String>,
 }
 
 impl<R> Archive<R> {
-    fn verify_pre_header<E>(pre_header: &[u8]) -> Result<(), ArchiveError<E>> {
-        if pre_header.len() < header::ARCHIVE_MAGIC.len() {
-            return Err(ArchiveError::invalid_archive("not an archive"));
-        }
-        // Allow both legacy type file magic (prefixed with \0 but no null
-        // termination) and 'BITA\0'.
-        if &pre_header[0..header::ARCHIVE_MAGIC.len()] != header::ARCHIVE_MAGIC
-            && &pre_header[0..header::ARCHIVE_MAGIC.len()] != b"\0BITA1"
-        {
-            return Err(ArchiveError::invalid_archive("not an archive"));
-        }
-        Ok(())
-    }
     /// Try to initialize an archive from a reader.
-    pub async fn try_init(mut reader: R) -> Result<Self, ArchiveError<R::Error>>
+    ///
+    /// Fails with [`ArchiveError::EncryptedDictionary`] if the dictionary of the archive is
+    /// encrypted, see [`Archive::try_init_with_key`].
+    pub async fn try_init(reader: R) -> Result<Self, ArchiveError<R::Error>>
     where
         R: ArchiveReader,
     {
-        // Read the pre-header (file magic and size)
-        let mut header: Vec<u8> = reader
-            .read_at(0, header::PRE_HEADER_SIZE)
-            .await
-            .map_err(ArchiveError::ReaderError)?
-            .to_vec();
-        Self::verify_pre_header(&header)?;
-
-        let dictionary_size = u64::from_le_bytes(
-            header[header::ARCHIVE_MAGIC.len()..header::PRE_HEADER_SIZE]
-                .try_into()
-                .unwrap(),
-        ) as usize;
+        Self::init(reader, None).await
+    }
 
-        // Read the dictionary, chunk data offset and header hash
-        header.extend_from_slice(
-            &reader
-                .read_at(header::PRE_HEADER_SIZE as u64, dictionary_size + 8 + 64)
-                .await
-                .map_err(ArchiveError::ReaderError)?,
-        );
+    /// Try to initialize an archive from a reader, decrypting the dictionary using the key.
+    ///
+    /// Archives with a plain dictionary are read as with [`Archive::try_init`].
+    pub async fn try_init_with_key(
+        reader: R,
+        key: &DictionaryKey,
+    ) -> Result<Self, ArchiveError<R::Error>>
+    where
+        R: ArchiveReader,
+    {
+        Self::init(reader, Some(key)).await
+    }
 
-        // Verify the header against the header checksum
-        let header_checksum = {
-            let mut hasher = Blake2b512::new();
-            let offs = header::PRE_HEADER_SIZE + dictionary_size + 8;
-            hasher.update(&header[..offs]);
-            let header_checksum = HashSum::from(&header[offs..(offs + 64)]);
-            if header_checksum != &hasher.finalize()[..] {
-                return Err(ArchiveError::invalid_archive("invalid header checksum"));
-            }
-            header_checksum
-        };
+    async fn init(
+        mut reader: R,
+        key: Option<&DictionaryKey>,
+    ) -> Result<Self, ArchiveError<R::Error>>
+    where
+        R: ArchiveReader,
+    {
+        let raw_header = RawHeader::read(&mut reader).await?;
+        let header_checksum = raw_header.header_checksum.clone();
+        let chunk_data_offset = raw_header.chunk_data_offset;
 
         // Deserialize the chunk dictionary
-        let dictionary: dict::ChunkDictionary = {
-            let offs = header::PRE_HEADER_SIZE;
-            prost::Message::decode(&header[offs..(offs + dictionary_size)])?
-        };
+        let mut dictionary: dict::ChunkDictionary =
+            prost::Message::decode(&raw_header.dictionary(key)?[..])?;
 
-        // Get chunk data offset
-        let chunk_data_offset = {
-            let offs = header::PRE_HEADER_SIZE + dictionary_size;
-            u64::from_le_bytes(header[offs..(offs + 8)].try_into().unwrap())
-        };
-        let archive_chunks = dictionary
+        let chunk_crc32c = dictionary.chunk_crc32c;
+        let archive_chunks: Vec<ChunkDescriptor> = dictionary
             .chunk_descriptors
             .into_iter()
             .map(|dict| ChunkDescriptor {
@@ -157,24 +262,115 @@ impl<R> Archive<R> {
                 archive_size: dict.archive_size as usize,
                 archive_offset: chunk_data_offset + dict.archive_offset,
                 source_size: dict.source_size,
+                crc32c: if chunk_crc32c {
+                    Some(dict.crc32c)
+                } else {
+                    None
+                },
             })
             .collect();
         let chunker_params = dictionary
             .chunker_params
             .ok_or_else(|| ArchiveError::invalid_archive("invalid chunker parameters"))?;
         let chunk_hash_length = chunker_params.chunk_hash_length as usize;
-        let source_order: Vec<usize> = dictionary
-            .rebuild_order
-            .into_iter()
-            .map(|v| v as usize)
+        // Chunk hashes are blake2 sums truncated to the hash length
+        let chunk_hasher = ChunkHasher::for_params(HashFunction::Blake2b, chunk_hash_length)
+            .map_err(ArchiveError::invalid_archive)?;
+        if archive_chunks
+            .iter()
+            .any(|descriptor| descriptor.checksum.len() != chunk_hash_length)
+        {
+            return Err(ArchiveError::invalid_archive(
+                "chunk checksum doesn't match the chunk hash length",
+            ));
+        }
+        let chunker_config = chunker_params
+            .chunker_config()
+            .map_err(ArchiveError::invalid_archive)?;
+        // Chunks are decompressed into buffers of their source size, which is bounded by the
+        // chunker to not let a tampered descriptor make us allocate arbitrary amounts of memory.
+        let max_chunk_size = chunker_config.max_chunk_size();
+        if let Some(descriptor) = archive_chunks
+            .iter()
+            .find(|descriptor| descriptor.source_size as usize > max_chunk_size)
+        {
+            return Err(ArchiveError::invalid_archive(format!(
+                "chunk size {} exceeds the max chunk size {}",
+                descriptor.source_size, max_chunk_size
+            )));
+        }
+        let parts_size: u64 = dictionary.chunk_data_part_sizes.iter().sum();
+        if !dictionary.chunk_data_part_sizes.is_empty()
+            && archive_chunks
+                .iter()
+                .any(|descriptor| descriptor.archive_end_offset() - chunk_data_offset > parts_size)
+        {
+            return Err(ArchiveError::invalid_archive(
+                "chunk data is outside of the archive parts",
+            ));
+        }
+        let chunk_sizes: Vec<u32> = archive_chunks
+            .iter()
+            .map(|descriptor| descriptor.source_size)
             .collect();
+        let min_chunk_size = chunker_config.min_chunk_size();
+        for source in &mut dictionary.additional_sources {
+            source.rebuild_order = rebuild_order(
+                std::mem::take(&mut source.rebuild_order),
+                source.rebuild_order_runs.take(),
+                &chunk_sizes,
+                min_chunk_size,
+                source.source_total_size,
+            )?;
+        }
+        if dictionary.additional_sources.iter().any(|source| {
+            source
+                .rebuild_order
+                .iter()
+                .any(|&index| index as usize >= archive_chunks.len())
+        }) {
+            return Err(ArchiveError::invalid_archive(
+                "invalid rebuild order of additional source",
+            ));
+        }
+        let source_order: Vec<usize> = rebuild_order(
+            dictionary.rebuild_order,
+            dictionary.rebuild_order_runs,
+            &chunk_sizes,
+            min_chunk_size,
+            dictionary.source_total_size,
+        )?
+        .into_iter()
+        .map(|v| v as usize)
+        .collect();
+        let source_checksum: HashSum = dictionary.source_checksum.into();
+        let sources = std::iter::once(SourceInfo {
+            name: dictionary.source_name.clone(),
+            size: dictionary.source_total_size,
+            checksum: source_checksum.clone(),
+        })
+        .chain(
+            dictionary
+                .additional_sources
+                .iter()
+                .map(|source| SourceInfo {
+                    name: source.name.clone(),
+                    size: source.source_total_size,
+                    checksum: HashSum::from(&source.source_checksum[..]),
+                }),
+        )
+        .collect();
+        let chunk_data_checksum = chunk_data_checksum(dictionary.chunk_data_checksum);
         Ok(Self {
             reader,
             archive_chunks,
             header_checksum,
-            header_size: header.len(),
+            header_size: raw_header.header.len(),
             source_total_size: dictionary.source_total_size,
-            source_checksum: dictionary.source_checksum.into(),
+            source_checksum,
+            source_entry: source_entry_from_dictionary(dictionary.source_entry)?,
+            source_checkpoints: source_checkpoints_from_dictionary(dictionary.source_checkpoints)?,
+            chunk_data_part_sizes: dictionary.chunk_data_part_sizes,
             created_by_app_version: dictionary.application_version.clone(),
             chunk_compression: compression_from_dictionary(
                 dictionary
@@ -185,7 +381,14 @@ impl<R> Archive<R> {
             source_order,
             chunk_data_offset,
             chunk_hash_length,
-            chunker_config: chunker_config_from_params(chunker_params)?,
+            chunk_hasher,
+            chunker_config,
+            source_name: dictionary.source_name,
+            additional_sources: dictionary.additional_sources,
+            sources,
+            chunk_data_checksum,
+            chunk_data_alignment: chunk_data_alignment(dictionary.chunk_data_alignment),
+            content_type: content_type(dictionary.content_type),
         })
     }
     /// Total number of chunks in archive (including duplicates).
@@ -219,6 +422,305 @@ impl<R> Archive<R> {
     pub fn source_checksum(&self) -> &HashSum {
         &self.source_checksum
     }
+    /// Kind of file system entry the source was made from.
+    pub fn source_entry(&self) -> &SourceEntry {
+        &self.source_entry
+    }
+    /// Name of the source, typically the input file name. Empty if the source was read from
+    /// stdin or the archive was built by an older version.
+    pub fn source_name(&self) -> &str {
+        &self.source_name
+    }
+    /// Hint of the content of the source, like a MIME type, if stored in archive.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+    /// Sources stored in the archive besides the main one, sharing its chunks.
+    ///
+    /// The rebuild order of each source refers to chunks by their index in
+    /// [`Archive::chunk_descriptors`].
+    pub fn additional_sources(&self) -> &[dict::Source] {
+        &self.additional_sources
+    }
+    /// All sources stored in the archive, the main source first.
+    pub fn sources(&self) -> &[SourceInfo] {
+        &self.sources
+    }
+    /// Build a ChunkIndex representing the source of the given name.
+    pub fn build_index_of_source(&self, name: &str) -> Option<ChunkIndex> {
+        let position = self.sources.iter().position(|source| source.name == name)?;
+        if position == 0 {
+            return Some(self.build_source_index());
+        }
+        let mut ci = ChunkIndex::new_empty(self.chunk_hash_length);
+        let mut offset = 0;
+        for &index in &self.additional_sources[position - 1].rebuild_order {
+            let cd = &self.archive_chunks[index as usize];
+            ci.add_chunk(cd.checksum.clone(), cd.source_size as usize, &[offset]);
+            offset += cd.source_size as u64;
+        }
+        Some(ci)
+    }
+    /// Unpack the source of the given name to the output.
+    ///
+    /// Seeds are scanned in the given order for chunks of the source, which are then written
+    /// from there. The remaining chunks are fetched from the archive. Every chunk is verified
+    /// using the hasher before being written.
+    pub async fn unpack_source<W, S>(
+        &mut self,
+        name: &str,
+        output: W,
+        seeds: impl IntoIterator<Item = S>,
+        hasher: &ChunkHasher,
+    ) -> Result<(), UnpackError<R::Error>>
+    where
+        R: ArchiveReader,
+        W: AsyncWrite + AsyncSeek + Unpin + Send,
+        S: AsyncRead + Unpin + Send,
+    {
+        let size = self
+            .sources
+            .iter()
+            .find(|source| source.name == name)
+            .map(|source| source.size)
+            .ok_or_else(|| UnpackError::UnknownSource(name.to_string()))?;
+        let index = self.build_index_of_source(name).expect("source exists");
+        let mut output = CloneOutput::new(output, index).size_limit(size);
+        for seed in seeds {
+            if output.is_empty() {
+                break;
+            }
+            let mut chunker = self.chunker_config.new_chunker(seed);
+            while let Some(result) = chunker.next().await {
+                let (_offset, chunk) = result?;
+                output.feed(&chunk.verify_with(hasher)).await?;
+            }
+        }
+        let mut chunk_stream = self.chunk_stream(output.chunks());
+        while let Some(result) = chunk_stream.next().await {
+            let verified = result
+                .map_err(UnpackError::ReaderError)?
+                .decompress()
+                .map_err(UnpackError::CompressionError)?
+                .verify_with(hasher)
+                .map_err(|err| UnpackError::HashSumMismatch(Box::new(err)))?;
+            output.feed(&verified).await?;
+        }
+        output.flush().await?;
+        Ok(())
+    }
+    /// Unpack the source of the given name by giving every chunk to the sink, in source order
+    /// together with its offset in the source.
+    ///
+    /// Every unique chunk is fetched and decompressed once, chunks repeated later in the source
+    /// are kept in memory until their last use. Every chunk is verified using the hasher before
+    /// given to the sink. Unpacking stops at the first error returned by the sink.
+    pub async fn unpack_source_to_sink<F, Fut>(
+        &mut self,
+        name: &str,
+        hasher: &ChunkHasher,
+        mut sink: F,
+    ) -> Result<(), UnpackError<R::Error>>
+    where
+        R: ArchiveReader,
+        F: FnMut(u64, Bytes) -> Fut,
+        Fut: Future<Output = io::Result<()>>,
+    {
+        let order: Vec<usize> = match self.sources.iter().position(|source| source.name == name) {
+            Some(0) => self.source_order.clone(),
+            Some(position) => self.additional_sources[position - 1]
+                .rebuild_order
+                .iter()
+                .map(|&index| index as usize)
+                .collect(),
+            None => return Err(UnpackError::UnknownSource(name.to_string())),
+        };
+        let mut remaining_uses: HashMap<usize, usize> = HashMap::new();
+        let mut first_uses = Vec::new();
+        for &index in &order {
+            let uses = remaining_uses.entry(index).or_insert(0);
+            if *uses == 0 {
+                first_uses.push(index);
+            }
+            *uses += 1;
+        }
+        let archive_chunks = &self.archive_chunks;
+        let compression = self.chunk_compression;
+        let read_at: Vec<ChunkOffset> = first_uses
+            .iter()
+            .map(|&index| {
+                let cd = &archive_chunks[index];
+                ChunkOffset::new(cd.archive_offset, cd.archive_size)
+            })
+            .collect();
+        // Chunks are read in order of first use in source
+        let mut chunk_stream = self.reader.read_chunks(read_at);
+        let mut kept: HashMap<usize, Bytes> = HashMap::new();
+        let mut offset = 0;
+        for index in order {
+            let cd = &archive_chunks[index];
+            let data = match kept.get(&index) {
+                Some(data) => data.clone(),
+                None => {
+                    let data = chunk_stream
+                        .next()
+                        .await
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?
+                        .map_err(UnpackError::ReaderError)?;
+                    let (_hash, chunk) = archive_chunk(cd, compression, data)
+                        .decompress()
+                        .map_err(UnpackError::CompressionError)?
+                        .verify_with(hasher)
+                        .map_err(|err| UnpackError::HashSumMismatch(Box::new(err)))?
+                        .into_parts();
+                    chunk.into_inner()
+                }
+            };
+            let uses = remaining_uses.get_mut(&index).expect("chunk in source");
+            *uses -= 1;
+            if *uses > 0 {
+                kept.insert(index, data.clone());
+            } else {
+                kept.remove(&index);
+            }
+            sink(offset, data).await?;
+            offset += cd.source_size as u64;
+        }
+        Ok(())
+    }
+    /// Checkpoints of the source checksum, if stored in archive.
+    pub fn source_checkpoints(&self) -> Option<&SourceCheckpoints> {
+        self.source_checkpoints.as_ref()
+    }
+    /// Size of each part file if the chunk data is split across multiple files.
+    ///
+    /// Empty if all chunk data is stored in the archive itself.
+    pub fn chunk_data_part_sizes(&self) -> &[u64] {
+        &self.chunk_data_part_sizes
+    }
+    /// Size of the chunk data section, the sum of the part sizes if split.
+    pub fn chunk_data_size(&self) -> u64 {
+        if !self.chunk_data_part_sizes.is_empty() {
+            return self.chunk_data_part_sizes.iter().sum();
+        }
+        self.archive_chunks
+            .iter()
+            .map(|descriptor| descriptor.archive_end_offset() - self.chunk_data_offset)
+            .max()
+            .unwrap_or(0)
+    }
+    /// Blake2b-512 checksum of the whole chunk data section, if stored in archive.
+    pub fn chunk_data_checksum(&self) -> Option<&HashSum> {
+        self.chunk_data_checksum.as_ref()
+    }
+    /// Read the whole chunk data section in one pass and hash it, to compare against
+    /// [`Archive::chunk_data_checksum`].
+    pub async fn hash_chunk_data(&mut self) -> Result<HashSum, R::Error>
+    where
+        R: ArchiveReader,
+    {
+        const READ_SIZE: u64 = 1024 * 1024;
+        let part_sizes = if self.chunk_data_part_sizes.is_empty() {
+            vec![self.chunk_data_size()]
+        } else {
+            self.chunk_data_part_sizes.clone()
+        };
+        // Reads never span multiple parts
+        let mut read_at = Vec::new();
+        let mut part_offset = self.chunk_data_offset;
+        for part_size in part_sizes {
+            let part_end = part_offset + part_size;
+            let mut offset = part_offset;
+            while offset < part_end {
+                let size = READ_SIZE.min(part_end - offset);
+                read_at.push(ChunkOffset::new(offset, size as usize));
+                offset += size;
+            }
+            part_offset = part_end;
+        }
+        let mut hasher = Blake2b512::new();
+        let mut data = self.reader.read_chunks(read_at);
+        while let Some(data) = data.next().await {
+            hasher.update(&data?);
+        }
+        Ok(HashSum::from(&hasher.finalize()[..]))
+    }
+    /// Alignment of the chunk data if the archive was built with padding between chunks.
+    ///
+    /// Every chunk offset is then a multiple of the alignment, both in the archive file and in
+    /// the part files of a split archive.
+    pub fn chunk_data_alignment(&self) -> Option<u64> {
+        self.chunk_data_alignment
+    }
+    /// Whether the archive stores a CRC32C for each chunk.
+    pub fn has_chunk_crc32c(&self) -> bool {
+        matches!(
+            self.archive_chunks.first(),
+            Some(descriptor) if descriptor.crc32c.is_some()
+        )
+    }
+    /// Read the chunk data of a split archive using the given part readers.
+    ///
+    /// The header is still read using the current reader. Fails if the number of readers
+    /// doesn't match the number of parts, which is none for an archive that isn't split.
+    pub fn with_part_readers(
+        self,
+        parts: Vec<R>,
+    ) -> Result<Archive<SplitReader<R>>, ArchiveError<R::Error>>
+    where
+        R: ArchiveReader,
+    {
+        if parts.len() != self.chunk_data_part_sizes.len() {
+            return Err(ArchiveError::PartCountMismatch {
+                expected: self.chunk_data_part_sizes.len(),
+                got: parts.len(),
+            });
+        }
+        let chunk_data_offset = self.chunk_data_offset;
+        let part_sizes = self.chunk_data_part_sizes.clone();
+        Ok(self.map_reader(|main| SplitReader::new(main, chunk_data_offset, &part_sizes, parts)))
+    }
+    /// Read the chunk data from a chunk store holding every chunk as an object named by its
+    /// hash, see [`chunk_store_path`](crate::archive_reader::chunk_store_path).
+    ///
+    /// The header is still read using the current reader.
+    pub fn with_chunk_store<S>(self, store: S) -> Archive<StoreReader<R, S>> {
+        let chunk_data_offset = self.chunk_data_offset;
+        let objects = self
+            .archive_chunks
+            .iter()
+            .map(|cd| (cd.archive_offset, cd.checksum.clone()))
+            .collect();
+        self.map_reader(|main| StoreReader::new(main, chunk_data_offset, objects, store))
+    }
+    // Replace the reader, keeping everything read from the header.
+    fn map_reader<T>(self, f: impl FnOnce(R) -> T) -> Archive<T> {
+        Archive {
+            reader: f(self.reader),
+            archive_chunks: self.archive_chunks,
+            source_order: self.source_order,
+            total_chunks: self.total_chunks,
+            header_size: self.header_size,
+            header_checksum: self.header_checksum,
+            chunk_compression: self.chunk_compression,
+            created_by_app_version: self.created_by_app_version,
+            chunk_data_offset: self.chunk_data_offset,
+            source_total_size: self.source_total_size,
+            source_checksum: self.source_checksum,
+            source_entry: self.source_entry,
+            source_checkpoints: self.source_checkpoints,
+            chunk_data_part_sizes: self.chunk_data_part_sizes,
+            chunker_config: self.chunker_config,
+            chunk_hash_length: self.chunk_hash_length,
+            chunk_hasher: self.chunk_hasher,
+            source_name: self.source_name,
+            additional_sources: self.additional_sources,
+            sources: self.sources,
+            chunk_data_checksum: self.chunk_data_checksum,
+            chunk_data_alignment: self.chunk_data_alignment,
+            content_type: self.content_type,
+        }
+    }
     /// Get the chunker configuration used when building the archive.
     pub fn chunker_config(&self) -> &chunker::Config {
         &self.chunker_config
@@ -235,6 +737,13 @@ impl<R> Archive<R> {
     pub fn chunk_hash_length(&self) -> usize {
         self.chunk_hash_length
     }
+    /// Get the hasher to verify the chunks of the archive with.
+    ///
+    /// A personalization is not stored in the archive, an archive created using one has to be
+    /// verified using a hasher created by [`ChunkHasher::with_personalization`].
+    pub fn chunk_hasher(&self) -> ChunkHasher {
+        self.chunk_hasher
+    }
     /// Get the compression used for chunks in the archive.
     pub fn chunk_compression(&self) -> Option<Compression> {
         self.chunk_compression
@@ -261,6 +770,18 @@ impl<R> Archive<R> {
         });
         ci
     }
+    /// Reader of the archive, for reading chunk data as stored at the descriptor offsets.
+    pub fn reader_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+    /// Bypass any caches between the reader and the archive, see
+    /// [`ArchiveReader::bypass_cache`].
+    pub fn bypass_cache(&mut self)
+    where
+        R: ArchiveReader,
+    {
+        self.reader.bypass_cache();
+    }
     /// Get a stream of chunks from the archive.
     pub fn chunk_stream<'a>(
         &'a mut self,
@@ -278,85 +799,535 @@ impl<R> Archive<R> {
             .iter()
             .map(|cd| ChunkOffset::new(cd.archive_offset, cd.archive_size))
             .collect();
-        let archive_chunk_compression = self.chunk_compression().map(|c| c.algorithm);
+        let compression = self.chunk_compression();
         self.reader
             .read_chunks(read_at)
             .enumerate()
             .map(move |(index, result)| {
-                let source_size = descriptors[index].source_size as usize;
-                match result {
-                    Ok(chunk) => Ok(CompressedArchiveChunk {
-                        chunk: CompressedChunk {
-                            compression: if source_size == chunk.len() {
-                                // When chunk size matches the source chunk size chunk has not been compressed
-                                // since compressing it probably made it bigger.
-                                None
-                            } else {
-                                archive_chunk_compression
-                            },
-                            data: chunk,
-                            source_size,
-                        },
-                        expected_hash: descriptors[index].checksum.clone(),
-                    }),
-                    Err(err) => Err(err),
-                }
+                result.map(|chunk| archive_chunk(descriptors[index], compression, chunk))
+            })
+    }
+    /// Get a stream of chunks in source order, starting with the chunk holding the source byte
+    /// at `offset`. Each chunk comes with its offset in source.
+    ///
+    /// Writing the source from a stream which is interrupted after emitting some number of
+    /// bytes can then be resumed by a new stream starting at that number of bytes, only fetching
+    /// the chunks not yet fully written.
+    pub fn source_chunk_stream<'a>(
+        &'a mut self,
+        offset: u64,
+    ) -> impl Stream<Item = Result<(u64, CompressedArchiveChunk), R::Error>> + Unpin + Sized + 'a
+    where
+        R: ArchiveReader + 'a,
+    {
+        let archive_chunks = &self.archive_chunks;
+        let chunks: Vec<(u64, &ChunkDescriptor)> = self
+            .source_order
+            .iter()
+            .scan(0, |chunk_offset, &index| {
+                let cd = &archive_chunks[index];
+                let offset = *chunk_offset;
+                *chunk_offset += cd.source_size as u64;
+                Some((offset, cd))
+            })
+            .filter(|(chunk_offset, cd)| chunk_offset + cd.source_size as u64 > offset)
+            .collect();
+        let read_at: Vec<ChunkOffset> = chunks
+            .iter()
+            .map(|(_, cd)| ChunkOffset::new(cd.archive_offset, cd.archive_size))
+            .collect();
+        let compression = self.chunk_compression();
+        self.reader
+            .read_chunks(read_at)
+            .enumerate()
+            .map(move |(index, result)| {
+                let (chunk_offset, cd) = chunks[index];
+                result.map(|chunk| (chunk_offset, archive_chunk(cd, compression, chunk)))
             })
     }
 }
 
-fn chunker_config_from_params<R>(
-    p: dict::ChunkerParameters,
-) -> Result<chunker::Config, ArchiveError<R>> {
-    use dict::chunker_parameters::ChunkingAlgorithm;
-    match ChunkingAlgorithm::from_i32(p.chunking_algorithm) {
-        Some(ChunkingAlgorithm::Buzhash) => Ok(chunker::Config::BuzHash(chunker::FilterConfig {
-            filter_bits: chunker::FilterBits::from_bits(p.chunk_filter_bits),
-            min_chunk_size: p.min_chunk_size as usize,
-            max_chunk_size: p.max_chunk_size as usize,
-            window_size: p.rolling_hash_window_size as usize,
-        })),
-        Some(ChunkingAlgorithm::Rollsum) => Ok(chunker::Config::RollSum(chunker::FilterConfig {
-            filter_bits: chunker::FilterBits::from_bits(p.chunk_filter_bits),
-            min_chunk_size: p.min_chunk_size as usize,
-            max_chunk_size: p.max_chunk_size as usize,
-            window_size: p.rolling_hash_window_size as usize,
-        })),
-        Some(ChunkingAlgorithm::FixedSize) => {
-            Ok(chunker::Config::FixedSize(p.max_chunk_size as usize))
-        }
-        _ => Err(ArchiveError::invalid_archive("unknown chunking algorithm")),
+fn archive_chunk(
+    descriptor: &ChunkDescriptor,
+    compression: Option<Compression>,
+    data: bytes::Bytes,
+) -> CompressedArchiveChunk {
+    let source_size = descriptor.source_size as usize;
+    CompressedArchiveChunk {
+        chunk: CompressedChunk {
+            compression: if source_size == data.len() {
+                // When chunk size matches the source chunk size chunk has not been compressed
+                // since compressing it probably made it bigger.
+                None
+            } else {
+                compression.map(|c| c.algorithm)
+            },
+            data,
+            source_size,
+            window_log: compression.and_then(|c| c.window_log),
+        },
+        expected_hash: descriptor.checksum.clone(),
+        expected_crc32c: descriptor.crc32c,
+    }
+}
+
+pub(crate) fn source_entry_from_dictionary<R>(
+    entry: Option<dict::SourceEntry>,
+) -> Result<SourceEntry, ArchiveError<R>> {
+    use dict::source_entry::EntryType;
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(SourceEntry::File),
+    };
+    match EntryType::from_i32(entry.entry_type) {
+        Some(EntryType::File) => Ok(SourceEntry::File),
+        Some(EntryType::Symlink) if !entry.link_target.is_empty() => {
+            Ok(SourceEntry::Symlink(entry.link_target))
+        }
+        Some(EntryType::Symlink) => Err(ArchiveError::invalid_archive(
+            "symbolic link without a target",
+        )),
+        // A plain file name, a path could make a hard link point anywhere
+        Some(EntryType::HardLink)
+            if !entry.link_target.is_empty()
+                && entry.link_target != "."
+                && entry.link_target != ".."
+                && !entry.link_target.contains(&['/', '\\'][..]) =>
+        {
+            Ok(SourceEntry::HardLink(entry.link_target))
+        }
+        Some(EntryType::HardLink) => {
+            Err(ArchiveError::invalid_archive("invalid hard link file name"))
+        }
+        None => Err(ArchiveError::invalid_archive("unknown source entry type")),
+    }
+}
+
+pub(crate) fn source_checkpoints_from_dictionary<R>(
+    checkpoints: Option<dict::SourceCheckpoints>,
+) -> Result<Option<SourceCheckpoints>, ArchiveError<R>> {
+    match checkpoints {
+        // Checkpoints can't be verified with a zero interval
+        Some(checkpoints) if checkpoints.interval == 0 => Err(ArchiveError::invalid_archive(
+            "zero source checkpoint interval",
+        )),
+        checkpoints => Ok(checkpoints.map(SourceCheckpoints::from)),
     }
 }
 
-fn compression_from_dictionary<R>(
+// The archive header as read, verified against the header checksum.
+pub(crate) struct RawHeader {
+    // Pre-header, dictionary, chunk data offset and header checksum
+    pub(crate) header: Vec<u8>,
+    pub(crate) dictionary_size: usize,
+    pub(crate) header_checksum: HashSum,
+    pub(crate) chunk_data_offset: u64,
+    pub(crate) encrypted: bool,
+}
+
+impl RawHeader {
+    pub(crate) async fn read<R>(reader: &mut R) -> Result<Self, ArchiveError<R::Error>>
+    where
+        R: ArchiveReader,
+    {
+        // Read the pre-header (file magic and size)
+        let mut header: Vec<u8> = reader
+            .read_at(0, header::PRE_HEADER_SIZE)
+            .await
+            .map_err(ArchiveError::ReaderError)?
+            .to_vec();
+        let encrypted = header::has_encrypted_archive_magic(&header);
+        if !encrypted && !header::has_archive_magic(&header) {
+            return Err(ArchiveError::NotAnArchive);
+        }
+
+        let dictionary_size = u64::from_le_bytes(
+            header[header::ARCHIVE_MAGIC.len()..header::PRE_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+
+        // Read the dictionary, chunk data offset and header hash
+        header.extend_from_slice(
+            &reader
+                .read_at(header::PRE_HEADER_SIZE as u64, dictionary_size + 8 + 64)
+                .await
+                .map_err(ArchiveError::ReaderError)?,
+        );
+
+        // Verify the header against the header checksum
+        let header_checksum = {
+            let mut hasher = Blake2b512::new();
+            let offs = header::PRE_HEADER_SIZE + dictionary_size + 8;
+            hasher.update(&header[..offs]);
+            let header_checksum = HashSum::from(&header[offs..(offs + 64)]);
+            if header_checksum != &hasher.finalize()[..] {
+                return Err(ArchiveError::invalid_archive("invalid header checksum"));
+            }
+            header_checksum
+        };
+
+        // Get chunk data offset
+        let chunk_data_offset = {
+            let offs = header::PRE_HEADER_SIZE + dictionary_size;
+            u64::from_le_bytes(header[offs..(offs + 8)].try_into().unwrap())
+        };
+        Ok(Self {
+            header,
+            dictionary_size,
+            header_checksum,
+            chunk_data_offset,
+            encrypted,
+        })
+    }
+
+    // The encoded chunk dictionary, decrypted using the key if encrypted.
+    pub(crate) fn dictionary<E>(
+        &self,
+        key: Option<&DictionaryKey>,
+    ) -> Result<Cow<'_, [u8]>, ArchiveError<E>> {
+        let dictionary =
+            &self.header[header::PRE_HEADER_SIZE..header::PRE_HEADER_SIZE + self.dictionary_size];
+        match (self.encrypted, key) {
+            (false, _) => Ok(Cow::Borrowed(dictionary)),
+            (true, None) => Err(ArchiveError::EncryptedDictionary),
+            (true, Some(key)) => Ok(Cow::Owned(
+                key.decrypt(dictionary)
+                    .map_err(ArchiveError::invalid_archive)?,
+            )),
+        }
+    }
+}
+
+// A checksum of an unknown algorithm is ignored, it's only used to verify the archive.
+pub(crate) fn chunk_data_checksum(checksum: Option<dict::ChunkDataChecksum>) -> Option<HashSum> {
+    checksum.and_then(|checksum| {
+        match dict::chunk_data_checksum::ChecksumAlgorithm::from_i32(checksum.algorithm) {
+            Some(dict::chunk_data_checksum::ChecksumAlgorithm::Blake2b512) => {
+                Some(HashSum::from(checksum.checksum))
+            }
+            None => None,
+        }
+    })
+}
+
+pub(crate) fn chunk_data_alignment(alignment: u32) -> Option<u64> {
+    match alignment {
+        0 => None,
+        alignment => Some(u64::from(alignment)),
+    }
+}
+
+pub(crate) fn content_type(content_type: String) -> Option<String> {
+    Some(content_type).filter(|content_type| !content_type.is_empty())
+}
+
+// Rebuild order of a source, stored either as is or as runs. Runs are bounded by the chunk
+// sizes, as a few bytes of runs could otherwise expand to any number of chunks.
+fn rebuild_order<R>(
+    rebuild_order: Vec<u32>,
+    runs: Option<dict::RebuildOrderRuns>,
+    chunk_sizes: &[u32],
+    min_chunk_size: usize,
+    source_size: u64,
+) -> Result<Vec<u32>, ArchiveError<R>> {
+    match runs {
+        Some(runs) => runs
+            .decode(chunk_sizes, min_chunk_size as u64, source_size)
+            .ok_or_else(|| ArchiveError::invalid_archive("invalid rebuild order runs")),
+        None => Ok(rebuild_order),
+    }
+}
+
+impl dict::ChunkDataChecksum {
+    /// Blake2b-512 checksum of the chunk data section.
+    pub fn blake2b_512(checksum: &HashSum) -> Self {
+        Self {
+            algorithm: dict::chunk_data_checksum::ChecksumAlgorithm::Blake2b512 as i32,
+            checksum: checksum.to_vec(),
+        }
+    }
+}
+
+pub(crate) fn compression_from_dictionary<R>(
     c: dict::ChunkCompression,
 ) -> Result<Option<Compression>, ArchiveError<R>> {
     use dict::chunk_compression::CompressionType;
-    match CompressionType::from_i32(c.compression) {
+    let algorithm = match CompressionType::from_i32(c.compression) {
         #[cfg(feature = "lzma-compression")]
-        Some(dict::chunk_compression::CompressionType::Lzma) => Ok(Some(Compression {
-            algorithm: CompressionAlgorithm::Lzma,
-            level: c.compression_level,
-        })),
+        Some(dict::chunk_compression::CompressionType::Lzma) => CompressionAlgorithm::Lzma,
         #[cfg(not(feature = "lzma-compression"))]
-        Some(CompressionType::Lzma) => Err(ArchiveError::invalid_archive(
-            "LZMA compression not enabled",
-        )),
+        Some(CompressionType::Lzma) => {
+            return Err(ArchiveError::invalid_archive(
+                crate::compression::CompressionFeatureMissingError::new("LZMA", "lzma-compression"),
+            ))
+        }
         #[cfg(feature = "zstd-compression")]
-        Some(CompressionType::Zstd) => Ok(Some(Compression {
-            algorithm: CompressionAlgorithm::Zstd,
-            level: c.compression_level,
-        })),
+        Some(CompressionType::Zstd) => CompressionAlgorithm::Zstd,
         #[cfg(not(feature = "zstd-compression"))]
-        Some(CompressionType::Zstd) => Err(ArchiveError::invalid_archive(
-            "ZSTD compression not enabled",
+        Some(CompressionType::Zstd) => {
+            return Err(ArchiveError::invalid_archive(
+                crate::compression::CompressionFeatureMissingError::new("zstd", "zstd-compression"),
+            ))
+        }
+        Some(CompressionType::Brotli) => CompressionAlgorithm::Brotli,
+        Some(CompressionType::None) => return Ok(None),
+        None if c.compression as u32 >= crate::compression::CUSTOM_CODEC_MIN_ID => {
+            return Ok(Some(
+                Compression::custom(c.compression as u32).map_err(ArchiveError::invalid_archive)?,
+            ))
+        }
+        None => return Err(ArchiveError::invalid_archive("unknown compression")),
+    };
+    let compression = Compression {
+        algorithm,
+        level: c.compression_level,
+        window_log: None,
+        threads: None,
+    };
+    // The decoder allocates a buffer of the window size, only accept what the compressor could
+    // have used.
+    match c.window_log {
+        0 => Ok(Some(compression)),
+        window_log => Ok(Some(
+            compression
+                .with_window_log(window_log)
+                .map_err(ArchiveError::invalid_archive)?,
         )),
-        Some(CompressionType::Brotli) => Ok(Some(Compression {
-            algorithm: CompressionAlgorithm::Brotli,
-            level: c.compression_level,
-        })),
-        Some(CompressionType::None) => Ok(None),
-        None => Err(ArchiveError::invalid_archive("unknown compression")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_reader::IoReader;
+    use std::io::Cursor;
+
+    const CHUNK: &[u8] = b"chunk data";
+
+    fn dictionary_with_hash_length(
+        chunk_hash_length: u32,
+        checksum_length: usize,
+    ) -> dict::ChunkDictionary {
+        let chunk = CHUNK;
+        dict::ChunkDictionary {
+            rebuild_order: vec![0],
+            application_version: "test".to_string(),
+            chunk_descriptors: vec![dict::ChunkDescriptor {
+                checksum: HashSum::b2_digest(chunk).slice()[..checksum_length].to_vec(),
+                archive_size: chunk.len() as u32,
+                archive_offset: 0,
+                source_size: chunk.len() as u32,
+                crc32c: 0,
+            }],
+            source_checksum: HashSum::b2_digest(chunk).to_vec(),
+            chunk_compression: Some(None.into()),
+            source_total_size: chunk.len() as u64,
+            chunker_params: Some(dict::ChunkerParameters {
+                chunk_filter_bits: 0,
+                min_chunk_size: 0,
+                max_chunk_size: 16,
+                rolling_hash_window_size: 0,
+                chunk_hash_length,
+                chunking_algorithm: dict::chunker_parameters::ChunkingAlgorithm::FixedSize as i32,
+                buzhash_table: vec![],
+            }),
+            source_entry: None,
+            source_checkpoints: None,
+            chunk_data_part_sizes: vec![],
+            source_name: String::new(),
+            additional_sources: vec![],
+            chunk_crc32c: false,
+            rebuild_order_runs: None,
+            chunk_data_checksum: None,
+            chunk_data_alignment: 0,
+            content_type: String::new(),
+        }
+    }
+
+    fn build_archive(dictionary: &dict::ChunkDictionary) -> Vec<u8> {
+        let mut archive = header::build(dictionary, None).unwrap();
+        archive.extend(CHUNK);
+        archive
+    }
+
+    fn archive_with_hash_length(chunk_hash_length: u32, checksum_length: usize) -> Vec<u8> {
+        build_archive(&dictionary_with_hash_length(
+            chunk_hash_length,
+            checksum_length,
+        ))
+    }
+
+    async fn init(archive: Vec<u8>) -> Result<Archive<IoReader<Cursor<Vec<u8>>>>, ()> {
+        Archive::try_init(IoReader::new(Cursor::new(archive)))
+            .await
+            .map_err(|_| ())
+    }
+
+    #[tokio::test]
+    async fn valid_hash_length() {
+        let archive = init(archive_with_hash_length(64, 64)).await.unwrap();
+        assert_eq!(archive.chunk_hash_length(), 64);
+        let archive = init(archive_with_hash_length(8, 8)).await.unwrap();
+        assert_eq!(archive.chunk_hash_length(), 8);
+    }
+
+    #[tokio::test]
+    async fn invalid_hash_length() {
+        assert!(init(archive_with_hash_length(65, 64)).await.is_err());
+        assert!(init(archive_with_hash_length(0, 0)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn checksum_length_mismatch() {
+        assert!(init(archive_with_hash_length(32, 64)).await.is_err());
+        assert!(init(archive_with_hash_length(32, 16)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn chunk_larger_than_max_chunk_size() {
+        let mut dictionary = dictionary_with_hash_length(64, 64);
+        dictionary.chunk_descriptors[0].source_size = u32::MAX;
+        match Archive::try_init(IoReader::new(Cursor::new(build_archive(&dictionary)))).await {
+            Err(ArchiveError::InvalidArchive(err)) => assert_eq!(
+                err.to_string(),
+                format!("chunk size {} exceeds the max chunk size 16", u32::MAX)
+            ),
+            other => panic!("unexpected result {:?}", other.map(|_| ()).err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn zero_source_checkpoint_interval() {
+        let mut dictionary = dictionary_with_hash_length(64, 64);
+        dictionary.source_checkpoints = Some(dict::SourceCheckpoints {
+            interval: 0,
+            checksums: vec![],
+        });
+        match Archive::try_init(IoReader::new(Cursor::new(build_archive(&dictionary)))).await {
+            Err(ArchiveError::InvalidArchive(err)) => {
+                assert_eq!(err.to_string(), "zero source checkpoint interval")
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ()).err()),
+        }
+    }
+
+    #[test]
+    fn window_log_out_of_range() {
+        let compression = |window_log| {
+            compression_from_dictionary::<()>(dict::ChunkCompression {
+                compression: dict::chunk_compression::CompressionType::Brotli as i32,
+                compression_level: 6,
+                window_log,
+            })
+        };
+        assert_eq!(compression(0).unwrap().unwrap().window_log(), None);
+        assert_eq!(compression(24).unwrap().unwrap().window_log(), Some(24));
+        match compression(u32::MAX) {
+            Err(ArchiveError::InvalidArchive(err)) => assert_eq!(
+                err.to_string(),
+                "Brotli window log out of range (valid range is 10-24)"
+            ),
+            other => panic!("unexpected result {:?}", other.map(|_| ()).err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn unpack_sources_by_name() {
+        let chunks: Vec<Vec<u8>> = (1..=3u8).map(|n| vec![n; 16]).collect();
+        let first = [&chunks[0][..], &chunks[1]].concat();
+        let second = [&chunks[2][..], &chunks[1], &chunks[0], &chunks[2]].concat();
+        let mut dictionary = dictionary_with_hash_length(64, 64);
+        dictionary.chunk_descriptors = chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| dict::ChunkDescriptor {
+                checksum: HashSum::b2_digest(chunk).to_vec(),
+                archive_size: chunk.len() as u32,
+                archive_offset: (index * chunk.len()) as u64,
+                source_size: chunk.len() as u32,
+                crc32c: 0,
+            })
+            .collect();
+        dictionary.rebuild_order = vec![0, 1];
+        dictionary.source_name = "first".to_string();
+        dictionary.source_checksum = HashSum::b2_digest(&first).to_vec();
+        dictionary.source_total_size = first.len() as u64;
+        dictionary.additional_sources = vec![dict::Source {
+            name: "second".to_string(),
+            source_checksum: HashSum::b2_digest(&second).to_vec(),
+            source_total_size: second.len() as u64,
+            rebuild_order: vec![2, 1, 0, 2],
+            rebuild_order_runs: None,
+            source_entry: None,
+        }];
+        let mut archive_data = header::build(&dictionary, None).unwrap();
+        archive_data.extend(chunks.concat());
+        let mut archive = init(archive_data).await.unwrap();
+
+        let sources: Vec<(&str, u64)> = archive
+            .sources()
+            .iter()
+            .map(|source| (source.name.as_str(), source.size))
+            .collect();
+        assert_eq!(sources, vec![("first", 32), ("second", 64)]);
+        assert_eq!(archive.sources()[1].checksum, HashSum::b2_digest(&second));
+
+        let hasher = ChunkHasher::default();
+        let no_seeds: Vec<&[u8]> = vec![];
+        for (name, expected) in &[("first", &first), ("second", &second)] {
+            let mut output = Cursor::new(Vec::new());
+            archive
+                .unpack_source(name, &mut output, no_seeds.clone(), &hasher)
+                .await
+                .unwrap();
+            assert_eq!(&output.into_inner(), *expected);
+        }
+        // Chunks of the second source are found in the first one used as seed
+        let mut output = Cursor::new(Vec::new());
+        archive
+            .unpack_source("second", &mut output, vec![&first[..]], &hasher)
+            .await
+            .unwrap();
+        assert_eq!(output.into_inner(), second);
+        assert!(matches!(
+            archive
+                .unpack_source("third", Cursor::new(Vec::new()), no_seeds, &hasher)
+                .await,
+            Err(UnpackError::UnknownSource(_))
+        ));
+
+        // Chunks given to a sink reassemble the source
+        for (name, expected) in &[("first", &first), ("second", &second)] {
+            let mut received: Vec<(u64, Bytes)> = Vec::new();
+            archive
+                .unpack_source_to_sink(name, &hasher, |offset, data| {
+                    received.push((offset, data));
+                    async { Ok(()) }
+                })
+                .await
+                .unwrap();
+            let offsets: Vec<u64> = received.iter().map(|(offset, _)| *offset).collect();
+            assert_eq!(
+                offsets,
+                (0..expected.len() as u64).step_by(16).collect::<Vec<_>>()
+            );
+            let data: Vec<u8> = received
+                .iter()
+                .flat_map(|(_, data)| data.to_vec())
+                .collect();
+            assert_eq!(&data, *expected);
+        }
+        let err = archive
+            .unpack_source_to_sink("second", &hasher, |offset, _data| async move {
+                if offset > 0 {
+                    Err(io::Error::new(io::ErrorKind::Other, "sink is full"))
+                } else {
+                    Ok(())
+                }
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, UnpackError::IoError(_)));
     }
 }
@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use core::pin::Pin;
+use futures_util::stream::Stream;
+use tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::archive_reader::{ArchiveReader, ChunkOffset};
+
+// Writer of the ranges read from the fallback reader.
+trait CacheWriter: AsyncWrite + AsyncSeek + Unpin + Send {}
+
+impl<T> CacheWriter for T where T: AsyncWrite + AsyncSeek + Unpin + Send {}
+
+/// Read an archive from a primary reader, falling back to another reader for every range the
+/// primary one fails to read.
+///
+/// Useful to read a local copy of an archive which may be incomplete, with a remote archive
+/// as fallback. Both readers must hold the same archive. Ranges read from the fallback reader
+/// may also be written to a cache, e.g. to fill in the local copy.
+///
+/// Chunks are read one at a time from the primary reader, which suits a local copy with fast
+/// random access.
+pub struct FallbackReader<P, F> {
+    primary: P,
+    fallback: F,
+    cache: Option<Box<dyn CacheWriter>>,
+    // Skip the primary reader, set when bypassing caches.
+    fallback_only: bool,
+}
+
+impl<P, F> FallbackReader<P, F> {
+    /// Create a new reader trying `primary` first and `fallback` for anything it fails to read.
+    pub fn new(primary: P, fallback: F) -> Self {
+        Self {
+            primary,
+            fallback,
+            cache: None,
+            fallback_only: false,
+        }
+    }
+
+    /// Write every range read from the fallback reader to the cache, at its archive offset.
+    ///
+    /// The cache is dropped on the first error writing to it, without failing the read.
+    pub fn cache<W>(mut self, cache: W) -> Self
+    where
+        W: AsyncWrite + AsyncSeek + Unpin + Send + 'static,
+    {
+        self.cache = Some(Box::new(cache));
+        self
+    }
+}
+
+impl<P, F> FallbackReader<P, F>
+where
+    P: ArchiveReader + Send,
+    F: ArchiveReader + Send,
+{
+    async fn write_cache(&mut self, offset: u64, data: &[u8]) {
+        if let Some(cache) = &mut self.cache {
+            let result = async {
+                cache.seek(std::io::SeekFrom::Start(offset)).await?;
+                cache.write_all(data).await?;
+                cache.flush().await
+            }
+            .await;
+            if let Err(err) = result {
+                log::warn!("failed to cache range read from fallback: {}", err);
+                self.cache = None;
+            }
+        }
+    }
+
+    async fn read(&mut self, offset: u64, size: usize) -> Result<Bytes, F::Error> {
+        if !self.fallback_only {
+            if let Ok(data) = self.primary.read_at(offset, size).await {
+                return Ok(data);
+            }
+            log::debug!(
+                "reading {} bytes at offset {} from fallback reader",
+                size,
+                offset
+            );
+        }
+        let data = self.fallback.read_at(offset, size).await?;
+        self.write_cache(offset, &data).await;
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl<P, F> ArchiveReader for FallbackReader<P, F>
+where
+    P: ArchiveReader + Send,
+    F: ArchiveReader + Send,
+    F::Error: Send,
+{
+    type Error = F::Error;
+
+    async fn read_at(&mut self, offset: u64, size: usize) -> Result<Bytes, Self::Error> {
+        self.read(offset, size).await
+    }
+
+    fn read_chunks<'a>(
+        &'a mut self,
+        chunks: Vec<ChunkOffset>,
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Send + 'a>> {
+        if self.fallback_only && self.cache.is_none() {
+            return self.fallback.read_chunks(chunks);
+        }
+        Box::pin(futures_util::stream::unfold(
+            (self, chunks.into_iter()),
+            |(reader, mut chunks)| async move {
+                let chunk = chunks.next()?;
+                let result = reader.read(chunk.offset, chunk.size).await;
+                Some((result, (reader, chunks)))
+            },
+        ))
+    }
+
+    fn bypass_cache(&mut self) {
+        // The primary reader is a cache of the fallback one
+        self.fallback_only = true;
+        self.fallback.bypass_cache();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_reader::IoReader;
+    use futures_util::StreamExt;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    // Cursor shared with the test, to look at what was cached.
+    #[derive(Clone, Default)]
+    struct SharedCursor(Arc<Mutex<Cursor<Vec<u8>>>>);
+
+    impl AsyncWrite for SharedCursor {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+            buf: &[u8],
+        ) -> core::task::Poll<std::io::Result<usize>> {
+            Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+        }
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<std::io::Result<()>> {
+            core::task::Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<std::io::Result<()>> {
+            core::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncSeek for SharedCursor {
+        fn start_seek(self: Pin<&mut Self>, position: std::io::SeekFrom) -> std::io::Result<()> {
+            Pin::new(&mut *self.0.lock().unwrap()).start_seek(position)
+        }
+        fn poll_complete(
+            self: Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<std::io::Result<u64>> {
+            Pin::new(&mut *self.0.lock().unwrap()).poll_complete(cx)
+        }
+    }
+
+    type Reader = FallbackReader<IoReader<Cursor<Vec<u8>>>, IoReader<Cursor<Vec<u8>>>>;
+
+    // A primary copy missing everything from offset 4.
+    fn fallback_reader() -> Reader {
+        FallbackReader::new(
+            IoReader::new(Cursor::new(vec![0, 1, 2, 3])),
+            IoReader::new(Cursor::new(vec![10, 11, 12, 13, 14, 15, 16, 17])),
+        )
+    }
+
+    #[tokio::test]
+    async fn read_missing_range_from_fallback() {
+        let cache = SharedCursor::default();
+        let mut reader = fallback_reader().cache(cache.clone());
+        assert_eq!(&reader.read_at(1, 2).await.unwrap()[..], &[1, 2]);
+        assert_eq!(&reader.read_at(3, 2).await.unwrap()[..], &[13, 14]);
+        assert_eq!(&reader.read_at(6, 2).await.unwrap()[..], &[16, 17]);
+        assert!(reader.read_at(7, 2).await.is_err());
+        assert_eq!(
+            cache.0.lock().unwrap().get_ref(),
+            &vec![0, 0, 0, 13, 14, 0, 16, 17]
+        );
+    }
+
+    async fn read_chunks(reader: &mut Reader) -> Vec<Bytes> {
+        reader
+            .read_chunks(vec![
+                ChunkOffset::new(5, 2),
+                ChunkOffset::new(0, 2),
+                ChunkOffset::new(2, 2),
+            ])
+            .map(|result| result.unwrap())
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn read_chunks_in_requested_order() {
+        let mut reader = fallback_reader();
+        assert_eq!(
+            read_chunks(&mut reader).await,
+            vec![vec![15, 16], vec![0, 1], vec![2, 3]]
+        );
+        // Everything is read from the fallback when bypassing caches
+        reader.bypass_cache();
+        assert_eq!(
+            read_chunks(&mut reader).await,
+            vec![vec![15, 16], vec![10, 11], vec![12, 13]]
+        );
+    }
+}
@@ -7,14 +7,15 @@ use std::future::Future;
 use std::time::Duration;
 use tokio::time::sleep;
 
-use crate::archive_reader::HttpReaderError;
+use super::retry_backoff::{RetryBackoff, RetryJitter};
+use crate::archive_reader::{CacheValidators, HttpReaderError};
 
 pub(crate) struct HttpRangeRequest {
     request: RequestBuilder,
     state: RequestState,
     size: u64,
     offset: u64,
-    retry_delay: Duration,
+    retry_backoff: RetryBackoff,
     retry_count: u32,
 }
 
@@ -24,14 +25,14 @@ impl HttpRangeRequest {
             request,
             offset,
             size,
-            retry_delay: Duration::from_secs(0),
+            retry_backoff: RetryBackoff::new(Duration::from_secs(0), RetryJitter::None, None),
             retry_count: 0,
             state: RequestState::Init,
         }
     }
 
-    pub fn retry(mut self, retry_count: u32, retry_delay: Duration) -> Self {
-        self.retry_delay = retry_delay;
+    pub fn retry(mut self, retry_count: u32, retry_backoff: RetryBackoff) -> Self {
+        self.retry_backoff = retry_backoff;
         self.retry_count = retry_count;
         self
     }
@@ -40,17 +41,19 @@ impl HttpRangeRequest {
         request: RequestBuilder,
         offset: u64,
         size: u64,
-    ) -> Result<Bytes, HttpReaderError> {
+    ) -> Result<(Bytes, CacheValidators), HttpReaderError> {
         let end_offset = offset + size - 1;
         let request = request.header(
             reqwest::header::RANGE,
             format!("bytes={}-{}", offset, end_offset),
         );
-        let response = request.send().await?;
-        Ok(response.bytes().await?)
+        let response = check_range_response(request.send().await?)?;
+        let validators = CacheValidators::from_headers(response.headers());
+        Ok((response.bytes().await?, validators))
     }
 
-    pub async fn single(mut self) -> Result<Bytes, HttpReaderError> {
+    /// Read the whole range, also returns the cache validators of the response.
+    pub async fn single(mut self) -> Result<(Bytes, CacheValidators), HttpReaderError> {
         loop {
             match Self::single_fail(
                 self.request
@@ -63,7 +66,7 @@ impl HttpRangeRequest {
             {
                 Ok(item) => return Ok(item),
                 Err(err) => {
-                    if self.retry_count == 0 {
+                    if self.retry_count == 0 || !err.is_retryable() {
                         return Err(err);
                     } else {
                         log::warn!("request failed (retrying soon): {}", err);
@@ -71,7 +74,7 @@ impl HttpRangeRequest {
                     }
                 }
             }
-            sleep(self.retry_delay).await;
+            sleep(self.retry_backoff.next_delay()).await;
         }
     }
 
@@ -94,6 +97,10 @@ impl HttpRangeRequest {
                 }
                 RequestState::Request(request) => match ready!(Pin::new(&mut *request).poll(cx)) {
                     Ok(response) => {
+                        let response = match check_range_response(response) {
+                            Ok(response) => response,
+                            Err(err) => return Poll::Ready(Some(Err(err))),
+                        };
                         self.state = RequestState::Stream(Box::new(response.bytes_stream()))
                     }
                     Err(err) => return Poll::Ready(Some(Err(HttpReaderError::from(err)))),
@@ -119,12 +126,13 @@ impl HttpRangeRequest {
         loop {
             match self.poll_read_fail(cx) {
                 Poll::Ready(Some(Err(err))) => {
-                    if self.retry_count == 0 {
+                    if self.retry_count == 0 || !err.is_retryable() {
                         return Poll::Ready(Some(Err(err)));
                     } else {
                         log::warn!("request failed (retrying soon): {}", err);
                         self.retry_count -= 1;
-                        self.state = RequestState::Delay(Box::pin(sleep(self.retry_delay)));
+                        self.state =
+                            RequestState::Delay(Box::pin(sleep(self.retry_backoff.next_delay())));
                     }
                 }
                 result => return result,
@@ -133,6 +141,18 @@ impl HttpRangeRequest {
     }
 }
 
+// A server not supporting range requests responds with the whole file (200 OK) instead
+// of the requested range (206 Partial Content). A conditional request for an unchanged
+// archive is responded to with 304 Not Modified and no content. The body of an error
+// response is never taken as the range.
+fn check_range_response(response: reqwest::Response) -> Result<reqwest::Response, HttpReaderError> {
+    match response.status() {
+        reqwest::StatusCode::OK => Err(HttpReaderError::RangesNotSupported),
+        reqwest::StatusCode::NOT_MODIFIED => Err(HttpReaderError::NotModified),
+        _ => Ok(response.error_for_status()?),
+    }
+}
+
 enum RequestState {
     Init,
     Request(Box<dyn Future<Output = Result<reqwest::Response, reqwest::Error>> + Send + Unpin>),
@@ -4,25 +4,64 @@ use core::pin::Pin;
 use core::task::{Context, Poll};
 use futures_util::{ready, stream::Stream, StreamExt};
 use reqwest::{RequestBuilder, Url};
-use std::{fmt, time::Duration};
+use std::{fmt, future::Future, time::Duration};
 
 use super::http_range_request::HttpRangeRequest;
-use crate::archive_reader::{ArchiveReader, ChunkOffset};
+use super::retry_backoff::RetryBackoff;
+use crate::archive_reader::{ArchiveReader, ChunkOffset, RetryJitter};
+
+/// Validators identifying a version of a remote archive, used for conditional requests.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheValidators {
+    /// Value of the ETag header.
+    pub etag: Option<String>,
+    /// Value of the Last-Modified header.
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    pub(crate) fn from_headers(headers: &reqwest::header::HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header(reqwest::header::ETAG),
+            last_modified: header(reqwest::header::LAST_MODIFIED),
+        }
+    }
+}
 
 /// Read a http/https hosted archive.
 pub struct HttpReader {
     request_builder: RequestBuilder,
     retry_count: u32,
     retry_delay: Duration,
+    retry_jitter: RetryJitter,
+    retry_jitter_seed: Option<u64>,
+    full_download_limit: u64,
+    // Whole archive, if downloaded since the server doesn't support range requests.
+    full_content: Option<Bytes>,
+    validators: CacheValidators,
 }
 
 impl HttpReader {
+    /// Default max size of an archive to download in full.
+    pub const DEFAULT_FULL_DOWNLOAD_LIMIT: u64 = 16 * 1024 * 1024;
+
     /// Create a remote archive reader using RequestBuilder for the http request.
     pub fn from_request(request_builder: RequestBuilder) -> Self {
         Self {
             request_builder,
             retry_count: 0,
             retry_delay: Duration::from_secs(0),
+            retry_jitter: RetryJitter::None,
+            retry_jitter_seed: None,
+            full_download_limit: Self::DEFAULT_FULL_DOWNLOAD_LIMIT,
+            full_content: None,
+            validators: CacheValidators::default(),
         }
     }
 
@@ -50,31 +89,190 @@ impl HttpReader {
         self
     }
 
+    /// Add random jitter to the delay between attempts to reconnect.
+    ///
+    /// Spreads out the retries of many clients failing at the same time.
+    #[must_use]
+    pub fn retry_jitter(mut self, retry_jitter: RetryJitter) -> Self {
+        self.retry_jitter = retry_jitter;
+        self
+    }
+
+    /// Seed the random generator of the retry jitter, for reproducible delays.
+    ///
+    /// By default a random seed is used for every request.
+    #[must_use]
+    pub fn retry_jitter_seed(mut self, seed: u64) -> Self {
+        self.retry_jitter_seed = Some(seed);
+        self
+    }
+
+    /// Set max size of an archive to download in full if the server doesn't support range
+    /// requests.
+    ///
+    /// The whole archive is then kept in memory and all reads are served from there. Archives
+    /// bigger than the limit results in an error.
+    #[must_use]
+    pub fn full_download_limit(mut self, limit: u64) -> Self {
+        self.full_download_limit = limit;
+        self
+    }
+
+    /// Only read the archive if it has changed since the given validators were received.
+    ///
+    /// Requests are sent with If-None-Match and If-Modified-Since headers. If the archive is
+    /// unchanged the server responds with 304 Not Modified and reads fails with
+    /// [`HttpReaderError::NotModified`], no archive data is downloaded.
+    #[must_use]
+    pub fn if_changed_since(mut self, validators: &CacheValidators) -> Self {
+        if let Some(etag) = &validators.etag {
+            self.request_builder = self
+                .request_builder
+                .header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            self.request_builder = self
+                .request_builder
+                .header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+        self
+    }
+
+    /// Cache validators of the most recent single read, used to check for updates of the
+    /// archive using [`HttpReader::if_changed_since`].
+    pub fn validators(&self) -> &CacheValidators {
+        &self.validators
+    }
+
+    /// Read up to `size` bytes at `offset`, fewer if the archive ends before.
+    ///
+    /// Reads a prefix of an archive of unknown size, like its header, in a single request.
+    pub async fn read_up_to(&mut self, offset: u64, size: usize) -> Result<Bytes, HttpReaderError> {
+        self.read_range(offset, size, true).await
+    }
+
+    async fn read_range(
+        &mut self,
+        offset: u64,
+        size: usize,
+        allow_short: bool,
+    ) -> Result<Bytes, HttpReaderError> {
+        if let Some(content) = &self.full_content {
+            return slice_content(content, offset, size, allow_short);
+        }
+        let request = HttpRangeRequest::new(
+            self.request_builder
+                .try_clone()
+                .ok_or(HttpReaderError::RequestNotClonable)?,
+            offset,
+            size as u64,
+        )
+        .retry(self.retry_count, self.retry_backoff());
+
+        let mut res = match request.single().await {
+            Ok((res, validators)) => {
+                self.validators = validators;
+                res
+            }
+            Err(HttpReaderError::RangesNotSupported) => {
+                let content = self.download_full().await?;
+                return slice_content(&content, offset, size, allow_short);
+            }
+            Err(err) => return Err(err),
+        };
+        if res.len() >= size {
+            // Truncate the response if bigger than requested size
+            Ok(res.split_to(size))
+        } else if allow_short {
+            Ok(res)
+        } else {
+            Err(HttpReaderError::UnexpectedEnd)
+        }
+    }
+
+    // Download the whole archive into memory.
+    async fn download_full(&mut self) -> Result<Bytes, HttpReaderError> {
+        let (content, validators) = download_full(
+            self.request_builder
+                .try_clone()
+                .ok_or(HttpReaderError::RequestNotClonable)?,
+            self.full_download_limit,
+        )
+        .await?;
+        self.validators = validators;
+        self.full_content = Some(content.clone());
+        Ok(content)
+    }
+
+    fn retry_backoff(&self) -> RetryBackoff {
+        RetryBackoff::new(self.retry_delay, self.retry_jitter, self.retry_jitter_seed)
+    }
+
     fn read_chunk_stream(
         &mut self,
         chunks: Vec<ChunkOffset>,
     ) -> impl Stream<Item = Result<Bytes, HttpReaderError>> + '_ {
+        let retry_backoff = self.retry_backoff();
         ChunkReader {
             request_builder: &self.request_builder,
+            full_download_limit: self.full_download_limit,
+            full_content: &mut self.full_content,
+            download: None,
             chunk_buf: BytesMut::new(),
             chunk_index: 0,
             num_adjacent_reads: 0,
             chunks,
             retry_count: self.retry_count,
-            retry_delay: self.retry_delay,
+            retry_backoff,
             request: None,
         }
     }
 }
 
+// Download the whole archive into memory, for a server responding to range requests with the
+// whole archive. Fails if the archive is bigger than the limit.
+async fn download_full(
+    request_builder: RequestBuilder,
+    limit: u64,
+) -> Result<(Bytes, CacheValidators), HttpReaderError> {
+    let too_large = || HttpReaderError::ArchiveTooLarge(limit);
+    let mut response = request_builder.send().await?.error_for_status()?;
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Err(HttpReaderError::NotModified);
+    }
+    let validators = CacheValidators::from_headers(response.headers());
+    if response.content_length().unwrap_or(0) > limit {
+        return Err(too_large());
+    }
+    let mut content = BytesMut::new();
+    while let Some(data) = response.chunk().await? {
+        if (content.len() + data.len()) as u64 > limit {
+            return Err(too_large());
+        }
+        content.extend(data);
+    }
+    log::warn!(
+        "server doesn't support range requests, downloaded whole archive ({} bytes)",
+        content.len()
+    );
+    Ok((content.freeze(), validators))
+}
+
+type FullDownload =
+    Pin<Box<dyn Future<Output = Result<(Bytes, CacheValidators), HttpReaderError>> + Send>>;
+
 struct ChunkReader<'a> {
     request_builder: &'a RequestBuilder,
+    full_download_limit: u64,
+    // Shared with the reader, so that the archive is only downloaded in full once.
+    full_content: &'a mut Option<Bytes>,
+    download: Option<FullDownload>,
     chunk_buf: BytesMut,
     chunks: Vec<ChunkOffset>,
     chunk_index: usize,
     num_adjacent_reads: usize,
     retry_count: u32,
-    retry_delay: Duration,
+    retry_backoff: RetryBackoff,
     request: Option<HttpRangeRequest>,
 }
 
@@ -90,6 +288,17 @@ where
                 return Poll::Ready(None);
             }
             let next = &chunks[0];
+            if let Some(content) = self.full_content.as_ref() {
+                let chunk = slice_content(content, next.offset, next.size, false);
+                self.chunk_index += 1;
+                return Poll::Ready(Some(chunk));
+            }
+            if let Some(download) = self.download.as_mut() {
+                let (content, _) = ready!(download.as_mut().poll(cx))?;
+                self.download = None;
+                *self.full_content = Some(content);
+                continue;
+            }
             if self.chunk_buf.len() >= next.size {
                 self.chunk_index += 1;
                 let chunk = self.chunk_buf.split_to(next.size).freeze();
@@ -113,7 +322,7 @@ where
                 self.chunk_buf.clear();
                 self.request = Some(
                     HttpRangeRequest::new(request_builder, next.offset, total_size)
-                        .retry(self.retry_count, self.retry_delay),
+                        .retry(self.retry_count, self.retry_backoff.clone()),
                 );
             };
 
@@ -123,6 +332,20 @@ where
                 Some(Ok(chunk)) => {
                     self.chunk_buf.extend(chunk);
                 }
+                Some(Err(HttpReaderError::RangesNotSupported)) => {
+                    // The server responded with the whole archive instead of the range, no
+                    // chunk of this request has been read yet.
+                    self.request = None;
+                    self.chunk_buf.clear();
+                    let request_builder = self
+                        .request_builder
+                        .try_clone()
+                        .ok_or(HttpReaderError::RequestNotClonable)?;
+                    self.download = Some(Box::pin(download_full(
+                        request_builder,
+                        self.full_download_limit,
+                    )));
+                }
                 Some(Err(err)) => return Poll::Ready(Some(Err(err))),
                 None => return Poll::Ready(Some(Err(HttpReaderError::UnexpectedEnd))),
             }
@@ -156,23 +379,16 @@ impl ArchiveReader for HttpReader {
     type Error = HttpReaderError;
 
     async fn read_at(&mut self, offset: u64, size: usize) -> Result<Bytes, HttpReaderError> {
-        let request = HttpRangeRequest::new(
-            self.request_builder
-                .try_clone()
-                .ok_or(HttpReaderError::RequestNotClonable)?,
-            offset,
-            size as u64,
-        )
-        .retry(self.retry_count, self.retry_delay);
+        self.read_range(offset, size, false).await
+    }
 
-        let mut res = request.single().await?;
-        if res.len() >= size {
-            // Truncate the response if bigger than requested size
-            Ok(res.split_to(size))
-        } else if res.len() < size {
-            Err(HttpReaderError::UnexpectedEnd)
-        } else {
-            Ok(res)
+    /// Requests are sent with `Cache-Control: no-cache` and an archive downloaded in full is
+    /// downloaded again.
+    fn bypass_cache(&mut self) {
+        self.full_content = None;
+        if let Some(request_builder) = self.request_builder.try_clone() {
+            self.request_builder =
+                request_builder.header(reqwest::header::CACHE_CONTROL, "no-cache");
         }
     }
 
@@ -180,22 +396,62 @@ impl ArchiveReader for HttpReader {
         &'a mut self,
         chunks: Vec<ChunkOffset>,
     ) -> Pin<Box<dyn Stream<Item = Result<Bytes, HttpReaderError>> + Send + 'a>> {
+        if let Some(content) = &self.full_content {
+            let content = content.clone();
+            return Box::pin(futures_util::stream::iter(chunks.into_iter().map(
+                move |chunk| slice_content(&content, chunk.offset, chunk.size, false),
+            )));
+        }
         Box::pin(self.read_chunk_stream(chunks))
     }
 }
 
+// Slice the content of a read, which may end early if allowed to be short.
+fn slice_content(
+    content: &Bytes,
+    offset: u64,
+    size: usize,
+    allow_short: bool,
+) -> Result<Bytes, HttpReaderError> {
+    let len = content.len() as u64;
+    let end = offset + size as u64;
+    let end = if allow_short { end.min(len) } else { end };
+    if offset > len || end > len {
+        return Err(HttpReaderError::UnexpectedEnd);
+    }
+    Ok(content.slice(offset as usize..end as usize))
+}
+
 #[derive(Debug)]
 pub enum HttpReaderError {
     UnexpectedEnd,
     RequestNotClonable,
+    /// Server doesn't support range requests.
+    RangesNotSupported,
+    /// Server doesn't support range requests and the archive is bigger than the full download
+    /// limit.
+    ArchiveTooLarge(u64),
+    /// Archive is unchanged since the validators given to [`HttpReader::if_changed_since`].
+    NotModified,
     Http(reqwest::Error),
 }
 
+impl HttpReaderError {
+    // Errors which won't go away by retrying the request.
+    pub(crate) fn is_retryable(&self) -> bool {
+        !matches!(self, Self::RangesNotSupported | Self::NotModified)
+    }
+}
+
 impl std::error::Error for HttpReaderError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             HttpReaderError::Http(err) => Some(err),
-            HttpReaderError::UnexpectedEnd | HttpReaderError::RequestNotClonable => None,
+            HttpReaderError::UnexpectedEnd
+            | HttpReaderError::RequestNotClonable
+            | HttpReaderError::RangesNotSupported
+            | HttpReaderError::ArchiveTooLarge(_)
+            | HttpReaderError::NotModified => None,
         }
     }
 }
@@ -205,6 +461,13 @@ impl fmt::Display for HttpReaderError {
         match self {
             Self::UnexpectedEnd => write!(f, "unexpected end"),
             Self::RequestNotClonable => write!(f, "request is not clonable"),
+            Self::RangesNotSupported => write!(f, "server doesn't support range requests"),
+            Self::ArchiveTooLarge(limit) => write!(
+                f,
+                "server doesn't support range requests and archive is larger than {} bytes",
+                limit
+            ),
+            Self::NotModified => write!(f, "archive not modified"),
             Self::Http(_) => write!(f, "http error"),
         }
     }
@@ -242,7 +505,9 @@ mod tests {
                         let end = std::cmp::min(range[1] as usize + 1, data.len());
                         let data = data[start..end].to_vec();
                         async move {
-                            Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::from(data)))
+                            let mut response = hyper::Response::new(hyper::Body::from(data));
+                            *response.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+                            Ok::<_, hyper::Error>(response)
                         }
                     }))
                 }
@@ -251,6 +516,29 @@ mod tests {
             .unwrap();
     }
 
+    // Server ignoring the range header, responding to every request with the given status and
+    // the whole data.
+    async fn new_server_ignoring_ranges(
+        listener: std::net::TcpListener,
+        status: hyper::StatusCode,
+        data: Vec<u8>,
+    ) {
+        hyper::Server::from_tcp(listener)
+            .unwrap()
+            .serve(make_service_fn(move |_conn| {
+                let data = data.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(service_fn(move |_req| {
+                        let mut response = hyper::Response::new(hyper::Body::from(data.clone()));
+                        *response.status_mut() = status;
+                        async { Ok::<_, hyper::Error>(response) }
+                    }))
+                }
+            }))
+            .await
+            .unwrap();
+    }
+
     fn new_reader(port: u16) -> HttpReader {
         HttpReader::from_url(Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap())
     }
@@ -293,8 +581,12 @@ mod tests {
     fn builder() {
         let reader = HttpReader::from_url(Url::parse("http://localhost/file").unwrap())
             .retries(3)
-            .retry_delay(Duration::from_secs(10));
+            .retry_delay(Duration::from_secs(10))
+            .retry_jitter(RetryJitter::Full)
+            .retry_jitter_seed(1);
         assert_eq!(reader.retry_delay, Duration::from_secs(10));
+        assert_eq!(reader.retry_jitter, RetryJitter::Full);
+        assert_eq!(reader.retry_jitter_seed, Some(1));
         assert_eq!(reader.retry_count, 3);
         let request = reader.request_builder.build().unwrap();
         assert_eq!(request.url(), &Url::parse("http://localhost/file").unwrap());
@@ -351,6 +643,18 @@ mod tests {
         };
     }
 
+    #[tokio::test]
+    async fn read_up_to_end() {
+        let (listener, port) = new_listener();
+        let server = new_server(listener, vec![1, 2, 3, 4, 5, 6]);
+        let mut reader = new_reader(port);
+        let read = reader.read_up_to(2, 10);
+        tokio::select! {
+            _ = server => panic!("server ended"),
+            data = read => assert_eq!(&data.unwrap()[..], &[3, 4, 5, 6]),
+        };
+    }
+
     #[tokio::test]
     async fn read_chunks() {
         let expect = vec![
@@ -407,6 +711,88 @@ mod tests {
         };
     }
 
+    #[tokio::test]
+    async fn read_chunks_from_server_ignoring_ranges() {
+        let data: Vec<u8> = (1..=20).collect();
+        let (listener, port) = new_listener();
+        let server = new_server_ignoring_ranges(listener, hyper::StatusCode::OK, data.clone());
+        let chunks = vec![
+            ChunkOffset { offset: 2, size: 4 },
+            ChunkOffset { offset: 6, size: 3 },
+            ChunkOffset {
+                offset: 15,
+                size: 5,
+            },
+        ];
+        let expected = vec![
+            Bytes::from(vec![3, 4, 5, 6]),
+            Bytes::from(vec![7, 8, 9]),
+            Bytes::from(vec![16, 17, 18, 19, 20]),
+        ];
+        let reads = async {
+            // The body of the whole archive is never taken as the range
+            let mut reader = new_reader(port);
+            let read: Vec<Bytes> = reader
+                .read_chunks(chunks.clone())
+                .map(|v| v.expect("item"))
+                .collect()
+                .await;
+            assert_eq!(read, expected);
+            assert_eq!(&reader.read_at(10, 2).await.unwrap()[..], &[11, 12]);
+            // Also after the archive downloaded in full is dropped
+            reader.bypass_cache();
+            let read: Vec<Bytes> = reader
+                .read_chunks(chunks.clone())
+                .map(|v| v.expect("item"))
+                .collect()
+                .await;
+            assert_eq!(read, expected);
+
+            // An archive bigger than the limit is never downloaded in full
+            let mut reader = new_reader(port).full_download_limit(19);
+            match reader.read_chunks(chunks.clone()).next().await {
+                Some(Err(HttpReaderError::ArchiveTooLarge(19))) => {}
+                result => panic!("unexpected result {:?}", result),
+            }
+            match reader.read_at(2, 4).await {
+                Err(HttpReaderError::ArchiveTooLarge(19)) => {}
+                result => panic!("unexpected result {:?}", result),
+            }
+        };
+        tokio::select! {
+            _ = server => panic!("server ended"),
+            _ = reads => {},
+        };
+    }
+
+    #[tokio::test]
+    async fn error_response_is_not_read_as_range() {
+        let (listener, port) = new_listener();
+        let server = new_server_ignoring_ranges(
+            listener,
+            hyper::StatusCode::INTERNAL_SERVER_ERROR,
+            vec![0; 10],
+        );
+        let reads = async {
+            let mut reader = new_reader(port);
+            assert!(matches!(
+                reader.read_at(0, 4).await,
+                Err(HttpReaderError::Http(_))
+            ));
+            assert!(matches!(
+                reader
+                    .read_chunks(vec![ChunkOffset { offset: 0, size: 4 }])
+                    .next()
+                    .await,
+                Some(Err(HttpReaderError::Http(_)))
+            ));
+        };
+        tokio::select! {
+            _ = server => panic!("server ended"),
+            _ = reads => {},
+        };
+    }
+
     #[tokio::test]
     async fn connection_timeout() {
         let (listener, port) = new_listener();
@@ -422,4 +808,62 @@ mod tests {
             _ => panic!("unexpected result"),
         };
     }
+
+    #[tokio::test]
+    async fn not_modified() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        const ETAG: &str = "\"v1\"";
+        let (listener, port) = new_listener();
+        let bodies_served = Arc::new(AtomicUsize::new(0));
+        let server_bodies_served = bodies_served.clone();
+        let server = hyper::Server::from_tcp(listener)
+            .unwrap()
+            .serve(make_service_fn(move |_conn| {
+                let bodies_served = server_bodies_served.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                        let unchanged = req
+                            .headers()
+                            .get(hyper::header::IF_NONE_MATCH)
+                            .map(|etag| etag == ETAG)
+                            .unwrap_or(false);
+                        let mut response = if unchanged {
+                            let mut response = hyper::Response::new(hyper::Body::empty());
+                            *response.status_mut() = hyper::StatusCode::NOT_MODIFIED;
+                            response
+                        } else {
+                            bodies_served.fetch_add(1, Ordering::SeqCst);
+                            let mut response =
+                                hyper::Response::new(hyper::Body::from(vec![1, 2, 3]));
+                            *response.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+                            response
+                        };
+                        response
+                            .headers_mut()
+                            .insert(hyper::header::ETAG, ETAG.parse().unwrap());
+                        async { Ok::<_, hyper::Error>(response) }
+                    }))
+                }
+            }));
+        let reads = async {
+            let mut reader = new_reader(port);
+            assert_eq!(&reader.read_at(0, 3).await.unwrap()[..], &[1, 2, 3]);
+            assert_eq!(reader.validators().etag.as_deref(), Some(ETAG));
+
+            let mut reader = new_reader(port)
+                .retries(2)
+                .if_changed_since(&reader.validators().clone());
+            match reader.read_at(0, 3).await {
+                Err(HttpReaderError::NotModified) => {}
+                result => panic!("unexpected result {:?}", result),
+            }
+        };
+        tokio::select! {
+            _ = server => panic!("server ended"),
+            _ = reads => {},
+        };
+        assert_eq!(bodies_served.load(Ordering::SeqCst), 1);
+    }
 }
@@ -1,6 +1,12 @@
+mod fallback_reader;
 mod http_range_request;
 mod http_reader;
 mod io_reader;
+mod retry_backoff;
+#[cfg(feature = "sftp")]
+mod sftp_reader;
+mod split_reader;
+mod store_reader;
 
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -8,8 +14,14 @@ use core::pin::Pin;
 use futures_util::stream::Stream;
 
 // Re-export archive reader implementations.
-pub use http_reader::{HttpReader, HttpReaderError};
+pub use fallback_reader::FallbackReader;
+pub use http_reader::{CacheValidators, HttpReader, HttpReaderError};
 pub use io_reader::IoReader;
+pub use retry_backoff::RetryJitter;
+#[cfg(feature = "sftp")]
+pub use sftp_reader::{SftpCredentials, SftpReader, SftpReaderError};
+pub use split_reader::{SplitReader, SplitReaderError};
+pub use store_reader::{chunk_store_path, ChunkStore, StoreReader, StoreReaderError};
 
 use crate::ChunkOffset;
 
@@ -26,4 +38,10 @@ pub trait ArchiveReader {
         &'a mut self,
         chunks: Vec<ChunkOffset>,
     ) -> Pin<Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Send + 'a>>;
+
+    /// Bypass any caches between the reader and the archive for all following reads.
+    ///
+    /// Used before reading chunks again which failed verification, possibly corrupted on the
+    /// way. Does nothing by default.
+    fn bypass_cache(&mut self) {}
 }
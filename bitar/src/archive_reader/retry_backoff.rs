@@ -0,0 +1,138 @@
+use std::collections::hash_map::RandomState;
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Random jitter added to the delay between retries.
+///
+/// Clients failing at the same time would otherwise retry in sync with each other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetryJitter {
+    /// Always wait the retry delay.
+    None,
+    /// Wait a random time between zero and the retry delay.
+    Full,
+    /// Wait a random time between the retry delay and three times the previous delay, but
+    /// never longer than the given max delay.
+    Decorrelated {
+        /// Upper bound of the delay.
+        max_delay: Duration,
+    },
+}
+
+impl Default for RetryJitter {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+// Generates the delays between retries of a single request.
+#[derive(Debug, Clone)]
+pub(crate) struct RetryBackoff {
+    delay: Duration,
+    jitter: RetryJitter,
+    prev_delay: Duration,
+    rng: u64,
+}
+
+impl RetryBackoff {
+    pub fn new(delay: Duration, jitter: RetryJitter, seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| RandomState::new().build_hasher().finish());
+        Self {
+            delay,
+            jitter,
+            prev_delay: delay,
+            // xorshift gets stuck on zero
+            rng: seed | 1,
+        }
+    }
+
+    // xorshift64*
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Random duration in the range [low, high].
+    fn random_between(&mut self, low: Duration, high: Duration) -> Duration {
+        let low_nanos = u64::try_from(low.as_nanos()).unwrap_or(u64::MAX);
+        let high_nanos = u64::try_from(high.as_nanos()).unwrap_or(u64::MAX);
+        if high_nanos <= low_nanos {
+            return low;
+        }
+        let range = (high_nanos - low_nanos).saturating_add(1);
+        Duration::from_nanos(low_nanos + self.next_random() % range)
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        match self.jitter {
+            RetryJitter::None => self.delay,
+            RetryJitter::Full => self.random_between(Duration::from_secs(0), self.delay),
+            RetryJitter::Decorrelated { max_delay } => {
+                let high = std::cmp::min(
+                    max_delay,
+                    self.prev_delay.checked_mul(3).unwrap_or(max_delay),
+                );
+                let delay = std::cmp::min(max_delay, self.random_between(self.delay, high));
+                self.prev_delay = delay;
+                delay
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delays(jitter: RetryJitter, seed: u64) -> Vec<Duration> {
+        let mut backoff = RetryBackoff::new(Duration::from_secs(1), jitter, Some(seed));
+        (0..20).map(|_| backoff.next_delay()).collect()
+    }
+
+    #[test]
+    fn no_jitter() {
+        assert!(delays(RetryJitter::None, 1)
+            .iter()
+            .all(|delay| *delay == Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn full_jitter_varies_within_bounds() {
+        let delays = delays(RetryJitter::Full, 1);
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+        assert!(delays.iter().all(|delay| *delay <= Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn decorrelated_jitter_varies_within_bounds() {
+        let max_delay = Duration::from_secs(5);
+        let delays = delays(RetryJitter::Decorrelated { max_delay }, 1);
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+        assert!(delays
+            .iter()
+            .all(|delay| *delay >= Duration::from_secs(1) && *delay <= max_delay));
+    }
+
+    #[test]
+    fn decorrelated_jitter_does_not_overflow() {
+        let mut backoff = RetryBackoff::new(
+            Duration::from_secs(1),
+            RetryJitter::Decorrelated {
+                max_delay: Duration::new(u64::MAX, 999_999_999),
+            },
+            Some(1),
+        );
+        for _ in 0..200 {
+            assert!(backoff.next_delay() >= Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn seeded_jitter_is_deterministic() {
+        assert_eq!(delays(RetryJitter::Full, 7), delays(RetryJitter::Full, 7));
+        assert_ne!(delays(RetryJitter::Full, 7), delays(RetryJitter::Full, 8));
+    }
+}
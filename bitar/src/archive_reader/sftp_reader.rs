@@ -0,0 +1,652 @@
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use core::pin::Pin;
+use futures_util::stream::{self, Stream};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::{fmt, io};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{Child, Command};
+
+use crate::archive_reader::{ArchiveReader, ChunkOffset};
+
+// SFTP version 3 packet types and status codes, see draft-ietf-secsh-filexfer-02.
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXF_READ: u32 = 0x0000_0001;
+const SSH_FX_EOF: u32 = 1;
+const SFTP_VERSION: u32 = 3;
+
+// Size of each read request, servers are only required to serve reads of up to 32 KiB.
+const MAX_READ_SIZE: usize = 32 * 1024;
+// Number of read requests sent before waiting for their responses.
+const MAX_PENDING_READS: usize = 16;
+// Upper bound of a packet accepted from the server.
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+/// Credentials used to connect to the SSH server of an [`SftpReader`].
+///
+/// Anything not given is left to the ssh client configuration.
+#[derive(Debug, Clone, Default)]
+pub struct SftpCredentials {
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    /// Private key file used to authenticate.
+    pub identity_file: Option<PathBuf>,
+}
+
+/// Read an archive over SFTP.
+///
+/// Connects by running the system ssh client with the sftp subsystem, non-interactively so
+/// key based authentication is required. Ranges are read using SFTP random access reads.
+pub struct SftpReader {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    handle: Bytes,
+    next_id: u32,
+    // The ssh client process, killed when the reader is dropped.
+    _child: Option<Child>,
+}
+
+impl SftpReader {
+    /// Connect to the SSH server on host and open the archive at path for reading.
+    ///
+    /// Fails without running ssh if the host or user could be taken for an ssh option.
+    pub async fn connect(
+        host: &str,
+        path: &str,
+        credentials: &SftpCredentials,
+    ) -> Result<Self, SftpReaderError> {
+        let mut child = ssh_command(host, credentials)?
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let (stdout, stdin) = match (child.stdout.take(), child.stdin.take()) {
+            (Some(stdout), Some(stdin)) => (stdout, stdin),
+            _ => return Err(SftpReaderError::Protocol("no ssh stdio")),
+        };
+        let mut reader = Self::from_stream(stdout, stdin, path).await?;
+        reader._child = Some(child);
+        Ok(reader)
+    }
+
+    /// Connect using an URL on the form `sftp://[user@]host[:port]/path`.
+    ///
+    /// User and port are taken from the URL, anything else is left to the ssh client
+    /// configuration.
+    pub async fn from_url(url: Url) -> Result<Self, SftpReaderError> {
+        let (host, path, credentials) = parse_url(&url)?;
+        Self::connect(&host, &path, &credentials).await
+    }
+
+    /// Open the archive at path over an established SFTP session, given the stream read from
+    /// and written to the server.
+    pub async fn from_stream<R, W>(
+        reader: R,
+        writer: W,
+        path: &str,
+    ) -> Result<Self, SftpReaderError>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let mut sftp = Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            handle: Bytes::new(),
+            next_id: 0,
+            _child: None,
+        };
+        let mut init = BytesMut::new();
+        init.put_u32(SFTP_VERSION);
+        sftp.send(SSH_FXP_INIT, &init).await?;
+        let (packet_type, mut version) = sftp.receive().await?;
+        if packet_type != SSH_FXP_VERSION || version.remaining() < 4 {
+            return Err(SftpReaderError::Protocol("expected version"));
+        }
+        let version = version.get_u32();
+        if version < SFTP_VERSION {
+            return Err(SftpReaderError::UnsupportedVersion(version));
+        }
+
+        let id = sftp.request_id();
+        let mut open = BytesMut::new();
+        open.put_u32(id);
+        put_string(&mut open, path.as_bytes());
+        open.put_u32(SSH_FXF_READ);
+        // No attributes
+        open.put_u32(0);
+        sftp.send(SSH_FXP_OPEN, &open).await?;
+        let (packet_type, mut response) = sftp.receive().await?;
+        if response_id(&mut response)? != id {
+            return Err(SftpReaderError::Protocol("unexpected response id"));
+        }
+        sftp.handle = match packet_type {
+            SSH_FXP_HANDLE => get_string(&mut response)?,
+            SSH_FXP_STATUS => return Err(status_error(&mut response)?),
+            _ => return Err(SftpReaderError::Protocol("expected handle")),
+        };
+        Ok(sftp)
+    }
+
+    fn request_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+
+    async fn send(&mut self, packet_type: u8, payload: &[u8]) -> Result<(), SftpReaderError> {
+        let mut packet = BytesMut::with_capacity(payload.len() + 5);
+        packet.put_u32(payload.len() as u32 + 1);
+        packet.put_u8(packet_type);
+        packet.put_slice(payload);
+        self.writer.write_all(&packet).await?;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<(u8, Bytes), SftpReaderError> {
+        let len = self.reader.read_u32().await? as usize;
+        if len == 0 || len > MAX_PACKET_SIZE {
+            return Err(SftpReaderError::Protocol("invalid packet length"));
+        }
+        let mut packet = vec![0; len];
+        self.reader.read_exact(&mut packet).await?;
+        let mut packet = Bytes::from(packet);
+        let packet_type = packet.get_u8();
+        Ok((packet_type, packet))
+    }
+
+    // Send read requests for consecutive pieces of the range, then collect the responses.
+    // Returns the data read from the start of the range up to the first short read.
+    async fn read_pieces(&mut self, offset: u64, size: usize) -> Result<Bytes, SftpReaderError> {
+        let mut requests = Vec::new();
+        let mut piece_offset = 0;
+        while piece_offset < size && requests.len() < MAX_PENDING_READS {
+            let piece_size = (size - piece_offset).min(MAX_READ_SIZE);
+            let id = self.request_id();
+            let mut read = BytesMut::new();
+            read.put_u32(id);
+            put_string(&mut read, &self.handle);
+            read.put_u64(offset + piece_offset as u64);
+            read.put_u32(piece_size as u32);
+            self.send(SSH_FXP_READ, &read).await?;
+            requests.push((id, piece_size));
+            piece_offset += piece_size;
+        }
+        let mut responses = HashMap::new();
+        for _ in 0..requests.len() {
+            let (packet_type, mut response) = self.receive().await?;
+            let id = response_id(&mut response)?;
+            responses.insert(id, (packet_type, response));
+        }
+        let mut buf = BytesMut::with_capacity(piece_offset);
+        for (id, piece_size) in requests {
+            let (packet_type, mut response) = responses
+                .remove(&id)
+                .ok_or(SftpReaderError::Protocol("unexpected response id"))?;
+            let data = match packet_type {
+                SSH_FXP_DATA => get_string(&mut response)?,
+                SSH_FXP_STATUS => match status_error(&mut response)? {
+                    SftpReaderError::Status { code, .. } if code == SSH_FX_EOF => break,
+                    err => return Err(err),
+                },
+                _ => return Err(SftpReaderError::Protocol("expected data")),
+            };
+            if data.len() > piece_size {
+                return Err(SftpReaderError::Protocol("read more than requested"));
+            }
+            buf.put_slice(&data);
+            if data.len() < piece_size {
+                break;
+            }
+        }
+        Ok(buf.freeze())
+    }
+}
+
+#[async_trait]
+impl ArchiveReader for SftpReader {
+    type Error = SftpReaderError;
+
+    async fn read_at<'a>(&'a mut self, offset: u64, size: usize) -> Result<Bytes, Self::Error> {
+        let mut buf = BytesMut::with_capacity(size);
+        while buf.len() < size {
+            let data = self
+                .read_pieces(offset + buf.len() as u64, size - buf.len())
+                .await?;
+            if data.is_empty() {
+                return Err(SftpReaderError::UnexpectedEnd);
+            }
+            buf.put_slice(&data);
+        }
+        Ok(buf.freeze())
+    }
+
+    fn read_chunks<'a>(
+        &'a mut self,
+        chunks: Vec<ChunkOffset>,
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Send + 'a>> {
+        Box::pin(stream::unfold(
+            (self, chunks.into_iter()),
+            |(reader, mut chunks)| async move {
+                let chunk = chunks.next()?;
+                let result = reader.read_at(chunk.offset, chunk.size).await;
+                Some((result, (reader, chunks)))
+            },
+        ))
+    }
+}
+
+// The ssh client command running the sftp subsystem on host.
+fn ssh_command(host: &str, credentials: &SftpCredentials) -> Result<Command, SftpReaderError> {
+    // Anything starting with a dash could be taken as an option by ssh, e.g. -oProxyCommand
+    // running a local command.
+    if host.is_empty() || host.starts_with('-') {
+        return Err(SftpReaderError::InvalidSshArgument("invalid host"));
+    }
+    if let Some(user) = &credentials.user {
+        if user.is_empty() || user.starts_with('-') {
+            return Err(SftpReaderError::InvalidSshArgument("invalid user"));
+        }
+    }
+    let mut command = Command::new("ssh");
+    command.arg("-o").arg("BatchMode=yes").arg("-s");
+    if let Some(user) = &credentials.user {
+        command.arg("-l").arg(user);
+    }
+    if let Some(port) = credentials.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    if let Some(identity_file) = &credentials.identity_file {
+        command.arg("-i").arg(identity_file);
+    }
+    command.arg("--").arg(host).arg("sftp");
+    Ok(command)
+}
+
+// Host, path and credentials given by an sftp URL.
+fn parse_url(url: &Url) -> Result<(String, String, SftpCredentials), SftpReaderError> {
+    if url.scheme() != "sftp" {
+        return Err(SftpReaderError::InvalidUrl("expected sftp scheme"));
+    }
+    let host = match url.host_str() {
+        Some(host) if !host.is_empty() => host.to_string(),
+        _ => return Err(SftpReaderError::InvalidUrl("missing host")),
+    };
+    let path = percent_decode(url.path())?;
+    if path.is_empty() || path == "/" {
+        return Err(SftpReaderError::InvalidUrl("missing path"));
+    }
+    let credentials = SftpCredentials {
+        user: Some(percent_decode(url.username())?).filter(|user| !user.is_empty()),
+        port: url.port(),
+        identity_file: None,
+    };
+    Ok((host, path, credentials))
+}
+
+fn percent_decode(value: &str) -> Result<String, SftpReaderError> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+        let byte = std::str::from_utf8(&hex)
+            .ok()
+            .filter(|hex| hex.len() == 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or(SftpReaderError::InvalidUrl("invalid percent encoding"))?;
+        decoded.push(byte);
+    }
+    String::from_utf8(decoded).map_err(|_| SftpReaderError::InvalidUrl("invalid percent encoding"))
+}
+
+fn put_string(buf: &mut BytesMut, value: &[u8]) {
+    buf.put_u32(value.len() as u32);
+    buf.put_slice(value);
+}
+
+fn get_string(buf: &mut Bytes) -> Result<Bytes, SftpReaderError> {
+    if buf.remaining() < 4 {
+        return Err(SftpReaderError::Protocol("truncated packet"));
+    }
+    let len = buf.get_u32() as usize;
+    if buf.remaining() < len {
+        return Err(SftpReaderError::Protocol("truncated packet"));
+    }
+    Ok(buf.split_to(len))
+}
+
+fn response_id(buf: &mut Bytes) -> Result<u32, SftpReaderError> {
+    if buf.remaining() < 4 {
+        return Err(SftpReaderError::Protocol("truncated packet"));
+    }
+    Ok(buf.get_u32())
+}
+
+fn status_error(buf: &mut Bytes) -> Result<SftpReaderError, SftpReaderError> {
+    if buf.remaining() < 4 {
+        return Err(SftpReaderError::Protocol("truncated packet"));
+    }
+    let code = buf.get_u32();
+    // The message is optional in some server implementations
+    let message = get_string(buf)
+        .map(|message| String::from_utf8_lossy(&message).into_owned())
+        .unwrap_or_default();
+    Ok(SftpReaderError::Status { code, message })
+}
+
+#[derive(Debug)]
+pub enum SftpReaderError {
+    UnexpectedEnd,
+    /// Server only supports an older SFTP version than 3.
+    UnsupportedVersion(u32),
+    /// Server responded with an error status.
+    Status {
+        code: u32,
+        message: String,
+    },
+    /// Server sent something not following the SFTP protocol.
+    Protocol(&'static str),
+    /// The URL given is not a valid sftp URL.
+    InvalidUrl(&'static str),
+    /// The host or user can't be passed to ssh.
+    InvalidSshArgument(&'static str),
+    Io(io::Error),
+}
+
+impl std::error::Error for SftpReaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::UnexpectedEnd
+            | Self::UnsupportedVersion(_)
+            | Self::Status { .. }
+            | Self::Protocol(_)
+            | Self::InvalidUrl(_)
+            | Self::InvalidSshArgument(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for SftpReaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedEnd => write!(f, "unexpected end"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported sftp version {}", version)
+            }
+            Self::Status { code, message } => {
+                write!(f, "sftp server error {}: {}", code, message)
+            }
+            Self::Protocol(message) => write!(f, "sftp protocol error: {}", message),
+            Self::InvalidUrl(message) => write!(f, "invalid sftp url: {}", message),
+            Self::InvalidSshArgument(message) => write!(f, "invalid ssh argument: {}", message),
+            Self::Io(_) => write!(f, "i/o error"),
+        }
+    }
+}
+
+impl From<io::Error> for SftpReaderError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use tokio::io::DuplexStream;
+
+    const SSH_FX_NO_SUCH_FILE: u32 = 2;
+
+    async fn read_packet(stream: &mut DuplexStream) -> Option<(u8, Bytes)> {
+        let len = stream.read_u32().await.ok()? as usize;
+        let mut packet = vec![0; len];
+        stream.read_exact(&mut packet).await.ok()?;
+        let mut packet = Bytes::from(packet);
+        let packet_type = packet.get_u8();
+        Some((packet_type, packet))
+    }
+
+    async fn write_packet(stream: &mut DuplexStream, packet_type: u8, payload: &[u8]) {
+        let mut packet = BytesMut::new();
+        packet.put_u32(payload.len() as u32 + 1);
+        packet.put_u8(packet_type);
+        packet.put_slice(payload);
+        stream.write_all(&packet).await.unwrap();
+    }
+
+    async fn write_status(stream: &mut DuplexStream, id: u32, code: u32) {
+        let mut status = BytesMut::new();
+        status.put_u32(id);
+        status.put_u32(code);
+        put_string(&mut status, b"status");
+        put_string(&mut status, b"");
+        write_packet(stream, SSH_FXP_STATUS, &status).await;
+    }
+
+    // In-process SFTP server serving a single file. Reads are served at most max_read bytes at
+    // a time and the responses of each batch of pending reads are sent in reverse order.
+    async fn serve(mut stream: DuplexStream, path: &'static str, data: Vec<u8>, max_read: usize) {
+        let (packet_type, _) = read_packet(&mut stream).await.unwrap();
+        assert_eq!(packet_type, SSH_FXP_INIT);
+        let mut version = BytesMut::new();
+        version.put_u32(SFTP_VERSION);
+        write_packet(&mut stream, SSH_FXP_VERSION, &version).await;
+        let mut pending: Vec<(u32, Option<Vec<u8>>)> = Vec::new();
+        loop {
+            // Wait for more requests to arrive before responding
+            let packet = tokio::select! {
+                packet = read_packet(&mut stream) => packet,
+                _ = tokio::time::sleep(std::time::Duration::from_millis(5)), if !pending.is_empty() => {
+                    for (id, response) in pending.drain(..).rev() {
+                        match response {
+                            Some(piece) => {
+                                let mut response = BytesMut::new();
+                                response.put_u32(id);
+                                put_string(&mut response, &piece);
+                                write_packet(&mut stream, SSH_FXP_DATA, &response).await;
+                            }
+                            None => write_status(&mut stream, id, SSH_FX_EOF).await,
+                        }
+                    }
+                    continue;
+                }
+            };
+            let (packet_type, mut request) = match packet {
+                Some(packet) => packet,
+                None => return,
+            };
+            let id = request.get_u32();
+            match packet_type {
+                SSH_FXP_OPEN => {
+                    if get_string(&mut request).unwrap() != path.as_bytes() {
+                        write_status(&mut stream, id, SSH_FX_NO_SUCH_FILE).await;
+                        continue;
+                    }
+                    let mut handle = BytesMut::new();
+                    handle.put_u32(id);
+                    put_string(&mut handle, b"handle");
+                    write_packet(&mut stream, SSH_FXP_HANDLE, &handle).await;
+                }
+                SSH_FXP_READ => {
+                    assert_eq!(get_string(&mut request).unwrap(), &b"handle"[..]);
+                    let offset = request.get_u64() as usize;
+                    let len = (request.get_u32() as usize).min(max_read);
+                    pending.push((
+                        id,
+                        if offset < data.len() {
+                            Some(data[offset..(offset + len).min(data.len())].to_vec())
+                        } else {
+                            None
+                        },
+                    ));
+                }
+                _ => panic!("unexpected request {}", packet_type),
+            }
+        }
+    }
+
+    async fn reader(data: &[u8], max_read: usize) -> SftpReader {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve(server, "/archive.cba", data.to_vec(), max_read));
+        let (read, write) = tokio::io::split(client);
+        SftpReader::from_stream(read, write, "/archive.cba")
+            .await
+            .unwrap()
+    }
+
+    fn test_data() -> Vec<u8> {
+        (0..200 * 1024).map(|v| (v % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn read_scattered_offsets() {
+        let data = test_data();
+        for &max_read in &[MAX_READ_SIZE, 1000] {
+            let mut reader = reader(&data, max_read).await;
+            for &(offset, size) in &[(150_000, 10), (0, 100_000), (7, 1), (70_000, 130_000)] {
+                assert_eq!(
+                    reader.read_at(offset as u64, size).await.unwrap(),
+                    &data[offset..offset + size]
+                );
+            }
+            let chunks = vec![
+                ChunkOffset::new(190_000, 10_000),
+                ChunkOffset::new(12, 34),
+                ChunkOffset::new(64 * 1024, 64 * 1024),
+            ];
+            let read: Vec<Bytes> = reader
+                .read_chunks(chunks.clone())
+                .map(|result| result.unwrap())
+                .collect()
+                .await;
+            for (chunk, read) in chunks.iter().zip(read) {
+                assert_eq!(read, &data[chunk.offset as usize..chunk.end() as usize]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn read_past_end() {
+        let data = test_data();
+        let mut reader = reader(&data, MAX_READ_SIZE).await;
+        match reader.read_at(data.len() as u64 - 10, 20).await {
+            Err(SftpReaderError::UnexpectedEnd) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn ssh_command_arguments() {
+        let args = |command: Command| -> Vec<String> {
+            command
+                .as_std()
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect()
+        };
+        let command = ssh_command("example.com", &SftpCredentials::default()).unwrap();
+        assert_eq!(command.as_std().get_program(), "ssh");
+        assert_eq!(
+            args(command),
+            ["-o", "BatchMode=yes", "-s", "--", "example.com", "sftp"]
+        );
+        let credentials = SftpCredentials {
+            user: Some("bita".to_string()),
+            port: Some(2222),
+            identity_file: Some(PathBuf::from("/keys/id_ed25519")),
+        };
+        assert_eq!(
+            args(ssh_command("example.com", &credentials).unwrap()),
+            [
+                "-o",
+                "BatchMode=yes",
+                "-s",
+                "-l",
+                "bita",
+                "-p",
+                "2222",
+                "-i",
+                "/keys/id_ed25519",
+                "--",
+                "example.com",
+                "sftp"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn option_like_host_or_user_rejected() {
+        // Fails before ever running ssh
+        let (host, path, credentials) =
+            parse_url(&Url::parse("sftp://-oProxyCommand=id/x").unwrap()).unwrap();
+        assert_eq!(host, "-oProxyCommand=id");
+        match SftpReader::connect(&host, &path, &credentials).await {
+            Err(SftpReaderError::InvalidSshArgument(_)) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("connected to option-like host"),
+        }
+        let credentials = SftpCredentials {
+            user: Some("-oProxyCommand=id".to_string()),
+            ..Default::default()
+        };
+        match ssh_command("example.com", &credentials) {
+            Err(SftpReaderError::InvalidSshArgument(_)) => {}
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parse_sftp_url() {
+        let (host, path, credentials) =
+            parse_url(&Url::parse("sftp://bita@example.com:2222/srv/my%20archive.cba").unwrap())
+                .unwrap();
+        assert_eq!(host, "example.com");
+        assert_eq!(path, "/srv/my archive.cba");
+        assert_eq!(credentials.user.as_deref(), Some("bita"));
+        assert_eq!(credentials.port, Some(2222));
+        assert!(credentials.identity_file.is_none());
+
+        let (_, _, credentials) =
+            parse_url(&Url::parse("sftp://example.com/archive.cba").unwrap()).unwrap();
+        assert!(credentials.user.is_none());
+        assert!(credentials.port.is_none());
+
+        for url in &[
+            "http://example.com/archive.cba",
+            "sftp://example.com/",
+            "sftp://example.com/bad%2",
+        ] {
+            match parse_url(&Url::parse(url).unwrap()) {
+                Err(SftpReaderError::InvalidUrl(_)) => {}
+                result => panic!("{} gave {:?}", url, result.map(|(host, ..)| host)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn open_missing_file() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve(server, "/archive.cba", vec![], MAX_READ_SIZE));
+        let (read, write) = tokio::io::split(client);
+        match SftpReader::from_stream(read, write, "/missing.cba").await {
+            Err(SftpReaderError::Status { code, .. }) => assert_eq!(code, SSH_FX_NO_SUCH_FILE),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("opened missing file"),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::{ready, stream::Stream, StreamExt};
+use std::fmt;
+
+use crate::archive_reader::{ArchiveReader, ChunkOffset};
+
+/// Read an archive with its chunk data split across multiple part files.
+///
+/// The header is read from the main archive reader while the chunk data is read from the part
+/// readers. Without any parts everything is read from the main reader. Created by
+/// [`Archive::with_part_readers`](crate::Archive::with_part_readers).
+pub struct SplitReader<R> {
+    main: R,
+    chunk_data_offset: u64,
+    // Start offset (relative to the chunk data) and size of each part.
+    part_ranges: Vec<(u64, u64)>,
+    parts: Vec<R>,
+}
+
+impl<R> SplitReader<R> {
+    pub(crate) fn new(main: R, chunk_data_offset: u64, part_sizes: &[u64], parts: Vec<R>) -> Self {
+        let mut start = 0;
+        let part_ranges = part_sizes
+            .iter()
+            .map(|&size| {
+                let range = (start, size);
+                start += size;
+                range
+            })
+            .collect();
+        Self {
+            main,
+            chunk_data_offset,
+            part_ranges,
+            parts,
+        }
+    }
+
+    // Resolve an archive offset to the part index and the offset within that part.
+    fn resolve<E>(&self, offset: u64, size: usize) -> Result<(usize, u64), SplitReaderError<E>> {
+        let invalid = || SplitReaderError::InvalidRange { offset, size };
+        let data_offset = offset
+            .checked_sub(self.chunk_data_offset)
+            .ok_or_else(invalid)?;
+        let index = self
+            .part_ranges
+            .iter()
+            .position(|&(start, part_size)| data_offset >= start && data_offset < start + part_size)
+            .ok_or_else(invalid)?;
+        let (start, part_size) = self.part_ranges[index];
+        if data_offset + size as u64 > start + part_size {
+            // Chunks never span multiple parts.
+            return Err(invalid());
+        }
+        Ok((index, data_offset - start))
+    }
+}
+
+#[async_trait]
+impl<R> ArchiveReader for SplitReader<R>
+where
+    R: ArchiveReader + Send,
+    R::Error: Send,
+{
+    type Error = SplitReaderError<R::Error>;
+
+    async fn read_at(&mut self, offset: u64, size: usize) -> Result<Bytes, Self::Error> {
+        if self.parts.is_empty() || offset + size as u64 <= self.chunk_data_offset {
+            return self
+                .main
+                .read_at(offset, size)
+                .await
+                .map_err(SplitReaderError::Reader);
+        }
+        let (index, part_offset) = self.resolve(offset, size)?;
+        self.parts[index]
+            .read_at(part_offset, size)
+            .await
+            .map_err(SplitReaderError::Reader)
+    }
+
+    fn read_chunks<'a>(
+        &'a mut self,
+        chunks: Vec<ChunkOffset>,
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Send + 'a>> {
+        if self.parts.is_empty() {
+            return Box::pin(
+                self.main
+                    .read_chunks(chunks)
+                    .map(|result| result.map_err(SplitReaderError::Reader)),
+            );
+        }
+        let mut part_chunks = vec![Vec::new(); self.parts.len()];
+        let mut order = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            match self.resolve(chunk.offset, chunk.size) {
+                Ok((index, part_offset)) => {
+                    part_chunks[index].push(ChunkOffset::new(part_offset, chunk.size));
+                    order.push(index);
+                }
+                Err(err) => return Box::pin(futures_util::stream::once(async { Err(err) })),
+            }
+        }
+        let streams = self
+            .parts
+            .iter_mut()
+            .zip(part_chunks)
+            .map(|(part, chunks)| part.read_chunks(chunks))
+            .collect();
+        Box::pin(SplitChunkStream {
+            streams,
+            order,
+            next: 0,
+        })
+    }
+
+    fn bypass_cache(&mut self) {
+        self.main.bypass_cache();
+        self.parts.iter_mut().for_each(ArchiveReader::bypass_cache);
+    }
+}
+
+type PartChunkStream<'a, E> = Pin<Box<dyn Stream<Item = Result<Bytes, E>> + Send + 'a>>;
+
+// Yields the chunks of each part's stream in the order they were requested.
+struct SplitChunkStream<'a, E> {
+    streams: Vec<PartChunkStream<'a, E>>,
+    // Part index of each requested chunk.
+    order: Vec<usize>,
+    next: usize,
+}
+
+impl<'a, E> Stream for SplitChunkStream<'a, E> {
+    type Item = Result<Bytes, SplitReaderError<E>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let index = match self.order.get(self.next) {
+            Some(&index) => index,
+            None => return Poll::Ready(None),
+        };
+        let item = ready!(self.streams[index].poll_next_unpin(cx));
+        self.next += 1;
+        Poll::Ready(Some(match item {
+            Some(result) => result.map_err(SplitReaderError::Reader),
+            None => Err(SplitReaderError::UnexpectedEnd),
+        }))
+    }
+}
+
+#[derive(Debug)]
+pub enum SplitReaderError<E> {
+    /// Requested range is not within a single part.
+    InvalidRange {
+        offset: u64,
+        size: usize,
+    },
+    /// Part ended before all requested chunks were read.
+    UnexpectedEnd,
+    Reader(E),
+}
+
+impl<E> std::error::Error for SplitReaderError<E>
+where
+    E: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidRange { .. } | Self::UnexpectedEnd => None,
+            Self::Reader(err) => Some(err),
+        }
+    }
+}
+
+impl<E> fmt::Display for SplitReaderError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRange { offset, size } => write!(
+                f,
+                "range at offset {} of size {} is not within a single part",
+                offset, size
+            ),
+            Self::UnexpectedEnd => write!(f, "unexpected end"),
+            Self::Reader(_) => write!(f, "part reader error"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_reader::IoReader;
+    use std::io::Cursor;
+
+    fn split_reader() -> SplitReader<IoReader<Cursor<Vec<u8>>>> {
+        SplitReader::new(
+            IoReader::new(Cursor::new(vec![0, 1, 2, 3])),
+            4,
+            &[3, 2],
+            vec![
+                IoReader::new(Cursor::new(vec![4, 5, 6])),
+                IoReader::new(Cursor::new(vec![7, 8])),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn read_at_resolves_part() {
+        let mut reader = split_reader();
+        assert_eq!(&reader.read_at(1, 2).await.unwrap()[..], &[1, 2]);
+        assert_eq!(&reader.read_at(5, 2).await.unwrap()[..], &[5, 6]);
+        assert_eq!(&reader.read_at(7, 2).await.unwrap()[..], &[7, 8]);
+        assert!(reader.read_at(6, 2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_chunks_in_requested_order() {
+        let mut reader = split_reader();
+        let chunks: Vec<Bytes> = reader
+            .read_chunks(vec![
+                ChunkOffset::new(8, 1),
+                ChunkOffset::new(4, 2),
+                ChunkOffset::new(7, 1),
+                ChunkOffset::new(6, 1),
+            ])
+            .map(|result| result.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks, vec![vec![8], vec![4, 5], vec![7], vec![6]]);
+    }
+
+    #[tokio::test]
+    async fn without_parts_reads_main() {
+        let mut reader =
+            SplitReader::new(IoReader::new(Cursor::new(vec![0, 1, 2, 3])), 2, &[], vec![]);
+        assert_eq!(&reader.read_at(1, 2).await.unwrap()[..], &[1, 2]);
+        let chunks: Vec<Bytes> = reader
+            .read_chunks(vec![ChunkOffset::new(3, 1), ChunkOffset::new(2, 1)])
+            .map(|result| result.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks, vec![vec![3], vec![2]]);
+    }
+}
@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use core::pin::Pin;
+use futures_util::{stream::Stream, StreamExt};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::archive_reader::{ArchiveReader, ChunkOffset};
+use crate::HashSum;
+
+// Number of objects read from the store at the same time.
+const CONCURRENT_OBJECT_READS: usize = 8;
+
+/// Path of a chunk object in a chunk store, relative to the store root.
+///
+/// Objects are named by the hex encoded chunk hash and spread over directories named by the
+/// first byte of the hash, `<hh>/<hash>`.
+pub fn chunk_store_path(checksum: &HashSum) -> String {
+    let hash = checksum.to_string();
+    format!("{}/{}", &hash[..2.min(hash.len())], hash)
+}
+
+/// A content-addressed store holding the data of every chunk as a separate object.
+///
+/// The store decides where the object of a chunk is kept, bita stores use
+/// [`chunk_store_path`].
+#[async_trait]
+pub trait ChunkStore {
+    type Error;
+
+    /// Read the object of the chunk with the given hash. The size is the size of the chunk
+    /// in the archive.
+    async fn read_object(&self, checksum: &HashSum, size: usize) -> Result<Bytes, Self::Error>;
+}
+
+/// Read an archive with its chunk data in a chunk store.
+///
+/// The header is read from the main archive reader while every chunk is read from the store
+/// object of its hash. Created by
+/// [`Archive::with_chunk_store`](crate::Archive::with_chunk_store).
+pub struct StoreReader<R, S> {
+    main: R,
+    chunk_data_offset: u64,
+    // Chunk hash by archive offset.
+    objects: HashMap<u64, HashSum>,
+    store: S,
+}
+
+impl<R, S> StoreReader<R, S> {
+    pub(crate) fn new(
+        main: R,
+        chunk_data_offset: u64,
+        objects: HashMap<u64, HashSum>,
+        store: S,
+    ) -> Self {
+        Self {
+            main,
+            chunk_data_offset,
+            objects,
+            store,
+        }
+    }
+}
+
+// Resolve an archive offset to the hash of the chunk.
+fn resolve<E, SE>(
+    objects: &HashMap<u64, HashSum>,
+    offset: u64,
+    size: usize,
+) -> Result<&HashSum, StoreReaderError<E, SE>> {
+    objects
+        .get(&offset)
+        .ok_or(StoreReaderError::UnknownChunk { offset, size })
+}
+
+#[async_trait]
+impl<R, S> ArchiveReader for StoreReader<R, S>
+where
+    R: ArchiveReader + Send,
+    R::Error: Send,
+    S: ChunkStore + Send + Sync,
+    S::Error: Send,
+{
+    type Error = StoreReaderError<R::Error, S::Error>;
+
+    async fn read_at(&mut self, offset: u64, size: usize) -> Result<Bytes, Self::Error> {
+        if offset + size as u64 <= self.chunk_data_offset {
+            return self
+                .main
+                .read_at(offset, size)
+                .await
+                .map_err(StoreReaderError::Reader);
+        }
+        let checksum = resolve(&self.objects, offset, size)?;
+        self.store
+            .read_object(checksum, size)
+            .await
+            .map_err(StoreReaderError::Store)
+    }
+
+    fn read_chunks<'a>(
+        &'a mut self,
+        chunks: Vec<ChunkOffset>,
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Send + 'a>> {
+        let objects = &self.objects;
+        let store = &self.store;
+        Box::pin(
+            futures_util::stream::iter(chunks)
+                .map(move |chunk| async move {
+                    let checksum = resolve(objects, chunk.offset, chunk.size)?;
+                    store
+                        .read_object(checksum, chunk.size)
+                        .await
+                        .map_err(StoreReaderError::Store)
+                })
+                .buffered(CONCURRENT_OBJECT_READS),
+        )
+    }
+
+    fn bypass_cache(&mut self) {
+        self.main.bypass_cache();
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreReaderError<E, SE> {
+    /// Requested range is not a chunk of the archive.
+    UnknownChunk {
+        offset: u64,
+        size: usize,
+    },
+    Reader(E),
+    Store(SE),
+}
+
+impl<E, SE> std::error::Error for StoreReaderError<E, SE>
+where
+    E: std::error::Error + 'static,
+    SE: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::UnknownChunk { .. } => None,
+            Self::Reader(err) => Some(err),
+            Self::Store(err) => Some(err),
+        }
+    }
+}
+
+impl<E, SE> fmt::Display for StoreReaderError<E, SE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownChunk { offset, size } => write!(
+                f,
+                "range at offset {} of size {} is not a chunk of the archive",
+                offset, size
+            ),
+            Self::Reader(_) => write!(f, "archive reader error"),
+            Self::Store(_) => write!(f, "chunk store error"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_reader::IoReader;
+    use std::io::Cursor;
+
+    struct MemoryStore(HashMap<String, Vec<u8>>);
+
+    #[async_trait]
+    impl ChunkStore for MemoryStore {
+        type Error = std::io::Error;
+
+        async fn read_object(
+            &self,
+            checksum: &HashSum,
+            _size: usize,
+        ) -> Result<Bytes, Self::Error> {
+            self.0
+                .get(&chunk_store_path(checksum))
+                .map(|data| Bytes::from(data.clone()))
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        }
+    }
+
+    fn store_reader() -> StoreReader<IoReader<Cursor<Vec<u8>>>, MemoryStore> {
+        let a = HashSum::from(&[0xab, 0x01][..]);
+        let b = HashSum::from(&[0x12, 0x02][..]);
+        StoreReader::new(
+            IoReader::new(Cursor::new(vec![0, 1, 2, 3])),
+            4,
+            vec![(4, a), (7, b)].into_iter().collect(),
+            MemoryStore(
+                vec![
+                    ("ab/ab01".to_string(), vec![4, 5, 6]),
+                    ("12/1202".to_string(), vec![7, 8]),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+        )
+    }
+
+    #[test]
+    fn path_of_chunk() {
+        assert_eq!(
+            chunk_store_path(&HashSum::from(&[0x0f, 0xa0, 0x11][..])),
+            "0f/0fa011"
+        );
+    }
+
+    #[tokio::test]
+    async fn read_at_resolves_object() {
+        let mut reader = store_reader();
+        assert_eq!(&reader.read_at(1, 2).await.unwrap()[..], &[1, 2]);
+        assert_eq!(&reader.read_at(4, 3).await.unwrap()[..], &[4, 5, 6]);
+        assert_eq!(&reader.read_at(7, 2).await.unwrap()[..], &[7, 8]);
+        assert!(matches!(
+            reader.read_at(5, 2).await,
+            Err(StoreReaderError::UnknownChunk { offset: 5, size: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_chunks_in_requested_order() {
+        let mut reader = store_reader();
+        let chunks: Vec<Bytes> = reader
+            .read_chunks(vec![ChunkOffset::new(7, 2), ChunkOffset::new(4, 3)])
+            .map(|result| result.unwrap())
+            .collect()
+            .await;
+        assert_eq!(chunks, vec![vec![7, 8], vec![4, 5, 6]]);
+    }
+}
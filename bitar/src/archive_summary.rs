@@ -0,0 +1,469 @@
+use prost::encoding::{decode_key, decode_varint, WireType};
+use prost::Message;
+
+use crate::{
+    archive::{
+        chunk_data_alignment, chunk_data_checksum, compression_from_dictionary, content_type,
+        source_checkpoints_from_dictionary, source_entry_from_dictionary, RawHeader,
+    },
+    archive_reader::ArchiveReader,
+    chunk_dictionary as dict, chunker,
+    header::DictionaryKey,
+    Archive, ArchiveError, Compression, HashSum, SourceCheckpoints, SourceEntry,
+};
+
+// Field numbers of the chunk dictionary which grow with the number of chunks.
+const REBUILD_ORDER_FIELD: u32 = 6;
+const CHUNK_DESCRIPTORS_FIELD: u32 = 7;
+
+/// Source size statistics of the unique chunks of an archive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkSizeStats {
+    /// Number of unique chunks.
+    pub count: usize,
+    /// Size of the smallest chunk.
+    pub min: u64,
+    /// Size of the largest chunk.
+    pub max: u64,
+    /// Sum of the chunk sizes.
+    pub total: u64,
+    /// Number of chunks cut at the chunker's max chunk size, always zero for fixed size
+    /// chunking where every chunk but the last has the max size.
+    pub at_max_size: usize,
+}
+
+impl ChunkSizeStats {
+    /// Average chunk size.
+    pub fn average(&self) -> u64 {
+        self.total / self.count as u64
+    }
+}
+
+// Accumulates chunk size statistics one chunk at a time.
+struct ChunkSizeAccumulator {
+    max_chunk_size: Option<u64>,
+    stats: Option<ChunkSizeStats>,
+}
+
+impl ChunkSizeAccumulator {
+    fn new(config: &chunker::Config) -> Self {
+        let max_chunk_size = match config {
+            chunker::Config::BuzHash(hc)
+            | chunker::Config::RollSum(hc)
+            | chunker::Config::Custom(_, hc) => Some(hc.max_chunk_size as u64),
+            chunker::Config::FixedSize(_) => None,
+        };
+        Self {
+            max_chunk_size,
+            stats: None,
+        }
+    }
+    fn add(&mut self, size: u64) {
+        let at_max_size = usize::from(self.max_chunk_size == Some(size));
+        self.stats = Some(match self.stats {
+            None => ChunkSizeStats {
+                count: 1,
+                min: size,
+                max: size,
+                total: size,
+                at_max_size,
+            },
+            Some(stats) => ChunkSizeStats {
+                count: stats.count + 1,
+                min: stats.min.min(size),
+                max: stats.max.max(size),
+                total: stats.total + size,
+                at_max_size: stats.at_max_size + at_max_size,
+            },
+        });
+    }
+}
+
+/// Summary of an archive, read from the header without keeping any chunk descriptors.
+///
+/// Reading a summary only decodes one chunk descriptor at a time, for archives with so many
+/// chunks that [`Archive::try_init`] would use a lot of memory just to print some numbers.
+#[derive(Debug, Clone)]
+pub struct ArchiveSummary {
+    built_with_version: String,
+    header_size: usize,
+    header_checksum: HashSum,
+    chunk_data_part_sizes: Vec<u64>,
+    chunk_data_checksum: Option<HashSum>,
+    chunk_data_alignment: Option<u64>,
+    chunk_hash_length: usize,
+    chunk_crc32c: bool,
+    chunk_compression: Option<Compression>,
+    chunker_config: chunker::Config,
+    source_name: String,
+    content_type: Option<String>,
+    source_checksum: HashSum,
+    source_entry: SourceEntry,
+    source_checkpoints: Option<SourceCheckpoints>,
+    source_total_size: u64,
+    total_chunks: usize,
+    compressed_size: u64,
+    chunk_size_stats: Option<ChunkSizeStats>,
+}
+
+// Call f with the field number, wire type and value of each top level field of a message,
+// along with the whole encoded field.
+fn for_each_field<E>(
+    message: &[u8],
+    mut f: impl FnMut(u32, WireType, &[u8], &[u8]) -> Result<(), ArchiveError<E>>,
+) -> Result<(), ArchiveError<E>> {
+    let mut buf = message;
+    while !buf.is_empty() {
+        let field_start = message.len() - buf.len();
+        let (tag, wire_type) = decode_key(&mut buf)?;
+        let value = match wire_type {
+            WireType::Varint => {
+                let value_start = message.len() - buf.len();
+                decode_varint(&mut buf)?;
+                &message[value_start..message.len() - buf.len()]
+            }
+            WireType::LengthDelimited => {
+                let len = decode_varint(&mut buf)? as usize;
+                if len > buf.len() {
+                    return Err(ArchiveError::invalid_archive("truncated dictionary field"));
+                }
+                let (value, rest) = buf.split_at(len);
+                buf = rest;
+                value
+            }
+            WireType::ThirtyTwoBit | WireType::SixtyFourBit => {
+                let len = if wire_type == WireType::ThirtyTwoBit {
+                    4
+                } else {
+                    8
+                };
+                if len > buf.len() {
+                    return Err(ArchiveError::invalid_archive("truncated dictionary field"));
+                }
+                let (value, rest) = buf.split_at(len);
+                buf = rest;
+                value
+            }
+            WireType::StartGroup | WireType::EndGroup => {
+                return Err(ArchiveError::invalid_archive(
+                    "unexpected group in dictionary",
+                ))
+            }
+        };
+        f(
+            tag,
+            wire_type,
+            value,
+            &message[field_start..message.len() - buf.len()],
+        )?;
+    }
+    Ok(())
+}
+
+impl ArchiveSummary {
+    /// Read the summary of an archive. Only the header is read, no chunk data.
+    pub async fn try_init<R>(reader: R) -> Result<Self, ArchiveError<R::Error>>
+    where
+        R: ArchiveReader,
+    {
+        Self::init(reader, None).await
+    }
+
+    /// Read the summary of an archive, decrypting the dictionary using the key.
+    pub async fn try_init_with_key<R>(
+        reader: R,
+        key: &DictionaryKey,
+    ) -> Result<Self, ArchiveError<R::Error>>
+    where
+        R: ArchiveReader,
+    {
+        Self::init(reader, Some(key)).await
+    }
+
+    async fn init<R>(
+        mut reader: R,
+        key: Option<&DictionaryKey>,
+    ) -> Result<Self, ArchiveError<R::Error>>
+    where
+        R: ArchiveReader,
+    {
+        let raw_header = RawHeader::read(&mut reader).await?;
+        let encoded = raw_header.dictionary(key)?;
+        // Decode everything but the fields growing with the number of chunks
+        let mut small_fields = Vec::new();
+        let mut rebuild_order_len = 0;
+        for_each_field(&encoded, |tag, wire_type, value, field| {
+            match (tag, wire_type) {
+                (REBUILD_ORDER_FIELD, WireType::LengthDelimited) => {
+                    // Packed varints, each ends with a byte without the continuation bit
+                    rebuild_order_len += value.iter().filter(|&&b| b & 0x80 == 0).count();
+                }
+                (REBUILD_ORDER_FIELD, _) => rebuild_order_len += 1,
+                (CHUNK_DESCRIPTORS_FIELD, _) => {}
+                _ => small_fields.extend_from_slice(field),
+            }
+            Ok(())
+        })?;
+        let dictionary = dict::ChunkDictionary::decode(&small_fields[..])?;
+        let chunker_config = dictionary
+            .chunker_params
+            .as_ref()
+            .ok_or_else(|| ArchiveError::invalid_archive("invalid chunker parameters"))?
+            .chunker_config()
+            .map_err(ArchiveError::invalid_archive)?;
+
+        // Then go through the chunk descriptors one at a time
+        let mut chunk_sizes = ChunkSizeAccumulator::new(&chunker_config);
+        let mut compressed_size = 0;
+        for_each_field(&encoded, |tag, _, value, _| {
+            if tag == CHUNK_DESCRIPTORS_FIELD {
+                let descriptor = dict::ChunkDescriptor::decode(value)?;
+                compressed_size += u64::from(descriptor.archive_size);
+                chunk_sizes.add(u64::from(descriptor.source_size));
+            }
+            Ok(())
+        })?;
+        let total_chunks = match &dictionary.rebuild_order_runs {
+            Some(runs) => runs.lengths.iter().map(|&length| length as usize).sum(),
+            None => rebuild_order_len,
+        };
+        Ok(Self {
+            header_size: raw_header.header.len(),
+            header_checksum: raw_header.header_checksum,
+            chunk_data_part_sizes: dictionary.chunk_data_part_sizes,
+            chunk_data_checksum: chunk_data_checksum(dictionary.chunk_data_checksum),
+            chunk_data_alignment: chunk_data_alignment(dictionary.chunk_data_alignment),
+            chunk_hash_length: dictionary
+                .chunker_params
+                .map(|params| params.chunk_hash_length as usize)
+                .unwrap_or(0),
+            chunk_crc32c: dictionary.chunk_crc32c && chunk_sizes.stats.is_some(),
+            chunk_compression: compression_from_dictionary(
+                dictionary
+                    .chunk_compression
+                    .ok_or_else(|| ArchiveError::invalid_archive("invalid compression"))?,
+            )?,
+            chunker_config,
+            source_name: dictionary.source_name,
+            content_type: content_type(dictionary.content_type),
+            source_checksum: dictionary.source_checksum.into(),
+            source_entry: source_entry_from_dictionary(dictionary.source_entry)?,
+            source_checkpoints: source_checkpoints_from_dictionary(dictionary.source_checkpoints)?,
+            source_total_size: dictionary.source_total_size,
+            built_with_version: dictionary.application_version,
+            total_chunks,
+            compressed_size,
+            chunk_size_stats: chunk_sizes.stats,
+        })
+    }
+    /// Version of the application which built the archive.
+    pub fn built_with_version(&self) -> &str {
+        &self.built_with_version
+    }
+    /// Size of the header.
+    pub fn header_size(&self) -> usize {
+        self.header_size
+    }
+    /// Checksum of the header.
+    pub fn header_checksum(&self) -> &HashSum {
+        &self.header_checksum
+    }
+    /// Size of each part if the chunk data is split across multiple files.
+    pub fn chunk_data_part_sizes(&self) -> &[u64] {
+        &self.chunk_data_part_sizes
+    }
+    /// Blake2b-512 checksum of the whole chunk data section, if stored in archive.
+    pub fn chunk_data_checksum(&self) -> Option<&HashSum> {
+        self.chunk_data_checksum.as_ref()
+    }
+    /// Alignment of the chunk data if the archive was built with padding between chunks.
+    pub fn chunk_data_alignment(&self) -> Option<u64> {
+        self.chunk_data_alignment
+    }
+    /// Length of the chunk hashes stored in the archive.
+    pub fn chunk_hash_length(&self) -> usize {
+        self.chunk_hash_length
+    }
+    /// Whether the archive stores a CRC32C for each chunk.
+    pub fn has_chunk_crc32c(&self) -> bool {
+        self.chunk_crc32c
+    }
+    /// Compression used for all chunks in archive.
+    pub fn chunk_compression(&self) -> Option<Compression> {
+        self.chunk_compression
+    }
+    /// Chunker configuration used when building the archive.
+    pub fn chunker_config(&self) -> &chunker::Config {
+        &self.chunker_config
+    }
+    /// Name of the main source, typically the input file name.
+    pub fn source_name(&self) -> &str {
+        &self.source_name
+    }
+    /// Hint of the content of the main source, like a MIME type, if stored in archive.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+    /// Checksum of the main source (Blake2).
+    pub fn source_checksum(&self) -> &HashSum {
+        &self.source_checksum
+    }
+    /// Kind of file system entry the main source was made from.
+    pub fn source_entry(&self) -> &SourceEntry {
+        &self.source_entry
+    }
+    /// Checkpoints of the main source checksum, if stored in archive.
+    pub fn source_checkpoints(&self) -> Option<&SourceCheckpoints> {
+        self.source_checkpoints.as_ref()
+    }
+    /// Total size of the main source.
+    pub fn total_source_size(&self) -> u64 {
+        self.source_total_size
+    }
+    /// Number of chunks in the main source (including duplicates).
+    pub fn total_chunks(&self) -> usize {
+        self.total_chunks
+    }
+    /// Number of unique chunks in archive.
+    pub fn unique_chunks(&self) -> usize {
+        self.chunk_size_stats.map_or(0, |stats| stats.count)
+    }
+    /// Total size of chunks in archive when compressed.
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+    /// Source size statistics of the unique chunks, `None` if the archive has no chunks.
+    pub fn chunk_size_stats(&self) -> Option<ChunkSizeStats> {
+        self.chunk_size_stats
+    }
+}
+
+impl<R> From<&Archive<R>> for ArchiveSummary {
+    fn from(archive: &Archive<R>) -> Self {
+        let mut chunk_sizes = ChunkSizeAccumulator::new(archive.chunker_config());
+        archive
+            .chunk_descriptors()
+            .iter()
+            .for_each(|descriptor| chunk_sizes.add(u64::from(descriptor.source_size)));
+        Self {
+            built_with_version: archive.built_with_version().to_string(),
+            header_size: archive.header_size(),
+            header_checksum: archive.header_checksum().clone(),
+            chunk_data_part_sizes: archive.chunk_data_part_sizes().to_vec(),
+            chunk_data_checksum: archive.chunk_data_checksum().cloned(),
+            chunk_data_alignment: archive.chunk_data_alignment(),
+            chunk_hash_length: archive.chunk_hash_length(),
+            chunk_crc32c: archive.has_chunk_crc32c(),
+            chunk_compression: archive.chunk_compression(),
+            chunker_config: archive.chunker_config().clone(),
+            source_name: archive.source_name().to_string(),
+            content_type: archive.content_type().map(str::to_string),
+            source_checksum: archive.source_checksum().clone(),
+            source_entry: archive.source_entry().clone(),
+            source_checkpoints: archive.source_checkpoints().cloned(),
+            source_total_size: archive.total_source_size(),
+            total_chunks: archive.total_chunks(),
+            compressed_size: archive.compressed_size(),
+            chunk_size_stats: chunk_sizes.stats,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{archive_reader::IoReader, header};
+    use std::io::Cursor;
+
+    fn dictionary(source_sizes: &[u32], rebuild_order: Vec<u32>) -> dict::ChunkDictionary {
+        let mut archive_offset = 0;
+        let chunk_descriptors = source_sizes
+            .iter()
+            .enumerate()
+            .map(|(index, &source_size)| {
+                let descriptor = dict::ChunkDescriptor {
+                    checksum: vec![index as u8; 64],
+                    archive_size: source_size,
+                    archive_offset,
+                    source_size,
+                    crc32c: 0,
+                };
+                archive_offset += u64::from(source_size);
+                descriptor
+            })
+            .collect();
+        dict::ChunkDictionary {
+            source_total_size: rebuild_order
+                .iter()
+                .map(|&index| u64::from(source_sizes[index as usize]))
+                .sum(),
+            rebuild_order,
+            application_version: "test".to_string(),
+            chunk_descriptors,
+            source_checksum: vec![0; 64],
+            chunk_compression: Some(None.into()),
+            chunker_params: Some(dict::ChunkerParameters {
+                chunk_filter_bits: 6,
+                min_chunk_size: 10,
+                max_chunk_size: 100,
+                rolling_hash_window_size: 16,
+                chunk_hash_length: 64,
+                chunking_algorithm: dict::chunker_parameters::ChunkingAlgorithm::Rollsum as i32,
+                buzhash_table: vec![],
+            }),
+            source_checkpoints: None,
+            chunk_data_part_sizes: vec![],
+            source_name: String::new(),
+            additional_sources: vec![],
+            chunk_crc32c: false,
+            rebuild_order_runs: None,
+            chunk_data_checksum: None,
+            chunk_data_alignment: 0,
+            source_entry: None,
+            content_type: String::new(),
+        }
+    }
+
+    async fn summary(dictionary: &dict::ChunkDictionary) -> ArchiveSummary {
+        let header = header::build(dictionary, None).unwrap();
+        ArchiveSummary::try_init(IoReader::new(Cursor::new(header)))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn chunk_size_stats_counts_max_size_chunks() {
+        let summary = summary(&dictionary(&[10, 100, 100, 30], vec![0, 1, 2, 3, 1])).await;
+        assert_eq!(summary.total_chunks(), 5);
+        assert_eq!(summary.unique_chunks(), 4);
+        assert_eq!(summary.compressed_size(), 240);
+        assert_eq!(
+            summary.chunk_size_stats(),
+            Some(ChunkSizeStats {
+                count: 4,
+                min: 10,
+                max: 100,
+                total: 240,
+                at_max_size: 2,
+            })
+        );
+        assert_eq!(summary.chunk_size_stats().unwrap().average(), 60);
+    }
+
+    #[tokio::test]
+    async fn total_chunks_of_rebuild_order_runs() {
+        let rebuild_order = vec![0, 0, 0, 1, 1, 0];
+        let mut dictionary = dictionary(&[10, 20], rebuild_order.clone());
+        dictionary.rebuild_order = vec![];
+        dictionary.rebuild_order_runs = Some(dict::RebuildOrderRuns::encode(&rebuild_order));
+        assert_eq!(summary(&dictionary).await.total_chunks(), 6);
+    }
+
+    #[tokio::test]
+    async fn summary_of_empty_archive() {
+        let summary = summary(&dictionary(&[], vec![])).await;
+        assert_eq!(summary.total_chunks(), 0);
+        assert_eq!(summary.unique_chunks(), 0);
+        assert_eq!(summary.chunk_size_stats(), None);
+    }
+}
@@ -0,0 +1,436 @@
+//! Read casync blob indexes (.caibx) and chunk stores (.castr).
+//!
+//! A blob index is a fixed size header followed by a table of chunks, all values little endian.
+//!
+//! | Offset      | Size | Description                                                   |
+//! |-------------|------|---------------------------------------------------------------|
+//! |           0 |    8 | Header size (48).                                             |
+//! |           8 |    8 | Header type (`CA_FORMAT_INDEX`).                              |
+//! |          16 |    8 | Feature flags, selects the chunk id hash.                     |
+//! |          24 |   24 | Min, average and max chunk size the source was chunked with.  |
+//! |          48 |   16 | Table header, size (u64::MAX) and type (`CA_FORMAT_TABLE`).   |
+//! |          64 | 40*n | Table items, end offset of chunk in source and 32 byte id.    |
+//! |   64 + 40*n |   40 | Table tail, two zero fills, table offset, table size, marker. |
+//!
+//! Every chunk is kept in the store as `<first 4 hex digits>/<hex id>.cacnk`, compressed as
+//! a single zstd or xz stream. The chunk id is the SHA-512/256 (or SHA-256 for old indexes)
+//! of the uncompressed chunk.
+//!
+//! An index is read as a bita archive by building an archive header from it, see
+//! [`Index::to_header`], and reading the chunks through a
+//! [`ChunkStore`](crate::archive_reader::ChunkStore) which returns them decompressed.
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+
+use crate::chunk_dictionary as dict;
+use crate::chunker::{self, FilterBits, FilterConfig};
+use crate::{ChunkHasher, CompressionAlgorithm, CompressionError, HashFunction, HashSum};
+
+/// Type of the index header.
+pub const CA_FORMAT_INDEX: u64 = 0x9682_4d9c_7b12_9ff9;
+/// Type of the chunk table.
+pub const CA_FORMAT_TABLE: u64 = 0xe75b_9e11_2f17_417d;
+/// Marker ending the chunk table.
+pub const CA_FORMAT_TABLE_TAIL_MARKER: u64 = 0x4b4f_050e_5549_ecd1;
+/// Feature flag set if chunk ids are SHA-512/256 rather than SHA-256.
+pub const CA_FORMAT_SHA512_256: u64 = 0x2000_0000_0000_0000;
+
+const INDEX_HEADER_SIZE: usize = 48;
+const TABLE_HEADER_SIZE: usize = 16;
+const TABLE_ITEM_SIZE: usize = 40;
+const CHUNK_ID_SIZE: usize = 32;
+// casync hashes its rolling window of 48 bytes using buzhash.
+const CASYNC_WINDOW_SIZE: usize = 48;
+
+/// Path of a chunk object in a casync chunk store, relative to the store root.
+pub fn chunk_path(id: &HashSum) -> String {
+    let id = id.to_string();
+    format!("{}/{}.cacnk", &id[..4.min(id.len())], id)
+}
+
+// Compression of a chunk object, detected from the stream magic.
+fn object_compression(object: &[u8]) -> Result<CompressionAlgorithm, CasyncError> {
+    const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+    const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+    if object.starts_with(ZSTD_MAGIC) {
+        #[cfg(feature = "zstd-compression")]
+        return Ok(CompressionAlgorithm::Zstd);
+        #[cfg(not(feature = "zstd-compression"))]
+        return Err(CasyncError::UnsupportedCompression("zstd"));
+    }
+    if object.starts_with(XZ_MAGIC) {
+        #[cfg(feature = "lzma-compression")]
+        return Ok(CompressionAlgorithm::Lzma);
+        #[cfg(not(feature = "lzma-compression"))]
+        return Err(CasyncError::UnsupportedCompression("xz"));
+    }
+    Err(CasyncError::UnsupportedCompression("unknown"))
+}
+
+/// Decompress a chunk object of a casync chunk store.
+///
+/// Only the compressions compiled into bitar can be decompressed, zstd (casync's default)
+/// needs the `zstd-compression` feature and xz the `lzma-compression` feature.
+pub fn decompress_chunk(object: Bytes, size_hint: usize) -> Result<Bytes, CasyncError> {
+    object_compression(&object)?
+        .decompress(object, size_hint, None, None)
+        .map_err(CasyncError::Compression)
+}
+
+/// A chunk of a casync index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexChunk {
+    /// Offset of the end of the chunk in the source.
+    pub end_offset: u64,
+    /// Chunk id, the hash of the uncompressed chunk.
+    pub id: HashSum,
+}
+
+/// A parsed casync blob index.
+#[derive(Debug, Clone)]
+pub struct Index {
+    feature_flags: u64,
+    chunk_hasher: ChunkHasher,
+    chunk_size_min: u64,
+    chunk_size_avg: u64,
+    chunk_size_max: u64,
+    chunks: Vec<IndexChunk>,
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+// Chunk size as stored in an archive header, which must fit in 32 bits.
+fn chunk_size(size: u64) -> Result<u32, std::io::Error> {
+    u32::try_from(size).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            CasyncError::InvalidIndex("chunk size out of range"),
+        )
+    })
+}
+
+impl Index {
+    /// Parse a blob index from the whole index file.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, CasyncError> {
+        let invalid = CasyncError::InvalidIndex;
+        if buf.len() < INDEX_HEADER_SIZE + TABLE_HEADER_SIZE + TABLE_ITEM_SIZE {
+            return Err(invalid("file is too small"));
+        }
+        if u64_at(buf, 0) != INDEX_HEADER_SIZE as u64 || u64_at(buf, 8) != CA_FORMAT_INDEX {
+            return Err(invalid("not a casync index"));
+        }
+        let table = &buf[INDEX_HEADER_SIZE..];
+        if u64_at(table, 0) != u64::MAX || u64_at(table, 8) != CA_FORMAT_TABLE {
+            return Err(invalid("missing chunk table"));
+        }
+        let items = &table[TABLE_HEADER_SIZE..];
+        if items.len() % TABLE_ITEM_SIZE != 0 {
+            return Err(invalid("truncated chunk table"));
+        }
+        let (items, tail) = items.split_at(items.len() - TABLE_ITEM_SIZE);
+        if u64_at(tail, 0) != 0
+            || u64_at(tail, 8) != 0
+            || u64_at(tail, 16) != INDEX_HEADER_SIZE as u64
+            || u64_at(tail, 24) != table.len() as u64
+            || u64_at(tail, 32) != CA_FORMAT_TABLE_TAIL_MARKER
+        {
+            return Err(invalid("invalid chunk table tail"));
+        }
+        let mut chunks = Vec::with_capacity(items.len() / TABLE_ITEM_SIZE);
+        let mut start_offset = 0;
+        for item in items.chunks(TABLE_ITEM_SIZE) {
+            let end_offset = u64_at(item, 0);
+            if end_offset <= start_offset {
+                return Err(invalid("chunk offsets are not increasing"));
+            }
+            chunks.push(IndexChunk {
+                end_offset,
+                id: HashSum::from(&item[8..8 + CHUNK_ID_SIZE]),
+            });
+            start_offset = end_offset;
+        }
+        let feature_flags = u64_at(buf, 16);
+        let function = if feature_flags & CA_FORMAT_SHA512_256 != 0 {
+            HashFunction::Sha512_256
+        } else {
+            HashFunction::Sha256
+        };
+        let chunk_hasher = ChunkHasher::for_params(function, CHUNK_ID_SIZE)
+            .map_err(|_| invalid("chunk id length doesn't match the hash function"))?;
+        Ok(Self {
+            feature_flags,
+            chunk_hasher,
+            chunk_size_min: u64_at(buf, 24),
+            chunk_size_avg: u64_at(buf, 32),
+            chunk_size_max: u64_at(buf, 40),
+            chunks,
+        })
+    }
+    /// Chunks in source order.
+    pub fn chunks(&self) -> &[IndexChunk] {
+        &self.chunks
+    }
+    /// Total size of the source.
+    pub fn source_size(&self) -> u64 {
+        self.chunks
+            .last()
+            .map(|chunk| chunk.end_offset)
+            .unwrap_or(0)
+    }
+    /// Feature flags of the index.
+    pub fn feature_flags(&self) -> u64 {
+        self.feature_flags
+    }
+    /// Hasher creating the chunk ids of the index.
+    pub fn chunk_hasher(&self) -> ChunkHasher {
+        self.chunk_hasher
+    }
+    /// The bita chunker closest to the one the source was chunked with.
+    ///
+    /// Both use buzhash over the same window size and chunk size limits, but casync finds
+    /// boundaries using a discriminator which isn't a power of two. Seeds chunked using this
+    /// configuration will therefore only partly match the chunks of the index.
+    pub fn chunker_config(&self) -> chunker::Config {
+        let target = self
+            .chunk_size_avg
+            .saturating_sub(self.chunk_size_min)
+            .max(1);
+        chunker::Config::BuzHash(FilterConfig {
+            filter_bits: FilterBits::from_size(target.min(u64::from(u32::MAX)) as u32),
+            min_chunk_size: self.chunk_size_min as usize,
+            max_chunk_size: self.chunk_size_max as usize,
+            window_size: CASYNC_WINDOW_SIZE,
+            buzhash_table: None,
+        })
+    }
+    /// Build the header of a bita archive with the chunks of the index.
+    ///
+    /// Every chunk is described as stored uncompressed, using its id as hash, with the chunk
+    /// data laid out in order of first occurrence. The archive has no source checksum since
+    /// the index doesn't hold one.
+    pub fn to_header(&self) -> Result<Vec<u8>, std::io::Error> {
+        let mut descriptors = Vec::new();
+        let mut descriptor_index: HashMap<&HashSum, u32> = HashMap::new();
+        let mut rebuild_order = Vec::with_capacity(self.chunks.len());
+        let mut archive_offset = 0;
+        let mut start_offset = 0;
+        for chunk in &self.chunks {
+            let size = chunk_size(chunk.end_offset - start_offset)?;
+            start_offset = chunk.end_offset;
+            let index = *descriptor_index.entry(&chunk.id).or_insert_with(|| {
+                descriptors.push(dict::ChunkDescriptor {
+                    checksum: chunk.id.to_vec(),
+                    archive_size: size,
+                    archive_offset,
+                    source_size: size,
+                    crc32c: 0,
+                });
+                archive_offset += u64::from(size);
+                descriptors.len() as u32 - 1
+            });
+            rebuild_order.push(index);
+        }
+        let chunker_params = match self.chunker_config() {
+            chunker::Config::BuzHash(config) => dict::ChunkerParameters {
+                chunk_filter_bits: config.filter_bits.bits(),
+                min_chunk_size: chunk_size(self.chunk_size_min)?,
+                max_chunk_size: chunk_size(self.chunk_size_max)?,
+                rolling_hash_window_size: config.window_size as u32,
+                chunk_hash_length: CHUNK_ID_SIZE as u32,
+                chunking_algorithm: dict::chunker_parameters::ChunkingAlgorithm::Buzhash as i32,
+                buzhash_table: vec![],
+            },
+            _ => unreachable!("casync chunks using buzhash"),
+        };
+        let dictionary = dict::ChunkDictionary {
+            rebuild_order,
+            application_version: "casync".to_string(),
+            chunk_descriptors: descriptors,
+            source_checksum: vec![],
+            chunk_compression: Some(None.into()),
+            source_total_size: self.source_size(),
+            chunker_params: Some(chunker_params),
+            source_checkpoints: None,
+            chunk_data_part_sizes: vec![],
+            source_name: String::new(),
+            additional_sources: vec![],
+            chunk_crc32c: false,
+            rebuild_order_runs: None,
+            chunk_data_checksum: None,
+            chunk_data_alignment: 0,
+            content_type: String::new(),
+            source_entry: None,
+        };
+        crate::header::build(&dictionary, None)
+    }
+}
+
+#[derive(Debug)]
+pub enum CasyncError {
+    /// The index file is not a valid casync blob index.
+    InvalidIndex(&'static str),
+    /// The chunk object is compressed using a compression not compiled into bitar.
+    UnsupportedCompression(&'static str),
+    Compression(CompressionError),
+}
+
+impl std::error::Error for CasyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidIndex(_) | Self::UnsupportedCompression(_) => None,
+            Self::Compression(err) => Some(err),
+        }
+    }
+}
+
+impl fmt::Display for CasyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidIndex(reason) => write!(f, "invalid casync index: {}", reason),
+            Self::UnsupportedCompression(compression) => {
+                write!(f, "unsupported chunk compression ({})", compression)
+            }
+            Self::Compression(_) => write!(f, "failed to decompress chunk"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_reader::IoReader;
+    use crate::Archive;
+    use std::io::Cursor;
+
+    // Index file of chunks given by end offset and id, as written by casync.
+    fn index_file(feature_flags: u64, chunks: &[(u64, HashSum)]) -> Vec<u8> {
+        let mut file = Vec::new();
+        for value in &[INDEX_HEADER_SIZE as u64, CA_FORMAT_INDEX, feature_flags] {
+            file.extend(&value.to_le_bytes());
+        }
+        for value in &[
+            16u64 * 1024,
+            64 * 1024,
+            256 * 1024,
+            u64::MAX,
+            CA_FORMAT_TABLE,
+        ] {
+            file.extend(&value.to_le_bytes());
+        }
+        for (end_offset, id) in chunks {
+            file.extend(&end_offset.to_le_bytes());
+            file.extend(id.slice());
+        }
+        let table_size = TABLE_HEADER_SIZE + (chunks.len() + 1) * TABLE_ITEM_SIZE;
+        for value in &[
+            0,
+            0,
+            INDEX_HEADER_SIZE as u64,
+            table_size as u64,
+            CA_FORMAT_TABLE_TAIL_MARKER,
+        ] {
+            file.extend(&value.to_le_bytes());
+        }
+        file
+    }
+
+    fn chunks() -> Vec<(u64, HashSum)> {
+        let hasher = ChunkHasher::sha512_256();
+        vec![
+            (100, hasher.digest(b"a")),
+            (250, hasher.digest(b"b")),
+            (350, hasher.digest(b"a")),
+        ]
+    }
+
+    #[test]
+    fn parse_index() {
+        let index = Index::from_bytes(&index_file(CA_FORMAT_SHA512_256, &chunks())).unwrap();
+        assert_eq!(
+            index
+                .chunks()
+                .iter()
+                .map(|chunk| (chunk.end_offset, chunk.id.clone()))
+                .collect::<Vec<_>>(),
+            chunks()
+        );
+        assert_eq!(index.source_size(), 350);
+        assert_eq!(index.chunk_hasher(), ChunkHasher::sha512_256());
+        assert_eq!(
+            Index::from_bytes(&index_file(0, &chunks()))
+                .unwrap()
+                .chunk_hasher(),
+            ChunkHasher::sha256()
+        );
+        assert_eq!(
+            format!("{}", index.chunker_config()),
+            "buzhash(bits=14, min=16KiB, max=256KiB, window=48)"
+        );
+    }
+
+    #[test]
+    fn reject_invalid_index() {
+        let file = index_file(CA_FORMAT_SHA512_256, &chunks());
+        assert!(Index::from_bytes(&file[..file.len() - 1]).is_err());
+        let mut bad_marker = file.clone();
+        *bad_marker.last_mut().unwrap() ^= 1;
+        assert!(Index::from_bytes(&bad_marker).is_err());
+        let mut bad_type = file;
+        bad_type[8] ^= 1;
+        assert!(Index::from_bytes(&bad_type).is_err());
+    }
+
+    #[test]
+    fn path_of_chunk() {
+        let id = HashSum::from(&[0x12, 0x34, 0x56][..]);
+        assert_eq!(chunk_path(&id), "1234/123456.cacnk");
+    }
+
+    #[tokio::test]
+    async fn header_describes_index_chunks() {
+        let index = Index::from_bytes(&index_file(CA_FORMAT_SHA512_256, &chunks())).unwrap();
+        let archive = Archive::try_init(IoReader::new(Cursor::new(index.to_header().unwrap())))
+            .await
+            .unwrap();
+        assert_eq!(archive.total_source_size(), 350);
+        assert_eq!(archive.total_chunks(), 3);
+        assert_eq!(archive.unique_chunks(), 2);
+        assert_eq!(archive.chunk_hash_length(), 32);
+        assert_eq!(
+            archive
+                .iter_source_chunks()
+                .map(|(offset, cd)| (offset, cd.source_size, cd.checksum.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, 100, chunks()[0].1.clone()),
+                (100, 150, chunks()[1].1.clone()),
+                (250, 100, chunks()[0].1.clone()),
+            ]
+        );
+    }
+
+    #[test]
+    fn header_rejects_oversized_chunk() {
+        let mut chunks = chunks();
+        chunks[1].0 = 100 + (1 << 32);
+        chunks[2].0 = 200 + (1 << 32);
+        let index = Index::from_bytes(&index_file(CA_FORMAT_SHA512_256, &chunks)).unwrap();
+        assert_eq!(
+            index.to_header().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn unsupported_compression() {
+        assert!(matches!(
+            decompress_chunk(Bytes::from_static(b"plain"), 5),
+            Err(CasyncError::UnsupportedCompression(_))
+        ));
+    }
+}
@@ -4,7 +4,7 @@ use std::fmt;
 
 #[cfg(feature = "compress")]
 use crate::Compression;
-use crate::{CompressionAlgorithm, CompressionError, HashSum};
+use crate::{ChunkHasher, CodecDictionaries, CompressionAlgorithm, CompressionError, HashSum};
 
 /// A single chunk.
 ///
@@ -37,6 +37,19 @@ impl Chunk {
     pub fn verify(self) -> VerifiedChunk {
         VerifiedChunk::new(self)
     }
+    /// Create a verified chunk by calculating a hash sum for it using the given hasher.
+    #[inline]
+    pub fn verify_with(self, hasher: &ChunkHasher) -> VerifiedChunk {
+        VerifiedChunk {
+            hash_sum: hasher.digest(self.data()),
+            chunk: self,
+        }
+    }
+    /// CRC32C (Castagnoli) checksum of the chunk data.
+    #[inline]
+    pub fn crc32c(&self) -> u32 {
+        crate::crc32c::crc32c(self.data())
+    }
     #[cfg(feature = "compress")]
     /// Create a compressed chunk.
     #[inline]
@@ -100,6 +113,7 @@ pub struct CompressedChunk {
     pub(crate) data: Bytes,
     pub(crate) source_size: usize,
     pub(crate) compression: Option<CompressionAlgorithm>,
+    pub(crate) window_log: Option<u32>,
 }
 
 impl CompressedChunk {
@@ -114,12 +128,14 @@ impl CompressedChunk {
                 source_size: chunk.len(),
                 data: compression.compress(chunk.0)?,
                 compression: Some(compression.algorithm),
+                window_log: compression.window_log,
             })
         } else {
             Ok(CompressedChunk {
                 source_size: chunk.len(),
                 data: chunk.0,
                 compression: None,
+                window_log: None,
             })
         }
     }
@@ -135,8 +151,26 @@ impl CompressedChunk {
     }
     /// Decompress the chunk.
     pub fn decompress(self) -> Result<Chunk, CompressionError> {
+        self.decompress_with(&CodecDictionaries::default())
+    }
+    /// Decompress the chunk, passing the matching dictionary to a custom codec.
+    pub fn decompress_with(
+        self,
+        dictionaries: &CodecDictionaries,
+    ) -> Result<Chunk, CompressionError> {
         Ok(match self.compression {
-            Some(compression) => Chunk::from(compression.decompress(self.data, self.source_size)?),
+            Some(compression) => {
+                let dictionary = match compression {
+                    CompressionAlgorithm::Custom(id) => dictionaries.get(id),
+                    _ => None,
+                };
+                Chunk::from(compression.decompress(
+                    self.data,
+                    self.source_size,
+                    self.window_log,
+                    dictionary,
+                )?)
+            }
             // Chunk not compressed.
             None => Chunk::from(self.data),
         })
@@ -159,6 +193,7 @@ impl CompressedChunk {
 pub struct CompressedArchiveChunk {
     pub(crate) chunk: CompressedChunk,
     pub(crate) expected_hash: HashSum,
+    pub(crate) expected_crc32c: Option<u32>,
 }
 
 impl CompressedArchiveChunk {
@@ -166,11 +201,24 @@ impl CompressedArchiveChunk {
     pub fn len(&self) -> usize {
         self.chunk.len()
     }
+    /// Hash of the chunk as stored in the archive.
+    #[inline]
+    pub fn expected_hash(&self) -> &HashSum {
+        &self.expected_hash
+    }
     /// Decompress the chunk.
     pub fn decompress(self) -> Result<ArchiveChunk, CompressionError> {
+        self.decompress_with(&CodecDictionaries::default())
+    }
+    /// Decompress the chunk, passing the matching dictionary to a custom codec.
+    pub fn decompress_with(
+        self,
+        dictionaries: &CodecDictionaries,
+    ) -> Result<ArchiveChunk, CompressionError> {
         Ok(ArchiveChunk {
-            chunk: self.chunk.decompress()?,
+            chunk: self.chunk.decompress_with(dictionaries)?,
             expected_hash: self.expected_hash,
+            expected_crc32c: self.expected_crc32c,
         })
     }
 }
@@ -193,6 +241,7 @@ impl fmt::Display for HashSumMismatchError {
 pub struct ArchiveChunk {
     pub(crate) chunk: Chunk,
     pub(crate) expected_hash: HashSum,
+    pub(crate) expected_crc32c: Option<u32>,
 }
 
 impl ArchiveChunk {
@@ -205,7 +254,12 @@ impl ArchiveChunk {
     /// Results in a verified chunk or an error if the chunk hash sum doesn't
     /// match with the expected one.
     pub fn verify(self) -> Result<VerifiedChunk, HashSumMismatchError> {
-        let mut hash_sum = HashSum::b2_digest(self.chunk.data());
+        self.verify_with(&ChunkHasher::default())
+    }
+    /// Verify an unverified chunk using the given hasher.
+    #[allow(clippy::result_large_err)]
+    pub fn verify_with(self, hasher: &ChunkHasher) -> Result<VerifiedChunk, HashSumMismatchError> {
+        let mut hash_sum = hasher.digest(self.chunk.data());
         hash_sum.truncate(self.expected_hash.len());
         if hash_sum != self.expected_hash {
             Err(HashSumMismatchError {
@@ -220,4 +274,89 @@ impl ArchiveChunk {
             })
         }
     }
+    /// Verify an unverified chunk using the CRC32C stored in the archive as a cheap corruption
+    /// screen.
+    ///
+    /// The hash sum is only calculated if `full_verify` is set, if the chunk has no CRC stored
+    /// or if the CRC doesn't match.
+    #[allow(clippy::result_large_err)]
+    pub fn verify_crc_with(
+        self,
+        hasher: &ChunkHasher,
+        full_verify: bool,
+    ) -> Result<VerifiedChunk, HashSumMismatchError> {
+        match self.expected_crc32c {
+            Some(expected) if !full_verify => {
+                if self.chunk.crc32c() == expected {
+                    return Ok(VerifiedChunk {
+                        chunk: self.chunk,
+                        hash_sum: self.expected_hash,
+                    });
+                }
+                log::warn!(
+                    "CRC mismatch for chunk {}, verifying its hash",
+                    self.expected_hash
+                );
+                self.verify_with(hasher)
+            }
+            _ => self.verify_with(hasher),
+        }
+    }
+    /// CRC32C of the chunk as stored in the archive, if any.
+    #[inline]
+    pub fn expected_crc32c(&self) -> Option<u32> {
+        self.expected_crc32c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_with_matching_personalization() {
+        let hasher = ChunkHasher::with_personalization(b"bita-test").unwrap();
+        let chunk = Chunk::from(vec![1, 2, 3, 4, 5]);
+        let archive_chunk = ArchiveChunk {
+            expected_hash: chunk.clone().verify_with(&hasher).hash().clone(),
+            expected_crc32c: None,
+            chunk,
+        };
+        assert!(archive_chunk.clone().verify_with(&hasher).is_ok());
+        assert!(archive_chunk.clone().verify().is_err());
+        assert!(archive_chunk
+            .verify_with(&ChunkHasher::with_personalization(b"other").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn crc_mismatch_triggers_hash_verification() {
+        let hasher = ChunkHasher::default();
+        let chunk = Chunk::from(vec![1, 2, 3, 4, 5]);
+        let archive_chunk = |data: Vec<u8>, crc: u32, hash: &HashSum| ArchiveChunk {
+            chunk: Chunk::from(data),
+            expected_hash: hash.clone(),
+            expected_crc32c: Some(crc),
+        };
+        let hash = chunk.clone().verify().hash().clone();
+        let crc = chunk.crc32c();
+        // Matching CRC skips the hash, even when it's wrong, unless a full verification is requested.
+        let bogus_hash = HashSum::from(&[0u8; 64][..]);
+        assert!(archive_chunk(vec![1, 2, 3, 4, 5], crc, &bogus_hash)
+            .verify_crc_with(&hasher, false)
+            .is_ok());
+        assert!(archive_chunk(vec![1, 2, 3, 4, 5], crc, &bogus_hash)
+            .verify_crc_with(&hasher, true)
+            .is_err());
+        // Corrupt data is caught by the CRC and then by the hash.
+        let err = archive_chunk(vec![1, 2, 3, 4, 6], crc, &hash)
+            .verify_crc_with(&hasher, false)
+            .unwrap_err();
+        assert_eq!(err.invalid_chunk.data(), &[1, 2, 3, 4, 6]);
+        // A CRC mismatch on intact data is cleared by the hash.
+        let verified = archive_chunk(vec![1, 2, 3, 4, 5], crc ^ 1, &hash)
+            .verify_crc_with(&hasher, false)
+            .unwrap();
+        assert_eq!(verified.hash(), &hash);
+    }
 }
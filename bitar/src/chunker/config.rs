@@ -1,7 +1,13 @@
+use std::fmt;
 use tokio::io::AsyncRead;
 
-use super::{fixed_size::FixedSizeChunker, rolling_hash::RollingHashChunker, Chunker};
-use crate::rolling_hash::{BuzHash, RollSum};
+use super::{
+    custom::{registered_chunker, UnknownChunker},
+    fixed_size::FixedSizeChunker,
+    rolling_hash::RollingHashChunker,
+    Chunker,
+};
+use crate::rolling_hash::{BuzHash, BuzHashTable, RollSum};
 
 /// Helper type for creating a bit mask to use while scanning for chunk boundaries.
 ///
@@ -17,7 +23,7 @@ impl FilterBits {
     ///
     /// The actual target size will be the given size rounded down to the closest power of 2 value.
     pub fn from_size(size: u32) -> Self {
-        Self(30 - size.leading_zeros())
+        Self(30u32.saturating_sub(size.leading_zeros()))
     }
     /// Create new filter mask from a number of bits.
     ///
@@ -26,12 +32,17 @@ impl FilterBits {
         Self(bits)
     }
     /// Get the bit mask value of the filter.
+    ///
+    /// No bits set gives an empty mask and more than 32 bits a full mask.
     pub fn mask(self) -> u32 {
-        (!0 as u32) >> (32 - self.0)
+        (!0u32)
+            .checked_shr(32u32.saturating_sub(self.0))
+            .unwrap_or(0)
     }
     /// Get the average target size from the filter.
     pub fn chunk_target_average(self) -> u32 {
-        1 << (self.0 + 1)
+        1u32.checked_shl(self.0.saturating_add(1))
+            .unwrap_or(u32::MAX)
     }
     /// Get number of bits set in the filter.
     pub fn bits(self) -> u32 {
@@ -50,6 +61,42 @@ pub struct FilterConfig {
     pub max_chunk_size: usize,
     /// Number of bytes kept in the rolling hash window while scanning.
     pub window_size: usize,
+    /// Substitution table of the buzhash chunker, the default table if not set.
+    pub buzhash_table: Option<BuzHashTable>,
+}
+
+// Size formatted using the largest binary unit which divides it evenly.
+struct Size(usize);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MIB: usize = 1024 * 1024;
+        match self.0 {
+            0 => write!(f, "0"),
+            size if size % MIB == 0 => write!(f, "{}MiB", size / MIB),
+            size if size % 1024 == 0 => write!(f, "{}KiB", size / 1024),
+            size => write!(f, "{}", size),
+        }
+    }
+}
+
+/// Formats as `bits=16, min=16KiB, max=256KiB, window=48`, followed by `, table=custom` if
+/// a buzhash table is set.
+impl fmt::Display for FilterConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bits={}, min={}, max={}, window={}",
+            self.filter_bits.bits(),
+            Size(self.min_chunk_size),
+            Size(self.max_chunk_size),
+            Size(self.window_size)
+        )?;
+        if self.buzhash_table.is_some() {
+            write!(f, ", table=custom")?;
+        }
+        Ok(())
+    }
 }
 
 /// Algorithm and configuration to use while scanning for chunk boundaries.
@@ -58,16 +105,80 @@ pub enum Config {
     BuzHash(FilterConfig),
     RollSum(FilterConfig),
     FixedSize(usize),
+    /// Registered custom chunker of given id.
+    Custom(u32, FilterConfig),
+}
+
+/// Formats as e.g. `buzhash(bits=16, min=16KiB, max=256KiB, window=48)` or `fixed(size=64KiB)`.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Config::BuzHash(filter_config) => write!(f, "buzhash({})", filter_config),
+            Config::RollSum(filter_config) => write!(f, "rollsum({})", filter_config),
+            Config::FixedSize(size) => write!(f, "fixed(size={})", Size(*size)),
+            Config::Custom(id, filter_config) => write!(f, "custom:{}({})", id, filter_config),
+        }
+    }
 }
 
 impl Config {
+    /// Create a buzhash configuration expected to cut a source of the given size into about
+    /// `chunks` chunks.
+    ///
+    /// Only useful when the source size is known up front. The chunk sizes vary with the
+    /// content, and so does the actual number of chunks. The min chunk size is set so that
+    /// the average chunk size, the min chunk size plus the average distance between hash
+    /// matches, is the source size divided by the number of chunks.
+    pub fn for_chunk_count(source_size: u64, chunks: u64) -> Self {
+        // Small enough to keep the window inside the min chunk size, and large enough for
+        // the max chunk size to fit in the dictionary.
+        const MIN_TARGET: u64 = 64;
+        const MAX_TARGET: u64 = 1 << 29;
+        let target = (source_size / chunks.max(1)).clamp(MIN_TARGET, MAX_TARGET);
+        // Matches are at least a quarter of the target apart on average
+        let filter_bits = 63 - target.leading_zeros() - 1;
+        let min_chunk_size = target - (1 << filter_bits);
+        Config::BuzHash(FilterConfig {
+            filter_bits: FilterBits(filter_bits),
+            min_chunk_size: min_chunk_size as usize,
+            // Rarely reached, to not skew the average
+            max_chunk_size: (target * 4) as usize,
+            window_size: 16,
+            buzhash_table: None,
+        })
+    }
+    /// Size of the largest chunk the chunker may produce.
+    ///
+    /// Custom chunkers are expected to honor the max chunk size of their filter configuration.
+    pub fn max_chunk_size(&self) -> usize {
+        match self {
+            Config::BuzHash(filter_config)
+            | Config::RollSum(filter_config)
+            | Config::Custom(_, filter_config) => filter_config
+                .max_chunk_size
+                .max(filter_config.min_chunk_size),
+            Config::FixedSize(fixed_size) => *fixed_size,
+        }
+    }
+    /// Size of the smallest chunk the chunker produces, except for the last chunk of a source.
+    pub fn min_chunk_size(&self) -> usize {
+        match self {
+            Config::BuzHash(filter_config)
+            | Config::RollSum(filter_config)
+            | Config::Custom(_, filter_config) => filter_config.min_chunk_size,
+            Config::FixedSize(fixed_size) => *fixed_size,
+        }
+    }
     pub fn new_chunker<'chunker, R>(&self, source: R) -> Box<dyn Chunker + Send + Unpin + 'chunker>
     where
         R: AsyncRead + Unpin + Send + 'chunker,
     {
         match self {
             Config::BuzHash(filter_config) => Box::new(RollingHashChunker::new(
-                BuzHash::new(filter_config.window_size),
+                match &filter_config.buzhash_table {
+                    Some(table) => BuzHash::with_table(filter_config.window_size, table),
+                    None => BuzHash::new(filter_config.window_size),
+                },
                 filter_config,
                 source,
             )),
@@ -77,6 +188,83 @@ impl Config {
                 source,
             )),
             Config::FixedSize(fixed_size) => Box::new(FixedSizeChunker::new(*fixed_size, source)),
+            Config::Custom(id, filter_config) => match registered_chunker(*id) {
+                Ok(factory) => factory.new_chunker(filter_config, Box::new(source)),
+                Err(err) => Box::new(UnknownChunker(Some(err))),
+            },
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::tests::random_data;
+    use futures_util::StreamExt;
+
+    #[test]
+    fn filter_bits_out_of_range() {
+        assert_eq!(FilterBits(0).mask(), 0);
+        assert_eq!(FilterBits(3).mask(), 0b111);
+        assert_eq!(FilterBits(32).mask(), u32::MAX);
+        assert_eq!(FilterBits(40).mask(), u32::MAX);
+        assert_eq!(FilterBits(40).chunk_target_average(), u32::MAX);
+        assert_eq!(FilterBits::from_size(0).bits(), 0);
+    }
+
+    fn filter_config() -> FilterConfig {
+        FilterConfig {
+            filter_bits: FilterBits(16),
+            min_chunk_size: 16 * 1024,
+            max_chunk_size: 256 * 1024,
+            window_size: 48,
+            buzhash_table: None,
+        }
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            Config::BuzHash(filter_config()).to_string(),
+            "buzhash(bits=16, min=16KiB, max=256KiB, window=48)"
+        );
+        assert_eq!(
+            Config::RollSum(filter_config()).to_string(),
+            "rollsum(bits=16, min=16KiB, max=256KiB, window=48)"
+        );
+        assert_eq!(
+            Config::FixedSize(4 * 1024 * 1024).to_string(),
+            "fixed(size=4MiB)"
+        );
+        assert_eq!(Config::FixedSize(1000).to_string(), "fixed(size=1000)");
+        assert_eq!(
+            Config::Custom(300, filter_config()).to_string(),
+            "custom:300(bits=16, min=16KiB, max=256KiB, window=48)"
+        );
+    }
+
+    #[tokio::test]
+    async fn chunk_count_config_gives_about_that_many_chunks() {
+        let source = random_data(8 * 1024 * 1024, 0);
+        for &chunks in &[50u64, 300, 2000] {
+            let config = Config::for_chunk_count(source.len() as u64, chunks);
+            let count = config.new_chunker(&source[..]).count().await as u64;
+            assert!(
+                count * 100 >= chunks * 85 && count * 100 <= chunks * 115,
+                "{} chunks using {} when targeting {}",
+                count,
+                config,
+                chunks
+            );
+        }
+    }
+
+    #[test]
+    fn chunk_count_config_of_tiny_source() {
+        let config = Config::for_chunk_count(10, 0);
+        assert_eq!(config.min_chunk_size(), 32);
+        assert_eq!(config.max_chunk_size(), 256);
+        let config = Config::for_chunk_count(u64::MAX, 1);
+        assert_eq!(config.max_chunk_size(), 2 << 30);
+    }
+}
@@ -0,0 +1,80 @@
+use core::task::{Context, Poll};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, RwLock};
+use tokio::io::AsyncRead;
+
+use super::{Chunker, FilterConfig};
+use crate::Chunk;
+
+/// No custom chunker registered with the given id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownChunkerError(pub u32);
+impl std::error::Error for UnknownChunkerError {}
+impl fmt::Display for UnknownChunkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no chunker registered with id {}", self.0)
+    }
+}
+
+/// Factory of a custom chunker.
+///
+/// Implement to scan for chunks using an algorithm which isn't built into bitar. The algorithm
+/// is identified in the archive by its id and must be registered using [`register_chunker`]
+/// both when creating and when reading an archive.
+pub trait ChunkerFactory: Send + Sync {
+    /// Chunking algorithm id stored in the archive.
+    fn id(&self) -> u32;
+    /// Create a chunker scanning the given source.
+    fn new_chunker<'chunker>(
+        &self,
+        config: &FilterConfig,
+        source: Box<dyn AsyncRead + Unpin + Send + 'chunker>,
+    ) -> Box<dyn Chunker + Send + Unpin + 'chunker>;
+}
+
+/// Lowest id allowed for a custom chunker, ids below are reserved for the built-in chunkers.
+pub const CUSTOM_CHUNKER_MIN_ID: u32 = 256;
+
+static CHUNKERS: Lazy<RwLock<HashMap<u32, Arc<dyn ChunkerFactory>>>> = Lazy::new(Default::default);
+
+/// Register a custom chunker.
+///
+/// A chunker registered with an id already in use replaces the previous one.
+///
+/// # Panics
+///
+/// Panics if the chunker id is below [`CUSTOM_CHUNKER_MIN_ID`].
+pub fn register_chunker(factory: Arc<dyn ChunkerFactory>) {
+    let id = factory.id();
+    assert!(
+        id >= CUSTOM_CHUNKER_MIN_ID,
+        "chunker id {} is reserved for built-in chunkers",
+        id
+    );
+    CHUNKERS.write().unwrap().insert(id, factory);
+}
+
+pub(crate) fn registered_chunker(id: u32) -> Result<Arc<dyn ChunkerFactory>, UnknownChunkerError> {
+    CHUNKERS
+        .read()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or(UnknownChunkerError(id))
+}
+
+// Chunker used in place of an unregistered custom chunker, fails on first poll.
+pub(crate) struct UnknownChunker(pub(crate) Option<UnknownChunkerError>);
+
+impl Chunker for UnknownChunker {
+    fn poll_chunk(&mut self, _cx: &mut Context) -> Poll<Option<io::Result<(u64, Chunk)>>> {
+        Poll::Ready(
+            self.0
+                .take()
+                .map(|err| Err(io::Error::new(io::ErrorKind::Other, err))),
+        )
+    }
+}
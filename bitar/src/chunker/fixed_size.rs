@@ -16,7 +16,9 @@ pub struct FixedSizeChunker<R> {
 }
 
 impl<'a, R> FixedSizeChunker<R> {
+    /// Create a chunker of the given chunk size, a size of zero is treated as one byte.
     pub fn new(fixed_size: usize, source: R) -> Self {
+        let fixed_size = fixed_size.max(1);
         Self {
             chunk_size: fixed_size,
             read_buf: BytesMut::with_capacity(fixed_size + CHUNKER_BUF_SIZE),
@@ -35,6 +37,7 @@ where
             if self.read_buf.len() >= self.chunk_size {
                 let chunk_start = self.chunk_start;
                 let chunk = Chunk(self.read_buf.split_to(self.chunk_size).freeze());
+                self.source_index += chunk.len() as u64;
                 self.chunk_start = self.source_index;
                 return Poll::Ready(Some(Ok((chunk_start, chunk))));
             } else {
@@ -53,6 +56,7 @@ where
                     if !self.read_buf.is_empty() {
                         let chunk_start = self.chunk_start;
                         let chunk = Chunk(self.read_buf.split().freeze());
+                        self.source_index += chunk.len() as u64;
                         self.chunk_start = self.source_index;
                         return Poll::Ready(Some(Ok((chunk_start, chunk))));
                     } else {
@@ -1,11 +1,19 @@
 //! Chunker related functions and types.
 mod config;
+pub(crate) mod custom;
 mod fixed_size;
+mod parameters;
 mod rolling_hash;
+mod rolling_hash_scanner;
 
 pub use config::{Config, FilterBits, FilterConfig};
+pub use custom::{register_chunker, ChunkerFactory, UnknownChunkerError, CUSTOM_CHUNKER_MIN_ID};
 pub use fixed_size::FixedSizeChunker;
+pub use parameters::ChunkerParametersError;
 pub use rolling_hash::RollingHashChunker;
+pub use rolling_hash_scanner::RollingHashScanner;
+
+pub use crate::rolling_hash::{BuzHash, BuzHashTable, RollSum, RollingHash};
 
 use bytes::BytesMut;
 use core::pin::Pin;
@@ -19,6 +27,9 @@ use crate::Chunk;
 const CHUNKER_BUF_SIZE: usize = 1024 * 1024;
 
 /// A chunker scans a readable source for chunks and emits them as a stream.
+///
+/// A chunker never emits an empty chunk, an empty source gives no chunks at all. Source
+/// lengths which are an exact multiple of the max chunk size end with a full chunk.
 pub trait Chunker {
     fn poll_chunk(&mut self, cx: &mut Context) -> Poll<Option<io::Result<(u64, Chunk)>>>;
 }
@@ -26,7 +37,13 @@ pub trait Chunker {
 impl Stream for dyn Chunker + Send + Unpin + '_ {
     type Item = io::Result<(u64, Chunk)>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
-        self.poll_chunk(cx)
+        loop {
+            match self.poll_chunk(cx) {
+                // Empty chunks of a custom chunker are dropped to keep the guarantee
+                Poll::Ready(Some(Ok((_, chunk)))) if chunk.len() == 0 => continue,
+                poll => return poll,
+            }
+        }
     }
 }
 
@@ -41,22 +58,16 @@ where
 {
     let mut read_count = 0;
     let before_size = read_buf.len();
-    {
-        // Use set_len() here instead of resize as we don't care for zeroing the content of buf.
-        let new_size = before_size + want;
-        if read_buf.capacity() < new_size {
-            read_buf.reserve(want);
-        }
-        unsafe {
-            read_buf.set_len(new_size);
-        }
-    }
+    // The buffer is zeroed since ReadBuf::new expects initialized memory.
+    read_buf.resize(before_size + want, 0);
     while read_count < want {
         let offset = before_size + read_count;
         let mut buf = ReadBuf::new(&mut read_buf[offset..]);
         let rc = match Pin::new(&mut source).poll_read(cx, &mut buf) {
             Poll::Ready(Ok(())) if buf.filled().is_empty() => break, // EOF
             Poll::Ready(Ok(())) => buf.filled().len(),
+            // Interrupted reads are retried, like std::io::Read::read_exact does
+            Poll::Ready(Err(err)) if err.kind() == io::ErrorKind::Interrupted => continue,
             Poll::Ready(Err(err)) => {
                 read_buf.resize(before_size + read_count, 0);
                 return Poll::Ready(Err(err));
@@ -76,7 +87,7 @@ where
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::config::{Config, FilterBits, FilterConfig};
     use super::*;
     use core::pin::Pin;
@@ -85,20 +96,42 @@ mod tests {
     use std::cmp;
     use tokio::io::AsyncRead;
 
-    // The MockSource will return bytes_per_read bytes every other read
-    // and Pending every other, to replicate a source with limited I/O.
+    // Pseudo random test data, the same for the same seed.
+    pub(crate) fn random_data(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = 0x1234_5678_9abc_def1 ^ seed.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    // The MockSource gives reads of sizes from a repeated pattern and returns Pending after
+    // every pending_cadence reads, to replicate a source with limited I/O.
     struct MockSource {
         data: Vec<u8>,
         offset: usize,
-        bytes_per_read: usize,
+        // Number of bytes given by each read, repeated.
+        read_pattern: Vec<usize>,
+        // Return Pending after this many reads, never if zero.
+        pending_cadence: usize,
+        reads: usize,
         pending: bool,
     }
     impl MockSource {
         fn new(data: Vec<u8>, bytes_per_read: usize) -> Self {
+            Self::with_pattern(data, vec![bytes_per_read], 1)
+        }
+        fn with_pattern(data: Vec<u8>, read_pattern: Vec<usize>, pending_cadence: usize) -> Self {
             Self {
                 data,
                 offset: 0,
-                bytes_per_read,
+                read_pattern,
+                pending_cadence,
+                reads: 0,
                 pending: false,
             }
         }
@@ -106,7 +139,7 @@ mod tests {
     impl AsyncRead for MockSource {
         fn poll_read(
             mut self: Pin<&mut Self>,
-            _cx: &mut Context,
+            cx: &mut Context,
             buf: &mut ReadBuf,
         ) -> Poll<io::Result<()>> {
             let data_available = self.data.len() - self.offset;
@@ -114,20 +147,132 @@ mod tests {
                 Poll::Ready(Ok(()))
             } else if self.pending {
                 self.pending = false;
+                cx.waker().wake_by_ref();
                 Poll::Pending
             } else {
+                let bytes_per_read = self.read_pattern[self.reads % self.read_pattern.len()];
                 let read = cmp::min(
                     data_available,
-                    cmp::min(buf.initialized().len(), self.bytes_per_read),
+                    cmp::min(buf.remaining(), cmp::max(bytes_per_read, 1)),
                 );
-                buf.put_slice(&self.data[self.offset..self.offset + read]);
+                let offset = self.offset;
+                buf.put_slice(&self.data[offset..offset + read]);
                 self.offset += read;
-                self.pending = true;
+                self.reads += 1;
+                self.pending = self.pending_cadence > 0 && self.reads % self.pending_cadence == 0;
                 Poll::Ready(Ok(()))
             }
         }
     }
 
+    // Chunk offsets when reading the whole source at once.
+    async fn chunk_offsets<R: AsyncRead + Unpin + Send>(config: &Config, source: R) -> Vec<u64> {
+        config
+            .new_chunker(source)
+            .map(|result| {
+                let (offset, chunk) = result.unwrap();
+                assert!(!chunk.data().is_empty());
+                offset
+            })
+            .collect::<Vec<u64>>()
+            .await
+    }
+
+    // Same as the fuzz target (see fuzz/fuzz_targets/chunker.rs), run for pseudo random input.
+    #[tokio::test]
+    async fn random_configs_and_read_patterns() {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move |max: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % (max as u64 + 1)) as usize
+        };
+        for _ in 0..500 {
+            let filter_config = FilterConfig {
+                filter_bits: FilterBits(next(34) as u32),
+                min_chunk_size: next(300),
+                max_chunk_size: next(1000),
+                window_size: if next(10) == 0 { next(20000) } else { next(70) },
+                buzhash_table: None,
+            };
+            let config = match next(2) {
+                0 => Config::BuzHash(filter_config),
+                1 => Config::RollSum(filter_config),
+                _ => Config::FixedSize(next(500)),
+            };
+            let source: Vec<u8> = (0..next(5000)).map(|_| next(255) as u8).collect();
+            let pattern: Vec<usize> = (0..next(4) + 1).map(|_| next(300)).collect();
+            let expected = chunk_offsets(&config, &source[..]).await;
+            let offsets =
+                chunk_offsets(&config, MockSource::with_pattern(source, pattern, next(3))).await;
+            assert_eq!(offsets, expected, "{:?}", config);
+        }
+    }
+
+    // Windows larger than a byte can index must roll out the right bytes. Every boundary not at
+    // max chunk size has a sum matching the filter, also when hashing the window from scratch.
+    #[tokio::test]
+    async fn large_window() {
+        const WINDOW: usize = 1024;
+        let filter_config = FilterConfig {
+            filter_bits: FilterBits(10),
+            min_chunk_size: 512,
+            max_chunk_size: 16 * 1024,
+            window_size: WINDOW,
+            buzhash_table: None,
+        };
+        let source = random_data(64 * 1024, 1);
+        let window_sum = |config: &Config, end: usize| {
+            let window = &source[end - WINDOW..end];
+            match config {
+                Config::BuzHash(_) => {
+                    let mut hasher = BuzHash::new(WINDOW);
+                    window.iter().for_each(|&v| hasher.init(v));
+                    hasher.sum()
+                }
+                _ => {
+                    let mut hasher = RollSum::new(WINDOW);
+                    window.iter().for_each(|&v| hasher.input(v));
+                    hasher.sum()
+                }
+            }
+        };
+        let mask = filter_config.filter_bits.mask();
+        for (config, expected) in &[
+            (
+                Config::BuzHash(filter_config.clone()),
+                vec![
+                    0, 2281, 3352, 4149, 5315, 6711, 8140, 9269, 9905, 11546, 12458, 13430, 14448,
+                    16394, 17554, 19155, 20007, 20961, 22186, 23726, 24591, 25637, 26361, 27181,
+                    27926, 29031, 30360, 32081, 35855, 36748, 37391, 38234, 40181, 42426, 43601,
+                    44162, 45521, 47939, 49165, 50215, 54033, 54685, 55499, 56493, 59034, 62724,
+                    63294, 65476,
+                ],
+            ),
+            (
+                Config::RollSum(filter_config.clone()),
+                vec![
+                    0, 1051, 1659, 2596, 3248, 4038, 4663, 6454, 7995, 8581, 9541, 11237, 11838,
+                    13027, 14424, 15178, 15855, 17850, 20589, 23292, 24440, 26330, 28273, 28929,
+                    31735, 32635, 33376, 34840, 36503, 40617, 41585, 42681, 43404, 44220, 46422,
+                    47428, 48198, 49325, 50363, 51918, 53101, 53788, 55022, 55633, 57091, 57949,
+                    60673, 62009, 62881, 63702, 64594, 65225,
+                ],
+            ),
+        ] {
+            let offsets = chunk_offsets(config, &source[..]).await;
+            let ends = offsets.iter().skip(1).map(|&offset| offset as usize);
+            for (start, end) in offsets.iter().map(|&offset| offset as usize).zip(ends) {
+                if end - start < filter_config.max_chunk_size {
+                    let sum = window_sum(config, end);
+                    assert_eq!(sum | mask, sum, "{:?} boundary at {}", config, end);
+                }
+            }
+            assert_eq!(&offsets, expected, "{:?}", config);
+        }
+    }
+
     #[tokio::test]
     async fn single_byte_per_source_read() {
         for chunker_config in &[
@@ -136,12 +281,14 @@ mod tests {
                 min_chunk_size: 20,
                 max_chunk_size: 600,
                 window_size: 10,
+                buzhash_table: None,
             }),
             Config::BuzHash(FilterConfig {
                 filter_bits: FilterBits(10),
                 min_chunk_size: 20,
                 max_chunk_size: 600,
                 window_size: 10,
+                buzhash_table: None,
             }),
         ] {
             let source_data: Vec<u8> = {
@@ -177,6 +324,49 @@ mod tests {
             assert_eq!(expected_offsets, offsets);
         }
     }
+
+    // Source failing its first read with Interrupted, then reading from the inner source.
+    struct InterruptedOnce<R> {
+        inner: R,
+        interrupted: bool,
+    }
+    impl<R: AsyncRead + Unpin> AsyncRead for InterruptedOnce<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut ReadBuf,
+        ) -> Poll<io::Result<()>> {
+            if !self.interrupted {
+                self.interrupted = true;
+                return Poll::Ready(Err(io::ErrorKind::Interrupted.into()));
+            }
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    #[tokio::test]
+    async fn interrupted_read_is_retried() {
+        let source_data: Vec<u8> = (0..10000u32).map(|v| (v * 7 % 251) as u8).collect();
+        for chunker_config in &[
+            Config::FixedSize(1000),
+            Config::BuzHash(FilterConfig {
+                filter_bits: FilterBits(8),
+                min_chunk_size: 20,
+                max_chunk_size: 600,
+                window_size: 10,
+                buzhash_table: None,
+            }),
+        ] {
+            let expected = chunk_offsets(chunker_config, &source_data[..]).await;
+            let source = InterruptedOnce {
+                inner: &source_data[..],
+                interrupted: false,
+            };
+            let offsets = chunk_offsets(chunker_config, source).await;
+            assert_eq!(offsets, expected, "{:?}", chunker_config);
+        }
+    }
+
     #[tokio::test]
     async fn zero_data() {
         for chunker_config in &[
@@ -185,12 +375,14 @@ mod tests {
                 min_chunk_size: 3,
                 max_chunk_size: 640,
                 window_size: 5,
+                buzhash_table: None,
             }),
             Config::BuzHash(FilterConfig {
                 filter_bits: FilterBits(5),
                 min_chunk_size: 3,
                 max_chunk_size: 640,
                 window_size: 5,
+                buzhash_table: None,
             }),
         ] {
             let expected_chunk_offsets: [u64; 0] = [0; 0];
@@ -209,6 +401,99 @@ mod tests {
             );
         }
     }
+    #[tokio::test]
+    async fn source_of_max_chunk_size_multiples() {
+        // A filter which practically never matches, so every chunk is cut at the max size
+        let filter_config = FilterConfig {
+            filter_bits: FilterBits(31),
+            min_chunk_size: 64,
+            max_chunk_size: 1000,
+            window_size: 16,
+            buzhash_table: None,
+        };
+        let configs = [
+            Config::BuzHash(filter_config.clone()),
+            Config::RollSum(filter_config),
+            Config::FixedSize(1000),
+        ];
+        let data = random_data(3000, 2);
+        for config in &configs {
+            for multiple in 0..=3 {
+                let source = &data[..multiple * 1000];
+                for &read_size in &[1, 7, 1000, 4096] {
+                    let chunks: Vec<(u64, usize)> = config
+                        .new_chunker(MockSource::new(source.to_vec(), read_size))
+                        .map(|result| {
+                            let (offset, chunk) = result.unwrap();
+                            (offset, chunk.len())
+                        })
+                        .collect()
+                        .await;
+                    let expected: Vec<(u64, usize)> =
+                        (0..multiple).map(|i| (i as u64 * 1000, 1000)).collect();
+                    assert_eq!(chunks, expected, "{} of size {}", config, source.len());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fixed_size_source_boundaries() {
+        const SIZE: usize = 1000;
+        let data: Vec<u8> = (0..3 * SIZE + 1).map(|i| (i % 251) as u8).collect();
+        for &(source_size, expected_sizes) in &[
+            (0, &[][..]),
+            (1, &[1][..]),
+            (SIZE - 1, &[SIZE - 1][..]),
+            (SIZE, &[SIZE][..]),
+            (SIZE + 1, &[SIZE, 1][..]),
+            (3 * SIZE, &[SIZE, SIZE, SIZE][..]),
+            (3 * SIZE + 1, &[SIZE, SIZE, SIZE, 1][..]),
+        ] {
+            let source = &data[..source_size];
+            for &read_size in &[1, SIZE - 1, SIZE, SIZE + 1, 4096] {
+                let chunks: Vec<(u64, Chunk)> = Config::FixedSize(SIZE)
+                    .new_chunker(MockSource::new(source.to_vec(), read_size))
+                    .map(|result| result.unwrap())
+                    .collect()
+                    .await;
+                let sizes: Vec<usize> = chunks.iter().map(|(_, chunk)| chunk.len()).collect();
+                assert_eq!(sizes, expected_sizes, "source of size {}", source_size);
+                for (index, (offset, chunk)) in chunks.iter().enumerate() {
+                    assert_eq!(*offset, (index * SIZE) as u64);
+                    assert_eq!(
+                        chunk.data(),
+                        &source[index * SIZE..index * SIZE + chunk.len()]
+                    );
+                }
+            }
+        }
+    }
+
+    struct EmptyChunksChunker(Vec<usize>);
+
+    impl Chunker for EmptyChunksChunker {
+        fn poll_chunk(&mut self, _cx: &mut Context) -> Poll<Option<io::Result<(u64, Chunk)>>> {
+            Poll::Ready(if self.0.is_empty() {
+                None
+            } else {
+                let size = self.0.remove(0);
+                Some(Ok((0, Chunk::from(vec![1; size]))))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_chunks_are_never_emitted() {
+        let chunker: Box<dyn Chunker + Send + Unpin> =
+            Box::new(EmptyChunksChunker(vec![0, 10, 0, 0, 5, 0]));
+        let sizes: Vec<usize> = chunker
+            .map(|result| result.unwrap().1.len())
+            .collect()
+            .await;
+        assert_eq!(sizes, [10, 5]);
+    }
+
     #[tokio::test]
     async fn source_smaller_than_hash_window() {
         for chunker_config in &[
@@ -217,12 +502,14 @@ mod tests {
                 min_chunk_size: 0,
                 max_chunk_size: 40,
                 window_size: 10,
+                buzhash_table: None,
             }),
             Config::BuzHash(FilterConfig {
                 filter_bits: FilterBits(5),
                 min_chunk_size: 0,
                 max_chunk_size: 40,
                 window_size: 10,
+                buzhash_table: None,
             }),
         ] {
             let expected_chunk_offsets: [u64; 1] = [0; 1];
@@ -249,12 +536,14 @@ mod tests {
                 min_chunk_size: 10,
                 max_chunk_size: 40,
                 window_size: 5,
+                buzhash_table: None,
             }),
             Config::BuzHash(FilterConfig {
                 filter_bits: FilterBits(5),
                 min_chunk_size: 10,
                 max_chunk_size: 40,
                 window_size: 5,
+                buzhash_table: None,
             }),
         ] {
             let expected_chunk_offsets: [u64; 1] = [0; 1];
@@ -274,6 +563,33 @@ mod tests {
         }
     }
     #[tokio::test]
+    async fn fixed_size_offsets() {
+        static SRC: [u8; 10] = [0x1f, 0x55, 0x39, 0x5e, 0xfa, 0x12, 0x34, 0x56, 0x78, 0x9a];
+        let expected_chunk_offsets: [u64; 3] = [0, 4, 8];
+        assert_eq!(
+            Config::FixedSize(4)
+                .new_chunker(&mut Box::new(&SRC[..]))
+                .map(|result| {
+                    let (offset, chunk) = result.unwrap();
+                    assert_eq!(chunk.data(), &SRC[offset as usize..][..chunk.len()]);
+                    offset
+                })
+                .collect::<Vec<u64>>()
+                .await,
+            &expected_chunk_offsets
+        );
+        // Same offsets if the source only gives back a single byte per read
+        let mut source = MockSource::new(SRC.to_vec(), 1);
+        assert_eq!(
+            Config::FixedSize(4)
+                .new_chunker(&mut source)
+                .map(|result| result.unwrap().0)
+                .collect::<Vec<u64>>()
+                .await,
+            &expected_chunk_offsets
+        );
+    }
+    #[tokio::test]
     async fn consistency_small_min_chunk_buzhash() {
         let expected_chunk_offsets = vec![
             0, 23, 139, 162, 177, 194, 224, 237, 279, 395, 418, 433, 450, 480, 493, 535, 651, 674,
@@ -311,6 +627,7 @@ mod tests {
             min_chunk_size: 3,
             max_chunk_size: 640,
             window_size: 5,
+            buzhash_table: None,
         })
         .new_chunker(&mut Box::new(unsafe { &SRC[..] }))
         .map(|result| {
@@ -404,6 +721,7 @@ mod tests {
             min_chunk_size: 64,
             max_chunk_size: 1024,
             window_size: 20,
+            buzhash_table: None,
         })
         .new_chunker(&mut Box::new(unsafe { &SRC[..] }))
         .map(|result| {
@@ -0,0 +1,197 @@
+use std::convert::TryFrom;
+use std::fmt;
+use tokio::io::AsyncRead;
+
+use super::custom::registered_chunker;
+use super::{
+    BuzHashTable, Chunker, Config, FilterBits, FilterConfig, UnknownChunkerError,
+    CUSTOM_CHUNKER_MIN_ID,
+};
+use crate::chunk_dictionary::{chunker_parameters::ChunkingAlgorithm, ChunkerParameters};
+
+/// Chunker parameters which don't describe a usable chunker.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChunkerParametersError {
+    UnknownChunker(UnknownChunkerError),
+    /// The buzhash table doesn't hold a value for every input byte.
+    InvalidBuzHashTable(usize),
+}
+
+impl std::error::Error for ChunkerParametersError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::UnknownChunker(err) => Some(err),
+            Self::InvalidBuzHashTable(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ChunkerParametersError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownChunker(_) => write!(f, "unknown chunker"),
+            Self::InvalidBuzHashTable(size) => {
+                write!(f, "buzhash table of {} values (expected 256)", size)
+            }
+        }
+    }
+}
+
+impl From<UnknownChunkerError> for ChunkerParametersError {
+    fn from(err: UnknownChunkerError) -> Self {
+        Self::UnknownChunker(err)
+    }
+}
+
+impl ChunkerParameters {
+    /// Chunker configuration described by the parameters.
+    ///
+    /// Fails if the chunking algorithm is neither built in nor a registered custom chunker, or
+    /// if the buzhash table is of the wrong size.
+    pub fn chunker_config(&self) -> Result<Config, ChunkerParametersError> {
+        let buzhash_table = match &self.buzhash_table[..] {
+            [] => None,
+            table => Some(
+                BuzHashTable::from_slice(table)
+                    .ok_or(ChunkerParametersError::InvalidBuzHashTable(table.len()))?,
+            ),
+        };
+        let filter_config = FilterConfig {
+            filter_bits: FilterBits::from_bits(self.chunk_filter_bits),
+            min_chunk_size: self.min_chunk_size as usize,
+            max_chunk_size: self.max_chunk_size as usize,
+            window_size: self.rolling_hash_window_size as usize,
+            buzhash_table,
+        };
+        match ChunkingAlgorithm::from_i32(self.chunking_algorithm) {
+            Some(ChunkingAlgorithm::Buzhash) => Ok(Config::BuzHash(filter_config)),
+            Some(ChunkingAlgorithm::Rollsum) => Ok(Config::RollSum(filter_config)),
+            Some(ChunkingAlgorithm::FixedSize) => {
+                Ok(Config::FixedSize(self.max_chunk_size as usize))
+            }
+            None => match u32::try_from(self.chunking_algorithm) {
+                Ok(id) if id >= CUSTOM_CHUNKER_MIN_ID => {
+                    registered_chunker(id)?;
+                    Ok(Config::Custom(id, filter_config))
+                }
+                _ => Err(UnknownChunkerError(self.chunking_algorithm as u32).into()),
+            },
+        }
+    }
+
+    /// Create a chunker scanning the given source, giving the same chunks as the chunker used
+    /// when the parameters were built.
+    pub fn into_chunker<'chunker, R>(
+        self,
+        source: R,
+    ) -> Result<Box<dyn Chunker + Send + Unpin + 'chunker>, ChunkerParametersError>
+    where
+        R: AsyncRead + Unpin + Send + 'chunker,
+    {
+        Ok(self.chunker_config()?.new_chunker(source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::tests::random_data;
+    use futures_util::StreamExt;
+
+    fn params(config: &Config) -> ChunkerParameters {
+        let (algorithm, filter_config) = match config {
+            Config::BuzHash(filter_config) => (ChunkingAlgorithm::Buzhash as i32, filter_config),
+            Config::RollSum(filter_config) => (ChunkingAlgorithm::Rollsum as i32, filter_config),
+            Config::FixedSize(size) => {
+                return ChunkerParameters {
+                    max_chunk_size: *size as u32,
+                    chunking_algorithm: ChunkingAlgorithm::FixedSize as i32,
+                    ..Default::default()
+                }
+            }
+            Config::Custom(id, filter_config) => (*id as i32, filter_config),
+        };
+        ChunkerParameters {
+            chunk_filter_bits: filter_config.filter_bits.bits(),
+            min_chunk_size: filter_config.min_chunk_size as u32,
+            max_chunk_size: filter_config.max_chunk_size as u32,
+            rolling_hash_window_size: filter_config.window_size as u32,
+            chunk_hash_length: 64,
+            chunking_algorithm: algorithm,
+            buzhash_table: filter_config
+                .buzhash_table
+                .as_ref()
+                .map(|table| table.values().to_vec())
+                .unwrap_or_default(),
+        }
+    }
+
+    async fn offsets(chunker: Box<dyn Chunker + Send + Unpin + '_>) -> Vec<u64> {
+        chunker.map(|result| result.unwrap().0).collect().await
+    }
+
+    #[tokio::test]
+    async fn same_offsets_as_config() {
+        let source = random_data(200_000, 4);
+        let filter_config = FilterConfig {
+            filter_bits: FilterBits(8),
+            min_chunk_size: 128,
+            max_chunk_size: 4096,
+            window_size: 32,
+            buzhash_table: None,
+        };
+        let seeded_config = FilterConfig {
+            buzhash_table: Some(BuzHashTable::from_seed(7)),
+            ..filter_config.clone()
+        };
+        assert_ne!(
+            offsets(Config::BuzHash(seeded_config.clone()).new_chunker(&source[..])).await,
+            offsets(Config::BuzHash(filter_config.clone()).new_chunker(&source[..])).await
+        );
+        for config in &[
+            Config::BuzHash(filter_config.clone()),
+            Config::BuzHash(seeded_config),
+            Config::RollSum(filter_config.clone()),
+            Config::FixedSize(3000),
+        ] {
+            let params = params(config);
+            assert_eq!(
+                params.chunker_config().unwrap().to_string(),
+                config.to_string()
+            );
+            let expected = offsets(config.new_chunker(&source[..])).await;
+            assert!(expected.len() > 10);
+            assert_eq!(
+                offsets(params.into_chunker(&source[..]).unwrap()).await,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_algorithm() {
+        for &algorithm in &[3, 255, -1, 0x7fff_fff0] {
+            let params = ChunkerParameters {
+                chunking_algorithm: algorithm,
+                ..Default::default()
+            };
+            assert_eq!(
+                params.into_chunker(&b""[..]).err(),
+                Some(UnknownChunkerError(algorithm as u32).into())
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_buzhash_table() {
+        let params = ChunkerParameters {
+            chunking_algorithm: ChunkingAlgorithm::Buzhash as i32,
+            buzhash_table: vec![1; 100],
+            ..Default::default()
+        };
+        assert_eq!(
+            params.chunker_config().err(),
+            Some(ChunkerParametersError::InvalidBuzHashTable(100))
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use super::FilterConfig;
+use crate::rolling_hash::RollingHash;
+
+/// Chunk boundary detection of the rolling hash chunker, without any I/O.
+///
+/// Feed the source data in order using [`RollingHashScanner::scan`], in slices of any size.
+/// Gives the same chunk boundaries as [`RollingHashChunker`](super::RollingHashChunker) does
+/// for the same source, no matter how the source is sliced.
+pub struct RollingHashScanner<H> {
+    hasher: H,
+    filter_mask: u32,
+    min_chunk_size: usize,
+    max_chunk_size: usize,
+    hash_input_limit: usize,
+    // Number of source bytes used to initialize the hasher window.
+    initialized: usize,
+    // Number of bytes scanned of the current chunk.
+    chunk_len: usize,
+}
+
+impl<H> RollingHashScanner<H>
+where
+    H: RollingHash,
+{
+    pub fn new(hasher: H, config: &FilterConfig) -> Self {
+        Self {
+            hasher,
+            filter_mask: config.filter_bits.mask(),
+            min_chunk_size: config.min_chunk_size,
+            // A chunk is never empty or smaller than the min chunk size, which takes precedence
+            // over a smaller max chunk size.
+            max_chunk_size: config.max_chunk_size.max(config.min_chunk_size).max(1),
+            // Allow for chunk size less than buzhash window
+            hash_input_limit: config.min_chunk_size.saturating_sub(config.window_size),
+            initialized: 0,
+            chunk_len: 0,
+        }
+    }
+
+    /// Scan the next slice of source data for a chunk boundary.
+    ///
+    /// Returns the number of bytes of the slice which ends the current chunk if a boundary
+    /// was found, the rest of the slice should then be scanned again as the start of the next
+    /// chunk. Returns `None` if the whole slice belongs to the current chunk. A boundary is
+    /// never found before the first byte of a chunk, so a chunk is never empty.
+    pub fn scan(&mut self, data: &[u8]) -> Option<usize> {
+        let start = self.chunk_len;
+        let available = start + data.len();
+        let mut pos = start;
+        // A window larger than the max chunk size is initialized across multiple chunks
+        let init_end = core::cmp::min(self.max_chunk_size, available);
+        while self.initialized < self.hasher.window_size() && pos < init_end {
+            // Initialize the rolling hash
+            self.hasher.init(data[pos - start]);
+            self.initialized += 1;
+            pos += 1;
+        }
+        if self.hash_input_limit > 0 && pos < self.hash_input_limit {
+            // Skip past the minimum chunk size to minimize the number of hash inputs
+            pos = core::cmp::min(self.hash_input_limit - 1, available);
+        }
+        if self.min_chunk_size > 0 && pos < self.min_chunk_size {
+            // Hash the last part (rolling hash window size bytes) of the minimal possible chunk.
+            // There is no need to check the hash sum here since we're still below the minimal
+            // chunk size. Still we need the bytes in the hash window to get correct sum when
+            // reaching above the minimal chunk size.
+            let input_end = core::cmp::min(self.min_chunk_size - 1, available);
+            let hasher = &mut self.hasher;
+            data[pos - start..input_end - start]
+                .iter()
+                .for_each(|&val| hasher.input(val));
+            pos = input_end;
+        }
+        // Scan until end of data, chunk boundary (hash sum match) or max chunk size reached
+        let scan_end = core::cmp::min(self.max_chunk_size, available);
+        let hasher = &mut self.hasher;
+        let filter_mask = self.filter_mask;
+        let found_boundary = data[pos.min(scan_end) - start..scan_end - start]
+            .iter()
+            .any(|&val| {
+                pos += 1;
+                hasher.input(val);
+                let sum = hasher.sum();
+                sum | filter_mask == sum
+            });
+        if found_boundary || pos >= self.max_chunk_size {
+            self.chunk_len = 0;
+            Some(pos - start)
+        } else {
+            self.chunk_len = available;
+            None
+        }
+    }
+
+    /// Number of bytes scanned of the current chunk.
+    pub fn chunk_len(&self) -> usize {
+        self.chunk_len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunker::{tests::random_data, Config, FilterBits};
+    use crate::rolling_hash::{BuzHash, RollSum};
+    use futures_util::StreamExt;
+
+    // Scan the source in slices of the given size, returning the offset of each chunk.
+    fn scan_offsets<H: RollingHash>(
+        mut scanner: RollingHashScanner<H>,
+        source: &[u8],
+        slice_size: usize,
+    ) -> Vec<u64> {
+        let mut offsets = vec![];
+        let mut chunk_start = 0;
+        let mut scanned = 0;
+        for mut slice in source.chunks(slice_size) {
+            while let Some(chunk_end) = scanner.scan(slice) {
+                offsets.push(chunk_start as u64);
+                scanned += chunk_end;
+                chunk_start = scanned;
+                slice = &slice[chunk_end..];
+            }
+            scanned += slice.len();
+        }
+        if chunk_start < source.len() {
+            offsets.push(chunk_start as u64);
+        }
+        offsets
+    }
+
+    #[tokio::test]
+    async fn same_offsets_as_chunker() {
+        let config = FilterConfig {
+            filter_bits: FilterBits(6),
+            min_chunk_size: 64,
+            max_chunk_size: 1024,
+            window_size: 20,
+            buzhash_table: None,
+        };
+        let source = random_data(100_000, 3);
+        for chunker_config in &[
+            Config::BuzHash(config.clone()),
+            Config::RollSum(config.clone()),
+        ] {
+            let expected: Vec<u64> = chunker_config
+                .new_chunker(&source[..])
+                .map(|result| result.unwrap().0)
+                .collect()
+                .await;
+            assert!(expected.len() > 10);
+            for &slice_size in &[1, 7, 1000, source.len()] {
+                let offsets = match chunker_config {
+                    Config::BuzHash(_) => scan_offsets(
+                        RollingHashScanner::new(BuzHash::new(config.window_size), &config),
+                        &source,
+                        slice_size,
+                    ),
+                    _ => scan_offsets(
+                        RollingHashScanner::new(RollSum::new(config.window_size), &config),
+                        &source,
+                        slice_size,
+                    ),
+                };
+                assert_eq!(offsets, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn max_chunk_size_multiples_end_with_full_chunk() {
+        let config = FilterConfig {
+            filter_bits: FilterBits(31),
+            min_chunk_size: 64,
+            max_chunk_size: 1000,
+            window_size: 16,
+            buzhash_table: None,
+        };
+        let source: Vec<u8> = (0..3000u32)
+            .map(|v| (v.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        for multiple in 0..=3 {
+            for &slice_size in &[1, 7, 1000, 4096] {
+                let mut scanner =
+                    RollingHashScanner::new(BuzHash::new(config.window_size), &config);
+                let mut chunk_sizes = vec![];
+                let mut chunk_len = 0;
+                for mut slice in source[..multiple * 1000].chunks(slice_size) {
+                    // Empty slices, like reads at the end of a source, never end a chunk
+                    assert_eq!(scanner.scan(&[]), None);
+                    while let Some(chunk_end) = scanner.scan(slice) {
+                        assert!(chunk_len + chunk_end > 0);
+                        chunk_sizes.push(chunk_len + chunk_end);
+                        chunk_len = 0;
+                        slice = &slice[chunk_end..];
+                    }
+                    chunk_len += slice.len();
+                }
+                assert_eq!(scanner.scan(&[]), None);
+                assert_eq!(chunk_len, 0);
+                assert_eq!(chunk_sizes, vec![1000; multiple]);
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     io::{self, SeekFrom},
 };
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
@@ -10,6 +10,15 @@ use crate::{Chunk, ChunkIndex, HashSum, ReorderOp, VerifiedChunk};
 pub struct CloneOutput<T> {
     pub(crate) inner: T,
     pub(crate) clone_index: ChunkIndex,
+    ordered_writes: Option<OrderedWrites>,
+    size_limit: Option<u64>,
+}
+
+// Chunk writes waiting to be flushed in offset order.
+struct OrderedWrites {
+    max_buffered: usize,
+    buffered: usize,
+    pending: BTreeMap<u64, Bytes>,
 }
 
 impl<T> CloneOutput<T> {
@@ -17,6 +26,71 @@ impl<T> CloneOutput<T> {
         Self {
             inner: output,
             clone_index,
+            ordered_writes: None,
+            size_limit: None,
+        }
+    }
+    /// Fail to write any chunk which would end past the given size of the output.
+    ///
+    /// Set to the source size of the archive being cloned, so that a tampered archive can't
+    /// make the clone extend a file or write past the end of a block device.
+    #[must_use]
+    pub fn size_limit(mut self, size: u64) -> Self {
+        self.size_limit = Some(size);
+        self
+    }
+    /// Buffer chunk writes and issue them in ascending offset order.
+    ///
+    /// Up to `max_buffered` bytes of chunk data are kept in memory before being written
+    /// to the output, which may improve throughput on storage where seeking is expensive.
+    /// When enabled `flush` must be called before the output is consumed, use
+    /// [`CloneOutput::try_into_inner`] to check that no writes are still buffered.
+    #[must_use]
+    pub fn ordered_writes(mut self, max_buffered: usize) -> Self {
+        self.ordered_writes = Some(OrderedWrites {
+            max_buffered,
+            buffered: 0,
+            pending: BTreeMap::new(),
+        });
+        self
+    }
+    /// Write any buffered chunks to the output.
+    pub async fn flush(&mut self) -> io::Result<()>
+    where
+        T: AsyncWrite + AsyncSeek + Unpin + Send,
+    {
+        if let Some(ordered) = &mut self.ordered_writes {
+            ordered.buffered = 0;
+            // Chunks following each other are written without seeking in between, letting a
+            // buffered output merge them into larger writes.
+            let mut position = None;
+            for (offset, data) in std::mem::take(&mut ordered.pending) {
+                if position != Some(offset) {
+                    self.inner.seek(SeekFrom::Start(offset)).await?;
+                }
+                self.inner.write_all(&data).await?;
+                position = Some(offset + data.len() as u64);
+            }
+        }
+        Ok(())
+    }
+    fn check_size_limit(&self, offsets: &[u64], size: usize) -> io::Result<()> {
+        let limit = match self.size_limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        match offsets
+            .iter()
+            .find(|&&offset| !matches!(offset.checked_add(size as u64), Some(end) if end <= limit))
+        {
+            Some(offset) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "chunk of size {} at offset {} ends past the source size {}",
+                    size, offset, limit
+                ),
+            )),
+            None => Ok(()),
         }
     }
     async fn write_offset(&mut self, offsets: &[u64], verified: &VerifiedChunk) -> io::Result<usize>
@@ -35,10 +109,24 @@ impl<T> CloneOutput<T> {
     where
         T: AsyncWrite + AsyncSeek + Unpin + Send,
     {
-        if let Some(location) = self.clone_index.remove(verified.hash()) {
-            Ok(self.write_offset(location.offsets(), verified).await?)
+        let location = match self.clone_index.remove(verified.hash()) {
+            Some(location) => location,
+            None => return Ok(0),
+        };
+        self.check_size_limit(location.offsets(), verified.len())?;
+        if let Some(ordered) = &mut self.ordered_writes {
+            for &offset in location.offsets() {
+                ordered
+                    .pending
+                    .insert(offset, verified.chunk().clone().into_inner());
+                ordered.buffered += verified.len();
+            }
+            if ordered.buffered >= ordered.max_buffered {
+                self.flush().await?;
+            }
+            Ok(verified.len() * location.offsets().len())
         } else {
-            Ok(0)
+            self.write_offset(location.offsets(), verified).await
         }
     }
     pub fn chunks(&self) -> &ChunkIndex {
@@ -50,14 +138,43 @@ impl<T> CloneOutput<T> {
     pub fn is_empty(&self) -> bool {
         self.clone_index.is_empty()
     }
+    /// Consume the clone output and return the inner output.
+    ///
+    /// Ordered writes which have not been flushed to the output are discarded, use
+    /// [`CloneOutput::try_into_inner`] to detect those.
     pub fn into_inner(self) -> T {
+        if let Some(ordered) = &self.ordered_writes {
+            if !ordered.pending.is_empty() {
+                log::warn!(
+                    "{} chunk writes not flushed to output are discarded",
+                    ordered.pending.len()
+                );
+            }
+        }
         self.inner
     }
+    /// Consume the clone output and return the inner output.
+    ///
+    /// Fails if ordered writes are buffered which have not been flushed to the output.
+    pub fn try_into_inner(self) -> io::Result<T> {
+        match &self.ordered_writes {
+            Some(ordered) if !ordered.pending.is_empty() => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} chunk writes not flushed to output",
+                    ordered.pending.len()
+                ),
+            )),
+            _ => Ok(self.inner),
+        }
+    }
     /// Re-order chunks of output in place.
     pub async fn reorder_in_place(&mut self, output_index: ChunkIndex) -> io::Result<u64>
     where
         T: AsyncRead + AsyncWrite + AsyncSeek + Unpin + Send,
     {
+        // Chunks read back from the output must be in place before reordering.
+        self.flush().await?;
         let mut total_moved: u64 = 0;
         let (already_in_place, in_place_total_size) =
             output_index.strip_chunks_already_in_place(&mut self.clone_index);
@@ -78,6 +195,7 @@ impl<T> CloneOutput<T> {
                     source,
                     dest,
                 } => {
+                    self.check_size_limit(&dest[..], size)?;
                     if let Some(verified) = temp_store.remove(hash) {
                         self.write_offset(&dest[..], &verified).await?;
                     } else {
@@ -113,3 +231,132 @@ impl<T> CloneOutput<T> {
         Ok(total_moved + in_place_total_size)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+    use std::io::Cursor;
+
+    // Keeps track of the output position of every write.
+    struct RecordingOutput {
+        inner: Cursor<Vec<u8>>,
+        write_offsets: Vec<u64>,
+    }
+    impl AsyncWrite for RecordingOutput {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let position = self.inner.position();
+            self.write_offsets.push(position);
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+    impl AsyncSeek for RecordingOutput {
+        fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+            Pin::new(&mut self.inner).start_seek(position)
+        }
+        fn poll_complete(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+            Pin::new(&mut self.inner).poll_complete(cx)
+        }
+    }
+
+    fn source_chunks() -> Vec<VerifiedChunk> {
+        vec![
+            Chunk::from(vec![1; 10]).verify(),
+            Chunk::from(vec![2; 20]).verify(),
+            Chunk::from(vec![3; 5]).verify(),
+        ]
+    }
+
+    async fn clone_reversed(max_buffered: Option<usize>) -> RecordingOutput {
+        let mut index = ChunkIndex::new_empty(HashSum::MAX_LEN);
+        let chunks = source_chunks();
+        index.add_chunk(chunks[0].hash().clone(), 10, &[0, 35]);
+        index.add_chunk(chunks[1].hash().clone(), 20, &[10]);
+        index.add_chunk(chunks[2].hash().clone(), 5, &[30]);
+        let output = RecordingOutput {
+            inner: Cursor::new(Vec::new()),
+            write_offsets: Vec::new(),
+        };
+        let mut output = match max_buffered {
+            Some(max_buffered) => CloneOutput::new(output, index).ordered_writes(max_buffered),
+            None => CloneOutput::new(output, index),
+        };
+        for chunk in chunks.iter().rev() {
+            output.feed(chunk).await.unwrap();
+        }
+        output.flush().await.unwrap();
+        assert!(output.is_empty());
+        output.try_into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn chunk_past_size_limit() {
+        let chunks = source_chunks();
+        for &max_buffered in &[None, Some(1024)] {
+            let mut index = ChunkIndex::new_empty(HashSum::MAX_LEN);
+            index.add_chunk(chunks[0].hash().clone(), 10, &[0]);
+            // Ends one byte past the source size
+            index.add_chunk(chunks[1].hash().clone(), 20, &[10, 21]);
+            index.add_chunk(chunks[2].hash().clone(), 5, &[u64::MAX - 2]);
+            let mut output = CloneOutput::new(Cursor::new(Vec::new()), index).size_limit(40);
+            if let Some(max_buffered) = max_buffered {
+                output = output.ordered_writes(max_buffered);
+            }
+            output.feed(&chunks[0]).await.unwrap();
+            for chunk in &chunks[1..] {
+                let err = output.feed(chunk).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            }
+            output.flush().await.unwrap();
+            // Nothing but the valid chunk is written
+            assert_eq!(output.try_into_inner().unwrap().into_inner(), vec![1; 10]);
+        }
+    }
+
+    fn expected_output() -> Vec<u8> {
+        [vec![1; 10], vec![2; 20], vec![3; 5], vec![1; 10]].concat()
+    }
+
+    #[tokio::test]
+    async fn unordered_writes() {
+        let output = clone_reversed(None).await;
+        assert_eq!(output.inner.into_inner(), expected_output());
+    }
+
+    #[tokio::test]
+    async fn ordered_writes_in_offset_order() {
+        let output = clone_reversed(Some(1024)).await;
+        assert_eq!(output.write_offsets, vec![0, 10, 30, 35]);
+        assert_eq!(output.inner.into_inner(), expected_output());
+    }
+
+    #[tokio::test]
+    async fn unflushed_ordered_writes_not_dropped() {
+        let chunks = source_chunks();
+        let mut index = ChunkIndex::new_empty(HashSum::MAX_LEN);
+        index.add_chunk(chunks[0].hash().clone(), 10, &[0]);
+        let mut output = CloneOutput::new(Cursor::new(Vec::new()), index).ordered_writes(1024);
+        output.feed(&chunks[0]).await.unwrap();
+        let err = output.try_into_inner().unwrap_err();
+        assert_eq!(err.to_string(), "1 chunk writes not flushed to output");
+    }
+
+    #[tokio::test]
+    async fn ordered_writes_small_buffer() {
+        // Every feed fills the buffer, so writes are only ordered within each chunk.
+        let output = clone_reversed(Some(1)).await;
+        assert_eq!(output.write_offsets, vec![30, 10, 0, 35]);
+        assert_eq!(output.inner.into_inner(), expected_output());
+    }
+}
@@ -1,5 +1,8 @@
 use bytes::Bytes;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::{Arc, RwLock};
 
 use crate::chunk_dictionary as dict;
 
@@ -8,6 +11,7 @@ pub enum CompressionError {
     Io(std::io::Error),
     #[cfg(feature = "lzma-compression")]
     LZMA(lzma::LzmaError),
+    Codec(Box<dyn std::error::Error + Send + Sync>),
 }
 impl std::error::Error for CompressionError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
@@ -15,6 +19,7 @@ impl std::error::Error for CompressionError {
             CompressionError::Io(err) => Some(err),
             #[cfg(feature = "lzma-compression")]
             CompressionError::LZMA(err) => Some(err),
+            CompressionError::Codec(err) => Some(err.as_ref()),
         }
     }
 }
@@ -24,6 +29,7 @@ impl fmt::Display for CompressionError {
             Self::Io(_) => write!(f, "i/o error"),
             #[cfg(feature = "lzma-compression")]
             Self::LZMA(_) => write!(f, "LZMA error"),
+            Self::Codec(_) => write!(f, "codec error"),
         }
     }
 }
@@ -53,6 +59,195 @@ impl fmt::Display for CompressionLevelOutOfRangeError {
     }
 }
 
+/// Compression window not supported by the algorithm.
+#[derive(Debug)]
+pub struct WindowLogOutOfRangeError(CompressionAlgorithm);
+impl std::error::Error for WindowLogOutOfRangeError {}
+impl fmt::Display for WindowLogOutOfRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.window_log_range() {
+            Some((min, max)) => write!(
+                f,
+                "{} window log out of range (valid range is {}-{})",
+                self.0, min, max
+            ),
+            None => write!(f, "{} has no configurable window", self.0),
+        }
+    }
+}
+
+/// Compressing using multiple threads not supported by the algorithm.
+#[derive(Debug)]
+pub struct ThreadsUnsupportedError(CompressionAlgorithm);
+impl std::error::Error for ThreadsUnsupportedError {}
+impl fmt::Display for ThreadsUnsupportedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} can't compress a chunk using multiple threads",
+            self.0
+        )
+    }
+}
+
+/// Archive is compressed using an algorithm which bitar was built without.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressionFeatureMissingError {
+    algorithm: &'static str,
+    feature: &'static str,
+}
+impl CompressionFeatureMissingError {
+    #[allow(dead_code)]
+    pub(crate) fn new(algorithm: &'static str, feature: &'static str) -> Self {
+        Self { algorithm, feature }
+    }
+    /// Name of the compression algorithm used by the archive.
+    pub fn algorithm(&self) -> &'static str {
+        self.algorithm
+    }
+    /// Cargo feature required to decompress the archive.
+    pub fn feature(&self) -> &'static str {
+        self.feature
+    }
+}
+impl std::error::Error for CompressionFeatureMissingError {}
+impl fmt::Display for CompressionFeatureMissingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "archive is compressed using {} but bitar was built without the {} feature",
+            self.algorithm, self.feature
+        )
+    }
+}
+
+/// No custom codec registered with the given id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownCodecError(pub u32);
+impl std::error::Error for UnknownCodecError {}
+impl fmt::Display for UnknownCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no codec registered with id {}", self.0)
+    }
+}
+
+/// Chunk compression codec.
+///
+/// Implement to compress chunks using a codec which isn't built into bitar. The codec is
+/// identified in the archive by its id and must be registered using [`register_codec`] both
+/// when creating and when reading an archive.
+pub trait ChunkCodec: Send + Sync {
+    /// Codec id stored in the archive.
+    fn id(&self) -> u32;
+    /// Compress a block of data.
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError>;
+    /// Decompress a block of data, the expected size of the decompressed data is given as hint.
+    fn decompress(&self, data: &[u8], size_hint: usize) -> Result<Vec<u8>, CompressionError>;
+    /// Decompress a block of data using a dictionary supplied when reading the archive, see
+    /// [`CodecDictionaries`]. The dictionary is ignored unless implemented.
+    fn decompress_with_dictionary(
+        &self,
+        data: &[u8],
+        size_hint: usize,
+        dictionary: &[u8],
+    ) -> Result<Vec<u8>, CompressionError> {
+        let _ = dictionary;
+        self.decompress(data, size_hint)
+    }
+}
+
+/// Dictionaries for custom codecs, keyed by codec id.
+///
+/// For codecs which need a dictionary to decompress which isn't stored in the archive, e.g.
+/// because of its size.
+#[derive(Debug, Clone, Default)]
+pub struct CodecDictionaries {
+    dictionaries: HashMap<u32, Bytes>,
+}
+
+impl CodecDictionaries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the dictionary of the codec with the given id.
+    pub fn insert(&mut self, codec_id: u32, dictionary: impl Into<Bytes>) {
+        self.dictionaries.insert(codec_id, dictionary.into());
+    }
+    /// Dictionary of the codec with the given id.
+    pub fn get(&self, codec_id: u32) -> Option<&[u8]> {
+        self.dictionaries
+            .get(&codec_id)
+            .map(|dictionary| &dictionary[..])
+    }
+    pub fn is_empty(&self) -> bool {
+        self.dictionaries.is_empty()
+    }
+}
+
+/// Lowest id allowed for a custom codec, ids below are reserved for the built-in codecs.
+pub const CUSTOM_CODEC_MIN_ID: u32 = 256;
+
+static CODECS: Lazy<RwLock<HashMap<u32, Arc<dyn ChunkCodec>>>> = Lazy::new(Default::default);
+
+/// Register a custom codec.
+///
+/// A codec registered with an id already in use replaces the previous one.
+///
+/// # Panics
+///
+/// Panics if the codec id is below [`CUSTOM_CODEC_MIN_ID`].
+pub fn register_codec(codec: Arc<dyn ChunkCodec>) {
+    let id = codec.id();
+    assert!(
+        id >= CUSTOM_CODEC_MIN_ID,
+        "codec id {} is reserved for built-in codecs",
+        id
+    );
+    CODECS.write().unwrap().insert(id, codec);
+}
+
+pub(crate) fn registered_codec(id: u32) -> Result<Arc<dyn ChunkCodec>, UnknownCodecError> {
+    CODECS
+        .read()
+        .unwrap()
+        .get(&id)
+        .cloned()
+        .ok_or(UnknownCodecError(id))
+}
+
+/// Names of the built-in codecs bitar was built with.
+///
+/// Archives compressed using any other built-in codec can't be read by this build.
+pub fn compiled_codecs() -> &'static [&'static str] {
+    &[
+        "brotli",
+        #[cfg(feature = "lzma-compression")]
+        "lzma",
+        #[cfg(feature = "zstd-compression")]
+        "zstd",
+    ]
+}
+
+/// Optional cargo features bitar was built with.
+pub fn compiled_features() -> &'static [&'static str] {
+    &[
+        #[cfg(feature = "compress")]
+        "compress",
+        #[cfg(feature = "lzma-compression")]
+        "lzma-compression",
+        #[cfg(feature = "zstd-compression")]
+        "zstd-compression",
+        #[cfg(feature = "default-tls")]
+        "default-tls",
+        #[cfg(feature = "rustls-tls")]
+        "rustls-tls",
+        #[cfg(feature = "sftp")]
+        "sftp",
+        #[cfg(feature = "test-codec")]
+        "test-codec",
+    ]
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CompressionAlgorithm {
     #[cfg(feature = "lzma-compression")]
@@ -60,6 +255,8 @@ pub enum CompressionAlgorithm {
     #[cfg(feature = "zstd-compression")]
     Zstd,
     Brotli,
+    /// Registered custom codec of given id.
+    Custom(u32),
 }
 
 impl CompressionAlgorithm {
@@ -71,13 +268,28 @@ impl CompressionAlgorithm {
             #[cfg(feature = "zstd-compression")]
             CompressionAlgorithm::Zstd => 22,
             CompressionAlgorithm::Brotli => 11,
+            CompressionAlgorithm::Custom(_) => 0,
+        }
+    }
+    /// Get the range of window sizes (as log2) the algorithm supports, if configurable.
+    pub fn window_log_range(self) -> Option<(u32, u32)> {
+        match self {
+            #[cfg(feature = "zstd-compression")]
+            CompressionAlgorithm::Zstd => Some((10, 31)),
+            CompressionAlgorithm::Brotli => Some((10, 24)),
+            _ => None,
         }
     }
     /// Decompress a block of data using the set compression.
+    ///
+    /// The window the data was compressed with must be given if not the default one, for the
+    /// decoder to allow it. The dictionary is passed to custom codecs.
     pub(crate) fn decompress(
         self,
         compressed: Bytes,
         size_hint: usize,
+        #[allow(unused_variables)] window_log: Option<u32>,
+        dictionary: Option<&[u8]>,
     ) -> Result<Bytes, CompressionError> {
         let mut output = Vec::with_capacity(size_hint);
         match self {
@@ -91,12 +303,27 @@ impl CompressionAlgorithm {
             }
             #[cfg(feature = "zstd-compression")]
             CompressionAlgorithm::Zstd => {
-                zstd::stream::copy_decode(&compressed[..], &mut output)?;
+                let mut decoder = zstd::stream::Decoder::new(&compressed[..])?;
+                if let Some(window_log) = window_log {
+                    decoder.window_log_max(window_log)?;
+                }
+                std::io::copy(&mut decoder, &mut output)?;
             }
             CompressionAlgorithm::Brotli => {
+                // The window is given by the stream header
                 let mut input_slice = &compressed[..];
                 brotli_decompressor::BrotliDecompress(&mut input_slice, &mut output)?;
             }
+            CompressionAlgorithm::Custom(id) => {
+                let codec =
+                    registered_codec(id).map_err(|err| CompressionError::Codec(err.into()))?;
+                output = match dictionary {
+                    Some(dictionary) => {
+                        codec.decompress_with_dictionary(&compressed, size_hint, dictionary)?
+                    }
+                    None => codec.decompress(&compressed, size_hint)?,
+                };
+            }
         }
         Ok(Bytes::from(output))
     }
@@ -110,19 +337,47 @@ impl fmt::Display for CompressionAlgorithm {
             #[cfg(feature = "zstd-compression")]
             CompressionAlgorithm::Zstd => "zstd",
             CompressionAlgorithm::Brotli => "Brotli",
+            CompressionAlgorithm::Custom(id) => return write!(f, "custom codec {}", id),
         };
         write!(f, "{}", algorithm_name)
     }
 }
 
+/// Name of a built-in codec, whether or not bitar was built with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecName {
+    Lzma,
+    Zstd,
+    Brotli,
+}
+
+impl CodecName {
+    // The codec's algorithm if built in.
+    fn algorithm(self) -> Option<CompressionAlgorithm> {
+        match self {
+            #[cfg(feature = "lzma-compression")]
+            CodecName::Lzma => Some(CompressionAlgorithm::Lzma),
+            #[cfg(feature = "zstd-compression")]
+            CodecName::Zstd => Some(CompressionAlgorithm::Zstd),
+            CodecName::Brotli => Some(CompressionAlgorithm::Brotli),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
 /// Compression.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Compression {
     pub(crate) algorithm: CompressionAlgorithm,
     pub(crate) level: u32,
+    pub(crate) window_log: Option<u32>,
+    pub(crate) threads: Option<u32>,
 }
 
 impl Compression {
+    /// Level used when none is given, valid for all built-in algorithms.
+    pub const DEFAULT_LEVEL: u32 = 6;
     /// Create a new compression of given algorithm and level.
     pub fn try_new(
         algorithm: CompressionAlgorithm,
@@ -131,7 +386,12 @@ impl Compression {
         if level < 1 || level > algorithm.max_level() {
             return Err(CompressionLevelOutOfRangeError(algorithm));
         }
-        Ok(Compression { algorithm, level })
+        Ok(Compression {
+            algorithm,
+            level,
+            window_log: None,
+            threads: None,
+        })
     }
     /// Create a new brotli compression of given level.
     pub fn brotli(level: u32) -> Result<Compression, CompressionLevelOutOfRangeError> {
@@ -147,6 +407,76 @@ impl Compression {
     pub fn zstd(level: u32) -> Result<Compression, CompressionLevelOutOfRangeError> {
         Self::try_new(CompressionAlgorithm::Zstd, level)
     }
+    /// Create a compression using the first codec in priority order which bitar was built
+    /// with, at the default level. No compression if none of them was.
+    pub fn best_effort(priority: &[CodecName]) -> Option<Compression> {
+        priority
+            .iter()
+            .find_map(|name| name.algorithm())
+            .map(|algorithm| Compression {
+                algorithm,
+                level: Self::DEFAULT_LEVEL,
+                window_log: None,
+                threads: None,
+            })
+    }
+    /// Create a compression using a registered custom codec.
+    pub fn custom(id: u32) -> Result<Compression, UnknownCodecError> {
+        registered_codec(id)?;
+        Ok(Compression {
+            algorithm: CompressionAlgorithm::Custom(id),
+            level: 0,
+            window_log: None,
+            threads: None,
+        })
+    }
+    /// Use a window of 2^`window_log` bytes instead of the algorithm's default.
+    ///
+    /// A larger window may improve the compression ratio of large chunks, at the cost of
+    /// memory when compressing and decompressing.
+    pub fn with_window_log(mut self, window_log: u32) -> Result<Self, WindowLogOutOfRangeError> {
+        match self.algorithm.window_log_range() {
+            Some((min, max)) if (min..=max).contains(&window_log) => {
+                self.window_log = Some(window_log);
+                Ok(self)
+            }
+            _ => Err(WindowLogOutOfRangeError(self.algorithm)),
+        }
+    }
+    /// Compress each chunk using the given number of codec internal threads, on top of any
+    /// chunks compressed in parallel. Only supported by zstd.
+    ///
+    /// The output is the same for any number of threads, but differs from the output of
+    /// compressing without threads. Not stored in the archive as it's not needed to decompress.
+    pub fn with_threads(
+        self,
+        #[allow(unused_variables)] threads: u32,
+    ) -> Result<Self, ThreadsUnsupportedError> {
+        match self.algorithm {
+            #[cfg(feature = "zstd-compression")]
+            CompressionAlgorithm::Zstd => Ok(Self {
+                threads: Some(threads.max(1)),
+                ..self
+            }),
+            _ => Err(ThreadsUnsupportedError(self.algorithm)),
+        }
+    }
+    /// Compression algorithm.
+    pub fn algorithm(&self) -> CompressionAlgorithm {
+        self.algorithm
+    }
+    /// Compression level.
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+    /// Compression window size as log2, if not the algorithm's default.
+    pub fn window_log(&self) -> Option<u32> {
+        self.window_log
+    }
+    /// Number of codec internal threads used to compress a chunk, if any.
+    pub fn threads(&self) -> Option<u32> {
+        self.threads
+    }
     /// Compress a block of data with set compression.
     #[cfg(feature = "compress")]
     pub(crate) fn compress(self, chunk: Bytes) -> Result<Bytes, CompressionError> {
@@ -164,51 +494,277 @@ impl Compression {
             }
             #[cfg(feature = "zstd-compression")]
             CompressionAlgorithm::Zstd => {
-                zstd::stream::copy_encode(&chunk[..], &mut output, self.level as i32)?;
+                let mut encoder = zstd::stream::Encoder::new(&mut output, self.level as i32)?;
+                if let Some(window_log) = self.window_log {
+                    encoder.window_log(window_log)?;
+                }
+                if let Some(threads) = self.threads {
+                    encoder.multithread(threads)?;
+                }
+                std::io::copy(&mut &chunk[..], &mut encoder)?;
+                encoder.finish()?;
             }
             CompressionAlgorithm::Brotli => {
-                let params = BrotliEncoderParams {
+                let mut params = BrotliEncoderParams {
                     quality: self.level as i32,
                     magic_number: false,
                     ..Default::default()
                 };
+                if let Some(window_log) = self.window_log {
+                    params.lgwin = window_log as i32;
+                }
                 let mut writer =
                     brotli::CompressorWriter::with_params(&mut output, 1024 * 1024, &params);
                 writer.write_all(&chunk)?;
             }
+            CompressionAlgorithm::Custom(id) => {
+                let codec =
+                    registered_codec(id).map_err(|err| CompressionError::Codec(err.into()))?;
+                output = codec.compress(&chunk)?;
+            }
         }
         Ok(Bytes::from(output))
     }
 }
 
+#[cfg(feature = "compress")]
+impl ChunkCodec for Compression {
+    fn id(&self) -> u32 {
+        dict::ChunkCompression::from(Some(*self)).compression as u32
+    }
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        Ok(Compression::compress(*self, Bytes::copy_from_slice(data))?.to_vec())
+    }
+    fn decompress(&self, data: &[u8], size_hint: usize) -> Result<Vec<u8>, CompressionError> {
+        Ok(self
+            .algorithm
+            .decompress(
+                Bytes::copy_from_slice(data),
+                size_hint,
+                self.window_log,
+                None,
+            )?
+            .to_vec())
+    }
+}
+
+/// Formats as `algorithm:level`, e.g. `zstd:19`, or `custom:id` for custom codecs. A
+/// non-default window is appended, e.g. `zstd:19,window_log=27`.
 impl fmt::Display for Compression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} (level {})", self.algorithm, self.level)
+        let algorithm_name = match self.algorithm {
+            #[cfg(feature = "lzma-compression")]
+            CompressionAlgorithm::Lzma => "lzma",
+            #[cfg(feature = "zstd-compression")]
+            CompressionAlgorithm::Zstd => "zstd",
+            CompressionAlgorithm::Brotli => "brotli",
+            CompressionAlgorithm::Custom(id) => return write!(f, "custom:{}", id),
+        };
+        write!(f, "{}:{}", algorithm_name, self.level)?;
+        if let Some(window_log) = self.window_log {
+            write!(f, ",window_log={}", window_log)?;
+        }
+        Ok(())
     }
 }
 
 impl From<Option<Compression>> for dict::ChunkCompression {
     fn from(c: Option<Compression>) -> Self {
+        let window_log = c.and_then(|c| c.window_log).unwrap_or(0);
         let (compression, compression_level) = match c {
             #[cfg(feature = "lzma-compression")]
             Some(Compression {
                 algorithm: CompressionAlgorithm::Lzma,
                 level,
+                ..
             }) => (dict::chunk_compression::CompressionType::Lzma, level),
             #[cfg(feature = "zstd-compression")]
             Some(Compression {
                 algorithm: CompressionAlgorithm::Zstd,
                 level,
+                ..
             }) => (dict::chunk_compression::CompressionType::Zstd, level),
             Some(Compression {
                 algorithm: CompressionAlgorithm::Brotli,
                 level,
+                ..
             }) => (dict::chunk_compression::CompressionType::Brotli, level),
+            Some(Compression {
+                algorithm: CompressionAlgorithm::Custom(id),
+                level,
+                ..
+            }) => {
+                return Self {
+                    compression: id as i32,
+                    compression_level: level,
+                    window_log,
+                }
+            }
             None => (dict::chunk_compression::CompressionType::None, 0),
         };
         Self {
             compression: compression as i32,
             compression_level,
+            window_log,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiled_codecs_and_features_of_build() {
+        assert!(compiled_codecs().contains(&"brotli"));
+        assert_eq!(
+            compiled_codecs().contains(&"lzma"),
+            cfg!(feature = "lzma-compression")
+        );
+        assert_eq!(
+            compiled_codecs().contains(&"zstd"),
+            cfg!(feature = "zstd-compression")
+        );
+        assert_eq!(
+            compiled_features().contains(&"lzma-compression"),
+            cfg!(feature = "lzma-compression")
+        );
+        assert_eq!(
+            compiled_features().contains(&"zstd-compression"),
+            cfg!(feature = "zstd-compression")
+        );
+        assert_eq!(
+            compiled_features().contains(&"compress"),
+            cfg!(feature = "compress")
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Compression::brotli(6).unwrap().to_string(), "brotli:6");
+        assert_eq!(
+            Compression {
+                algorithm: CompressionAlgorithm::Custom(300),
+                level: 0,
+                window_log: None,
+                threads: None,
+            }
+            .to_string(),
+            "custom:300"
+        );
+    }
+
+    #[cfg(all(feature = "zstd-compression", feature = "compress"))]
+    #[test]
+    fn zstd_threads_give_identical_output() {
+        let data: Bytes = crate::chunker::tests::random_data(4 * 1024 * 1024, 5)
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| if i % 64 < 16 { v } else { (i / 4096) as u8 })
+            .collect::<Vec<u8>>()
+            .into();
+        let compression = Compression::zstd(3).unwrap();
+        let single = compression.compress(data.clone()).unwrap();
+        let two = compression
+            .with_threads(2)
+            .unwrap()
+            .compress(data.clone())
+            .unwrap();
+        let four = compression
+            .with_threads(4)
+            .unwrap()
+            .compress(data.clone())
+            .unwrap();
+        assert_eq!(two, four);
+        for compressed in vec![single, two] {
+            let decompressed = CompressionAlgorithm::Zstd
+                .decompress(compressed, data.len(), None, None)
+                .unwrap();
+            assert_eq!(decompressed, data);
+        }
+        assert!(Compression::brotli(6).unwrap().with_threads(2).is_err());
+    }
+
+    #[cfg(feature = "zstd-compression")]
+    #[test]
+    fn display_zstd() {
+        assert_eq!(Compression::zstd(19).unwrap().to_string(), "zstd:19");
+    }
+
+    #[cfg(feature = "lzma-compression")]
+    #[test]
+    fn display_lzma() {
+        assert_eq!(Compression::lzma(9).unwrap().to_string(), "lzma:9");
+    }
+
+    #[test]
+    fn best_effort_picks_first_built_in() {
+        assert_eq!(Compression::best_effort(&[]), None);
+        assert_eq!(
+            Compression::best_effort(&[CodecName::Brotli, CodecName::Zstd]),
+            Some(Compression::brotli(6).unwrap())
+        );
+    }
+
+    #[cfg(feature = "zstd-compression")]
+    #[test]
+    fn best_effort_zstd() {
+        assert_eq!(
+            Compression::best_effort(&[CodecName::Zstd, CodecName::Brotli]),
+            Some(Compression::zstd(6).unwrap())
+        );
+    }
+
+    #[cfg(not(feature = "zstd-compression"))]
+    #[test]
+    fn best_effort_without_zstd() {
+        assert_eq!(
+            Compression::best_effort(&[CodecName::Zstd, CodecName::Brotli]),
+            Some(Compression::brotli(6).unwrap())
+        );
+        assert_eq!(Compression::best_effort(&[CodecName::Zstd]), None);
+    }
+
+    #[cfg(feature = "lzma-compression")]
+    #[test]
+    fn best_effort_lzma() {
+        assert_eq!(
+            Compression::best_effort(&[CodecName::Lzma, CodecName::Zstd]),
+            Some(Compression::lzma(6).unwrap())
+        );
+    }
+
+    #[cfg(not(feature = "lzma-compression"))]
+    #[test]
+    fn best_effort_without_lzma() {
+        assert_eq!(Compression::best_effort(&[CodecName::Lzma]), None);
+    }
+
+    #[test]
+    fn brotli_window_log() {
+        let compression = Compression::brotli(6).unwrap().with_window_log(12).unwrap();
+        assert_eq!(compression.to_string(), "brotli:6,window_log=12");
+        assert_eq!(
+            dict::ChunkCompression::from(Some(compression)).window_log,
+            12
+        );
+        assert!(Compression::brotli(6).unwrap().with_window_log(25).is_err());
+        assert!(Compression::brotli(6).unwrap().with_window_log(9).is_err());
+    }
+
+    #[cfg(all(feature = "zstd-compression", feature = "compress"))]
+    #[test]
+    fn zstd_large_window_decompress() {
+        let data: Vec<u8> = (0..64 * 1024u32).map(|v| (v % 251) as u8).collect();
+        let compression = Compression::zstd(3).unwrap().with_window_log(28).unwrap();
+        let compressed = compression.compress(Bytes::from(data.clone())).unwrap();
+        // The default decoder refuses windows larger than 2^27
+        assert!(CompressionAlgorithm::Zstd
+            .decompress(compressed.clone(), data.len(), None, None)
+            .is_err());
+        let decompressed = CompressionAlgorithm::Zstd
+            .decompress(compressed, data.len(), Some(28), None)
+            .unwrap();
+        assert_eq!(&decompressed[..], &data[..]);
+    }
+}
@@ -53,6 +53,154 @@ impl HashSum {
     }
 }
 
+/// Personalization is longer than the 16 bytes supported by blake2b.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PersonalizationTooLongError(pub usize);
+impl std::error::Error for PersonalizationTooLongError {}
+impl fmt::Display for PersonalizationTooLongError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "personalization is {} bytes (max is {})",
+            self.0,
+            ChunkHasher::MAX_PERSONALIZATION_LEN
+        )
+    }
+}
+
+/// Hash length is not valid for the hash function.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidHashLengthError {
+    pub function: HashFunction,
+    pub length: usize,
+}
+impl std::error::Error for InvalidHashLengthError {}
+impl fmt::Display for InvalidHashLengthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid {:?} hash length {} (valid range is 1-{})",
+            self.function,
+            self.length,
+            self.function.max_len()
+        )
+    }
+}
+
+/// Hash function used to create the hash sums of chunks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashFunction {
+    Blake2b,
+    Sha256,
+    Sha512_256,
+}
+
+impl HashFunction {
+    /// Length in bytes of the full hash sum.
+    pub fn max_len(self) -> usize {
+        match self {
+            Self::Blake2b => HashSum::MAX_LEN,
+            Self::Sha256 | Self::Sha512_256 => 32,
+        }
+    }
+}
+
+/// Creates the hash sums of chunks.
+///
+/// By default chunks are hashed using plain blake2b. A personalization may be given to get
+/// domain separated chunk hashes, the same personalization then has to be used both when
+/// creating and when reading an archive. The personalization is not stored in the archive.
+///
+/// The SHA-2 variants are only used to read casync indexes, whose chunk ids are hashed
+/// using SHA-512/256 or SHA-256, see [`casync`](crate::casync).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChunkHasher {
+    algorithm: HashAlgorithm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum HashAlgorithm {
+    Blake2b(Option<[u8; ChunkHasher::MAX_PERSONALIZATION_LEN]>),
+    Sha256,
+    Sha512_256,
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> Self {
+        Self::Blake2b(None)
+    }
+}
+
+impl ChunkHasher {
+    /// Max length of a personalization.
+    pub const MAX_PERSONALIZATION_LEN: usize = 16;
+
+    /// Create a hasher using blake2b with the given personalization.
+    pub fn with_personalization(
+        personalization: &[u8],
+    ) -> Result<Self, PersonalizationTooLongError> {
+        if personalization.len() > Self::MAX_PERSONALIZATION_LEN {
+            return Err(PersonalizationTooLongError(personalization.len()));
+        }
+        let mut padded = [0; Self::MAX_PERSONALIZATION_LEN];
+        padded[..personalization.len()].copy_from_slice(personalization);
+        Ok(Self {
+            algorithm: HashAlgorithm::Blake2b(Some(padded)),
+        })
+    }
+    /// Create a hasher using the given function, for hash sums truncated to the given length.
+    ///
+    /// Fails if the length is zero, since an empty hash sum matches any data, or longer than
+    /// the hash sums created by the function.
+    pub fn for_params(
+        function: HashFunction,
+        length: usize,
+    ) -> Result<Self, InvalidHashLengthError> {
+        if length == 0 || length > function.max_len() {
+            return Err(InvalidHashLengthError { function, length });
+        }
+        Ok(match function {
+            HashFunction::Blake2b => Self::default(),
+            HashFunction::Sha256 => Self::sha256(),
+            HashFunction::Sha512_256 => Self::sha512_256(),
+        })
+    }
+    /// Create a hasher using SHA-256.
+    pub fn sha256() -> Self {
+        Self {
+            algorithm: HashAlgorithm::Sha256,
+        }
+    }
+    /// Create a hasher using SHA-512/256.
+    pub fn sha512_256() -> Self {
+        Self {
+            algorithm: HashAlgorithm::Sha512_256,
+        }
+    }
+    /// Create hash sum of data.
+    pub fn digest(&self, data: &[u8]) -> HashSum {
+        match &self.algorithm {
+            HashAlgorithm::Blake2b(None) => HashSum::b2_digest(data),
+            HashAlgorithm::Blake2b(Some(personalization)) => {
+                use blake2::digest::core_api::{Buffer, UpdateCore, VariableOutputCore};
+                let mut core = blake2::Blake2bVarCore::new_with_params(
+                    &[],
+                    personalization,
+                    0,
+                    HashSum::MAX_LEN,
+                );
+                let mut buffer = Buffer::<blake2::Blake2bVarCore>::default();
+                buffer.digest_blocks(data, |blocks| core.update_blocks(blocks));
+                let mut sum = Default::default();
+                core.finalize_variable_core(&mut buffer, &mut sum);
+                HashSum::from(&sum[..])
+            }
+            HashAlgorithm::Sha256 => HashSum::from(&sha2::Sha256::digest(data)[..]),
+            HashAlgorithm::Sha512_256 => HashSum::from(&sha2::Sha512_256::digest(data)[..]),
+        }
+    }
+}
+
 impl<T> From<T> for HashSum
 where
     T: AsRef<[u8]>,
@@ -119,6 +267,71 @@ impl fmt::Debug for HashSum {
 mod tests {
     use super::*;
 
+    #[test]
+    fn default_hasher_is_blake2b() {
+        assert_eq!(
+            ChunkHasher::default().digest(b"chunk data"),
+            HashSum::b2_digest(b"chunk data")
+        );
+    }
+
+    #[test]
+    fn sha2_hashers() {
+        // Reference values from python's hashlib.sha256 and hashlib.new("sha512_256")
+        assert_eq!(
+            format!("{}", ChunkHasher::sha256().digest(b"chunk data")),
+            "83c24c9251ed5710267e07682a8f83542d6da7c0627372c12a9c412739248f9d"
+        );
+        assert_eq!(
+            format!("{}", ChunkHasher::sha512_256().digest(b"chunk data")),
+            "6c4e28e259e39488058103880795fae14703cbfbed094d9ae440bec361fdfe5c"
+        );
+    }
+
+    #[test]
+    fn personalization_changes_digest() {
+        let hasher = ChunkHasher::with_personalization(b"bita-test").unwrap();
+        let sum = hasher.digest(b"chunk data");
+        assert_ne!(sum, ChunkHasher::default().digest(b"chunk data"));
+        assert_ne!(
+            sum,
+            ChunkHasher::with_personalization(b"other")
+                .unwrap()
+                .digest(b"chunk data")
+        );
+        // Reference value from python's hashlib.blake2b(b"chunk data", person=b"bita-test")
+        assert_eq!(
+            format!("{}", sum),
+            "bbec0fb3fa57285a944ab9bfa38cb21d7c9116e4f848735edc669286d323f80f72ed795bb98fdfaed3b4d9179f5fd645a4aca7a1b729317f51697ab565d0502d"
+        );
+        assert!(ChunkHasher::with_personalization(&[0; 17]).is_err());
+    }
+
+    #[test]
+    fn hasher_for_valid_params() {
+        assert_eq!(
+            ChunkHasher::for_params(HashFunction::Blake2b, 64),
+            Ok(ChunkHasher::default())
+        );
+        assert_eq!(
+            ChunkHasher::for_params(HashFunction::Sha256, 32),
+            Ok(ChunkHasher::sha256())
+        );
+    }
+
+    #[test]
+    fn hasher_for_too_long_hash() {
+        assert_eq!(
+            ChunkHasher::for_params(HashFunction::Blake2b, 65),
+            Err(InvalidHashLengthError {
+                function: HashFunction::Blake2b,
+                length: 65
+            })
+        );
+        assert!(ChunkHasher::for_params(HashFunction::Sha256, 33).is_err());
+        assert!(ChunkHasher::for_params(HashFunction::Blake2b, 0).is_err());
+    }
+
     #[test]
     fn zero_length() {
         let zero_length_hash = HashSum::from(&[]);
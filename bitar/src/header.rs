@@ -7,22 +7,157 @@
 //! |     14 |    n | Protobuf encoded dictionary.                                        |
 //! |      n |    8 | Chunk data offset in archive, absolute from archive start (u64 le). |
 //! |  n + 8 |   64 | Full header checksum (blake2), from offset 0 to n + 8.              |
+//!
+//! An archive with an encrypted dictionary starts with its own magic (BITAE\0) and holds a
+//! 12 byte nonce followed by the dictionary encrypted using ChaCha20-Poly1305 in place of the
+//! dictionary. The cipher key and the key of the MAC giving the nonce are derived separately
+//! from the dictionary key. The dictionary size is the size of the nonce and the encrypted
+//! dictionary. The chunk data is left as is.
 
-use blake2::{Blake2b512, Digest};
+use blake2::digest::Mac;
+use blake2::{Blake2b512, Blake2bMac512, Digest};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
 use prost::Message;
+use std::fmt;
 
 use crate::chunk_dictionary::ChunkDictionary;
 
 /// Archive file magic
 pub const ARCHIVE_MAGIC: &[u8; 6] = b"BITA1\0";
 
+/// Magic of an archive with an encrypted dictionary.
+pub const ENCRYPTED_ARCHIVE_MAGIC: &[u8; 6] = b"BITAE\0";
+
+const NONCE_SIZE: usize = 12;
+
 /// Pre header is the file magic + the size of the dictionary length value (u64)
 pub const PRE_HEADER_SIZE: usize = 6 + std::mem::size_of::<u64>();
 
+/// Check if the given buffer starts with an archive file magic.
+///
+/// Both the current magic and the legacy one ('\0BITA1') are accepted.
+pub fn has_archive_magic(buf: &[u8]) -> bool {
+    buf.len() >= ARCHIVE_MAGIC.len()
+        && (&buf[0..ARCHIVE_MAGIC.len()] == ARCHIVE_MAGIC
+            || &buf[0..ARCHIVE_MAGIC.len()] == b"\0BITA1")
+}
+
+/// Check if the given buffer starts with the magic of an archive with an encrypted dictionary.
+pub fn has_encrypted_archive_magic(buf: &[u8]) -> bool {
+    buf.starts_with(ENCRYPTED_ARCHIVE_MAGIC)
+}
+
+/// Key used to encrypt and decrypt the dictionary of an archive.
+#[derive(Clone, PartialEq, Eq)]
+pub struct DictionaryKey([u8; 32]);
+
+impl DictionaryKey {
+    /// Size of a key in bytes.
+    pub const SIZE: usize = 32;
+
+    /// Create a key from its bytes, None unless given exactly [`DictionaryKey::SIZE`] bytes.
+    pub fn from_slice(key: &[u8]) -> Option<Self> {
+        let mut bytes = [0; Self::SIZE];
+        if key.len() != bytes.len() {
+            return None;
+        }
+        bytes.copy_from_slice(key);
+        Some(Self(bytes))
+    }
+
+    // Derive a subkey for a single use from the key, so the cipher and the nonce never share
+    // a key.
+    fn subkey(&self, persona: &[u8]) -> [u8; 32] {
+        let mac = Blake2bMac512::new_with_salt_and_personal(&self.0, &[], persona)
+            .expect("valid key length");
+        let mut subkey = [0; 32];
+        subkey.copy_from_slice(&mac.finalize().into_bytes()[..32]);
+        subkey
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new((&self.subkey(b"bita-cipher")).into())
+    }
+
+    // The nonce is a MAC of the dictionary keyed with a subkey of the dictionary key (SIV
+    // style), so building the same header twice gives the same result while the stored nonce
+    // can't be used to confirm a guessed dictionary without the key. Only identical dictionaries
+    // share a nonce.
+    fn nonce(&self, dictionary: &[u8]) -> Vec<u8> {
+        let mut mac =
+            Blake2bMac512::new_with_salt_and_personal(&self.subkey(b"bita-nonce"), &[], &[])
+                .expect("valid key length");
+        mac.update(dictionary);
+        mac.finalize().into_bytes()[..NONCE_SIZE].to_vec()
+    }
+
+    pub(crate) fn encrypt(&self, dictionary: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce(dictionary);
+        let nonce = Nonce::from_slice(&nonce);
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(
+            self.cipher()
+                .encrypt(nonce, dictionary)
+                .expect("encrypt dictionary"),
+        );
+        encrypted
+    }
+
+    pub(crate) fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, DecryptError> {
+        if encrypted.len() < NONCE_SIZE {
+            return Err(DecryptError);
+        }
+        let (nonce, encrypted) = encrypted.split_at(NONCE_SIZE);
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .map_err(|_| DecryptError)
+    }
+}
+
+impl fmt::Debug for DictionaryKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DictionaryKey(..)")
+    }
+}
+
+/// The dictionary could not be decrypted using the key, either the key is wrong or the
+/// dictionary was modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecryptError;
+
+impl std::error::Error for DecryptError {}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to decrypt dictionary (wrong key?)")
+    }
+}
+
 /// Build an archive header from dictionary.
 pub fn build(
     dictionary: &ChunkDictionary,
     chunk_data_offset: Option<u64>,
+) -> Result<Vec<u8>, std::io::Error> {
+    build_header(dictionary, chunk_data_offset, None)
+}
+
+/// Build an archive header with the dictionary encrypted using the key.
+///
+/// The descriptors, order and checksums of the chunks are only readable using the key, while
+/// the chunk data is stored as usual.
+pub fn build_encrypted(
+    dictionary: &ChunkDictionary,
+    chunk_data_offset: Option<u64>,
+    key: &DictionaryKey,
+) -> Result<Vec<u8>, std::io::Error> {
+    build_header(dictionary, chunk_data_offset, Some(key))
+}
+
+fn build_header(
+    dictionary: &ChunkDictionary,
+    chunk_data_offset: Option<u64>,
+    key: Option<&DictionaryKey>,
 ) -> Result<Vec<u8>, std::io::Error> {
     let mut header: Vec<u8> = vec![];
     let mut hasher = Blake2b512::new();
@@ -31,7 +166,13 @@ pub fn build(
     dictionary.encode(&mut dictionary_buf)?;
 
     // File magic indicating bita archive version 1
-    header.extend(ARCHIVE_MAGIC);
+    match key {
+        Some(key) => {
+            dictionary_buf = key.encrypt(&dictionary_buf);
+            header.extend(ENCRYPTED_ARCHIVE_MAGIC);
+        }
+        None => header.extend(ARCHIVE_MAGIC),
+    }
 
     // Chunk dictionary size
     header.extend(&(dictionary_buf.len() as u64).to_le_bytes());
@@ -52,3 +193,48 @@ pub fn build(
 
     Ok(header)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_dictionary_round_trip() {
+        let key = DictionaryKey::from_slice(&[7; DictionaryKey::SIZE]).unwrap();
+        let encrypted = key.encrypt(b"dictionary");
+        assert_eq!(encrypted, key.encrypt(b"dictionary"));
+        assert_eq!(encrypted.len(), NONCE_SIZE + b"dictionary".len() + 16);
+        assert_eq!(key.decrypt(&encrypted).unwrap(), b"dictionary");
+
+        let other_key = DictionaryKey::from_slice(&[8; DictionaryKey::SIZE]).unwrap();
+        assert_eq!(other_key.decrypt(&encrypted), Err(DecryptError));
+        assert_eq!(key.decrypt(&encrypted[..4]), Err(DecryptError));
+        assert!(DictionaryKey::from_slice(&[7; 16]).is_none());
+    }
+
+    #[test]
+    fn nonce_depends_on_key() {
+        let key = DictionaryKey::from_slice(&[7; DictionaryKey::SIZE]).unwrap();
+        let other_key = DictionaryKey::from_slice(&[8; DictionaryKey::SIZE]).unwrap();
+        let nonce = &key.encrypt(b"dictionary")[..NONCE_SIZE];
+        // The plain digest of a guessed dictionary must not match the stored nonce
+        assert_ne!(nonce, &Blake2b512::digest(b"dictionary")[..NONCE_SIZE]);
+        assert_ne!(nonce, &other_key.encrypt(b"dictionary")[..NONCE_SIZE]);
+    }
+
+    #[test]
+    fn cipher_and_nonce_use_separate_subkeys() {
+        let key = DictionaryKey::from_slice(&[7; DictionaryKey::SIZE]).unwrap();
+        let cipher_key = key.subkey(b"bita-cipher");
+        let nonce_key = key.subkey(b"bita-nonce");
+        assert_ne!(cipher_key, nonce_key);
+        assert_ne!(cipher_key, key.0);
+        assert_ne!(nonce_key, key.0);
+        // Decrypting with the master key used directly as cipher key fails
+        let encrypted = key.encrypt(b"dictionary");
+        let (nonce, encrypted) = encrypted.split_at(NONCE_SIZE);
+        assert!(ChaCha20Poly1305::new((&key.0).into())
+            .decrypt(Nonce::from_slice(nonce), encrypted)
+            .is_err());
+    }
+}
@@ -1,18 +1,27 @@
 mod archive;
+mod archive_summary;
 mod chunk;
 mod chunk_index;
 mod chunk_location_map;
 mod chunk_offset;
 mod clone_output;
 mod compression;
+mod crc32c;
 mod hashsum;
+mod rebuild_order;
 mod rolling_hash;
+mod source_checkpoints;
+#[cfg(feature = "test-codec")]
+mod test_codec;
+mod transfer_estimate;
 
 pub mod archive_reader;
+pub mod casync;
 pub mod chunker;
 pub mod header;
 
-pub use archive::{Archive, ArchiveError};
+pub use archive::{Archive, ArchiveError, ChunkDescriptor, SourceEntry, SourceInfo, UnpackError};
+pub use archive_summary::{ArchiveSummary, ChunkSizeStats};
 pub use chunk::{
     ArchiveChunk, Chunk, CompressedArchiveChunk, CompressedChunk, HashSumMismatchError,
     VerifiedChunk,
@@ -21,9 +30,18 @@ pub use chunk_index::{ChunkIndex, ChunkLocation, ReorderOp};
 pub use chunk_offset::ChunkOffset;
 pub use clone_output::CloneOutput;
 pub use compression::{
-    Compression, CompressionAlgorithm, CompressionError, CompressionLevelOutOfRangeError,
+    compiled_codecs, compiled_features, register_codec, ChunkCodec, CodecDictionaries, CodecName,
+    Compression, CompressionAlgorithm, CompressionError, CompressionFeatureMissingError,
+    CompressionLevelOutOfRangeError, ThreadsUnsupportedError, UnknownCodecError,
+    WindowLogOutOfRangeError, CUSTOM_CODEC_MIN_ID,
 };
-pub use hashsum::HashSum;
+pub use hashsum::{
+    ChunkHasher, HashFunction, HashSum, InvalidHashLengthError, PersonalizationTooLongError,
+};
+pub use source_checkpoints::{SourceCheckpoints, SourceHasher};
+#[cfg(feature = "test-codec")]
+pub use test_codec::{TestCodec, TEST_CODEC_ID};
+pub use transfer_estimate::{estimate_transfer, TransferEstimate};
 
 pub mod chunk_dictionary {
     include!(concat!(env!("OUT_DIR"), "/chunk_dictionary.rs"));
@@ -0,0 +1,143 @@
+use std::convert::TryFrom;
+
+use crate::chunk_dictionary::RebuildOrderRuns;
+
+impl RebuildOrderRuns {
+    /// Encode a rebuild order as runs of the same chunk index.
+    pub fn encode(rebuild_order: &[u32]) -> Self {
+        let mut runs = Self::default();
+        let mut prev_index = 0;
+        for &index in rebuild_order {
+            match runs.lengths.last_mut() {
+                Some(length) if index == prev_index && *length < u32::MAX => *length += 1,
+                _ => {
+                    runs.index_deltas
+                        .push(i64::from(index) - i64::from(prev_index));
+                    runs.lengths.push(1);
+                    prev_index = index;
+                }
+            }
+        }
+        runs
+    }
+
+    /// Decode into the rebuild order of a source of `source_size` bytes, given the source size
+    /// of every chunk in the archive.
+    ///
+    /// Every chunk but the last one of a source is at least `min_chunk_size` bytes, which bounds
+    /// the number of chunks by the source size. Returns `None` if the runs are invalid, would
+    /// expand to more chunks than that, refer to a chunk which isn't there or don't add up to
+    /// the source size. Runs are validated before anything is allocated.
+    pub fn decode(
+        &self,
+        chunk_sizes: &[u32],
+        min_chunk_size: u64,
+        source_size: u64,
+    ) -> Option<Vec<u32>> {
+        if self.index_deltas.len() != self.lengths.len() {
+            return None;
+        }
+        let max_len = source_size / min_chunk_size.max(1) + 1;
+        let mut total_len: u64 = 0;
+        let mut total_size: u64 = 0;
+        let mut index: i64 = 0;
+        for (&delta, &length) in self.index_deltas.iter().zip(&self.lengths) {
+            index = index.checked_add(delta)?;
+            let chunk_size = *chunk_sizes.get(usize::try_from(index).ok()?)?;
+            total_len += u64::from(length);
+            total_size = total_size.checked_add(u64::from(chunk_size) * u64::from(length))?;
+            if total_len > max_len || total_size > source_size {
+                return None;
+            }
+        }
+        if total_size != source_size {
+            return None;
+        }
+        let mut rebuild_order = Vec::new();
+        let mut index: i64 = 0;
+        for (&delta, &length) in self.index_deltas.iter().zip(&self.lengths) {
+            index += delta;
+            rebuild_order.extend(std::iter::repeat(index as u32).take(length as usize));
+        }
+        Some(rebuild_order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn repeated_chunk() {
+        // An image with a few unique chunks separated by long runs of the zero chunk
+        let chunk_sizes = vec![100; 10];
+        let mut rebuild_order = vec![0, 1, 2];
+        for index in 3..10 {
+            rebuild_order.extend(vec![1; 5000]);
+            rebuild_order.push(index);
+        }
+        rebuild_order.extend(&[9, 9, 0, 2]);
+        let source_size = rebuild_order.len() as u64 * 100;
+        let runs = RebuildOrderRuns::encode(&rebuild_order);
+        assert_eq!(runs.lengths.len(), 19);
+        assert_eq!(
+            runs.decode(&chunk_sizes, 100, source_size),
+            Some(rebuild_order.clone())
+        );
+        assert!(runs.encoded_len() < 100);
+        assert_eq!(runs.decode(&chunk_sizes, 100, source_size - 1), None);
+        assert_eq!(runs.decode(&chunk_sizes, 100, source_size + 1), None);
+    }
+
+    #[test]
+    fn empty() {
+        let runs = RebuildOrderRuns::encode(&[]);
+        assert_eq!(runs, RebuildOrderRuns::default());
+        assert_eq!(runs.decode(&[], 0, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn invalid_runs() {
+        let chunk_sizes = [1, 1];
+        let runs = RebuildOrderRuns {
+            index_deltas: vec![1, -2],
+            lengths: vec![1, 1],
+        };
+        assert_eq!(runs.decode(&chunk_sizes, 1, 2), None);
+        let runs = RebuildOrderRuns {
+            index_deltas: vec![1],
+            lengths: vec![1, 1],
+        };
+        assert_eq!(runs.decode(&chunk_sizes, 1, 2), None);
+        let runs = RebuildOrderRuns {
+            index_deltas: vec![i64::from(u32::MAX) + 1],
+            lengths: vec![1],
+        };
+        assert_eq!(runs.decode(&chunk_sizes, 1, 1), None);
+        // Refers to a chunk which isn't there
+        let runs = RebuildOrderRuns {
+            index_deltas: vec![2],
+            lengths: vec![1],
+        };
+        assert_eq!(runs.decode(&chunk_sizes, 1, 1), None);
+    }
+
+    #[test]
+    fn runs_bounded_by_min_chunk_size() {
+        // A few bytes of runs claiming a huge source of tiny chunks
+        let runs = RebuildOrderRuns {
+            index_deltas: vec![0, 1],
+            lengths: vec![u32::MAX, 1],
+        };
+        let chunk_sizes = [1, 16];
+        let source_size = u64::from(u32::MAX) + 16;
+        assert_eq!(runs.decode(&chunk_sizes, 16, source_size), None);
+        // Only the last chunk of a source may be smaller than the min chunk size
+        let runs = RebuildOrderRuns {
+            index_deltas: vec![1, -1],
+            lengths: vec![3, 1],
+        };
+        assert_eq!(runs.decode(&chunk_sizes, 16, 49), Some(vec![1, 1, 1, 0]));
+    }
+}
@@ -1,3 +1,5 @@
+use std::convert::TryInto;
+
 use crate::rolling_hash::RollingHash;
 
 const BUZHASH_SEED: u32 = 0x1032_4195;
@@ -38,6 +40,54 @@ static BUZHASH_TABLE: &[u32] = &[
     0x87abd4a7, 0x2799ae4f, 0x3b80cac, 0xd56e7604, 0x8b07ed07, 0x944552c5, 0x5b93e058, 0x8fbd2c92,
 ];
 
+/// Substitution table of the buzhash, giving the value hashed for every input byte.
+///
+/// Chunking using another table than the default one gives other chunk boundaries. Used to
+/// get domain separated boundaries or to match the boundaries of another implementation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BuzHashTable(Box<[u32; 256]>);
+
+impl BuzHashTable {
+    /// Create a table from the values of every input byte.
+    pub fn new(table: [u32; 256]) -> Self {
+        Self(Box::new(table))
+    }
+    /// Create a table from a slice of 256 values, `None` if the slice is of any other size.
+    pub fn from_slice(table: &[u32]) -> Option<Self> {
+        let table: [u32; 256] = table.try_into().ok()?;
+        Some(Self::new(table))
+    }
+    /// Generate a table deterministically from a seed, using splitmix64.
+    pub fn from_seed(seed: u64) -> Self {
+        let mut state = seed;
+        let mut table = [0; 256];
+        for value in table.iter_mut() {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *value = ((z ^ (z >> 31)) >> 32) as u32;
+        }
+        Self::new(table)
+    }
+    /// Values of the table, indexed by input byte.
+    pub fn values(&self) -> &[u32] {
+        &self.0[..]
+    }
+}
+
+/// The table used unless another one is given.
+impl Default for BuzHashTable {
+    fn default() -> Self {
+        let mut table = [0; 256];
+        table
+            .iter_mut()
+            .zip(BUZHASH_TABLE)
+            .for_each(|(value, default)| *value = default ^ BUZHASH_SEED);
+        Self::new(table)
+    }
+}
+
 /// Rolling hash algorithm which can be used for chunking.
 #[derive(Clone)]
 pub struct BuzHash {
@@ -52,22 +102,24 @@ pub struct BuzHash {
 }
 
 impl BuzHash {
-    /// Create a new instance of BuzHash with the given window size.
+    /// Create a new instance of BuzHash with the given window size (at least 1).
     pub fn new(window: usize) -> Self {
+        Self::with_table(window, &BuzHashTable::default())
+    }
+    /// Create a new instance of BuzHash using the given substitution table.
+    pub fn with_table(window: usize, table: &BuzHashTable) -> Self {
+        let window = window.max(1);
         BuzHash {
             index: 0,
             buf: vec![0; window],
             window,
             hash_sum: 0,
-            buzhash_table: Self::generate_seeded_table(BUZHASH_SEED),
+            buzhash_table: table.values().to_vec(),
             window_full: false,
             last_input: 0,
             repeated_input: 0,
         }
     }
-    fn generate_seeded_table(seed: u32) -> Vec<u32> {
-        BUZHASH_TABLE.iter().map(|x| x ^ seed).collect()
-    }
     /// Should be used for processing input until hash is valid.
     pub fn init(&mut self, in_val: u8) {
         if !self.window_full {
@@ -129,7 +181,20 @@ impl RollingHash for BuzHash {
 
 #[cfg(test)]
 mod tests {
-    use crate::rolling_hash::BuzHash;
+    use crate::rolling_hash::{BuzHash, BuzHashTable};
+
+    #[test]
+    fn seeded_tables() {
+        assert_eq!(BuzHashTable::from_seed(1), BuzHashTable::from_seed(1));
+        assert_ne!(BuzHashTable::from_seed(1), BuzHashTable::from_seed(2));
+        assert_ne!(BuzHashTable::from_seed(0), BuzHashTable::default());
+        assert_eq!(
+            BuzHashTable::from_slice(BuzHashTable::from_seed(1).values()),
+            Some(BuzHashTable::from_seed(1))
+        );
+        assert_eq!(BuzHashTable::from_slice(&[0; 255]), None);
+    }
+
     #[test]
     fn equal_sums_for_equal_range() {
         let window_size = 8;
@@ -1,7 +1,7 @@
 mod buzhash;
 mod rollsum;
 
-pub use buzhash::BuzHash;
+pub use buzhash::{BuzHash, BuzHashTable};
 pub use rollsum::RollSum;
 
 /// Rolling hash.
@@ -0,0 +1,148 @@
+use blake2::{Blake2b512, Digest};
+
+use crate::{chunk_dictionary as dict, HashSum};
+
+/// Checkpoints of the source checksum.
+///
+/// Holds the checksum of the source from its start up to every interval boundary, which makes it
+/// possible to verify a prefix of the source or to locate where a source differs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceCheckpoints {
+    interval: u64,
+    checksums: Vec<HashSum>,
+}
+
+impl SourceCheckpoints {
+    /// Number of source bytes between each checkpoint.
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+    /// Checksums of the source. Checksum at index n is the checksum of the first
+    /// (n + 1) * interval bytes of source.
+    pub fn checksums(&self) -> &[HashSum] {
+        &self.checksums[..]
+    }
+}
+
+impl From<SourceCheckpoints> for dict::SourceCheckpoints {
+    fn from(checkpoints: SourceCheckpoints) -> Self {
+        Self {
+            interval: checkpoints.interval,
+            checksums: checkpoints
+                .checksums
+                .into_iter()
+                .map(|checksum| checksum.to_vec())
+                .collect(),
+        }
+    }
+}
+
+impl From<dict::SourceCheckpoints> for SourceCheckpoints {
+    fn from(checkpoints: dict::SourceCheckpoints) -> Self {
+        Self {
+            interval: checkpoints.interval,
+            checksums: checkpoints
+                .checksums
+                .into_iter()
+                .map(HashSum::from)
+                .collect(),
+        }
+    }
+}
+
+/// Builds the checksum of a source, optionally with checkpoints.
+#[derive(Debug, Clone)]
+pub struct SourceHasher {
+    hasher: Blake2b512,
+    checkpoint_interval: Option<u64>,
+    checkpoints: Vec<HashSum>,
+    size: u64,
+}
+
+impl Default for SourceHasher {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl SourceHasher {
+    /// Create a new source hasher, storing a checkpoint every interval bytes if given.
+    ///
+    /// Panics if interval is zero.
+    pub fn new(checkpoint_interval: Option<u64>) -> Self {
+        assert_ne!(checkpoint_interval, Some(0), "zero checkpoint interval");
+        Self {
+            hasher: Blake2b512::new(),
+            checkpoint_interval,
+            checkpoints: Vec::new(),
+            size: 0,
+        }
+    }
+    /// Feed source data to the hasher.
+    pub fn update(&mut self, mut data: &[u8]) {
+        if let Some(interval) = self.checkpoint_interval {
+            while !data.is_empty() {
+                let to_boundary = interval - self.size % interval;
+                let (head, tail) =
+                    data.split_at(std::cmp::min(to_boundary, data.len() as u64) as usize);
+                self.hasher.update(head);
+                self.size += head.len() as u64;
+                if head.len() as u64 == to_boundary {
+                    self.checkpoints
+                        .push(HashSum::from(&self.hasher.clone().finalize()[..]));
+                }
+                data = tail;
+            }
+        } else {
+            self.hasher.update(data);
+            self.size += data.len() as u64;
+        }
+    }
+    /// Number of bytes fed to the hasher.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    /// Checkpoints passed so far.
+    pub fn checkpoints(&self) -> &[HashSum] {
+        &self.checkpoints[..]
+    }
+    /// Get the checksum of the source and its checkpoints.
+    pub fn finalize(self) -> (HashSum, Option<SourceCheckpoints>) {
+        let checkpoints = self.checkpoints;
+        let checkpoints = self.checkpoint_interval.map(|interval| SourceCheckpoints {
+            interval,
+            checksums: checkpoints,
+        });
+        (HashSum::from(&self.hasher.finalize()[..]), checkpoints)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoints_independent_of_update_size() {
+        let source: Vec<u8> = (0..1000u32).map(|v| v as u8).collect();
+        let mut whole = SourceHasher::new(Some(100));
+        whole.update(&source);
+        let mut parts = SourceHasher::new(Some(100));
+        source.chunks(37).for_each(|part| parts.update(part));
+        let (checksum, checkpoints) = whole.finalize();
+        assert_eq!((checksum.clone(), checkpoints.clone()), parts.finalize());
+        let checkpoints = checkpoints.unwrap();
+        assert_eq!(checksum, HashSum::b2_digest(&source));
+        assert_eq!(checkpoints.checksums().len(), 10);
+        assert_eq!(
+            checkpoints.checksums()[2],
+            HashSum::b2_digest(&source[..300])
+        );
+    }
+
+    #[test]
+    fn no_checkpoints() {
+        let mut hasher = SourceHasher::default();
+        hasher.update(b"source");
+        assert_eq!(hasher.finalize(), (HashSum::b2_digest(b"source"), None));
+    }
+}
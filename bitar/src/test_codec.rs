@@ -0,0 +1,67 @@
+use crate::{register_codec, ChunkCodec, Compression, CompressionError};
+use std::sync::Arc;
+
+/// Codec id of [`TestCodec`].
+pub const TEST_CODEC_ID: u32 = 0x7465_7374;
+
+/// Deterministic codec for tests.
+///
+/// Stores the data as runs of repeated bytes. Unlike the real codecs the output doesn't depend on
+/// the platform or the version of any compression library, which makes it possible to compare
+/// archives byte by byte. Not meant for real archives.
+pub struct TestCodec;
+
+impl TestCodec {
+    /// Register the codec and get a compression using it.
+    pub fn compression() -> Compression {
+        register_codec(Arc::new(TestCodec));
+        Compression::custom(TEST_CODEC_ID).expect("registered codec")
+    }
+}
+
+impl ChunkCodec for TestCodec {
+    fn id(&self) -> u32 {
+        TEST_CODEC_ID
+    }
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let mut output: Vec<u8> = Vec::new();
+        for &byte in data {
+            let len = output.len();
+            if len >= 2 && output[len - 1] == byte && output[len - 2] < u8::MAX {
+                output[len - 2] += 1;
+            } else {
+                output.extend(&[1, byte]);
+            }
+        }
+        Ok(output)
+    }
+    fn decompress(&self, data: &[u8], size_hint: usize) -> Result<Vec<u8>, CompressionError> {
+        let runs = data.chunks_exact(2);
+        if !runs.remainder().is_empty() {
+            return Err(CompressionError::Codec("truncated run".into()));
+        }
+        let mut output = Vec::with_capacity(size_hint);
+        for run in runs {
+            output.resize(output.len() + run[0] as usize, run[1]);
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_runs() {
+        let data = b"aaabccccc\0\0";
+        let compressed = TestCodec.compress(data).unwrap();
+        assert_eq!(compressed, b"\x03a\x01b\x05c\x02\0");
+        assert_eq!(TestCodec.decompress(&compressed, 0).unwrap(), data);
+        let long_run = vec![7; 300];
+        let compressed = TestCodec.compress(&long_run).unwrap();
+        assert_eq!(compressed, [255, 7, 45, 7]);
+        assert_eq!(TestCodec.decompress(&compressed, 300).unwrap(), long_run);
+        assert!(TestCodec.decompress(&compressed[..3], 300).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use futures_util::StreamExt;
+use std::io;
+use tokio::io::AsyncRead;
+
+use crate::{Archive, ChunkHasher};
+
+/// Estimated amount of data transferred when cloning an archive.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransferEstimate {
+    /// Bytes of output which can be taken from the seeds.
+    pub from_seeds: u64,
+    /// Bytes of chunk data to fetch from the archive (possibly compressed).
+    pub from_archive: u64,
+    /// Number of chunks to fetch from the archive.
+    pub archive_chunks: usize,
+    /// Bytes of output which the chunks fetched from the archive make up.
+    pub output_from_archive: u64,
+}
+
+/// Estimate how much data cloning the archive would use from seeds and from the archive.
+///
+/// Seeds are scanned in the given order for chunks of the archive, in the same way as when
+/// cloning, but nothing is fetched from the archive and no output is written.
+pub async fn estimate_transfer<R, S>(
+    archive: &Archive<R>,
+    seeds: impl IntoIterator<Item = S>,
+    hasher: &ChunkHasher,
+) -> Result<TransferEstimate, io::Error>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    let mut remaining = archive.build_source_index();
+    let mut estimate = TransferEstimate::default();
+    for seed in seeds {
+        let mut chunker = archive.chunker_config().new_chunker(seed);
+        while let Some(result) = chunker.next().await {
+            let (_offset, chunk) = result?;
+            let verified = chunk.verify_with(hasher);
+            if let Some(location) = remaining.remove(verified.hash()) {
+                estimate.from_seeds += (verified.len() * location.offsets().len()) as u64;
+            }
+        }
+    }
+    for descriptor in archive.chunk_descriptors() {
+        if let Some(offsets) = remaining.offsets(&descriptor.checksum) {
+            estimate.from_archive += descriptor.archive_size as u64;
+            estimate.archive_chunks += 1;
+            estimate.output_from_archive += descriptor.source_size as u64 * offsets.count() as u64;
+        }
+    }
+    Ok(estimate)
+}
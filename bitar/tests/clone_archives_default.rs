@@ -1,27 +1,42 @@
 mod common;
 
-use bitar::{archive_reader::IoReader, Archive};
+use bitar::{
+    archive_reader::{HttpReader, HttpReaderError, IoReader},
+    Archive, ArchiveError, CloneOutput,
+};
+use blake2::{Blake2b512, Digest};
 use futures_util::stream::StreamExt;
+use reqwest::Url;
+use std::io::Cursor;
 use tokio::fs::File;
 
 use common::*;
 
+#[allow(dead_code)]
+async fn expect_compression_feature_missing(path: &str, algorithm: &str, feature: &str) {
+    match Archive::try_init(IoReader::new(File::open(path).await.unwrap())).await {
+        Err(bitar::ArchiveError::InvalidArchive(err)) => {
+            let err = err
+                .downcast_ref::<bitar::CompressionFeatureMissingError>()
+                .expect("compression feature missing error");
+            assert_eq!(err.algorithm(), algorithm);
+            assert_eq!(err.feature(), feature);
+            assert!(err.to_string().contains(feature));
+        }
+        _ => panic!("expected invalid archive"),
+    }
+}
+
 #[cfg(not(feature = "lzma-compression"))]
 #[tokio::test]
 async fn clone_local_v0_1_1_lzma_not_supported() {
-    assert!(matches!(
-        Archive::try_init(IoReader::new(File::open(ARCHIVE_0_1_1_LZMA).await.unwrap())).await,
-        Err(bitar::ArchiveError::InvalidArchive(_))
-    ))
+    expect_compression_feature_missing(ARCHIVE_0_1_1_LZMA, "LZMA", "lzma-compression").await;
 }
 
 #[cfg(not(feature = "zstd-compression"))]
 #[tokio::test]
 async fn clone_local_v0_1_1_zstd_not_supported() {
-    assert!(matches!(
-        Archive::try_init(IoReader::new(File::open(ARCHIVE_0_1_1_ZSTD).await.unwrap())).await,
-        Err(bitar::ArchiveError::InvalidArchive(_))
-    ))
+    expect_compression_feature_missing(ARCHIVE_0_1_1_ZSTD, "zstd", "zstd-compression").await;
 }
 
 #[tokio::test]
@@ -44,6 +59,24 @@ async fn clone_remote_v0_7_1_brotli() {
     clone_remote_expect_checksum(ARCHIVE_0_7_1_BROTLI, ZERO_B2SUM).await;
 }
 
+#[tokio::test]
+async fn clone_remote_without_range_support() {
+    clone_remote_without_ranges(ARCHIVE_0_7_1_BROTLI, 1024 * 1024, ZERO_B2SUM)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn clone_remote_without_range_support_too_large() {
+    let archive_size = std::fs::metadata(ARCHIVE_0_7_1_BROTLI).unwrap().len();
+    match clone_remote_without_ranges(ARCHIVE_0_7_1_BROTLI, archive_size - 1, ZERO_B2SUM).await {
+        Err(ArchiveError::ReaderError(HttpReaderError::ArchiveTooLarge(limit))) => {
+            assert_eq!(limit, archive_size - 1)
+        }
+        _ => panic!("expected archive too large error"),
+    }
+}
+
 #[tokio::test]
 async fn clone_local_v0_7_1_corrupt_header() {
     assert!(matches!(
@@ -76,3 +109,157 @@ async fn clone_local_v0_7_1_corrupt_chunk() {
     }
     panic!("no hashsum mismatch error?!");
 }
+
+#[tokio::test]
+async fn init_real_archive_magic() {
+    let archive_buf = std::fs::read(ARCHIVE_0_7_1_BROTLI).unwrap();
+    assert!(bitar::header::has_archive_magic(&archive_buf));
+    Archive::try_init(IoReader::new(std::io::Cursor::new(archive_buf)))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn init_random_file_not_an_archive() {
+    let random: Vec<u8> = (0..1024u32).map(|v| (v * 7919 % 251) as u8).collect();
+    assert!(matches!(
+        Archive::try_init(IoReader::new(std::io::Cursor::new(random))).await,
+        Err(bitar::ArchiveError::NotAnArchive)
+    ));
+}
+
+#[tokio::test]
+async fn init_magic_only_is_truncated() {
+    let magic = bitar::header::ARCHIVE_MAGIC.to_vec();
+    assert!(matches!(
+        Archive::try_init(IoReader::new(std::io::Cursor::new(magic))).await,
+        Err(bitar::ArchiveError::ReaderError(_))
+    ));
+}
+
+#[tokio::test]
+async fn estimate_matches_clone_transfer() {
+    let open = || async {
+        Archive::try_init(IoReader::new(File::open(ARCHIVE_0_1_1_NONE).await.unwrap()))
+            .await
+            .unwrap()
+    };
+    let hasher = bitar::ChunkHasher::default();
+    // Get the archive source and use it, with the middle part overwritten, as seed.
+    let mut archive = open().await;
+    let estimate = bitar::estimate_transfer(&archive, Vec::<&[u8]>::new(), &hasher)
+        .await
+        .unwrap();
+    assert_eq!(estimate.from_seeds, 0);
+    assert_eq!(estimate.from_archive, archive.compressed_size());
+    assert_eq!(estimate.output_from_archive, archive.total_source_size());
+    let mut seed = vec![];
+    {
+        let mut output = CloneOutput::new(Cursor::new(&mut seed), archive.build_source_index());
+        let mut chunk_stream = archive.chunk_stream(output.chunks());
+        while let Some(result) = chunk_stream.next().await {
+            let verified = result.unwrap().decompress().unwrap().verify().unwrap();
+            output.feed(&verified).await.unwrap();
+        }
+    }
+    let len = seed.len();
+    seed[len / 3..len / 2].iter_mut().for_each(|b| *b = !*b);
+
+    let mut archive = open().await;
+    let estimate = bitar::estimate_transfer(&archive, vec![&seed[..]], &hasher)
+        .await
+        .unwrap();
+    assert!(estimate.from_seeds > 0 && estimate.archive_chunks > 0);
+    let total_source_size = archive.total_source_size();
+
+    let mut output_buf = vec![];
+    let mut output = CloneOutput::new(Cursor::new(&mut output_buf), archive.build_source_index());
+    let mut from_seeds = 0;
+    let mut chunker = archive.chunker_config().new_chunker(&seed[..]);
+    while let Some(result) = chunker.next().await {
+        let verified = result.unwrap().1.verify();
+        from_seeds += output.feed(&verified).await.unwrap() as u64;
+    }
+    let (mut from_archive, mut archive_chunks, mut output_from_archive) = (0, 0, 0);
+    let mut chunk_stream = archive.chunk_stream(output.chunks());
+    while let Some(result) = chunk_stream.next().await {
+        let compressed = result.unwrap();
+        from_archive += compressed.len() as u64;
+        archive_chunks += 1;
+        let verified = compressed.decompress().unwrap().verify().unwrap();
+        output_from_archive += output.feed(&verified).await.unwrap() as u64;
+    }
+    assert_eq!(
+        estimate,
+        bitar::TransferEstimate {
+            from_seeds,
+            from_archive,
+            archive_chunks,
+            output_from_archive,
+        }
+    );
+    assert_eq!(from_seeds + output_from_archive, total_source_size);
+}
+
+// Write the source to output starting at the given offset, stopping after the given number of
+// chunks if any. Returns the number of bytes written.
+async fn stream_source<R>(
+    archive: &mut Archive<R>,
+    offset: u64,
+    max_chunks: Option<usize>,
+    output: &mut Vec<u8>,
+) -> u64
+where
+    R: bitar::archive_reader::ArchiveReader,
+    R::Error: std::fmt::Debug,
+{
+    let mut written = 0;
+    let mut chunk_stream = archive
+        .source_chunk_stream(offset)
+        .take(max_chunks.unwrap_or(usize::MAX));
+    while let Some(result) = chunk_stream.next().await {
+        let (chunk_offset, compressed) = result.unwrap();
+        let verified = compressed.decompress().unwrap().verify().unwrap();
+        // The first chunk may already be partly written
+        let skip = offset.saturating_sub(chunk_offset) as usize;
+        output.extend_from_slice(&verified.data()[skip..]);
+        written += (verified.len() - skip) as u64;
+    }
+    written
+}
+
+#[tokio::test]
+async fn resume_interrupted_remote_stream() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_port = listener.local_addr().unwrap().port();
+    let server = serve_archive(listener, ARCHIVE_0_1_1_NONE);
+    let stream_task = tokio::spawn(async move {
+        let open = || async {
+            Archive::try_init(HttpReader::from_url(
+                Url::parse(&format!("http://127.0.0.1:{}", server_port)).unwrap(),
+            ))
+            .await
+            .unwrap()
+        };
+        let mut output = vec![];
+        let mut archive = open().await;
+        let total_source_size = archive.total_source_size();
+        let mut emitted = stream_source(&mut archive, 0, Some(3), &mut output).await;
+        assert!(emitted > 0 && emitted < total_source_size);
+        // Connection dropped while writing the last chunk
+        emitted -= 100;
+        output.truncate(emitted as usize);
+        drop(archive);
+
+        let mut archive = open().await;
+        emitted += stream_source(&mut archive, emitted, None, &mut output).await;
+        assert_eq!(emitted, total_source_size);
+        let mut hash = Blake2b512::new();
+        hash.update(&output[..]);
+        assert_eq!(&hash.finalize()[..], RAND_B2SUM);
+    });
+    tokio::select! {
+        _ = server => panic!("server ended"),
+        result = stream_task => result.unwrap(),
+    }
+}
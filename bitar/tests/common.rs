@@ -61,6 +61,30 @@ pub async fn clone_remote_expect_checksum(path: &str, b2sum: &'static [u8]) {
     };
 }
 
+// Clone from a server which doesn't support range requests.
+pub async fn clone_remote_without_ranges(
+    path: &str,
+    full_download_limit: u64,
+    b2sum: &'static [u8],
+) -> Result<(), bitar::ArchiveError<bitar::archive_reader::HttpReaderError>> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_port = listener.local_addr().unwrap().port();
+    let server = serve_archive_without_ranges(listener, path);
+    let clone_task = tokio::spawn(async move {
+        let archive = Archive::try_init(
+            HttpReader::from_url(Url::parse(&format!("http://127.0.0.1:{}", server_port)).unwrap())
+                .full_download_limit(full_download_limit),
+        )
+        .await?;
+        clone_expect_checksum(archive, b2sum).await;
+        Ok(())
+    });
+    tokio::select! {
+        _ = server => panic!("server ended"),
+        result = clone_task => result.unwrap(),
+    }
+}
+
 async fn clone_expect_checksum<R: bitar::archive_reader::ArchiveReader>(
     mut archive: Archive<R>,
     b2sum: &[u8],
@@ -92,7 +116,7 @@ async fn clone_expect_checksum<R: bitar::archive_reader::ArchiveReader>(
     assert_eq!(archive.source_checksum().slice(), b2sum);
 }
 
-async fn serve_archive(listener: std::net::TcpListener, path: &str) {
+pub async fn serve_archive(listener: std::net::TcpListener, path: &str) {
     let mut archive_data = vec![];
     std::fs::File::open(path)
         .unwrap()
@@ -117,6 +141,28 @@ async fn serve_archive(listener: std::net::TcpListener, path: &str) {
                     let start = range[0] as usize;
                     let end = std::cmp::min(range[1] as usize + 1, data.len());
                     let data = data[start..end].to_vec();
+                    async move {
+                        let mut response = hyper::Response::new(hyper::Body::from(data));
+                        *response.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+                        Ok::<_, hyper::Error>(response)
+                    }
+                }))
+            }
+        }))
+        .await
+        .unwrap();
+}
+
+// Serve the whole archive on every request, ignoring any range.
+async fn serve_archive_without_ranges(listener: std::net::TcpListener, path: &str) {
+    let archive_data = std::fs::read(path).unwrap();
+    hyper::Server::from_tcp(listener)
+        .unwrap()
+        .serve(make_service_fn(move |_conn| {
+            let data = archive_data.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |_req| {
+                    let data = data.clone();
                     async move {
                         Ok::<_, hyper::Error>(hyper::Response::new(hyper::Body::from(data)))
                     }
@@ -0,0 +1,132 @@
+use bitar::{
+    archive_reader::IoReader,
+    chunk_dictionary as dict,
+    chunker::{self, Chunker, ChunkerFactory, FixedSizeChunker},
+    Archive,
+};
+use blake2::{Blake2b512, Digest};
+use futures_util::stream::StreamExt;
+use std::io::Cursor;
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+
+const EVERY_100_CHUNKER_ID: u32 = 300;
+
+// Emits a chunk every 100 bytes.
+struct Every100Factory;
+
+impl ChunkerFactory for Every100Factory {
+    fn id(&self) -> u32 {
+        EVERY_100_CHUNKER_ID
+    }
+    fn new_chunker<'chunker>(
+        &self,
+        _config: &chunker::FilterConfig,
+        source: Box<dyn AsyncRead + Unpin + Send + 'chunker>,
+    ) -> Box<dyn Chunker + Send + Unpin + 'chunker> {
+        Box::new(FixedSizeChunker::new(100, source))
+    }
+}
+
+fn custom_config() -> chunker::Config {
+    chunker::Config::Custom(
+        EVERY_100_CHUNKER_ID,
+        chunker::FilterConfig {
+            filter_bits: chunker::FilterBits::from_bits(0),
+            min_chunk_size: 100,
+            max_chunk_size: 100,
+            window_size: 0,
+            buzhash_table: None,
+        },
+    )
+}
+
+async fn chunk_offsets(config: &chunker::Config, source: &[u8]) -> Vec<u64> {
+    config
+        .new_chunker(source)
+        .map(|result| result.unwrap().0)
+        .collect()
+        .await
+}
+
+// Build an uncompressed archive of the source using the custom chunker.
+async fn build_archive(source: &[u8]) -> Vec<u8> {
+    let mut chunk_data: Vec<u8> = Vec::new();
+    let mut chunk_descriptors = Vec::new();
+    let mut chunker = custom_config().new_chunker(source);
+    while let Some(result) = chunker.next().await {
+        let verified = result.unwrap().1.verify();
+        chunk_descriptors.push(dict::ChunkDescriptor {
+            checksum: verified.hash().to_vec(),
+            archive_size: verified.len() as u32,
+            archive_offset: chunk_data.len() as u64,
+            source_size: verified.len() as u32,
+            crc32c: 0,
+        });
+        chunk_data.extend(verified.data());
+    }
+    let dictionary = dict::ChunkDictionary {
+        rebuild_order: (0..chunk_descriptors.len() as u32).collect(),
+        application_version: "test".to_string(),
+        chunk_descriptors,
+        source_checksum: Blake2b512::digest(source).to_vec(),
+        chunk_compression: Some(None.into()),
+        source_total_size: source.len() as u64,
+        chunker_params: Some(dict::ChunkerParameters {
+            chunk_filter_bits: 0,
+            min_chunk_size: 100,
+            max_chunk_size: 100,
+            rolling_hash_window_size: 0,
+            chunk_hash_length: bitar::HashSum::MAX_LEN as u32,
+            chunking_algorithm: EVERY_100_CHUNKER_ID as i32,
+            buzhash_table: vec![],
+        }),
+        source_entry: None,
+        source_checkpoints: None,
+        chunk_data_part_sizes: vec![],
+        source_name: String::new(),
+        additional_sources: vec![],
+        chunk_crc32c: false,
+        rebuild_order_runs: None,
+        chunk_data_checksum: None,
+        chunk_data_alignment: 0,
+        content_type: String::new(),
+    };
+    let mut archive = bitar::header::build(&dictionary, None).unwrap();
+    archive.extend(chunk_data);
+    archive
+}
+
+#[tokio::test]
+async fn custom_chunker_round_trip() {
+    chunker::register_chunker(Arc::new(Every100Factory));
+    let source: Vec<u8> = (0..1050u32).map(|v| (v % 251) as u8).collect();
+    let archive = Archive::try_init(IoReader::new(Cursor::new(build_archive(&source).await)))
+        .await
+        .unwrap();
+    let config = archive.chunker_config();
+    assert!(matches!(
+        config,
+        chunker::Config::Custom(EVERY_100_CHUNKER_ID, _)
+    ));
+    assert_eq!(
+        chunk_offsets(config, &source).await,
+        (0..11).map(|i| i * 100).collect::<Vec<u64>>()
+    );
+}
+
+#[tokio::test]
+async fn unregistered_custom_chunker() {
+    let config = chunker::Config::Custom(
+        EVERY_100_CHUNKER_ID + 1,
+        chunker::FilterConfig {
+            filter_bits: chunker::FilterBits::from_bits(0),
+            min_chunk_size: 0,
+            max_chunk_size: 0,
+            window_size: 0,
+            buzhash_table: None,
+        },
+    );
+    let mut chunker = config.new_chunker(&b"data"[..]);
+    assert!(chunker.next().await.unwrap().is_err());
+}
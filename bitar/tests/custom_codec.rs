@@ -0,0 +1,230 @@
+#![cfg(feature = "compress")]
+
+use bitar::{
+    archive_reader::IoReader, chunk_dictionary as dict, Archive, Chunk, ChunkCodec, CloneOutput,
+    CodecDictionaries, Compression, CompressionError,
+};
+use blake2::{Blake2b512, Digest};
+use futures_util::stream::StreamExt;
+use std::io::Cursor;
+use std::sync::Arc;
+
+const XOR_CODEC_ID: u32 = 0x586f72;
+const DICT_CODEC_ID: u32 = 0x446963;
+
+// Run-length encodes and XORs the data. Chunks of the same size as the source are considered
+// uncompressed by the archive, so the codec has to actually shrink the data to be used.
+struct XorRleCodec;
+
+impl ChunkCodec for XorRleCodec {
+    fn id(&self) -> u32 {
+        XOR_CODEC_ID
+    }
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let mut output = Vec::new();
+        for &byte in data {
+            match output.len() {
+                len if len >= 2 && output[len - 1] == byte ^ 0x5a && output[len - 2] < 255 => {
+                    output[len - 2] += 1
+                }
+                _ => output.extend(&[1, byte ^ 0x5a]),
+            }
+        }
+        Ok(output)
+    }
+    fn decompress(&self, data: &[u8], size_hint: usize) -> Result<Vec<u8>, CompressionError> {
+        let mut output = Vec::with_capacity(size_hint);
+        for run in data.chunks(2) {
+            output.resize(output.len() + run[0] as usize, run[1] ^ 0x5a);
+        }
+        Ok(output)
+    }
+}
+
+// Run-length encodes the data XORed with a key, where the key is the dictionary supplied when
+// decompressing.
+struct DictRleCodec(Vec<u8>);
+
+impl DictRleCodec {
+    fn key(dictionary: &[u8], index: usize) -> u8 {
+        dictionary[index % dictionary.len()]
+    }
+}
+
+impl ChunkCodec for DictRleCodec {
+    fn id(&self) -> u32 {
+        DICT_CODEC_ID
+    }
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        let mut output: Vec<u8> = Vec::new();
+        for &byte in data {
+            let len = output.len();
+            if len >= 2 && output[len - 1] == byte && output[len - 2] < 255 {
+                output[len - 2] += 1;
+            } else {
+                output.extend(&[1, byte]);
+            }
+        }
+        let key = &self.0;
+        Ok(output
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ Self::key(key, index))
+            .collect())
+    }
+    fn decompress(&self, _data: &[u8], _size_hint: usize) -> Result<Vec<u8>, CompressionError> {
+        Err(CompressionError::Codec("dictionary required".into()))
+    }
+    fn decompress_with_dictionary(
+        &self,
+        data: &[u8],
+        size_hint: usize,
+        dictionary: &[u8],
+    ) -> Result<Vec<u8>, CompressionError> {
+        if dictionary.is_empty() {
+            return Err(CompressionError::Codec("empty dictionary".into()));
+        }
+        let mut output = Vec::with_capacity(size_hint);
+        for (index, run) in data.chunks(2).enumerate() {
+            let count = run[0] ^ Self::key(dictionary, index * 2);
+            let byte = run[1] ^ Self::key(dictionary, index * 2 + 1);
+            output.resize(output.len() + count as usize, byte);
+        }
+        Ok(output)
+    }
+}
+
+// Build an archive of the given chunks using the given compression.
+fn build_archive(chunks: &[&[u8]], compression: Compression) -> (Vec<u8>, Vec<u8>) {
+    let mut source = Vec::new();
+    let mut chunk_data: Vec<u8> = Vec::new();
+    let mut chunk_descriptors = Vec::new();
+    for data in chunks {
+        let verified = Chunk::from(data.to_vec()).verify();
+        let compressed = verified
+            .chunk()
+            .clone()
+            .compress(Some(compression))
+            .unwrap();
+        chunk_descriptors.push(dict::ChunkDescriptor {
+            checksum: verified.hash().to_vec(),
+            archive_size: compressed.len() as u32,
+            archive_offset: chunk_data.len() as u64,
+            source_size: data.len() as u32,
+            crc32c: 0,
+        });
+        chunk_data.extend(compressed.data());
+        source.extend(*data);
+    }
+    let dictionary = dict::ChunkDictionary {
+        rebuild_order: (0..chunks.len() as u32).collect(),
+        application_version: "test".to_string(),
+        chunk_descriptors,
+        source_checksum: Blake2b512::digest(&source).to_vec(),
+        chunk_compression: Some(Some(compression).into()),
+        source_total_size: source.len() as u64,
+        chunker_params: Some(dict::ChunkerParameters {
+            chunk_filter_bits: 0,
+            min_chunk_size: 0,
+            max_chunk_size: 16,
+            rolling_hash_window_size: 0,
+            chunk_hash_length: bitar::HashSum::MAX_LEN as u32,
+            chunking_algorithm: dict::chunker_parameters::ChunkingAlgorithm::FixedSize as i32,
+            buzhash_table: vec![],
+        }),
+        source_entry: None,
+        source_checkpoints: None,
+        chunk_data_part_sizes: vec![],
+        source_name: String::new(),
+        additional_sources: vec![],
+        chunk_crc32c: false,
+        rebuild_order_runs: None,
+        chunk_data_checksum: None,
+        chunk_data_alignment: 0,
+        content_type: String::new(),
+    };
+    let mut archive = bitar::header::build(&dictionary, None).unwrap();
+    archive.extend(chunk_data);
+    (archive, source)
+}
+
+#[tokio::test]
+async fn custom_codec_round_trip() {
+    bitar::register_codec(Arc::new(XorRleCodec));
+    let compression = Compression::custom(XOR_CODEC_ID).unwrap();
+    let (archive_buf, source) = build_archive(
+        &[b"aaaaaaaaaaaaaaab", b"bbbbbbbbbbbbcccc", b"ddddd"],
+        compression,
+    );
+    let mut archive = Archive::try_init(IoReader::new(Cursor::new(archive_buf)))
+        .await
+        .unwrap();
+    assert_eq!(archive.chunk_compression(), Some(compression));
+    let mut output_buf = vec![];
+    {
+        let mut output =
+            CloneOutput::new(Cursor::new(&mut output_buf), archive.build_source_index());
+        let mut chunk_stream = archive.chunk_stream(output.chunks());
+        while let Some(result) = chunk_stream.next().await {
+            let verified = result.unwrap().decompress().unwrap().verify().unwrap();
+            output.feed(&verified).await.unwrap();
+        }
+    }
+    assert_eq!(output_buf, source);
+}
+
+#[tokio::test]
+async fn codec_dictionary_given_when_decompressing() {
+    let key = b"secret key".to_vec();
+    bitar::register_codec(Arc::new(DictRleCodec(key.clone())));
+    let compression = Compression::custom(DICT_CODEC_ID).unwrap();
+    let (archive_buf, source) = build_archive(
+        &[b"aaaaaaaaaaaaaaab", b"bbbbbbbbbbbbcccc", b"ddddd"],
+        compression,
+    );
+    let mut archive = Archive::try_init(IoReader::new(Cursor::new(archive_buf)))
+        .await
+        .unwrap();
+    let mut dictionaries = CodecDictionaries::new();
+    dictionaries.insert(DICT_CODEC_ID, key);
+    let mut output_buf = vec![];
+    {
+        let mut output =
+            CloneOutput::new(Cursor::new(&mut output_buf), archive.build_source_index());
+        let mut chunk_stream = archive.chunk_stream(output.chunks());
+        while let Some(result) = chunk_stream.next().await {
+            let compressed = result.unwrap();
+            assert!(compressed.clone().decompress().is_err());
+            let verified = compressed
+                .decompress_with(&dictionaries)
+                .unwrap()
+                .verify()
+                .unwrap();
+            output.feed(&verified).await.unwrap();
+        }
+    }
+    assert_eq!(output_buf, source);
+}
+
+#[test]
+fn unregistered_codec() {
+    assert!(Compression::custom(XOR_CODEC_ID + 1).is_err());
+}
+
+#[test]
+#[should_panic]
+fn reserved_codec_id() {
+    struct ReservedCodec;
+    impl ChunkCodec for ReservedCodec {
+        fn id(&self) -> u32 {
+            3
+        }
+        fn compress(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+            Ok(data.to_vec())
+        }
+        fn decompress(&self, data: &[u8], _size_hint: usize) -> Result<Vec<u8>, CompressionError> {
+            Ok(data.to_vec())
+        }
+    }
+    bitar::register_codec(Arc::new(ReservedCodec));
+}
@@ -0,0 +1,119 @@
+use bitar::{archive_reader::IoReader, chunk_dictionary as dict, Archive, ArchiveSummary};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Allocator keeping track of the current and peak number of allocated bytes.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::SeqCst);
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const CHUNK_SIZE: u32 = 4096;
+const CHUNKS: u32 = 200_000;
+const HASH_LENGTH: usize = 16;
+
+// Header of an archive with many unique chunks.
+fn build_header() -> Vec<u8> {
+    let checksum = |index: u32| {
+        let mut checksum = vec![0; HASH_LENGTH];
+        checksum[..4].copy_from_slice(&index.to_le_bytes());
+        checksum
+    };
+    let dictionary = dict::ChunkDictionary {
+        rebuild_order: (0..CHUNKS).collect(),
+        application_version: "test".to_string(),
+        chunk_descriptors: (0..CHUNKS)
+            .map(|index| dict::ChunkDescriptor {
+                checksum: checksum(index),
+                archive_size: CHUNK_SIZE,
+                archive_offset: u64::from(index) * u64::from(CHUNK_SIZE),
+                source_size: CHUNK_SIZE,
+                crc32c: 0,
+            })
+            .collect(),
+        source_checksum: vec![0; 64],
+        chunk_compression: Some(None.into()),
+        source_total_size: u64::from(CHUNKS) * u64::from(CHUNK_SIZE),
+        chunker_params: Some(dict::ChunkerParameters {
+            chunk_filter_bits: 0,
+            min_chunk_size: 0,
+            max_chunk_size: CHUNK_SIZE,
+            rolling_hash_window_size: 0,
+            chunk_hash_length: HASH_LENGTH as u32,
+            chunking_algorithm: dict::chunker_parameters::ChunkingAlgorithm::FixedSize as i32,
+            buzhash_table: vec![],
+        }),
+        source_checkpoints: None,
+        chunk_data_part_sizes: vec![],
+        source_name: String::new(),
+        additional_sources: vec![],
+        chunk_crc32c: false,
+        rebuild_order_runs: None,
+        chunk_data_checksum: None,
+        chunk_data_alignment: 0,
+        source_entry: None,
+        content_type: String::new(),
+    };
+    bitar::header::build(&dictionary, None).unwrap()
+}
+
+// Peak number of bytes allocated while running f, on top of what was allocated before.
+async fn peak_allocated<F, T>(f: F) -> (T, usize)
+where
+    F: std::future::Future<Output = T>,
+{
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let result = f.await;
+    (result, PEAK.load(Ordering::SeqCst) - before)
+}
+
+#[tokio::test]
+async fn summary_peaks_below_materialized_archive() {
+    let header = build_header();
+
+    let (summary, summary_peak) = peak_allocated(ArchiveSummary::try_init(IoReader::new(
+        Cursor::new(header.clone()),
+    )))
+    .await;
+    let summary = summary.unwrap();
+    let (archive, archive_peak) = peak_allocated(Archive::try_init(IoReader::new(Cursor::new(
+        header.clone(),
+    ))))
+    .await;
+    let archive = archive.unwrap();
+
+    assert!(
+        summary_peak * 2 < archive_peak,
+        "summary peaked at {} bytes, archive at {} bytes",
+        summary_peak,
+        archive_peak
+    );
+
+    let expected = ArchiveSummary::from(&archive);
+    assert_eq!(summary.total_chunks(), CHUNKS as usize);
+    assert_eq!(summary.unique_chunks(), expected.unique_chunks());
+    assert_eq!(summary.compressed_size(), expected.compressed_size());
+    assert_eq!(summary.chunk_size_stats(), expected.chunk_size_stats());
+    assert_eq!(summary.header_size(), header.len());
+    assert_eq!(summary.header_checksum(), expected.header_checksum());
+}
@@ -0,0 +1,143 @@
+use anyhow::{Context, Result};
+use log::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::{human_size, info_cmd, local_file::LocalFile};
+use bitar::{header::DictionaryKey, ArchiveError, HashSum};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub dir: PathBuf,
+    // Key decrypting the archive dictionaries
+    pub dictionary_key: Option<DictionaryKey>,
+}
+
+// Sizes of a set of archives, stored apart and if sharing one chunk store.
+#[derive(Debug, Clone, PartialEq)]
+struct DedupReport {
+    archives: usize,
+    // Size of all sources of all archives
+    logical_size: u64,
+    // Size of the chunk data of all archives, each storing its own chunks
+    stored_size: u64,
+    // Size of the chunk data if every unique chunk was only stored once across all archives
+    shared_stored_size: u64,
+}
+
+impl DedupReport {
+    fn dedup_ratio(&self) -> f64 {
+        if self.shared_stored_size == 0 {
+            1.0
+        } else {
+            self.stored_size as f64 / self.shared_stored_size as f64
+        }
+    }
+}
+
+// Read the dictionary of every archive in the directory and union their chunks. Files which
+// aren't archives, like part files, are skipped. Chunks are only shared between archives using
+// the same hash length.
+async fn analyze_dir(dir: &Path, key: Option<&DictionaryKey>) -> Result<DedupReport> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir).context(format!("Failed to read {}", dir.display()))? {
+        let entry = entry.context(format!("Failed to read {}", dir.display()))?;
+        if entry.file_type()?.is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    let mut report = DedupReport {
+        archives: 0,
+        logical_size: 0,
+        stored_size: 0,
+        shared_stored_size: 0,
+    };
+    let mut chunks: HashMap<HashSum, u64> = HashMap::new();
+    for path in paths {
+        let archive = match info_cmd::init_archive(LocalFile::open_archive(&path).await?, key).await
+        {
+            Ok(archive) => archive,
+            Err(ArchiveError::NotAnArchive) => {
+                debug!("Skipping {}, not an archive", path.display());
+                continue;
+            }
+            Err(err) => {
+                return Err(err).context(format!("Failed to read archive {}", path.display()))
+            }
+        };
+        report.archives += 1;
+        report.logical_size += archive
+            .sources()
+            .iter()
+            .map(|source| source.size)
+            .sum::<u64>();
+        report.stored_size += archive.compressed_size();
+        for descriptor in archive.chunk_descriptors() {
+            chunks
+                .entry(descriptor.checksum.clone())
+                .or_insert(descriptor.archive_size as u64);
+        }
+    }
+    report.shared_stored_size = chunks.values().sum();
+    Ok(report)
+}
+
+pub async fn analyze_cmd(opts: Options) -> Result<()> {
+    let report = analyze_dir(&opts.dir, opts.dictionary_key.as_ref()).await?;
+    info!("Archives in {}: {}", opts.dir.display(), report.archives);
+    info!("  Logical size: {}", human_size!(report.logical_size));
+    info!("  Stored size: {}", human_size!(report.stored_size));
+    info!(
+        "  Stored size if sharing one store: {}",
+        human_size!(report.shared_stored_size)
+    );
+    info!("  Cross-archive dedup ratio: {:.2}", report.dedup_ratio());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_cmd::{
+        self,
+        tests::{random_data, test_options},
+    };
+
+    async fn compress(input: &[u8], output: &Path) {
+        let input_path = output.with_extension("img");
+        std::fs::write(&input_path, input).unwrap();
+        compress_cmd::compress_cmd(test_options(
+            vec![input_path.clone()],
+            compress_cmd::Output::File(output.to_path_buf()),
+        ))
+        .await
+        .unwrap();
+        std::fs::remove_file(input_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn overlapping_archives_dedup() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let shared = random_data(32 * 1024, 0);
+        let mut a = shared.clone();
+        a.extend(random_data(32 * 1024, 1));
+        let mut b = shared;
+        b.extend(random_data(32 * 1024, 2));
+        compress(&a, &temp_dir.path().join("a.cba")).await;
+        compress(&b, &temp_dir.path().join("b.cba")).await;
+        std::fs::write(temp_dir.path().join("notes.txt"), b"not an archive").unwrap();
+
+        let report = analyze_dir(temp_dir.path(), None).await.unwrap();
+        assert_eq!(
+            report,
+            DedupReport {
+                archives: 2,
+                logical_size: 128 * 1024,
+                stored_size: 128 * 1024,
+                shared_stored_size: 96 * 1024,
+            }
+        );
+        assert!((report.dedup_ratio() - 4.0 / 3.0).abs() < 1e-9);
+    }
+}
@@ -1,22 +1,42 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use blake2::{Blake2b512, Digest};
-use futures_util::StreamExt;
+use bytes::Bytes;
+use futures_util::{future, Future, Stream, StreamExt};
 use log::*;
 use reqwest::header::HeaderMap;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
 use std::io::SeekFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
 use std::time::Duration;
 use tokio::fs::File;
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite},
+    io::{
+        AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter,
+        ReadBuf,
+    },
     task::spawn_blocking,
 };
 use url::Url;
 
-use crate::{human_size, info_cmd};
+use crate::{
+    compress_cmd, human_size, info_cmd,
+    local_file::LocalFile,
+    progress::{Progress, ProgressFormat, ProgressWriter},
+    verify_cmd,
+};
 use bitar::{
-    archive_reader::{ArchiveReader, HttpReader, IoReader},
-    chunker, Archive, ChunkIndex, CloneOutput, HashSum, VerifiedChunk,
+    archive_reader::{
+        chunk_store_path, ArchiveReader, ChunkStore, FallbackReader, HttpReader, HttpReaderError,
+        IoReader, RetryJitter,
+    },
+    casync, chunker,
+    header::DictionaryKey,
+    Archive, ChunkHasher, ChunkIndex, CloneOutput, CodecDictionaries, CompressedArchiveChunk,
+    HashSum, SourceEntry, VerifiedChunk,
 };
 
 async fn file_size(file: &mut File) -> Result<u64, std::io::Error> {
@@ -24,8 +44,10 @@ async fn file_size(file: &mut File) -> Result<u64, std::io::Error> {
     file.seek(SeekFrom::End(0)).await
 }
 
-async fn file_checksum(file: &mut File) -> Result<HashSum, std::io::Error> {
+// Checksum of the first size bytes of the file, a block device may be larger than the source.
+async fn file_checksum(file: &mut File, size: u64) -> Result<HashSum, std::io::Error> {
     file.seek(SeekFrom::Start(0)).await?;
+    let mut file = file.take(size);
     let mut output_hasher = Blake2b512::new();
     let mut buffer: Vec<u8> = vec![0; 4 * 1024 * 1024];
     loop {
@@ -55,6 +77,127 @@ async fn is_block_dev(_file: &mut File) -> Result<bool, std::io::Error> {
     Ok(false)
 }
 
+// Remove an existing output file or link which is to be replaced by a link, never a device or
+// directory given as output.
+fn remove_output(output: &Path) -> Result<()> {
+    if let Ok(metadata) = std::fs::symlink_metadata(output) {
+        if metadata.file_type().is_file() || metadata.file_type().is_symlink() {
+            std::fs::remove_file(output)
+                .context(format!("Failed to remove {}", output.display()))?;
+        }
+    }
+    Ok(())
+}
+
+// Allocate the blocks of the whole file up front. Allocating in one go lets the filesystem
+// pick contiguous extents, while writing chunks out of order may leave the file fragmented.
+#[cfg(target_os = "linux")]
+fn allocate_contiguous(file: &File, size: u64) -> Result<(), std::io::Error> {
+    use std::convert::TryFrom;
+    use std::os::unix::io::AsRawFd;
+    let size = libc::off_t::try_from(size)
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    // Mode 0 also extends the file to the size
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate_contiguous(_file: &File, _size: u64) -> Result<(), std::io::Error> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "allocation is only supported on Linux",
+    ))
+}
+
+// A block device is written as is, so it must fit the source. A larger device only has the
+// source written to its start, which is opt in.
+fn check_device_size(device_size: u64, source_size: u64, allow_larger: bool) -> Result<()> {
+    if device_size < source_size {
+        bail!(
+            "Size of output device ({}) is less than archive source ({})",
+            human_size!(device_size),
+            human_size!(source_size)
+        );
+    }
+    if device_size > source_size && !allow_larger {
+        bail!(
+            "Size of output device ({}) differs from archive source ({}), use --allow-size-mismatch to write the source to the start of the device",
+            human_size!(device_size),
+            human_size!(source_size)
+        );
+    }
+    Ok(())
+}
+
+// Recreate a symbolic link stored in the archive at the output path.
+#[cfg(unix)]
+fn clone_symlink(target: &str, output: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, output).context(format!(
+        "Failed to create symbolic link {}",
+        output.display()
+    ))
+}
+
+#[cfg(not(unix))]
+fn clone_symlink(_target: &str, output: &Path) -> Result<()> {
+    Err(anyhow!(
+        "Unable to create symbolic link {}, not supported on this platform",
+        output.display()
+    ))
+}
+
+// Recreate a link stored in the archive at the output path. A hard link is made to the file of
+// the linked source's name next to the output, which must already have been cloned.
+fn clone_link(entry: &SourceEntry, output: &Path, force_create: bool) -> Result<()> {
+    if force_create {
+        remove_output(output)?;
+    }
+    match entry {
+        SourceEntry::File => Ok(()),
+        SourceEntry::Symlink(target) => {
+            clone_symlink(target, output)?;
+            info!(
+                "Successfully created symbolic link {} to {}.",
+                output.display(),
+                target
+            );
+            Ok(())
+        }
+        SourceEntry::HardLink(name) => {
+            let original = output.with_file_name(name);
+            std::fs::hard_link(&original, output).context(format!(
+                "Failed to create hard link {} to {}",
+                output.display(),
+                original.display()
+            ))?;
+            info!(
+                "Successfully created hard link {} to {}.",
+                output.display(),
+                original.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+// Check if path is a FIFO or socket, which can't be seeked in
+#[cfg(unix)]
+async fn is_non_seekable(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    match tokio::fs::metadata(path).await {
+        Ok(meta) => meta.file_type().is_fifo() || meta.file_type().is_socket(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+async fn is_non_seekable(_path: &Path) -> bool {
+    false
+}
+
 async fn feed_output<S, C>(output: &mut CloneOutput<C>, mut chunk_stream: S) -> Result<u64>
 where
     S: StreamExt<Item = Result<VerifiedChunk>> + Unpin,
@@ -75,6 +218,8 @@ where
 async fn clone_from_readable<I, C>(
     max_buffered_chunks: usize,
     config: &chunker::Config,
+    hasher: ChunkHasher,
+    strict_seeds: Option<&SeedCrcs>,
     input: I,
     output: &mut CloneOutput<C>,
 ) -> Result<u64>
@@ -84,17 +229,298 @@ where
 {
     let chunk_stream = config
         .new_chunker(input)
-        .map(|r| spawn_blocking(|| r.map(|(_, chunk)| chunk.verify())))
+        .map(|r| spawn_blocking(move || r.map(|(_, chunk)| chunk.verify_with(&hasher))))
         .buffered(max_buffered_chunks)
         .map(|r| match r {
             Ok(inner) => Ok(inner?),
             Err(err) => Err(anyhow!(err)),
+        })
+        .filter(|r| {
+            future::ready(match (r, strict_seeds) {
+                (Ok(verified), Some(strict_seeds)) => strict_seeds.accepts(verified),
+                _ => true,
+            })
         });
     feed_output(output, chunk_stream).await
 }
 
+// Reader which builds a checksum of everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Blake2b512,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Blake2b512::new(),
+        }
+    }
+    fn checksum(self) -> HashSum {
+        HashSum::from(&self.hasher.finalize()[..])
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            self.hasher.update(&buf.filled()[filled_before..]);
+        }
+        result
+    }
+}
+
+// Clone from a seed stream, optionally verifying the checksum of the whole stream. To verify, the
+// stream is first spooled to a temporary file in temp_dir so that no seed chunk is written to the
+// output unless the checksum matched.
+#[allow(clippy::too_many_arguments)]
+async fn clone_from_seed_stream<I, C>(
+    max_buffered_chunks: usize,
+    config: &chunker::Config,
+    hasher: ChunkHasher,
+    strict_seeds: Option<&SeedCrcs>,
+    input: I,
+    expected_checksum: Option<&HashSum>,
+    temp_dir: &Path,
+    output_path: &Path,
+    output: &mut CloneOutput<C>,
+) -> Result<u64>
+where
+    I: AsyncRead + Unpin + Send,
+    C: AsyncWrite + AsyncSeek + Unpin + Send,
+{
+    let expected_checksum = match expected_checksum {
+        Some(expected_checksum) => expected_checksum,
+        None => {
+            return clone_from_readable(
+                max_buffered_chunks,
+                config,
+                hasher,
+                strict_seeds,
+                input,
+                output,
+            )
+            .await
+        }
+    };
+    let temp_seed = TempOutput::in_dir(temp_dir, output_path, ".seed.tmp")?;
+    let mut seed_file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_seed.path)
+        .await
+        .context(format!("Failed to open {}", temp_seed.path.display()))?;
+    let mut input = HashingReader::new(input);
+    tokio::io::copy(&mut input, &mut seed_file)
+        .await
+        .context(format!("Failed to write {}", temp_seed.path.display()))?;
+    let checksum = input.checksum();
+    if checksum != *expected_checksum {
+        return Err(anyhow!(
+            "Seed checksum mismatch (expected: {}, got: {})",
+            expected_checksum,
+            checksum
+        ));
+    }
+    info!("Seed checksum verified Ok");
+    seed_file.seek(SeekFrom::Start(0)).await?;
+    clone_from_readable(
+        max_buffered_chunks,
+        config,
+        hasher,
+        strict_seeds,
+        seed_file,
+        output,
+    )
+    .await
+}
+
+// Which of the given number of chunks to fetch to verify the hash of, all of them (an empty
+// list) unless screening using the CRC.
+fn crc_full_verify<R>(archive: &Archive<R>, count: usize, crc_verify: Option<f64>) -> Vec<bool> {
+    match crc_verify {
+        Some(percent) if archive.has_chunk_crc32c() => {
+            let mut full_verify = vec![false; count];
+            let seed = std::collections::hash_map::RandomState::new()
+                .build_hasher()
+                .finish();
+            for index in verify_cmd::sample_chunks(count, percent, seed) {
+                full_verify[index] = true;
+            }
+            full_verify
+        }
+        Some(_) => {
+            warn!("Archive has no chunk CRCs stored, verifying the hash of all chunks");
+            vec![]
+        }
+        None => vec![],
+    }
+}
+
+// CRC32C of the chunks of an archive, which every chunk found in a seed must match with strict
+// seeds. The archive only stores a truncated hash of each chunk, the CRC is an independent check
+// of the content that a corrupted seed chunk matching the truncated hash is unlikely to pass.
+#[derive(Clone)]
+struct SeedCrcs {
+    hash_length: usize,
+    crcs: Arc<HashMap<HashSum, u32>>,
+}
+
+impl SeedCrcs {
+    fn new<R>(archive: &Archive<R>) -> Result<Self> {
+        if !archive.has_chunk_crc32c() {
+            return Err(anyhow!(
+                "Archive has no chunk CRCs stored, strict seeds requires an archive compressed with --chunk-crc"
+            ));
+        }
+        Ok(Self {
+            hash_length: archive.chunk_hash_length(),
+            crcs: Arc::new(
+                archive
+                    .chunk_descriptors()
+                    .iter()
+                    .filter_map(|cd| cd.crc32c.map(|crc| (cd.checksum.clone(), crc)))
+                    .collect(),
+            ),
+        })
+    }
+    // If a seed chunk may be used, a chunk not in the archive is accepted as it won't be.
+    fn accepts(&self, verified: &VerifiedChunk) -> bool {
+        let mut hash = verified.hash().clone();
+        hash.truncate(self.hash_length);
+        match self.crcs.get(&hash) {
+            Some(&crc) if crc != verified.chunk().crc32c() => {
+                warn!(
+                    "Seed chunk '{}' doesn't match the CRC of the archive chunk, not using it",
+                    hash
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+// Decompress and verify a chunk fetched from the archive. A chunk failing to decompress or
+// verify gives None if it's to be fetched again, as it may have been corrupted on the way.
+// Otherwise the decompression or hash mismatch error is returned as is.
+fn verify_archive_chunk(
+    compressed: CompressedArchiveChunk,
+    dictionaries: &CodecDictionaries,
+    hasher: &ChunkHasher,
+    full_verify: bool,
+    retry_mismatch: bool,
+) -> Result<Option<VerifiedChunk>> {
+    let verified = match compressed.decompress_with(dictionaries) {
+        Ok(chunk) => chunk
+            .verify_crc_with(hasher, full_verify)
+            .map_err(anyhow::Error::from),
+        Err(err) => Err(anyhow::Error::from(err)),
+    };
+    match verified {
+        Ok(verified) => Ok(Some(verified)),
+        Err(err) if retry_mismatch => {
+            warn!("Chunk failed verification ({}), fetching it again", err);
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Decompress, verify and write chunks fetched from the archive. Returns the number of bytes
+// fetched and written.
+async fn feed_archive_chunks<S, E, C>(
+    max_buffered_chunks: usize,
+    hasher: ChunkHasher,
+    full_verify: Vec<bool>,
+    retry_mismatch: bool,
+    dictionaries: &Arc<CodecDictionaries>,
+    chunk_stream: S,
+    output: &mut CloneOutput<C>,
+) -> Result<(u64, u64)>
+where
+    S: Stream<Item = Result<CompressedArchiveChunk, E>> + Unpin,
+    E: std::error::Error + Sync + Send + 'static,
+    C: AsyncWrite + AsyncSeek + Unpin + Send,
+{
+    let mut fetched = 0u64;
+    let chunk_stream = chunk_stream
+        .enumerate()
+        .map(|(index, r)| {
+            if let Ok(compressed) = &r {
+                fetched += compressed.len() as u64;
+            }
+            let full_verify = full_verify.get(index).copied().unwrap_or(true);
+            let dictionaries = dictionaries.clone();
+            spawn_blocking(move || -> Result<Option<VerifiedChunk>> {
+                verify_archive_chunk(
+                    r.context("read archive")?,
+                    &dictionaries,
+                    &hasher,
+                    full_verify,
+                    retry_mismatch,
+                )
+            })
+        })
+        .buffered(max_buffered_chunks)
+        .filter_map(|r| {
+            future::ready(match r {
+                Ok(inner) => inner.transpose(),
+                Err(err) => Some(Err(anyhow!(err))),
+            })
+        });
+    let written = feed_output(output, chunk_stream).await?;
+    Ok((fetched, written))
+}
+
+// Poll the stream ahead of the consumer, keeping up to `window` items ready, while running the
+// consumer on the prefetched items. Fetching then continues while earlier chunks are being
+// decompressed and written.
+async fn with_prefetch<S, F, Fut, T>(stream: S, window: usize, consume: F) -> T
+where
+    S: Stream + Unpin,
+    F: FnOnce(Pin<Box<dyn Stream<Item = S::Item> + Send>>) -> Fut,
+    Fut: Future<Output = T>,
+    S::Item: Send + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::channel(window.max(1));
+    let produce = async move {
+        let mut stream = stream;
+        while let Some(item) = stream.next().await {
+            if tx.send(item).await.is_err() {
+                break;
+            }
+        }
+    };
+    let prefetched = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    });
+    let consume = consume(Box::pin(prefetched));
+    futures_util::pin_mut!(produce, consume);
+    match future::select(produce, consume).await {
+        // Everything fetched (or the consumer is gone), let the consumer finish
+        future::Either::Left((_, consume)) => consume.await,
+        future::Either::Right((result, _)) => result,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn clone_from_archive<R, C>(
     max_buffered_chunks: usize,
+    prefetch_chunks: Option<usize>,
+    hasher: ChunkHasher,
+    crc_verify: Option<f64>,
+    chunk_retries: u32,
+    dictionaries: &Arc<CodecDictionaries>,
     archive: &mut Archive<R>,
     output: &mut CloneOutput<C>,
 ) -> Result<u64>
@@ -104,39 +530,409 @@ where
     C: AsyncWrite + AsyncSeek + Unpin + Send,
 {
     let mut total_fetched = 0u64;
-    let chunk_stream = archive
-        .chunk_stream(output.chunks())
-        .map(|r| {
-            if let Ok(compressed) = &r {
-                total_fetched += compressed.len() as u64;
+    let mut total_written = 0u64;
+    let mut retries_left = chunk_retries;
+    loop {
+        let retry_mismatch = retries_left > 0;
+        let full_verify = crc_full_verify(archive, output.chunks().len(), crc_verify);
+        let chunk_stream = archive.chunk_stream(output.chunks());
+        let (fetched, written) = match prefetch_chunks {
+            Some(window) => {
+                with_prefetch(chunk_stream, window, |prefetched| {
+                    feed_archive_chunks(
+                        max_buffered_chunks,
+                        hasher,
+                        full_verify,
+                        retry_mismatch,
+                        dictionaries,
+                        prefetched,
+                        output,
+                    )
+                })
+                .await?
             }
+            None => {
+                feed_archive_chunks(
+                    max_buffered_chunks,
+                    hasher,
+                    full_verify,
+                    retry_mismatch,
+                    dictionaries,
+                    chunk_stream,
+                    output,
+                )
+                .await?
+            }
+        };
+        total_fetched += fetched;
+        total_written += written;
+        if output.is_empty() || !retry_mismatch {
+            break;
+        }
+        retries_left -= 1;
+        info!(
+            "Fetching {} chunks which failed verification again...",
+            output.len()
+        );
+        archive.bypass_cache();
+    }
+    info!(
+        "Fetched {} from archive and decompressed to {}.",
+        human_size!(total_fetched),
+        human_size!(total_written)
+    );
+    Ok(total_fetched)
+}
+
+// Differences between the chunking of a seed archive and the target archive. Chunks of the
+// seed archive can only be used as is if they were chunked and hashed in the same way.
+fn chunker_mismatch<S, R>(seed: &Archive<S>, target: &Archive<R>) -> Result<Vec<String>> {
+    let seed_params =
+        compress_cmd::chunker_parameters(seed.chunker_config(), seed.chunk_hash_length())?;
+    let target_params =
+        compress_cmd::chunker_parameters(target.chunker_config(), target.chunk_hash_length())?;
+    let mut mismatch = Vec::new();
+    let mut compare = |name: &str, seed: u32, target: u32| {
+        if seed != target {
+            mismatch.push(format!("{} {} != {}", name, seed, target));
+        }
+    };
+    compare(
+        "algorithm",
+        seed_params.chunking_algorithm as u32,
+        target_params.chunking_algorithm as u32,
+    );
+    compare(
+        "filter bits",
+        seed_params.chunk_filter_bits,
+        target_params.chunk_filter_bits,
+    );
+    compare(
+        "min chunk size",
+        seed_params.min_chunk_size,
+        target_params.min_chunk_size,
+    );
+    compare(
+        "max chunk size",
+        seed_params.max_chunk_size,
+        target_params.max_chunk_size,
+    );
+    compare(
+        "window size",
+        seed_params.rolling_hash_window_size,
+        target_params.rolling_hash_window_size,
+    );
+    compare(
+        "hash length",
+        seed_params.chunk_hash_length,
+        target_params.chunk_hash_length,
+    );
+    Ok(mismatch)
+}
+
+// Fetch, decompress and verify the given chunks of an archive.
+fn verified_archive_chunks<'a, R>(
+    max_buffered_chunks: usize,
+    hasher: ChunkHasher,
+    dictionaries: &Arc<CodecDictionaries>,
+    archive: &'a mut Archive<R>,
+    chunks: &ChunkIndex,
+) -> impl futures_util::Stream<Item = Result<VerifiedChunk>> + Unpin + 'a
+where
+    R: ArchiveReader + 'a,
+    R::Error: std::error::Error + Sync + Send + 'static,
+{
+    let dictionaries = dictionaries.clone();
+    archive
+        .chunk_stream(chunks)
+        .map(move |r| {
+            let dictionaries = dictionaries.clone();
             spawn_blocking(move || -> Result<VerifiedChunk> {
-                let compressed = r.context("read archive")?;
-                let verified = compressed
-                    .decompress()
+                r.context("read archive")?
+                    .decompress_with(&dictionaries)
                     .context("decompress chunk")?
-                    .verify()
-                    .context("verify chunk")?;
-                Ok(verified)
+                    .verify_with(&hasher)
+                    .context("verify chunk")
             })
         })
         .buffered(max_buffered_chunks)
         .map(|r| match r {
             Ok(inner) => inner,
             Err(err) => Err(anyhow!(err)),
-        });
-    let total_written = feed_output(output, chunk_stream).await?;
-    info!(
-        "Fetched {} from archive and decompressed to {}.",
-        human_size!(total_fetched),
-        human_size!(total_written)
+        })
+}
+
+// Clone using the chunks of a (local) archive as seed. If the seed archive was chunked the same
+// way as the target its chunks are used directly, otherwise its source is unpacked to a
+// temporary file and re-chunked.
+#[allow(clippy::too_many_arguments)]
+async fn clone_from_seed_archive<R, C>(
+    max_buffered_chunks: usize,
+    hasher: ChunkHasher,
+    strict_seeds: Option<&SeedCrcs>,
+    dictionaries: &Arc<CodecDictionaries>,
+    seed_path: &Path,
+    target: &Archive<R>,
+    output_path: &Path,
+    output: &mut CloneOutput<C>,
+) -> Result<u64>
+where
+    C: AsyncWrite + AsyncSeek + Unpin + Send,
+{
+    let mut seed = Archive::try_init(LocalFile::open_archive(seed_path).await?)
+        .await
+        .context(format!("Failed to read archive {}", seed_path.display()))?;
+    if !seed.chunk_data_part_sizes().is_empty() {
+        return Err(anyhow!(
+            "Using split archive {} as seed is not supported",
+            seed_path.display()
+        ));
+    }
+    let mismatch = chunker_mismatch(&seed, target)?;
+    if mismatch.is_empty() {
+        let chunk_stream = verified_archive_chunks(
+            max_buffered_chunks,
+            hasher,
+            dictionaries,
+            &mut seed,
+            output.chunks(),
+        );
+        return feed_output(output, chunk_stream).await;
+    }
+    warn!(
+        "Seed archive {} is chunked differently than the target ({}), re-chunking its source",
+        seed_path.display(),
+        mismatch.join(", ")
     );
-    Ok(total_fetched)
+    let temp_seed = TempOutput::with_suffix(output_path, ".seed.tmp")?;
+    let mut seed_file = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_seed.path)
+        .await
+        .context(format!("Failed to open {}", temp_seed.path.display()))?;
+    {
+        let mut seed_output = CloneOutput::new(&mut seed_file, seed.build_source_index())
+            .size_limit(seed.total_source_size());
+        let chunk_stream = verified_archive_chunks(
+            max_buffered_chunks,
+            hasher,
+            dictionaries,
+            &mut seed,
+            seed_output.chunks(),
+        );
+        feed_output(&mut seed_output, chunk_stream)
+            .await
+            .context(format!("Failed to unpack {}", seed_path.display()))?;
+        seed_output.flush().await?;
+    }
+    seed_file.set_len(seed.total_source_size()).await?;
+    seed_file.seek(SeekFrom::Start(0)).await?;
+    clone_from_readable(
+        max_buffered_chunks,
+        target.chunker_config(),
+        hasher,
+        strict_seeds,
+        seed_file,
+        output,
+    )
+    .await
+}
+
+// Where the chunks of a concurrent clone were resolved from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct ResolvedBytes {
+    // Bytes of output written from the seeds
+    from_seeds: u64,
+    // Bytes of output written from the archive
+    from_archive: u64,
+    // Bytes of chunk data fetched from the archive, also chunks resolved from the seeds first
+    fetched: u64,
+}
+
+enum ResolvedChunk {
+    Seed(VerifiedChunk),
+    // Bytes fetched and the chunk, unless it was already resolved or is to be fetched again
+    Archive(u64, Option<VerifiedChunk>),
+}
+
+// Open a seed file to scan, only reading up to the scan limit of it. Chunks of the seed past the
+// limit are fetched from the archive instead.
+async fn open_seed_file(
+    seed_path: &Path,
+    scan_limit: Option<u64>,
+) -> Result<tokio::io::Take<LocalFile>> {
+    Ok(LocalFile::open(seed_path)
+        .await?
+        .take(scan_limit.unwrap_or(u64::MAX)))
+}
+
+// Scan the seeds for chunks while fetching from the archive, to overlap disk and network I/O.
+// Both sources feed the same output and whichever resolves a chunk first wins. The archive chunks
+// are requested in batches of max_buffered_chunks, leaving out the chunks seeds have resolved by
+// the time a batch is requested. Fetched chunks are passed on as they arrive, so the next batch is
+// requested while the previous one is still being decompressed. A chunk fetched after a seed
+// resolved it is dropped without being decompressed, and seed scanning stops as soon as no chunks
+// are left.
+#[allow(clippy::too_many_arguments)]
+async fn clone_concurrently<R, S, C>(
+    max_buffered_chunks: usize,
+    hasher: ChunkHasher,
+    crc_verify: Option<f64>,
+    retry_mismatch: bool,
+    strict_seeds: Option<&SeedCrcs>,
+    dictionaries: &Arc<CodecDictionaries>,
+    seeds: Vec<S>,
+    archive: &mut Archive<R>,
+    output: &mut CloneOutput<C>,
+) -> Result<ResolvedBytes>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Sync + Send + 'static,
+    S: AsyncRead + Unpin + Send,
+    C: AsyncWrite + AsyncSeek + Unpin + Send,
+{
+    let chunks_left = Arc::new(Mutex::new(output.chunks().clone()));
+    let full_verify = crc_full_verify(archive, output.chunks().len(), crc_verify);
+    let config = archive.chunker_config().clone();
+
+    let seed_chunks_left = chunks_left.clone();
+    let strict_seeds = strict_seeds.cloned();
+    let seed_stream = futures_util::stream::iter(seeds)
+        .map(|seed| config.new_chunker(seed))
+        .flatten()
+        .take_while(move |_| future::ready(!seed_chunks_left.lock().unwrap().is_empty()))
+        .map(|r| spawn_blocking(move || r.map(|(_, chunk)| chunk.verify_with(&hasher))))
+        .buffered(max_buffered_chunks)
+        .map(|r| match r {
+            Ok(inner) => Ok(ResolvedChunk::Seed(inner?)),
+            Err(err) => Err(anyhow!(err)),
+        })
+        .filter(move |r| {
+            future::ready(match (r, &strict_seeds) {
+                (Ok(ResolvedChunk::Seed(verified)), Some(strict_seeds)) => {
+                    strict_seeds.accepts(verified)
+                }
+                _ => true,
+            })
+        });
+
+    // Chunks to fetch in archive order, with their index among the chunks to pick the ones to
+    // fully verify.
+    let hash_length = archive.chunk_hash_length();
+    let batches: Vec<Vec<(usize, HashSum, usize)>> = archive
+        .chunk_descriptors()
+        .iter()
+        .filter(|cd| output.chunks().contains(&cd.checksum))
+        .enumerate()
+        .map(|(index, cd)| (index, cd.checksum.clone(), cd.source_size as usize))
+        .collect::<Vec<_>>()
+        .chunks(max_buffered_chunks.max(1))
+        .map(<[_]>::to_vec)
+        .collect();
+    // Fetch the batches in order, sending each chunk on as soon as it arrives. The channel bounds
+    // how far fetching runs ahead of the output.
+    let (chunk_tx, mut chunk_rx) = tokio::sync::mpsc::channel(max_buffered_chunks.max(1));
+    let fetch_chunks_left = chunks_left.clone();
+    let fetch = async move {
+        for batch in batches {
+            let mut batch_index = ChunkIndex::new_empty(hash_length);
+            let mut indices = Vec::with_capacity(batch.len());
+            {
+                let chunks_left = fetch_chunks_left.lock().unwrap();
+                for (index, hash, size) in batch {
+                    if chunks_left.contains(&hash) {
+                        batch_index.add_chunk(hash, size, &[]);
+                        indices.push(index);
+                    }
+                }
+            }
+            if indices.is_empty() {
+                continue;
+            }
+            let mut chunk_stream = archive.chunk_stream(&batch_index);
+            for index in indices {
+                let result = match chunk_stream.next().await {
+                    Some(result) => result,
+                    None => break,
+                };
+                if chunk_tx.send((index, result)).await.is_err() {
+                    // Output is done, no more chunks are needed
+                    return;
+                }
+            }
+        }
+    };
+    let archive_stream = futures_util::stream::poll_fn(move |cx| chunk_rx.poll_recv(cx));
+    let archive_chunks_left = chunks_left.clone();
+    let archive_stream = archive_stream
+        .map(move |(index, r)| {
+            let full_verify = full_verify.get(index).copied().unwrap_or(true);
+            // Chunks already resolved from a seed are not decompressed
+            let resolved = matches!(&r, Ok(compressed)
+                if !archive_chunks_left.lock().unwrap().contains(compressed.expected_hash()));
+            let dictionaries = dictionaries.clone();
+            spawn_blocking(move || -> Result<ResolvedChunk> {
+                let compressed = r.context("read archive")?;
+                let fetched = compressed.len() as u64;
+                if resolved {
+                    return Ok(ResolvedChunk::Archive(fetched, None));
+                }
+                let verified = verify_archive_chunk(
+                    compressed,
+                    &dictionaries,
+                    &hasher,
+                    full_verify,
+                    retry_mismatch,
+                )?;
+                Ok(ResolvedChunk::Archive(fetched, verified))
+            })
+        })
+        .buffered(max_buffered_chunks)
+        .map(|r| match r {
+            Ok(inner) => inner,
+            Err(err) => Err(anyhow!(err)),
+        });
+
+    let resolve = async move {
+        let mut resolved_bytes = ResolvedBytes::default();
+        let mut resolved = futures_util::stream::select(seed_stream, archive_stream);
+        while let Some(result) = resolved.next().await {
+            let (verified, from_seed) = match result? {
+                ResolvedChunk::Seed(verified) => (verified, true),
+                ResolvedChunk::Archive(fetched, verified) => {
+                    resolved_bytes.fetched += fetched;
+                    match verified {
+                        Some(verified) => (verified, false),
+                        None => continue,
+                    }
+                }
+            };
+            let wc = output.feed(&verified).await? as u64;
+            chunks_left.lock().unwrap().remove(verified.hash());
+            if from_seed {
+                resolved_bytes.from_seeds += wc;
+            } else {
+                resolved_bytes.from_archive += wc;
+            }
+            if output.is_empty() {
+                break;
+            }
+        }
+        Ok(resolved_bytes)
+    };
+    // The fetch stops once the resolved chunks are done with, as the channel is then closed
+    let ((), resolved_bytes) = future::join(fetch, resolve).await;
+    resolved_bytes
 }
 
 async fn chunk_index_from_readable<R>(
     hash_length: usize,
     config: &chunker::Config,
+    hasher: ChunkHasher,
+    strict_seeds: Option<&SeedCrcs>,
     max_buffered_chunks: usize,
     readable: &mut R,
 ) -> Result<ChunkIndex>
@@ -145,27 +941,175 @@ where
 {
     let mut chunk_stream = config
         .new_chunker(readable)
-        .map(|r| spawn_blocking(|| r.map(|(offset, chunk)| (offset, chunk.verify()))))
+        .map(|r| {
+            spawn_blocking(move || r.map(|(offset, chunk)| (offset, chunk.verify_with(&hasher))))
+        })
         .buffered(max_buffered_chunks);
     let mut index = ChunkIndex::new_empty(hash_length);
     while let Some(r) = chunk_stream.next().await {
         let (chunk_offset, verified) = r??;
+        if matches!(strict_seeds, Some(strict_seeds) if !strict_seeds.accepts(&verified)) {
+            continue;
+        }
         let (hash, chunk) = verified.into_parts();
         index.add_chunk(hash, chunk.len(), &[chunk_offset]);
     }
     Ok(index)
 }
 
-async fn clone_archive<R>(opts: Options, reader: R) -> Result<()>
+// Temporary output which is removed on drop unless persisted.
+struct TempOutput {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempOutput {
+    // Temporary file next to the target, a rename is then atomic as it's on the same file system.
+    fn for_target(target: &Path) -> Result<Self> {
+        Self::with_suffix(target, ".tmp")
+    }
+    fn with_suffix(target: &Path, suffix: &str) -> Result<Self> {
+        let file_name = target
+            .file_name()
+            .ok_or_else(|| anyhow!("Output {} has no file name", target.display()))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(suffix);
+        Ok(Self {
+            path: target.with_file_name(temp_name),
+            persisted: false,
+        })
+    }
+    // Temporary file in dir, named after the target and the process to not clash with another
+    // clone using the same directory.
+    fn in_dir(dir: &Path, target: &Path, suffix: &str) -> Result<Self> {
+        let file_name = target
+            .file_name()
+            .ok_or_else(|| anyhow!("Output {} has no file name", target.display()))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(".{}", std::process::id()));
+        temp_name.push(suffix);
+        Ok(Self {
+            path: dir.join(temp_name),
+            persisted: false,
+        })
+    }
+    fn persist(mut self, target: &Path) -> Result<()> {
+        std::fs::rename(&self.path, target).context(format!(
+            "Failed to rename {} to {}",
+            self.path.display(),
+            target.display()
+        ))?;
+        self.persisted = true;
+        sync_parent_dir(target)
+    }
+}
+
+// Sync the directory holding the path, so that a rename into it survives a crash.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .context(format!("Failed to sync {}", dir.display()))
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+impl Drop for TempOutput {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Plan of how to rebuild the source from the archive. Lists every unique chunk in archive order
+// together with where to find it in the archive and all offsets in the source where it goes.
+fn rebuild_plan<R>(archive: &Archive<R>) -> serde_json::Value {
+    let source_index = archive.build_source_index();
+    let compression = archive.chunk_compression();
+    let chunks: Vec<serde_json::Value> = archive
+        .chunk_descriptors()
+        .iter()
+        .map(|descriptor| {
+            let source_offsets: Vec<u64> = source_index
+                .offsets(&descriptor.checksum)
+                .map(|offsets| offsets.collect())
+                .unwrap_or_default();
+            // Chunks which didn't shrink when compressed are stored uncompressed
+            let compression = match compression {
+                Some(compression) if descriptor.archive_size != descriptor.source_size as usize => {
+                    compression.to_string()
+                }
+                _ => "none".to_string(),
+            };
+            serde_json::json!({
+                "hash": descriptor.checksum.to_string(),
+                "archive_offset": descriptor.archive_offset,
+                "archive_size": descriptor.archive_size,
+                "compression": compression,
+                "source_size": descriptor.source_size,
+                "source_offsets": source_offsets,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "source_size": archive.total_source_size(),
+        "source_checksum": archive.source_checksum().to_string(),
+        "chunk_data_part_sizes": archive.chunk_data_part_sizes(),
+        "chunks": chunks,
+    })
+}
+
+fn print_plan<R>(archive: &Archive<R>, format: PlanFormat) -> Result<()> {
+    let plan = rebuild_plan(archive);
+    match format {
+        PlanFormat::Json => println!("{}", serde_json::to_string_pretty(&plan)?),
+        PlanFormat::Text => {
+            for chunk in plan["chunks"].as_array().into_iter().flatten() {
+                info!(
+                    "{} at archive offset {} ({} bytes, {}) -> {} bytes at source offsets {}",
+                    chunk["hash"].as_str().unwrap_or_default(),
+                    chunk["archive_offset"],
+                    chunk["archive_size"],
+                    chunk["compression"].as_str().unwrap_or_default(),
+                    chunk["source_size"],
+                    chunk["source_offsets"]
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn clone_archive<R>(opts: Options, mut archive: Archive<R>) -> Result<()>
 where
     R: ArchiveReader,
     R::Error: std::error::Error + Send + Sync + 'static,
 {
-    let mut archive = Archive::try_init(reader).await.context(format!(
-        "Failed to read archive at {}",
-        opts.input_archive.source()
-    ))?;
+    if let Some(format) = opts.plan_only {
+        // Nothing but the plan is printed, which may be parsed by another tool
+        if matches!(&opts.header_checksum, Some(expected) if expected != archive.header_checksum())
+        {
+            return Err(anyhow!("Header checksum mismatch"));
+        }
+        return print_plan(&archive, format);
+    }
     let clone_index = archive.build_source_index();
+    let dictionaries = Arc::new(opts.codec_dictionaries.clone());
+    let strict_seeds = if opts.strict_seeds {
+        Some(SeedCrcs::new(&archive)?)
+    } else {
+        None
+    };
     let mut total_read_from_seed = 0u64;
 
     info_cmd::print_archive(&archive);
@@ -179,21 +1123,57 @@ where
             info!("Header checksum verified OK");
         }
     }
+    if *archive.source_entry() != SourceEntry::File {
+        return clone_link(archive.source_entry(), &opts.output, opts.force_create);
+    }
     info!(
         "Cloning archive {} to {}...",
         opts.input_archive.source(),
         opts.output.display()
     );
 
-    // Create or open output file
+    // Chunks are written at their offset in the output, which requires seeking. Check
+    // before opening since opening a FIFO for writing blocks until there is a reader.
+    if is_non_seekable(&opts.output).await {
+        return Err(anyhow!(
+            "Output {} is not seekable (FIFO, pipe or socket), clone to a regular file or block device instead",
+            opts.output.display()
+        ));
+    }
+
+    // Create or open output file. With atomic output a temporary file is written and then
+    // renamed to the output path.
+    let temp_output = if opts.atomic {
+        if opts.seed_output {
+            return Err(anyhow!(
+                "Atomic output can't be combined with seeding from output"
+            ));
+        }
+        if let Ok(mut existing) = File::open(&opts.output).await {
+            if is_block_dev(&mut existing).await? {
+                return Err(anyhow!("Atomic output is not supported for block devices"));
+            }
+            if !opts.force_create {
+                return Err(anyhow!("Output {} already exists", opts.output.display()));
+            }
+        }
+        Some(TempOutput::for_target(&opts.output)?)
+    } else {
+        None
+    };
+    let output_path = temp_output
+        .as_ref()
+        .map(|temp| temp.path.as_path())
+        .unwrap_or(&opts.output);
     let mut output_file = tokio::fs::OpenOptions::new()
         .write(true)
         .read(opts.verify_output || opts.seed_output)
-        .create(opts.force_create || opts.seed_output)
-        .create_new(!opts.force_create && !opts.seed_output)
-        .open(&opts.output)
+        .create(opts.force_create || opts.seed_output || opts.atomic)
+        .truncate(opts.atomic)
+        .create_new(!opts.force_create && !opts.seed_output && !opts.atomic)
+        .open(output_path)
         .await
-        .context(format!("Failed to open {}", opts.output.display()))?;
+        .context(format!("Failed to open {}", output_path.display()))?;
 
     // Check if the given output file is a regular file or block device.
     // If it is a block device we should check its size against the target size before
@@ -201,12 +1181,14 @@ where
     let output_is_block_dev = is_block_dev(&mut output_file).await?;
     if output_is_block_dev {
         let size = file_size(&mut output_file).await?;
-        if size < archive.total_source_size() {
-            return Err(anyhow!(
-                "Size of output device ({}) is less than archive target file ({})",
-                human_size!(size),
-                human_size!(archive.total_source_size())
-            ));
+        check_device_size(size, archive.total_source_size(), opts.allow_size_mismatch)?;
+    } else if opts.contiguous {
+        if let Err(err) = allocate_contiguous(&output_file, archive.total_source_size()) {
+            warn!(
+                "Failed to allocate {} up front, writing it as usual: {}",
+                output_path.display(),
+                err
+            );
         }
     }
 
@@ -217,6 +1199,8 @@ where
             chunk_index_from_readable(
                 archive.chunk_hash_length(),
                 archive.chunker_config(),
+                opts.chunk_hasher,
+                strict_seeds.as_ref(),
                 opts.num_chunk_buffers,
                 &mut output_file,
             )
@@ -226,7 +1210,21 @@ where
         None
     };
 
-    let mut output = CloneOutput::new(output_file, clone_index);
+    let progress = Progress::new(
+        opts.progress_format,
+        "clone",
+        Some(archive.total_source_size()),
+    );
+    let output_writer = ProgressWriter::new(output_file, progress);
+    let output_writer = match opts.write_buffer {
+        Some(capacity) => BufWriter::with_capacity(capacity, output_writer),
+        None => BufWriter::new(output_writer),
+    };
+    let mut output =
+        CloneOutput::new(output_writer, clone_index).size_limit(archive.total_source_size());
+    if let Some(max_buffered) = opts.ordered_write_buffer {
+        output = output.ordered_writes(max_buffered);
+    }
     if let Some(output_index) = output_index {
         info!("Re-ordering chunks of {}...", opts.output.display());
         let used_from_self = output
@@ -247,10 +1245,15 @@ where
             "Scanning stdin for chunks ({} left to find)...",
             output.len()
         );
-        let bytes_to_output = clone_from_readable(
+        let bytes_to_output = clone_from_seed_stream(
             opts.num_chunk_buffers,
             archive.chunker_config(),
-            &mut tokio::io::stdin(),
+            opts.chunk_hasher,
+            strict_seeds.as_ref(),
+            tokio::io::stdin(),
+            opts.stdin_seed_checksum.as_ref(),
+            &std::env::temp_dir(),
+            &opts.output,
             &mut output,
         )
         .await
@@ -258,19 +1261,20 @@ where
         info!("Used {} bytes from stdin", human_size!(bytes_to_output));
         total_read_from_seed += bytes_to_output;
     }
-    for seed_path in &opts.seed_files {
-        let file = File::open(seed_path)
-            .await
-            .context(format!("Failed to open seed file {}", seed_path.display()))?;
+    for seed_path in &opts.seed_archives {
         info!(
-            "Scanning {} for chunks ({} left to find)...",
+            "Scanning archive {} for chunks ({} left to find)...",
             seed_path.display(),
             output.len()
         );
-        let bytes_to_output = clone_from_readable(
+        let bytes_to_output = clone_from_seed_archive(
             opts.num_chunk_buffers,
-            archive.chunker_config(),
-            file,
+            opts.chunk_hasher,
+            strict_seeds.as_ref(),
+            &dictionaries,
+            seed_path,
+            &archive,
+            &opts.output,
             &mut output,
         )
         .await
@@ -282,23 +1286,124 @@ where
         );
         total_read_from_seed += bytes_to_output;
     }
-
-    // Read the rest from archive
-    info!(
-        "Fetching {} chunks from {}...",
-        output.len(),
-        opts.input_archive.source()
-    );
-
-    let total_read_from_remote =
-        clone_from_archive(opts.num_chunk_buffers, &mut archive, &mut output)
+    let total_read_from_remote = if opts.concurrent_seeds && !opts.seed_files.is_empty() {
+        let mut seeds = Vec::with_capacity(opts.seed_files.len());
+        for seed_path in &opts.seed_files {
+            seeds.push(open_seed_file(seed_path, opts.seed_scan_limit).await?);
+        }
+        info!(
+            "Scanning {} seed files while fetching {} chunks from {}...",
+            seeds.len(),
+            output.len(),
+            opts.input_archive.source()
+        );
+        let resolved = clone_concurrently(
+            opts.num_chunk_buffers,
+            opts.chunk_hasher,
+            opts.crc_verify,
+            opts.chunk_retries > 0,
+            strict_seeds.as_ref(),
+            &dictionaries,
+            seeds,
+            &mut archive,
+            &mut output,
+        )
+        .await
+        .context(format!(
+            "Failed to clone from seeds and archive at {}",
+            opts.input_archive.source()
+        ))?;
+        info!(
+            "Used {} from seed files, fetched {} from archive and decompressed to {}.",
+            human_size!(resolved.from_seeds),
+            human_size!(resolved.fetched),
+            human_size!(resolved.from_archive)
+        );
+        total_read_from_seed += resolved.from_seeds;
+        let mut fetched = resolved.fetched;
+        if !output.is_empty() {
+            // Chunks which failed verification
+            archive.bypass_cache();
+            fetched += clone_from_archive(
+                opts.num_chunk_buffers,
+                opts.prefetch_chunks,
+                opts.chunk_hasher,
+                opts.crc_verify,
+                opts.chunk_retries.saturating_sub(1),
+                &dictionaries,
+                &mut archive,
+                &mut output,
+            )
             .await
             .context(format!(
                 "Failed to clone from archive at {}",
                 opts.input_archive.source()
             ))?;
+        }
+        fetched
+    } else {
+        for seed_path in &opts.seed_files {
+            let file = open_seed_file(seed_path, opts.seed_scan_limit).await?;
+            info!(
+                "Scanning {} for chunks ({} left to find)...",
+                seed_path.display(),
+                output.len()
+            );
+            let bytes_to_output = clone_from_readable(
+                opts.num_chunk_buffers,
+                archive.chunker_config(),
+                opts.chunk_hasher,
+                strict_seeds.as_ref(),
+                file,
+                &mut output,
+            )
+            .await
+            .context(format!("Failed to clone from {}", seed_path.display()))?;
+            info!(
+                "Used {} bytes from {}",
+                human_size!(bytes_to_output),
+                seed_path.display()
+            );
+            total_read_from_seed += bytes_to_output;
+        }
+
+        // Read the rest from archive
+        info!(
+            "Fetching {} chunks from {}...",
+            output.len(),
+            opts.input_archive.source()
+        );
+
+        clone_from_archive(
+            opts.num_chunk_buffers,
+            opts.prefetch_chunks,
+            opts.chunk_hasher,
+            opts.crc_verify,
+            opts.chunk_retries,
+            &dictionaries,
+            &mut archive,
+            &mut output,
+        )
+        .await
+        .context(format!(
+            "Failed to clone from archive at {}",
+            opts.input_archive.source()
+        ))?
+    };
 
-    let mut output_file = output.into_inner();
+    output
+        .flush()
+        .await
+        .context(format!("Failed to write to {}", opts.output.display()))?;
+    let mut output_writer = output
+        .try_into_inner()
+        .context(format!("Failed to write to {}", opts.output.display()))?;
+    output_writer
+        .flush()
+        .await
+        .context(format!("Failed to write to {}", opts.output.display()))?;
+    let (mut output_file, mut progress) = output_writer.into_inner().into_inner();
+    progress.finish();
     if !output_is_block_dev {
         // Resize output file to same size as the archive source
         output_file
@@ -309,10 +1414,12 @@ where
 
     if opts.verify_output {
         info!("Verifying checksum of {}...", opts.output.display());
-        let sum = file_checksum(&mut output_file).await.context(format!(
-            "Failed to create checksum of {}",
-            opts.output.display()
-        ))?;
+        let sum = file_checksum(&mut output_file, archive.total_source_size())
+            .await
+            .context(format!(
+                "Failed to create checksum of {}",
+                opts.output.display()
+            ))?;
         let expected_checksum = archive.source_checksum();
         if sum == *expected_checksum {
             info!("Checksum verified Ok");
@@ -327,6 +1434,22 @@ where
         }
     }
 
+    match output_sync(&opts) {
+        OutputSync::None => {}
+        OutputSync::Data => output_file
+            .sync_data()
+            .await
+            .context(format!("Failed to sync {}", opts.output.display()))?,
+        OutputSync::All => output_file
+            .sync_all()
+            .await
+            .context(format!("Failed to sync {}", output_path.display()))?,
+    }
+    if let Some(temp_output) = temp_output {
+        drop(output_file);
+        temp_output.persist(&opts.output)?;
+    }
+
     info!(
         "Successfully cloned archive using {} from archive and {} from seeds.",
         human_size!(total_read_from_remote),
@@ -336,25 +1459,59 @@ where
     Ok(())
 }
 
+// How to get the output to stable storage once written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputSync {
+    None,
+    // Only the data and the metadata needed to read it back, like the file size
+    Data,
+    // Everything, before the temporary output of an atomic clone replaces the target
+    All,
+}
+
+fn output_sync(opts: &Options) -> OutputSync {
+    if opts.atomic {
+        // Renaming a file which isn't on disk yet could leave an empty target on power loss
+        OutputSync::All
+    } else if opts.sync {
+        OutputSync::Data
+    } else {
+        OutputSync::None
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RemoteInput {
     pub url: Url,
     pub retries: u32,
     pub retry_delay: Duration,
+    pub retry_jitter: RetryJitter,
     pub receive_timeout: Option<Duration>,
     pub headers: HeaderMap,
+    pub http2_prior_knowledge: bool,
+    pub full_download_limit: u64,
+}
+
+/// Format of the rebuild plan printed instead of cloning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    Text,
+    Json,
 }
 
 #[derive(Debug, Clone)]
 pub enum InputArchive {
     Local(std::path::PathBuf),
     Remote(Box<RemoteInput>),
+    // Archive at an sftp:// URL
+    Sftp(Url),
 }
 impl InputArchive {
     fn source(&self) -> String {
         match self {
             Self::Local(p) => format!("{}", p.display()),
             Self::Remote(input) => input.url.to_string(),
+            Self::Sftp(url) => url.to_string(),
         }
     }
 }
@@ -366,39 +1523,1929 @@ pub struct Options {
     pub header_checksum: Option<HashSum>,
     pub output: PathBuf,
     pub seed_stdin: bool,
+    pub stdin_seed_checksum: Option<HashSum>,
     pub seed_files: Vec<PathBuf>,
+    // Scan the seed files while fetching from the archive instead of before
+    pub concurrent_seeds: bool,
+    // Only scan up to this many bytes from the start of each seed file
+    pub seed_scan_limit: Option<u64>,
+    // Local archives to use as seed
+    pub seed_archives: Vec<PathBuf>,
     pub seed_output: bool,
     pub verify_output: bool,
+    pub atomic: bool,
     pub num_chunk_buffers: usize,
+    // Number of chunks to fetch ahead of decompressing and writing them, if any
+    pub prefetch_chunks: Option<usize>,
+    pub ordered_write_buffer: Option<usize>,
+    // Capacity of the buffer in front of the output, the default if not set
+    pub write_buffer: Option<usize>,
+    // Screen chunks using their CRC and only verify the hash of this percent of them
+    pub crc_verify: Option<f64>,
+    pub chunk_hasher: ChunkHasher,
+    // Dictionaries given to custom codecs when decompressing chunks
+    pub codec_dictionaries: CodecDictionaries,
+    // Print the plan of how to rebuild the source instead of cloning
+    pub plan_only: Option<PlanFormat>,
+    // Number of times to fetch a chunk again which failed verification
+    pub chunk_retries: u32,
+    pub progress_format: ProgressFormat,
+    // Only use seed chunks which also match the CRC of the archive chunk
+    pub strict_seeds: bool,
+    // Location of the detached chunk data, instead of the part next to the archive
+    pub chunk_data: Option<String>,
+    // Location of a chunk store to read every chunk from, instead of the archive
+    pub chunk_store: Option<String>,
+    // Archive to read every range missing from the local input archive from
+    pub fallback: Option<Box<RemoteInput>>,
+    // Write the ranges read from the fallback into the local input archive
+    pub cache_fallback: bool,
+    // Key to decrypt the dictionary of the archive with
+    pub dictionary_key: Option<DictionaryKey>,
+    // Write to an output device larger than the source, leaving the rest of it as is
+    pub allow_size_mismatch: bool,
+    // Make sure the output is on stable storage before returning
+    pub sync: bool,
+    // Allocate the whole output file before writing to it, for contiguous extents
+    pub contiguous: bool,
+}
+
+// A single client is used for all requests to the remote. Connections are pooled and with
+// HTTP/2 (negotiated using ALPN over https) concurrent requests share one connection.
+fn http_client(input: &RemoteInput) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if input.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    builder.build().context("Failed to create http client")
+}
+
+async fn init_archive<R>(opts: &Options, reader: R) -> Result<Archive<R>>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    info_cmd::init_archive(reader, opts.dictionary_key.as_ref())
+        .await
+        .context(format!(
+            "Failed to read archive at {}",
+            opts.input_archive.source()
+        ))
+}
+
+fn remote_reader(input: &RemoteInput, client: &reqwest::Client, url: Url) -> HttpReader {
+    let mut request = client.get(url).headers(input.headers.clone());
+    if let Some(timeout) = input.receive_timeout {
+        request = request.timeout(timeout);
+    }
+    HttpReader::from_request(request)
+        .retries(input.retries)
+        .retry_delay(input.retry_delay)
+        .retry_jitter(input.retry_jitter)
+        .full_download_limit(input.full_download_limit)
+}
+
+/// Url of a part file of a split remote archive.
+pub fn part_url(archive: &Url, index: usize) -> Url {
+    let mut url = archive.clone();
+    url.set_path(&format!("{}.part{}", archive.path(), index));
+    url
+}
+
+// Detached chunk data is a single part which may be read from elsewhere than next to the archive.
+fn detached_chunk_data(opts: &Options, num_parts: usize) -> Result<Option<&str>> {
+    match &opts.chunk_data {
+        Some(_) if num_parts != 1 => bail!(
+            "Archive at {} has no detached chunk data",
+            opts.input_archive.source()
+        ),
+        chunk_data => Ok(chunk_data.as_deref()),
+    }
+}
+
+// Chunk store in a local directory.
+struct LocalChunkStore(PathBuf);
+
+#[async_trait::async_trait]
+impl ChunkStore for LocalChunkStore {
+    type Error = std::io::Error;
+
+    async fn read_object(&self, checksum: &HashSum, _size: usize) -> Result<Bytes, Self::Error> {
+        Ok(tokio::fs::read(self.0.join(chunk_store_path(checksum)))
+            .await?
+            .into())
+    }
+}
+
+// Chunk store at a remote URL, every object fetched using its own request.
+struct RemoteChunkStore {
+    input: RemoteInput,
+    client: reqwest::Client,
+    url: Url,
+}
+
+#[async_trait::async_trait]
+impl ChunkStore for RemoteChunkStore {
+    type Error = HttpReaderError;
+
+    async fn read_object(&self, checksum: &HashSum, size: usize) -> Result<Bytes, Self::Error> {
+        let mut url = self.url.clone();
+        url.set_path(&format!(
+            "{}{}",
+            self.url.path(),
+            chunk_store_path(checksum)
+        ));
+        remote_reader(&self.input, &self.client, url)
+            .read_at(0, size)
+            .await
+    }
+}
+
+// Chunk store of casync in a local directory. Chunks are returned decompressed since their
+// compression isn't given by the index.
+struct CasyncChunkStore(PathBuf);
+
+#[async_trait::async_trait]
+impl ChunkStore for CasyncChunkStore {
+    type Error = std::io::Error;
+
+    async fn read_object(&self, checksum: &HashSum, size: usize) -> Result<Bytes, Self::Error> {
+        let object = tokio::fs::read(self.0.join(casync::chunk_path(checksum))).await?;
+        spawn_blocking(move || casync::decompress_chunk(object.into(), size))
+            .await
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+}
+
+fn is_casync_index(path: &Path) -> bool {
+    matches!(path.extension(), Some(extension) if extension == "caibx")
+}
+
+// Clone from a casync blob index, read as an archive of uncompressed chunks hashed by their
+// casync chunk id.
+async fn clone_casync(mut opts: Options, path: &Path) -> Result<()> {
+    let store = opts.chunk_store.clone().ok_or_else(|| {
+        anyhow!("Cloning a casync index needs its chunk store, given using --chunk-store")
+    })?;
+    if opts.verify_output {
+        bail!("A casync index has no checksum of the source to verify the output with");
+    }
+    let index = casync::Index::from_bytes(
+        &tokio::fs::read(path)
+            .await
+            .context(format!("Failed to read {}", path.display()))?,
+    )
+    .context(format!("Failed to read casync index {}", path.display()))?;
+    opts.chunk_hasher = index.chunk_hasher();
+    let header = index.to_header()?;
+    let archive = init_archive(&opts, IoReader::new(std::io::Cursor::new(header))).await?;
+    let store = CasyncChunkStore(PathBuf::from(store));
+    clone_archive(opts, archive.with_chunk_store(store)).await
+}
+
+// Clone a local archive, e.g. an incomplete mirror, reading every range missing from it from the
+// same archive at the fallback URL. A local range which is there but corrupt fails verification
+// and is read from the fallback when fetched again. With cache_fallback the ranges read from the
+// fallback are written into the local archive, filling it in for next time.
+async fn clone_with_fallback(opts: Options, path: &Path, fallback: &RemoteInput) -> Result<()> {
+    if is_casync_index(path) {
+        bail!("Cloning a casync index with a fallback is not supported");
+    }
+    let local = tokio::fs::OpenOptions::new()
+        .read(true)
+        .write(opts.cache_fallback)
+        .create(opts.cache_fallback)
+        .open(path)
+        .await
+        .context(format!("Failed to open {}", path.display()))?;
+    let client = http_client(fallback)?;
+    let remote = remote_reader(fallback, &client, fallback.url.clone());
+    let mut reader = FallbackReader::new(IoReader::new(local.try_clone().await?), remote);
+    if opts.cache_fallback {
+        reader = reader.cache(local);
+    }
+    let archive = init_archive(&opts, reader).await?;
+    if !archive.chunk_data_part_sizes().is_empty() {
+        bail!("Cloning a split archive with a fallback is not supported");
+    }
+    clone_archive(opts, archive).await
 }
 
 pub async fn clone_cmd(opts: Options) -> Result<()> {
     match opts.input_archive.clone() {
+        InputArchive::Local(path) if opts.fallback.is_some() => {
+            let fallback = opts.fallback.clone().unwrap();
+            clone_with_fallback(opts, &path, &fallback).await
+        }
+        InputArchive::Local(path) if is_casync_index(&path) => clone_casync(opts, &path).await,
         InputArchive::Local(path) => {
-            clone_archive(
-                opts,
-                IoReader::new(
-                    File::open(&path)
-                        .await
-                        .context(format!("Failed to open {}", path.display()))?,
-                ),
+            let archive = init_archive(&opts, LocalFile::open_archive(&path).await?).await?;
+            if let Some(store) = &opts.chunk_store {
+                let store = LocalChunkStore(PathBuf::from(store));
+                return clone_archive(opts, archive.with_chunk_store(store)).await;
+            }
+            let num_parts = archive.chunk_data_part_sizes().len();
+            if let Some(chunk_data) = detached_chunk_data(&opts, num_parts)? {
+                let part = LocalFile::open_part(Path::new(chunk_data)).await?;
+                return clone_archive(opts, archive.with_part_readers(vec![part])?).await;
+            }
+            if num_parts == 0 {
+                return clone_archive(opts, archive).await;
+            }
+            let parts = LocalFile::open_parts(&path, num_parts).await?;
+            clone_archive(opts, archive.with_part_readers(parts)?).await
+        }
+        InputArchive::Remote(input) => {
+            if is_casync_index(Path::new(input.url.path())) {
+                bail!("Cloning a casync index is only supported from a local file");
+            }
+            let client = http_client(&input)?;
+            let archive =
+                init_archive(&opts, remote_reader(&input, &client, input.url.clone())).await?;
+            if let Some(store) = &opts.chunk_store {
+                // The store is a directory, objects are relative to it.
+                let store = format!("{}/", store.trim_end_matches('/'));
+                let url = input
+                    .url
+                    .join(&store)
+                    .context(format!("Invalid chunk store URL {}", store))?;
+                let store = RemoteChunkStore {
+                    input: (*input).clone(),
+                    client: client.clone(),
+                    url,
+                };
+                return clone_archive(opts, archive.with_chunk_store(store)).await;
+            }
+            let num_parts = archive.chunk_data_part_sizes().len();
+            if let Some(chunk_data) = detached_chunk_data(&opts, num_parts)? {
+                let url = input
+                    .url
+                    .join(chunk_data)
+                    .context(format!("Invalid chunk data URL {}", chunk_data))?;
+                let part = remote_reader(&input, &client, url);
+                return clone_archive(opts, archive.with_part_readers(vec![part])?).await;
+            }
+            if num_parts == 0 {
+                return clone_archive(opts, archive).await;
+            }
+            let parts = (0..num_parts)
+                .map(|index| remote_reader(&input, &client, part_url(&input.url, index)))
+                .collect();
+            clone_archive(opts, archive.with_part_readers(parts)?).await
+        }
+        InputArchive::Sftp(url) => clone_sftp(opts, url).await,
+    }
+}
+
+#[cfg(feature = "sftp")]
+async fn clone_sftp(opts: Options, url: Url) -> Result<()> {
+    let reader = bitar::archive_reader::SftpReader::from_url(url.clone())
+        .await
+        .context(format!("Failed to open {}", url))?;
+    let archive = init_archive(&opts, reader).await?;
+    if opts.chunk_store.is_some()
+        || opts.chunk_data.is_some()
+        || !archive.chunk_data_part_sizes().is_empty()
+    {
+        bail!("Cloning over sftp is only supported for archives holding their chunk data");
+    }
+    clone_archive(opts, archive).await
+}
+
+#[cfg(not(feature = "sftp"))]
+async fn clone_sftp(_opts: Options, url: Url) -> Result<()> {
+    bail!(
+        "Unable to clone {}, bita was built without the sftp feature",
+        url
+    )
+}
+
+// Error of a clone cancelled before it was done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::error::Error for Cancelled {}
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Clone was cancelled")
+    }
+}
+
+// Clone until done or until cancel resolves. On cancel the clone is dropped, which aborts any
+// in-flight fetches and removes the temporary output of an atomic clone. Anything written to
+// the output of a non-atomic clone is left as is, to be used with --seed-output next time.
+pub async fn clone_cmd_cancellable<F>(opts: Options, cancel: F) -> Result<()>
+where
+    F: Future<Output = ()>,
+{
+    tokio::select! {
+        result = clone_cmd(opts) => result,
+        _ = cancel => Err(Cancelled.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_cmd::tests::{random_data, test_options};
+    use crate::export_cmd;
+    use bitar::ChunkCodec;
+    use std::io::Cursor;
+
+    async fn clone_from_stdin_seed(
+        seed: &[u8],
+        expected_checksum: &HashSum,
+        output_buf: &mut Vec<u8>,
+    ) -> Result<u64> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output_dir = tempfile::tempdir().unwrap();
+        let mut chunk_index = ChunkIndex::new_empty(HashSum::MAX_LEN);
+        chunk_index.add_chunk(ChunkHasher::default().digest(&seed[..1024]), 1024, &[0]);
+        let mut output = CloneOutput::new(Cursor::new(output_buf), chunk_index);
+        let result = clone_from_seed_stream(
+            2,
+            &chunker::Config::FixedSize(1024),
+            ChunkHasher::default(),
+            None,
+            seed,
+            Some(expected_checksum),
+            temp_dir.path(),
+            &output_dir.path().join("output"),
+            &mut output,
+        )
+        .await;
+        // The seed is spooled to the temporary directory only, and is removed either way
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(output_dir.path()).unwrap().count(), 0);
+        result
+    }
+
+    fn local_clone_options(archive: &str, output: &Path) -> Options {
+        Options {
+            force_create: true,
+            input_archive: InputArchive::Local(PathBuf::from(archive)),
+            header_checksum: None,
+            output: output.to_path_buf(),
+            seed_stdin: false,
+            stdin_seed_checksum: None,
+            seed_files: vec![],
+            concurrent_seeds: false,
+            seed_scan_limit: None,
+            seed_archives: vec![],
+            seed_output: false,
+            verify_output: false,
+            atomic: true,
+            num_chunk_buffers: 2,
+            prefetch_chunks: None,
+            ordered_write_buffer: None,
+            write_buffer: None,
+            crc_verify: None,
+            chunk_hasher: ChunkHasher::default(),
+            codec_dictionaries: CodecDictionaries::default(),
+            plan_only: None,
+            progress_format: ProgressFormat::Plain,
+            chunk_retries: 0,
+            strict_seeds: false,
+            chunk_data: None,
+            chunk_store: None,
+            fallback: None,
+            cache_fallback: false,
+            dictionary_key: None,
+            allow_size_mismatch: false,
+            sync: true,
+            contiguous: false,
+        }
+    }
+
+    #[test]
+    fn device_size_mismatch() {
+        let err = check_device_size(1000, 4096, true).unwrap_err().to_string();
+        assert!(err.contains("1000 bytes"), "{}", err);
+        assert!(err.contains("4.0 KiB (4096 bytes)"), "{}", err);
+        let err = check_device_size(8192, 4096, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("--allow-size-mismatch"), "{}", err);
+        check_device_size(8192, 4096, true).unwrap();
+        check_device_size(4096, 4096, false).unwrap();
+    }
+
+    #[tokio::test]
+    async fn atomic_clone_replaces_target() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("image");
+        std::fs::write(&output, b"original").unwrap();
+        clone_cmd(local_clone_options(
+            "bitar/tests/resources/zero-0_7_1-brotli.cba",
+            &output,
+        ))
+        .await
+        .unwrap();
+        let cloned = std::fs::read(&output).unwrap();
+        assert!(cloned.len() > 8 && cloned.iter().all(|&b| b == 0));
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn synced_clone_is_complete() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("image");
+        let mut opts = local_clone_options("bitar/tests/resources/zero-0_7_1-brotli.cba", &output);
+        opts.atomic = false;
+        assert_eq!(output_sync(&opts), OutputSync::Data);
+        clone_cmd(opts).await.unwrap();
+        let source_size = Archive::try_init(
+            LocalFile::open_archive("bitar/tests/resources/zero-0_7_1-brotli.cba")
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .total_source_size();
+        let cloned = std::fs::read(&output).unwrap();
+        assert_eq!(cloned.len() as u64, source_size);
+        assert!(cloned.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn no_sync_skips_sync() {
+        let mut opts = local_clone_options("archive.cba", Path::new("image"));
+        opts.atomic = false;
+        opts.sync = false;
+        assert_eq!(output_sync(&opts), OutputSync::None);
+        // The temporary output of an atomic clone is always synced before the rename
+        opts.atomic = true;
+        assert_eq!(output_sync(&opts), OutputSync::All);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn contiguous_output_is_allocated_up_front() {
+        use std::os::unix::fs::MetadataExt;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = random_data(4 * 1024 * 1024, 0);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("archive.cba");
+        // Chunks stored by hash are written all over the output
+        let mut compress_opts = compress_options(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(64 * 1024),
+        );
+        compress_opts.chunk_order = compress_cmd::ChunkOrder::Hash;
+        compress_cmd::compress_cmd(compress_opts).await.unwrap();
+
+        let output = temp_dir.path().join("output");
+        let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+        opts.contiguous = true;
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+
+        // The blocks are allocated before anything is written
+        let file = File::create(temp_dir.path().join("allocated"))
+            .await
+            .unwrap();
+        if allocate_contiguous(&file, 4 * 1024 * 1024).is_ok() {
+            let metadata = file.metadata().await.unwrap();
+            assert_eq!(metadata.len(), 4 * 1024 * 1024);
+            assert!(metadata.blocks() * 512 >= 4 * 1024 * 1024);
+        }
+    }
+
+    #[tokio::test]
+    async fn interrupted_atomic_clone_keeps_target() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("image");
+        std::fs::write(&output, b"original").unwrap();
+        let err = clone_cmd(local_clone_options(
+            "bitar/tests/resources/rand-0_7_1-corrupt-chunk.cba",
+            &output,
+        ))
+        .await
+        .unwrap_err();
+        assert!(
+            err.downcast_ref::<bitar::HashSumMismatchError>().is_some(),
+            "{:#}",
+            err
+        );
+        assert_eq!(std::fs::read(&output).unwrap(), b"original");
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn atomic_clone_rejects_seed_output() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("image");
+        let mut opts = local_clone_options("bitar/tests/resources/zero-0_7_1-brotli.cba", &output);
+        opts.seed_output = true;
+        let err = clone_cmd(opts).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Atomic output can't be combined with seeding from output"));
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn http2_concurrent_reads_share_connection() {
+        use hyper::service::{make_service_fn, service_fn};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let server_connections = connections.clone();
+        let server = hyper::Server::from_tcp(listener)
+            .unwrap()
+            .http2_only(true)
+            .serve(make_service_fn(move |_conn| {
+                server_connections.fetch_add(1, Ordering::SeqCst);
+                async {
+                    Ok::<_, std::convert::Infallible>(service_fn(|_req| async {
+                        let mut response = hyper::Response::new(hyper::Body::from(vec![0u8; 10]));
+                        *response.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+                        Ok::<_, hyper::Error>(response)
+                    }))
+                }
+            }));
+        let input = RemoteInput {
+            url: Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap(),
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            retry_jitter: RetryJitter::None,
+            receive_timeout: None,
+            headers: HeaderMap::new(),
+            http2_prior_knowledge: true,
+            full_download_limit: HttpReader::DEFAULT_FULL_DOWNLOAD_LIMIT,
+        };
+        let client = http_client(&input).unwrap();
+        let mut readers: Vec<HttpReader> = (0..4)
+            .map(|_| HttpReader::from_request(client.get(input.url.clone())))
+            .collect();
+        let reads =
+            futures_util::future::join_all(readers.iter_mut().map(|reader| reader.read_at(0, 10)));
+        tokio::select! {
+            _ = server => panic!("server ended"),
+            results = reads => {
+                for result in results {
+                    assert_eq!(result.unwrap().len(), 10);
+                }
+            }
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    // Serve the files of a directory, responding to range requests only.
+    async fn serve_dir_ranges(listener: std::net::TcpListener, dir: PathBuf) {
+        use hyper::service::{make_service_fn, service_fn};
+        hyper::Server::from_tcp(listener)
+            .unwrap()
+            .serve(make_service_fn(move |_conn| {
+                let dir = dir.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                        let data = std::fs::read(dir.join(&req.uri().path()[1..])).unwrap();
+                        let range = req.headers()[hyper::header::RANGE].to_str().unwrap();
+                        let mut bounds = range["bytes=".len()..].splitn(2, '-');
+                        let (start, end): (usize, usize) = (
+                            bounds.next().unwrap().parse().unwrap(),
+                            bounds.next().unwrap().parse().unwrap(),
+                        );
+                        let mut response =
+                            hyper::Response::new(hyper::Body::from(data[start..=end].to_vec()));
+                        *response.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+                        async { Ok::<_, hyper::Error>(response) }
+                    }))
+                }
+            }))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn clone_split_archive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = random_data(64 * 1024, 0);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("split.cba");
+        let mut opts = test_options(
+            vec![source_path],
+            compress_cmd::Output::File(archive_path.clone()),
+        );
+        opts.split_size = Some(40 * 1024);
+        compress_cmd::compress_cmd(opts).await.unwrap();
+        assert!(compress_cmd::part_path(&archive_path, 1).exists());
+        assert!(!compress_cmd::part_path(&archive_path, 2).exists());
+
+        let output = temp_dir.path().join("local");
+        clone_cmd(local_clone_options(archive_path.to_str().unwrap(), &output))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let output = temp_dir.path().join("remote");
+        let mut opts = local_clone_options("", &output);
+        opts.input_archive = InputArchive::Remote(Box::new(RemoteInput {
+            url: Url::parse(&format!("http://127.0.0.1:{}/split.cba", port)).unwrap(),
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            retry_jitter: RetryJitter::None,
+            receive_timeout: None,
+            headers: HeaderMap::new(),
+            http2_prior_knowledge: false,
+            full_download_limit: HttpReader::DEFAULT_FULL_DOWNLOAD_LIMIT,
+        }));
+        tokio::select! {
+            _ = serve_dir_ranges(listener, temp_dir.path().to_path_buf()) => panic!("server ended"),
+            result = clone_cmd(opts) => result.unwrap(),
+        }
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+    }
+
+    // Serve ranges of data, but never respond to a range starting at stall_from or later.
+    async fn serve_stalling(
+        listener: std::net::TcpListener,
+        data: Vec<u8>,
+        stall_from: usize,
+        stalled: Arc<tokio::sync::Notify>,
+    ) {
+        use hyper::service::{make_service_fn, service_fn};
+        hyper::Server::from_tcp(listener)
+            .unwrap()
+            .serve(make_service_fn(move |_conn| {
+                let data = data.clone();
+                let stalled = stalled.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                        let range = req.headers()[hyper::header::RANGE].to_str().unwrap();
+                        let mut bounds = range["bytes=".len()..].splitn(2, '-');
+                        let (start, end): (usize, usize) = (
+                            bounds.next().unwrap().parse().unwrap(),
+                            bounds.next().unwrap().parse().unwrap(),
+                        );
+                        let data = data[start..=end.min(data.len() - 1)].to_vec();
+                        let stalled = stalled.clone();
+                        async move {
+                            if start >= stall_from {
+                                stalled.notify_one();
+                                future::pending::<()>().await;
+                            }
+                            let mut response = hyper::Response::new(hyper::Body::from(data));
+                            *response.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+                            Ok::<_, hyper::Error>(response)
+                        }
+                    }))
+                }
+            }))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelled_remote_clone_removes_temp_output() {
+        let archive_path = "bitar/tests/resources/zero-0_7_1-brotli.cba";
+        let chunk_data_offset =
+            Archive::try_init(LocalFile::open_archive(archive_path).await.unwrap())
+                .await
+                .unwrap()
+                .chunk_data_offset();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("image");
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut opts = local_clone_options("", &output);
+        opts.input_archive = InputArchive::Remote(Box::new(RemoteInput {
+            url: Url::parse(&format!("http://127.0.0.1:{}/archive.cba", port)).unwrap(),
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            retry_jitter: RetryJitter::None,
+            receive_timeout: None,
+            headers: HeaderMap::new(),
+            http2_prior_knowledge: false,
+            full_download_limit: HttpReader::DEFAULT_FULL_DOWNLOAD_LIMIT,
+        }));
+        let stalled = Arc::new(tokio::sync::Notify::new());
+        let server = serve_stalling(
+            listener,
+            std::fs::read(archive_path).unwrap(),
+            chunk_data_offset as usize,
+            stalled.clone(),
+        );
+        let cancelled_at = Arc::new(Mutex::new(None));
+        let cancel = {
+            let cancelled_at = cancelled_at.clone();
+            async move {
+                stalled.notified().await;
+                *cancelled_at.lock().unwrap() = Some(std::time::Instant::now());
+            }
+        };
+        let err = tokio::select! {
+            _ = server => panic!("server ended"),
+            result = clone_cmd_cancellable(opts, cancel) => result.unwrap_err(),
+        };
+        let cancelled_at = cancelled_at.lock().unwrap().expect("cancelled mid-fetch");
+        assert!(cancelled_at.elapsed() < Duration::from_secs(1));
+        assert_eq!(err.downcast_ref::<Cancelled>(), Some(&Cancelled));
+        assert!(!output.exists());
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn clone_detached_chunk_data() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source: Vec<u8> = (0..64 * 1024u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("manifest.cba");
+        let mut compress_opts = compress_options(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(4096),
+        );
+        compress_opts.detach_chunk_data = true;
+        compress_cmd::compress_cmd(compress_opts).await.unwrap();
+
+        // The dictionary file holds no chunk data
+        let archive = Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(archive.chunk_data_part_sizes().len(), 1);
+        assert_eq!(
+            std::fs::metadata(&archive_path).unwrap().len(),
+            archive.chunk_data_offset()
+        );
+        std::fs::create_dir(temp_dir.path().join("data")).unwrap();
+        let chunk_data = temp_dir.path().join("data").join("chunks.bin");
+        std::fs::rename(compress_cmd::part_path(&archive_path, 0), &chunk_data).unwrap();
+
+        let output = temp_dir.path().join("local");
+        let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+        opts.chunk_data = Some(chunk_data.to_str().unwrap().to_string());
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let output = temp_dir.path().join("remote");
+        let mut opts = local_clone_options("", &output);
+        opts.input_archive = InputArchive::Remote(Box::new(RemoteInput {
+            url: Url::parse(&format!("http://127.0.0.1:{}/manifest.cba", port)).unwrap(),
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            retry_jitter: RetryJitter::None,
+            receive_timeout: None,
+            headers: HeaderMap::new(),
+            http2_prior_knowledge: false,
+            full_download_limit: HttpReader::DEFAULT_FULL_DOWNLOAD_LIMIT,
+        }));
+        opts.chunk_data = Some("data/chunks.bin".to_string());
+        tokio::select! {
+            _ = serve_dir_ranges(listener, temp_dir.path().to_path_buf()) => panic!("server ended"),
+            result = clone_cmd(opts) => result.unwrap(),
+        }
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+    }
+
+    #[tokio::test]
+    async fn clone_incomplete_local_archive_with_fallback() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source: Vec<u8> = (0..64 * 1024u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("archive.cba");
+        compress_with_chunker(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(4096),
+        )
+        .await;
+        let archive = std::fs::read(&archive_path).unwrap();
+
+        // The local mirror lacks the last half of the chunks
+        let mirror_path = temp_dir.path().join("mirror.cba");
+        std::fs::write(&mirror_path, &archive[..archive.len() - 32 * 1024]).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_dir_ranges(listener, temp_dir.path().to_path_buf()));
+        let fallback = RemoteInput {
+            url: Url::parse(&format!("http://127.0.0.1:{}/archive.cba", port)).unwrap(),
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            retry_jitter: RetryJitter::None,
+            receive_timeout: None,
+            headers: HeaderMap::new(),
+            http2_prior_knowledge: false,
+            full_download_limit: 0,
+        };
+        let output = temp_dir.path().join("output");
+        for &cache_fallback in &[false, true] {
+            let mut opts = local_clone_options(mirror_path.to_str().unwrap(), &output);
+            opts.fallback = Some(Box::new(fallback.clone()));
+            opts.cache_fallback = cache_fallback;
+            clone_cmd(opts).await.unwrap();
+            assert_eq!(std::fs::read(&output).unwrap(), source);
+        }
+        // The missing chunks were cached in the mirror
+        assert_eq!(std::fs::read(&mirror_path).unwrap(), archive);
+
+        // Without a fallback the mirror is now enough by itself
+        server.abort();
+        let opts = local_clone_options(mirror_path.to_str().unwrap(), &output);
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+    }
+
+    #[tokio::test]
+    async fn clone_from_exported_chunk_store() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // Compressible data with duplicated chunks.
+        let mut source: Vec<u8> = (0..48 * 1024u32).map(|i| (i / 100) as u8).collect();
+        source.extend(&source.clone());
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("source.cba");
+        let mut compress_opts = compress_options(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(4096),
+        );
+        compress_opts.compression = Some(bitar::Compression::brotli(6).unwrap());
+        compress_cmd::compress_cmd(compress_opts).await.unwrap();
+        let store_dir = temp_dir.path().join("export");
+        export_cmd::export_cmd(export_cmd::Options {
+            input: archive_path.clone(),
+            output: store_dir.clone(),
+            force_create: false,
+            format: export_cmd::Format::ChunkStore,
+            dictionary_key: None,
+        })
+        .await
+        .unwrap();
+
+        // Every object holds a chunk as stored in the archive, named by its hash.
+        let archive = Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+            .await
+            .unwrap();
+        let mut objects = 0;
+        for dir in std::fs::read_dir(store_dir.join(export_cmd::CHUNK_STORE_OBJECTS)).unwrap() {
+            let dir = dir.unwrap();
+            for object in std::fs::read_dir(dir.path()).unwrap() {
+                let object = object.unwrap();
+                let name = object.file_name().into_string().unwrap();
+                assert!(name.starts_with(dir.file_name().to_str().unwrap()));
+                let cd = archive
+                    .chunk_descriptors()
+                    .iter()
+                    .find(|cd| cd.checksum.to_string() == name)
+                    .unwrap();
+                let data = std::fs::read(object.path()).unwrap();
+                assert_eq!(data.len(), cd.archive_size);
+                let data = if data.len() == cd.source_size as usize {
+                    data
+                } else {
+                    archive
+                        .chunk_compression()
+                        .unwrap()
+                        .decompress(&data, cd.source_size as usize)
+                        .unwrap()
+                };
+                assert_eq!(ChunkHasher::default().digest(&data), cd.checksum);
+                objects += 1;
+            }
+        }
+        assert_eq!(objects, archive.unique_chunks());
+        assert!(archive.unique_chunks() < archive.total_chunks());
+
+        // The index is the archive header, unpacked using the objects of the store.
+        let index_path = store_dir.join(export_cmd::CHUNK_STORE_INDEX);
+        let output = temp_dir.path().join("local");
+        let mut opts = local_clone_options(index_path.to_str().unwrap(), &output);
+        opts.header_checksum = Some(archive.header_checksum().clone());
+        opts.chunk_store = Some(
+            store_dir
+                .join(export_cmd::CHUNK_STORE_OBJECTS)
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let output = temp_dir.path().join("remote");
+        let mut opts = local_clone_options("", &output);
+        opts.input_archive = InputArchive::Remote(Box::new(RemoteInput {
+            url: Url::parse(&format!("http://127.0.0.1:{}/index.cba", port)).unwrap(),
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            retry_jitter: RetryJitter::None,
+            receive_timeout: None,
+            headers: HeaderMap::new(),
+            http2_prior_knowledge: false,
+            full_download_limit: HttpReader::DEFAULT_FULL_DOWNLOAD_LIMIT,
+        }));
+        opts.chunk_store = Some(export_cmd::CHUNK_STORE_OBJECTS.to_string());
+        tokio::select! {
+            _ = serve_dir_ranges(listener, store_dir) => panic!("server ended"),
+            result = clone_cmd(opts) => result.unwrap(),
+        }
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+    }
+
+    #[tokio::test]
+    async fn export_split_archives_into_one_chunk_store() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source: Vec<u8> = (0..96 * 1024u32).map(|i| (i / 100) as u8).collect();
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let store_dir = temp_dir.path().join("export");
+        let export = |archive_path: PathBuf| {
+            export_cmd::export_cmd(export_cmd::Options {
+                input: archive_path,
+                output: store_dir.clone(),
+                force_create: true,
+                format: export_cmd::Format::ChunkStore,
+                dictionary_key: None,
+            })
+        };
+
+        // Chunk data is read from the parts of a split archive
+        let split_path = temp_dir.path().join("split.cba");
+        let mut compress_opts =
+            compress_options(&source_path, &split_path, chunker::Config::FixedSize(4096));
+        compress_opts.split_size = Some(16 * 1024);
+        compress_cmd::compress_cmd(compress_opts).await.unwrap();
+        export(split_path).await.unwrap();
+        let output = temp_dir.path().join("output");
+        let mut opts = local_clone_options(
+            store_dir
+                .join(export_cmd::CHUNK_STORE_INDEX)
+                .to_str()
+                .unwrap(),
+            &output,
+        );
+        opts.chunk_store = Some(
+            store_dir
+                .join(export_cmd::CHUNK_STORE_OBJECTS)
+                .to_str()
+                .unwrap()
+                .to_string(),
+        );
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+
+        // Objects of the same chunks stored with another compression are never mixed in
+        let brotli_path = temp_dir.path().join("brotli.cba");
+        let mut compress_opts =
+            compress_options(&source_path, &brotli_path, chunker::Config::FixedSize(4096));
+        compress_opts.compression = Some(bitar::Compression::brotli(6).unwrap());
+        compress_cmd::compress_cmd(compress_opts).await.unwrap();
+        let err = export(brotli_path).await.unwrap_err();
+        assert!(
+            err.to_string().contains("stored differently"),
+            "unexpected error {}",
+            err
+        );
+    }
+
+    // Single zstd frame holding the data in raw blocks, like a chunk casync failed to compress.
+    fn raw_zstd_frame(data: &[u8]) -> Vec<u8> {
+        // Magic, single segment with a 4 byte content size and no checksum
+        let mut frame = vec![0x28, 0xb5, 0x2f, 0xfd, 0xa0];
+        frame.extend(&(data.len() as u32).to_le_bytes());
+        let mut blocks = data.chunks(128 * 1024).peekable();
+        if blocks.peek().is_none() {
+            frame.extend(&[1, 0, 0]);
+        }
+        while let Some(block) = blocks.next() {
+            let last = blocks.peek().is_none() as u32;
+            frame.extend(&((block.len() as u32) << 3 | last).to_le_bytes()[..3]);
+            frame.extend(block);
+        }
+        frame
+    }
+
+    // Write a casync blob index of the source cut into chunks ending at the given offsets, and
+    // its chunk store, laid out the way casync writes them. The chunks are cut independently of
+    // the bita chunker, like casync cuts them using its own discriminator. Returns the path of
+    // the index and of the store.
+    fn write_casync_index(dir: &Path, source: &[u8], chunk_ends: &[usize]) -> (PathBuf, PathBuf) {
+        let store = dir.join("default.castr");
+        let mut index = Vec::new();
+        for value in &[48, casync::CA_FORMAT_INDEX, casync::CA_FORMAT_SHA512_256] {
+            index.extend(&u64::to_le_bytes(*value));
+        }
+        for value in &[1024, 4096, 16 * 1024, u64::MAX, casync::CA_FORMAT_TABLE] {
+            index.extend(&u64::to_le_bytes(*value));
+        }
+        let mut start = 0;
+        for &end in chunk_ends {
+            let id = ChunkHasher::sha512_256().digest(&source[start..end]);
+            let path = store.join(casync::chunk_path(&id));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, raw_zstd_frame(&source[start..end])).unwrap();
+            index.extend(&(end as u64).to_le_bytes());
+            index.extend(id.slice());
+            start = end;
+        }
+        let table_size = 16 + (chunk_ends.len() as u64 + 1) * 40;
+        for value in &[0, 0, 48, table_size, casync::CA_FORMAT_TABLE_TAIL_MARKER] {
+            index.extend(&u64::to_le_bytes(*value));
+        }
+        let index_path = dir.join("source.caibx");
+        std::fs::write(&index_path, index).unwrap();
+        (index_path, store)
+    }
+
+    // A source of two equal halves cut into chunks of irregular size, the chunks of the second
+    // half repeating those of the first.
+    fn casync_source() -> (Vec<u8>, Vec<usize>) {
+        let half = random_data(48 * 1024, 0);
+        let mut ends: Vec<usize> = random_data(64, 1)
+            .iter()
+            .map(|&v| 1024 + usize::from(v) * 8)
+            .scan(0, |end, size| {
+                *end += size;
+                Some(*end)
+            })
+            .take_while(|&end| end < half.len())
+            .collect();
+        ends.push(half.len());
+        let second_half: Vec<usize> = ends.iter().map(|end| end + half.len()).collect();
+        ends.extend(second_half);
+        (half.repeat(2), ends)
+    }
+
+    #[cfg(feature = "zstd-compression")]
+    #[tokio::test]
+    async fn clone_casync_index() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (source, chunk_ends) = casync_source();
+        let (index_path, store) = write_casync_index(temp_dir.path(), &source, &chunk_ends);
+        let index = casync::Index::from_bytes(&std::fs::read(&index_path).unwrap()).unwrap();
+        assert_eq!(index.chunks().len(), chunk_ends.len());
+
+        let output = temp_dir.path().join("output");
+        let mut opts = local_clone_options(index_path.to_str().unwrap(), &output);
+        opts.chunk_store = Some(store.to_str().unwrap().to_string());
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+
+        // A corrupted chunk fails verification.
+        let id = &index.chunks()[0].id;
+        std::fs::write(
+            store.join(casync::chunk_path(id)),
+            raw_zstd_frame(b"corrupt"),
+        )
+        .unwrap();
+        let mut opts =
+            local_clone_options(index_path.to_str().unwrap(), &temp_dir.path().join("bad"));
+        opts.chunk_store = Some(store.to_str().unwrap().to_string());
+        assert!(clone_cmd(opts).await.is_err());
+    }
+
+    #[cfg(not(feature = "zstd-compression"))]
+    #[tokio::test]
+    async fn clone_casync_index_needs_zstd() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (source, chunk_ends) = casync_source();
+        let (index_path, store) = write_casync_index(temp_dir.path(), &source, &chunk_ends);
+        let output = temp_dir.path().join("output");
+        let mut opts = local_clone_options(index_path.to_str().unwrap(), &output);
+        opts.chunk_store = Some(store.to_str().unwrap().to_string());
+        let err = clone_cmd(opts).await.unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unsupported chunk compression (zstd)"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn stdin_seed_checksum() {
+        let seed: Vec<u8> = (0..10_000u32).map(|v| v as u8).collect();
+        let checksum = HashSum::from(&Blake2b512::digest(&seed[..])[..]);
+        let mut output_buf = vec![];
+        assert_eq!(
+            clone_from_stdin_seed(&seed[..], &checksum, &mut output_buf)
+                .await
+                .unwrap(),
+            1024
+        );
+        assert_eq!(&output_buf[..], &seed[..1024]);
+        let mut output_buf = vec![];
+        let err = clone_from_stdin_seed(&seed[..9_000], &checksum, &mut output_buf)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Seed checksum mismatch"));
+        // Nothing from the mismatching seed made it to the output
+        assert!(output_buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn clone_screening_chunks_by_crc() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source: Vec<u8> = (0..32 * 1024u32).map(|v| (v * 7 / 3) as u8).collect();
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("crc.cba");
+        let mut opts = test_options(
+            vec![source_path],
+            compress_cmd::Output::File(archive_path.clone()),
+        );
+        opts.chunk_crc = true;
+        compress_cmd::compress_cmd(opts).await.unwrap();
+
+        let output = temp_dir.path().join("output");
+        let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+        opts.crc_verify = Some(0.0);
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+
+        // Corrupt the last chunk, the CRC mismatch is then confirmed by the hash
+        let mut archive = std::fs::read(&archive_path).unwrap();
+        *archive.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&archive_path, archive).unwrap();
+        let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+        opts.crc_verify = Some(0.0);
+        let err = clone_cmd(opts).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("expected hash"));
+    }
+
+    #[tokio::test]
+    async fn concurrent_seed_and_archive_resolve_chunks_once() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = random_data(64 * 1024, 1);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("archive.cba");
+        compress_cmd::compress_cmd(test_options(
+            vec![source_path],
+            compress_cmd::Output::File(archive_path.clone()),
+        ))
+        .await
+        .unwrap();
+
+        // Every chunk is in both the seed and the archive
+        let mut archive = Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+            .await
+            .unwrap();
+        let mut output_buf = vec![];
+        let mut output =
+            CloneOutput::new(Cursor::new(&mut output_buf), archive.build_source_index());
+        let resolved = clone_concurrently(
+            2,
+            ChunkHasher::default(),
+            None,
+            false,
+            None,
+            &Arc::new(CodecDictionaries::default()),
+            vec![&source[..]],
+            &mut archive,
+            &mut output,
+        )
+        .await
+        .unwrap();
+        assert!(output.is_empty());
+        assert_eq!(
+            resolved.from_seeds + resolved.from_archive,
+            source.len() as u64
+        );
+        assert_eq!(output_buf, source);
+
+        // A seed holding half of the chunks
+        let seed_path = temp_dir.path().join("seed");
+        std::fs::write(&seed_path, &source[..32 * 1024]).unwrap();
+        let output = temp_dir.path().join("output");
+        let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+        opts.seed_files = vec![seed_path];
+        opts.concurrent_seeds = true;
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+    }
+
+    // Archive reader recording the chunks requested from it. The data of the first request is
+    // held back until released.
+    struct GatedReader<R> {
+        inner: R,
+        requested: Arc<Mutex<Vec<bitar::ChunkOffset>>>,
+        release: Option<Arc<tokio::sync::Notify>>,
+    }
+
+    #[async_trait::async_trait]
+    impl<R> ArchiveReader for GatedReader<R>
+    where
+        R: ArchiveReader + Send,
+        R::Error: Send,
+    {
+        type Error = R::Error;
+        async fn read_at<'a>(
+            &'a mut self,
+            offset: u64,
+            size: usize,
+        ) -> Result<bytes::Bytes, Self::Error> {
+            self.inner.read_at(offset, size).await
+        }
+        fn read_chunks<'a>(
+            &'a mut self,
+            chunks: Vec<bitar::ChunkOffset>,
+        ) -> Pin<Box<dyn futures_util::Stream<Item = Result<bytes::Bytes, Self::Error>> + Send + 'a>>
+        {
+            self.requested
+                .lock()
+                .unwrap()
+                .extend(chunks.iter().copied());
+            let release = self.release.take();
+            let chunk_stream = self.inner.read_chunks(chunks);
+            Box::pin(
+                futures_util::stream::once(async move {
+                    if let Some(release) = release {
+                        release.notified().await;
+                    }
+                    chunk_stream
+                })
+                .flatten(),
             )
+        }
+    }
+
+    // Output notifying once a number of bytes have been written to it.
+    struct NotifyingOutput<W> {
+        inner: W,
+        written: u64,
+        notify_after: u64,
+        notify: Arc<tokio::sync::Notify>,
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for NotifyingOutput<W> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut TaskContext<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(written)) = result {
+                let before = self.written;
+                self.written += written as u64;
+                if before < self.notify_after && self.written >= self.notify_after {
+                    self.notify.notify_one();
+                }
+            }
+            result
+        }
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut TaskContext<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut TaskContext<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    impl<W: AsyncSeek + Unpin> AsyncSeek for NotifyingOutput<W> {
+        fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+            Pin::new(&mut self.inner).start_seek(position)
+        }
+        fn poll_complete(
+            mut self: Pin<&mut Self>,
+            cx: &mut TaskContext<'_>,
+        ) -> Poll<std::io::Result<u64>> {
+            Pin::new(&mut self.inner).poll_complete(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_clone_skips_fetching_seed_chunks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = random_data(64 * 1024, 2);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("archive.cba");
+        compress_cmd::compress_cmd(test_options(
+            vec![source_path],
+            compress_cmd::Output::File(archive_path.clone()),
+        ))
+        .await
+        .unwrap();
+
+        // The seed holds the last half of the chunks. The archive data is held back until the
+        // seed chunks are written, so the seed has resolved them before the archive gets to them.
+        let release = Arc::new(tokio::sync::Notify::new());
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let mut archive = Archive::try_init(GatedReader {
+            inner: LocalFile::open_archive(&archive_path).await.unwrap(),
+            requested: requested.clone(),
+            release: Some(release.clone()),
+        })
+        .await
+        .unwrap();
+        let mut output_buf = vec![];
+        let mut output = CloneOutput::new(
+            NotifyingOutput {
+                inner: Cursor::new(&mut output_buf),
+                written: 0,
+                notify_after: 32 * 1024,
+                notify: release,
+            },
+            archive.build_source_index(),
+        );
+        let resolved = clone_concurrently(
+            2,
+            ChunkHasher::default(),
+            None,
+            false,
+            None,
+            &Arc::new(CodecDictionaries::default()),
+            vec![&source[32 * 1024..]],
+            &mut archive,
+            &mut output,
+        )
+        .await
+        .unwrap();
+        assert!(output.is_empty());
+        drop(output);
+        assert_eq!(output_buf, source);
+        assert_eq!(resolved.from_seeds, 32 * 1024);
+        assert_eq!(resolved.from_archive, 32 * 1024);
+        assert_eq!(resolved.fetched, 32 * 1024);
+        // Only the chunks not in the seed were requested
+        let expected: Vec<bitar::ChunkOffset> = archive
+            .chunk_descriptors()
+            .iter()
+            .take(8)
+            .map(|cd| bitar::ChunkOffset::new(cd.archive_offset, cd.archive_size))
+            .collect();
+        assert_eq!(*requested.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn clone_archive_with_encrypted_dictionary() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source: Vec<u8> = (0..64 * 1024u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("archive.cba");
+        let key = DictionaryKey::from_slice(&[7; DictionaryKey::SIZE]).unwrap();
+        let mut compress_opts = compress_options(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(4096),
+        );
+        compress_opts.dictionary_key = Some(key.clone());
+        compress_opts.verify = true;
+        compress_cmd::compress_cmd(compress_opts).await.unwrap();
+
+        // The header is unreadable without the key
+        assert!(matches!(
+            Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap()).await,
+            Err(bitar::ArchiveError::EncryptedDictionary)
+        ));
+        assert!(matches!(
+            bitar::ArchiveSummary::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+                .await,
+            Err(bitar::ArchiveError::EncryptedDictionary)
+        ));
+        let wrong_key = DictionaryKey::from_slice(&[8; DictionaryKey::SIZE]).unwrap();
+        assert!(matches!(
+            Archive::try_init_with_key(
+                LocalFile::open_archive(&archive_path).await.unwrap(),
+                &wrong_key
+            )
+            .await,
+            Err(bitar::ArchiveError::InvalidArchive(_))
+        ));
+        // while the uncompressed chunk data is stored as is
+        let archive = std::fs::read(&archive_path).unwrap();
+        assert!(archive.ends_with(&source));
+
+        let output = temp_dir.path().join("output");
+        let opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+        assert!(clone_cmd(opts).await.is_err());
+        assert!(!output.exists());
+
+        let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+        opts.dictionary_key = Some(key);
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+    }
+
+    fn compress_options(
+        input: &Path,
+        output: &Path,
+        chunker_config: chunker::Config,
+    ) -> compress_cmd::Options {
+        let mut opts = test_options(
+            vec![input.to_path_buf()],
+            compress_cmd::Output::File(output.to_path_buf()),
+        );
+        opts.force_create = true;
+        opts.chunker_config = chunker_config;
+        opts
+    }
+
+    async fn compress_with_chunker(input: &Path, output: &Path, chunker_config: chunker::Config) {
+        compress_cmd::compress_cmd(compress_options(input, output, chunker_config))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn seed_archive_with_other_chunker_is_rechunked() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = random_data(256 * 1024, 3);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let buzhash = |average| {
+            chunker::Config::BuzHash(chunker::FilterConfig {
+                filter_bits: chunker::FilterBits::from_size(average),
+                min_chunk_size: 1024,
+                max_chunk_size: 64 * 1024,
+                window_size: 48,
+                buzhash_table: None,
+            })
+        };
+        let target_path = temp_dir.path().join("target.cba");
+        compress_with_chunker(&source_path, &target_path, buzhash(4096)).await;
+        let same_path = temp_dir.path().join("same.cba");
+        compress_with_chunker(&source_path, &same_path, buzhash(4096)).await;
+        let other_path = temp_dir.path().join("other.cba");
+        compress_with_chunker(&source_path, &other_path, buzhash(16 * 1024)).await;
+        let target = Archive::try_init(LocalFile::open_archive(&target_path).await.unwrap())
             .await
+            .unwrap();
+        let output_path = temp_dir.path().join("output");
+
+        for (seed_path, rechunked) in &[(&same_path, false), (&other_path, true)] {
+            let seed = Archive::try_init(LocalFile::open_archive(seed_path).await.unwrap())
+                .await
+                .unwrap();
+            let mismatch = chunker_mismatch(&seed, &target).unwrap();
+            assert_eq!(!mismatch.is_empty(), *rechunked, "{:?}", mismatch);
+            assert!(mismatch.iter().all(|m| m.starts_with("filter bits")));
+            let mut output_buf = vec![];
+            let mut output =
+                CloneOutput::new(Cursor::new(&mut output_buf), target.build_source_index());
+            let used = clone_from_seed_archive(
+                2,
+                ChunkHasher::default(),
+                None,
+                &Arc::new(CodecDictionaries::default()),
+                seed_path,
+                &target,
+                &output_path,
+                &mut output,
+            )
+            .await
+            .unwrap();
+            assert!(output.is_empty());
+            assert_eq!(used, source.len() as u64);
+            assert_eq!(output_buf, source);
         }
-        InputArchive::Remote(input) => {
-            let mut request = reqwest::Client::new()
-                .get(input.url.clone())
-                .headers(input.headers.clone());
-            if let Some(timeout) = input.receive_timeout {
-                request = request.timeout(timeout);
+        let mut opts = local_clone_options(target_path.to_str().unwrap(), &output_path);
+        opts.seed_archives = vec![other_path];
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output_path).unwrap(), source);
+        // The unpacked seed is removed when done
+        assert!(!temp_dir.path().join(".output.seed.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn seed_scan_limit_uses_prefix_of_seed() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = random_data(64 * 1024, 4);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("source.cba");
+        compress_with_chunker(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(1024),
+        )
+        .await;
+
+        // Only the chunks in the first 16KiB of the seed are used, the rest are fetched
+        let mut archive = Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+            .await
+            .unwrap();
+        let mut output_buf = vec![];
+        let mut output =
+            CloneOutput::new(Cursor::new(&mut output_buf), archive.build_source_index());
+        let seed = open_seed_file(&source_path, Some(16 * 1024)).await.unwrap();
+        let from_seed = clone_from_readable(
+            2,
+            archive.chunker_config(),
+            ChunkHasher::default(),
+            None,
+            seed,
+            &mut output,
+        )
+        .await
+        .unwrap();
+        assert_eq!(from_seed, 16 * 1024);
+        assert_eq!(output.len(), 48);
+        let fetched = clone_from_archive(
+            2,
+            None,
+            ChunkHasher::default(),
+            None,
+            0,
+            &Arc::new(CodecDictionaries::default()),
+            &mut archive,
+            &mut output,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fetched, 48 * 1024);
+        assert!(output.is_empty());
+        drop(output);
+        assert_eq!(output_buf, source);
+
+        let output = temp_dir.path().join("output");
+        for &concurrent_seeds in &[false, true] {
+            let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+            opts.seed_files = vec![source_path.clone()];
+            opts.seed_scan_limit = Some(16 * 1024);
+            opts.concurrent_seeds = concurrent_seeds;
+            clone_cmd(opts).await.unwrap();
+            assert_eq!(std::fs::read(&output).unwrap(), source);
+        }
+    }
+
+    #[tokio::test]
+    async fn write_buffer_sizes_clone_identically() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = random_data(256 * 1024, 5);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("source.cba");
+        compress_with_chunker(
+            &source_path,
+            &archive_path,
+            chunker::Config::BuzHash(chunker::FilterConfig {
+                filter_bits: chunker::FilterBits::from_size(4096),
+                min_chunk_size: 1024,
+                max_chunk_size: 64 * 1024,
+                window_size: 48,
+                buzhash_table: None,
+            }),
+        )
+        .await;
+        let output = temp_dir.path().join("output");
+        for &write_buffer in &[1, 16 * 1024 * 1024] {
+            for &ordered_write_buffer in &[None, Some(4)] {
+                let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+                opts.write_buffer = Some(write_buffer);
+                opts.ordered_write_buffer = ordered_write_buffer;
+                clone_cmd(opts).await.unwrap();
+                assert_eq!(std::fs::read(&output).unwrap(), source);
             }
-            clone_archive(
-                opts,
-                HttpReader::from_request(request)
-                    .retries(input.retries)
-                    .retry_delay(input.retry_delay),
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_seeds_reject_chunk_matching_short_hash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = random_data(4 * 1024, 6);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("source.cba");
+        let mut opts = compress_options(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(1024),
+        );
+        opts.hash_length = 1;
+        opts.chunk_crc = true;
+        compress_cmd::compress_cmd(opts).await.unwrap();
+
+        // Corrupt the first chunk of the seed while keeping the first byte of its hash
+        let hasher = ChunkHasher::default();
+        let first_hash = hasher.digest(&source[..1024]);
+        let mut seed = source.clone();
+        for value in 0..=u16::MAX {
+            seed[..2].copy_from_slice(&value.to_le_bytes());
+            let hash = hasher.digest(&seed[..1024]);
+            if hash != first_hash && hash.slice()[0] == first_hash.slice()[0] {
+                break;
+            }
+        }
+        assert_ne!(seed, source);
+        let seed_path = temp_dir.path().join("seed");
+        std::fs::write(&seed_path, &seed).unwrap();
+
+        let output = temp_dir.path().join("output");
+        let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+        opts.seed_files = vec![seed_path.clone()];
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), seed);
+
+        for &concurrent_seeds in &[false, true] {
+            let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+            opts.seed_files = vec![seed_path.clone()];
+            opts.concurrent_seeds = concurrent_seeds;
+            opts.strict_seeds = true;
+            clone_cmd(opts).await.unwrap();
+            assert_eq!(std::fs::read(&output).unwrap(), source);
+        }
+
+        // Strict seeds require the chunk CRCs
+        let mut opts = compress_options(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(1024),
+        );
+        opts.hash_length = 1;
+        compress_cmd::compress_cmd(opts).await.unwrap();
+        let mut opts = local_clone_options(archive_path.to_str().unwrap(), &output);
+        opts.seed_files = vec![seed_path];
+        opts.strict_seeds = true;
+        let err = clone_cmd(opts).await.unwrap_err();
+        assert!(err.to_string().contains("no chunk CRCs"));
+    }
+
+    // Archive reader taking some time to read every chunk.
+    struct SlowReader<R> {
+        inner: R,
+        delay: Duration,
+    }
+
+    #[async_trait::async_trait]
+    impl<R> ArchiveReader for SlowReader<R>
+    where
+        R: ArchiveReader + Send,
+        R::Error: Send,
+    {
+        type Error = R::Error;
+        async fn read_at<'a>(&'a mut self, offset: u64, size: usize) -> Result<Bytes, Self::Error> {
+            self.inner.read_at(offset, size).await
+        }
+        fn read_chunks<'a>(
+            &'a mut self,
+            chunks: Vec<bitar::ChunkOffset>,
+        ) -> Pin<Box<dyn Stream<Item = Result<Bytes, Self::Error>> + Send + 'a>> {
+            let delay = self.delay;
+            Box::pin(self.inner.read_chunks(chunks).then(move |r| async move {
+                tokio::time::sleep(delay).await;
+                r
+            }))
+        }
+    }
+
+    const SLOW_CODEC_ID: u32 = 0x736c_6f77;
+
+    // Codec taking some time to decompress every chunk.
+    struct SlowCodec(Duration);
+
+    impl bitar::ChunkCodec for SlowCodec {
+        fn id(&self) -> u32 {
+            SLOW_CODEC_ID
+        }
+        fn compress(&self, data: &[u8]) -> Result<Vec<u8>, bitar::CompressionError> {
+            bitar::TestCodec.compress(data)
+        }
+        fn decompress(
+            &self,
+            data: &[u8],
+            size_hint: usize,
+        ) -> Result<Vec<u8>, bitar::CompressionError> {
+            std::thread::sleep(self.0);
+            bitar::TestCodec.decompress(data, size_hint)
+        }
+    }
+
+    #[tokio::test]
+    async fn prefetch_overlaps_fetch_and_decompress() {
+        const CHUNKS: u32 = 10;
+        let delay = Duration::from_millis(40);
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source: Vec<u8> = (0..CHUNKS as u8).flat_map(|n| vec![n; 4096]).collect();
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("source.cba");
+        bitar::register_codec(Arc::new(SlowCodec(delay)));
+        let mut opts = compress_options(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(4096),
+        );
+        opts.compression = Some(bitar::Compression::custom(SLOW_CODEC_ID).unwrap());
+        compress_cmd::compress_cmd(opts).await.unwrap();
+
+        let mut elapsed = vec![];
+        for &prefetch_chunks in &[None, Some(4)] {
+            let mut archive = Archive::try_init(SlowReader {
+                inner: LocalFile::open_archive(&archive_path).await.unwrap(),
+                delay,
+            })
+            .await
+            .unwrap();
+            let mut output_buf = vec![];
+            let mut output =
+                CloneOutput::new(Cursor::new(&mut output_buf), archive.build_source_index());
+            let start = std::time::Instant::now();
+            clone_from_archive(
+                1,
+                prefetch_chunks,
+                ChunkHasher::default(),
+                None,
+                0,
+                &Arc::new(CodecDictionaries::default()),
+                &mut archive,
+                &mut output,
             )
             .await
+            .unwrap();
+            elapsed.push(start.elapsed());
+            assert_eq!(output_buf, source);
+        }
+        // Fetching and decompressing one chunk at a time takes the sum of both, while with
+        // prefetch it's closer to the time of one of them
+        let sum = delay * 2 * CHUNKS;
+        let max = delay * CHUNKS;
+        assert!(elapsed[0] >= sum, "{:?}", elapsed);
+        assert!(elapsed[1] < (sum + max) / 2, "{:?}", elapsed);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn clone_to_fifo_fails_clearly() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("fifo");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&output)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let mut opts = local_clone_options("bitar/tests/resources/zero-0_7_1-brotli.cba", &output);
+        opts.atomic = false;
+        let err = clone_cmd(opts).await.unwrap_err();
+        assert!(err.to_string().contains("is not seekable"));
+    }
+
+    #[tokio::test]
+    async fn rebuild_plan_covers_source() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let block = random_data(3 * 4096, 7);
+        // Repeated blocks give chunks with multiple source offsets
+        let mut source = block.repeat(3);
+        source.extend(&block[..1000]);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("archive.cba");
+        compress_with_chunker(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(4096),
+        )
+        .await;
+        let archive = Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+            .await
+            .unwrap();
+
+        let plan = rebuild_plan(&archive);
+        assert_eq!(plan["source_size"], source.len() as u64);
+        let chunks = plan["chunks"].as_array().unwrap();
+        assert_eq!(chunks.len(), 4);
+        let mut ranges: Vec<(u64, u64)> = chunks
+            .iter()
+            .flat_map(|chunk| {
+                let size = chunk["source_size"].as_u64().unwrap();
+                chunk["source_offsets"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(move |offset| (offset.as_u64().unwrap(), size))
+            })
+            .collect();
+        ranges.sort_unstable();
+        let mut end = 0;
+        for (offset, size) in ranges {
+            assert_eq!(offset, end, "gap or overlap at {}", offset);
+            end = offset + size;
+        }
+        assert_eq!(end, source.len() as u64);
+        for chunk in chunks {
+            assert_eq!(chunk["compression"], "none");
+            assert_eq!(chunk["archive_size"], chunk["source_size"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn chunk_failing_verification_is_fetched_again() {
+        fetch_corrupted_chunk_again(None).await;
+    }
+
+    #[tokio::test]
+    async fn chunk_failing_decompression_is_fetched_again() {
+        fetch_corrupted_chunk_again(Some(bitar::Compression::brotli(6).unwrap())).await;
+    }
+
+    // Clone from a server corrupting the first response with chunk data, failing without chunk
+    // retries and succeeding with them.
+    async fn fetch_corrupted_chunk_again(compression: Option<bitar::Compression>) {
+        use hyper::service::{make_service_fn, service_fn};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source: Vec<u8> = (0..32 * 1024u32).map(|v| (v * 13 / 5) as u8).collect();
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("archive.cba");
+        let mut compress_opts = compress_options(
+            &source_path,
+            &archive_path,
+            chunker::Config::FixedSize(4096),
+        );
+        compress_opts.compression = compression;
+        compress_cmd::compress_cmd(compress_opts).await.unwrap();
+        let archive_data = std::fs::read(&archive_path).unwrap();
+        let chunk_data_offset =
+            Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+                .await
+                .unwrap()
+                .chunk_data_offset() as usize;
+
+        // The first response with chunk data is corrupted
+        let corrupted = Arc::new(AtomicUsize::new(0));
+        let no_cache_requests = Arc::new(AtomicUsize::new(0));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = {
+            let corrupted = corrupted.clone();
+            let no_cache_requests = no_cache_requests.clone();
+            hyper::Server::from_tcp(listener)
+                .unwrap()
+                .serve(make_service_fn(move |_conn| {
+                    let archive_data = archive_data.clone();
+                    let corrupted = corrupted.clone();
+                    let no_cache_requests = no_cache_requests.clone();
+                    async move {
+                        Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                            if req.headers().get(hyper::header::CACHE_CONTROL)
+                                == Some(&hyper::header::HeaderValue::from_static("no-cache"))
+                            {
+                                no_cache_requests.fetch_add(1, Ordering::SeqCst);
+                            }
+                            let range = req.headers()[hyper::header::RANGE].to_str().unwrap();
+                            let mut bounds = range["bytes=".len()..].splitn(2, '-');
+                            let (start, end): (usize, usize) = (
+                                bounds.next().unwrap().parse().unwrap(),
+                                bounds.next().unwrap().parse().unwrap(),
+                            );
+                            let mut data = archive_data[start..=end].to_vec();
+                            if start >= chunk_data_offset
+                                && corrupted.fetch_add(1, Ordering::SeqCst) == 0
+                            {
+                                data[0] ^= 0xff;
+                            }
+                            let mut response = hyper::Response::new(hyper::Body::from(data));
+                            *response.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+                            async { Ok::<_, hyper::Error>(response) }
+                        }))
+                    }
+                }))
+        };
+        let output = temp_dir.path().join("output");
+        let mut opts = local_clone_options("", &output);
+        opts.input_archive = InputArchive::Remote(Box::new(RemoteInput {
+            url: Url::parse(&format!("http://127.0.0.1:{}/archive.cba", port)).unwrap(),
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            retry_jitter: RetryJitter::None,
+            receive_timeout: None,
+            headers: HeaderMap::new(),
+            http2_prior_knowledge: false,
+            full_download_limit: HttpReader::DEFAULT_FULL_DOWNLOAD_LIMIT,
+        }));
+        tokio::spawn(server);
+        let err = clone_cmd(opts.clone()).await.unwrap_err();
+        match compression {
+            None => assert!(err.downcast_ref::<bitar::HashSumMismatchError>().is_some()),
+            Some(_) => assert!(err.downcast_ref::<bitar::CompressionError>().is_some()),
         }
+
+        corrupted.store(0, Ordering::SeqCst);
+        opts.chunk_retries = 2;
+        clone_cmd(opts).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), source);
+        assert!(corrupted.load(Ordering::SeqCst) > 1);
+        assert!(no_cache_requests.load(Ordering::SeqCst) > 0);
     }
 }
@@ -1,44 +1,366 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use blake2::{Blake2b512, Digest};
-use futures_util::{future, StreamExt};
+use core::pin::Pin;
+use core::task::{self, Poll};
+use futures_util::{future, ready, Stream, StreamExt};
 use log::*;
-use std::collections::HashMap;
-use std::io::Write;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use tokio::{
     fs::{File, OpenOptions},
-    io::{AsyncRead, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
 };
 
-use crate::{human_size, info_cmd};
-use bitar::{archive_reader::IoReader, chunk_dictionary as dict};
-use bitar::{chunker, Compression};
+use crate::{
+    human_size, info_cmd,
+    local_file::LocalFile,
+    progress::{Progress, ProgressFormat},
+    verify_cmd,
+};
+use bitar::chunk_dictionary as dict;
+use bitar::header::DictionaryKey;
+use bitar::{
+    chunker, Chunk, ChunkHasher, Compression, HashSum, SourceCheckpoints, SourceEntry, SourceHasher,
+};
 
 pub const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// Abort compression early if the input doesn't deduplicate well enough.
+#[derive(Debug, Clone, Copy)]
+pub struct DedupCheck {
+    /// Number of source bytes to process before estimating the dedup ratio.
+    pub check_after: u64,
+    /// Minimal part (0.0 - 1.0) of the processed source which must be deduplicated.
+    pub min_ratio: f64,
+}
+
+/// Warn if the estimated probability of two unique chunks having the same (truncated) hash
+/// exceeds this.
+pub const MAX_COLLISION_PROBABILITY: f64 = 1e-6;
+
+/// Warn if an input of known size is expected to give more chunks than this, as every chunk
+/// adds a descriptor to the archive header.
+pub const MAX_EXPECTED_CHUNKS: usize = 1 << 20;
+
+/// Condition noticed while compressing which didn't stop the archive from being built.
+///
+/// Every warning is logged as it's found and also returned in the [`Summary`] of the archive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// The input is expected to give so many chunks that the archive header gets big.
+    ManyChunks {
+        source_size: u64,
+        expected_chunks: usize,
+        avg_chunk_size: u64,
+        suggested_avg_chunk_size: u64,
+    },
+    /// The probability of two unique chunks sharing a stored hash is above
+    /// [`MAX_COLLISION_PROBABILITY`].
+    HashCollision {
+        probability: f64,
+        unique_chunks: usize,
+        hash_length: usize,
+    },
+    /// Most of the chunk data was left uncompressed, as compressing it didn't make it smaller.
+    IncompressibleData {
+        uncompressed_size: u64,
+        unique_source_size: u64,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ManyChunks {
+                source_size,
+                expected_chunks,
+                avg_chunk_size,
+                suggested_avg_chunk_size,
+            } => write!(
+                f,
+                "Input of {} is expected to give about {} chunks with an average chunk size of {}, which makes a big archive header, consider an average chunk size of at least {}",
+                human_size!(*source_size),
+                expected_chunks,
+                human_size!(*avg_chunk_size),
+                human_size!(*suggested_avg_chunk_size)
+            ),
+            Self::HashCollision {
+                probability,
+                unique_chunks,
+                hash_length,
+            } => write!(
+                f,
+                "Probability of a chunk hash collision is {:.1e} with {} unique chunks and a hash length of {} bytes, consider a longer hash",
+                probability, unique_chunks, hash_length
+            ),
+            Self::IncompressibleData {
+                uncompressed_size,
+                unique_source_size,
+            } => write!(
+                f,
+                "{} of {} chunk data was left uncompressed, consider using no compression",
+                human_size!(*uncompressed_size),
+                human_size!(*unique_source_size)
+            ),
+        }
+    }
+}
+
+// Birthday bound estimate of the probability that any of the unique chunks share a hash
+// of the given length.
+fn collision_probability(unique_chunks: usize, hash_length: usize) -> f64 {
+    let pairs = unique_chunks as f64 * unique_chunks.saturating_sub(1) as f64 / 2.0;
+    let hashes = 2f64.powi(8 * hash_length as i32);
+    -(-pairs / hashes).exp_m1()
+}
+
+/// Order of the chunk data stored in the archive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChunkOrder {
+    /// Store chunks in the order they're first found in the source.
+    SourceOffset,
+    /// Store chunks sorted by their hash.
+    Hash,
+}
+
+impl Default for ChunkOrder {
+    fn default() -> Self {
+        Self::SourceOffset
+    }
+}
+
+struct ChunkedSource {
+    source_hash: Vec<u8>,
+    source_checkpoints: Option<SourceCheckpoints>,
+    source_size: u64,
+    chunk_order: Vec<usize>,
+}
+
+struct Chunked {
+    // One per input, in the order given
+    sources: Vec<ChunkedSource>,
+    archive_chunks: Vec<dict::ChunkDescriptor>,
+    // Size of the unique chunks which were already seen in earlier inputs
+    seen_source_size: u64,
+    collision_probability: f64,
+    warnings: Vec<Warning>,
+}
+
+// Estimate the entropy of data in bits per byte (0-8) from the byte histogram of an evenly
+// spread sample. The estimate is a bit low for small samples, about 7.8 for 1 KiB of random data.
+fn estimate_entropy(data: &[u8]) -> f64 {
+    const MAX_SAMPLES: usize = 4096;
+    if data.is_empty() {
+        return 0.0;
+    }
+    let step = (data.len() / MAX_SAMPLES).max(1);
+    let mut histogram = [0u32; 256];
+    let mut samples = 0;
+    for &byte in data.iter().step_by(step) {
+        histogram[byte as usize] += 1;
+        samples += 1;
+    }
+    histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / samples as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn average_chunk_size(config: &chunker::Config) -> u64 {
+    match config {
+        chunker::Config::BuzHash(filter_config)
+        | chunker::Config::RollSum(filter_config)
+        | chunker::Config::Custom(_, filter_config) => {
+            filter_config.filter_bits.chunk_target_average() as u64
+        }
+        chunker::Config::FixedSize(size) => *size as u64,
+    }
+}
+
+// Number of chunks an input of the given size is expected to be split into.
+fn expected_chunks(config: &chunker::Config, size: u64) -> usize {
+    usize::try_from(size / average_chunk_size(config).max(1)).unwrap_or(usize::MAX)
+}
+
+// Warning for an input expected to give too many chunks for its size, suggesting an average
+// chunk size which stays below the limit.
+fn chunk_count_warning(config: &chunker::Config, size: u64) -> Option<Warning> {
+    let expected = expected_chunks(config, size);
+    if expected <= MAX_EXPECTED_CHUNKS {
+        return None;
+    }
+    Some(Warning::ManyChunks {
+        source_size: size,
+        expected_chunks: expected,
+        avg_chunk_size: average_chunk_size(config),
+        suggested_avg_chunk_size: (size / MAX_EXPECTED_CHUNKS as u64).next_power_of_two(),
+    })
+}
+
+// Merge a chunk shorter than the min chunk size, which the chunker only gives at the end of a
+// source, into the chunk before it. Sources ending with a tiny tail then still end with a chunk
+// of regular size. The chunks are left as is if the merged chunk would exceed the max chunk
+// size.
+fn merge_short_tail<'a, S>(
+    chunks: S,
+    config: &chunker::Config,
+) -> impl Stream<Item = std::io::Result<(u64, Chunk)>> + Send + Unpin + 'a
+where
+    S: Stream<Item = std::io::Result<(u64, Chunk)>> + Send + Unpin + 'a,
+{
+    let min_chunk_size = config.min_chunk_size();
+    let max_chunk_size = config.max_chunk_size();
+    Box::pin(futures_util::stream::unfold(
+        (chunks, None),
+        move |(mut chunks, mut pending): (S, Option<(u64, Chunk)>)| async move {
+            loop {
+                match chunks.next().await {
+                    Some(Ok((offset, chunk))) => match pending.take() {
+                        Some((prev_offset, prev))
+                            if chunk.len() < min_chunk_size
+                                && prev.len() + chunk.len() <= max_chunk_size =>
+                        {
+                            let mut data = prev.data().to_vec();
+                            data.extend_from_slice(chunk.data());
+                            pending = Some((prev_offset, Chunk::from(data)));
+                        }
+                        Some(prev) => return Some((Ok(prev), (chunks, Some((offset, chunk))))),
+                        None => pending = Some((offset, chunk)),
+                    },
+                    Some(Err(err)) => return Some((Err(err), (chunks, pending))),
+                    None => return pending.take().map(|prev| (Ok(prev), (chunks, None))),
+                }
+            }
+        },
+    ))
+}
+
+// Interleave the streams by taking an item of each in turn, skipping streams which have ended.
+// All streams are polled concurrently, each buffering up to `prefetch` items, so a slow stream
+// doesn't stall reading the others. Unlike select_all the order of the items doesn't depend on
+// which stream happens to be ready first.
+struct RoundRobin<S: Stream> {
+    streams: Vec<RoundRobinStream<S>>,
+    prefetch: usize,
+    next: usize,
+}
+
+struct RoundRobinStream<S: Stream> {
+    stream: S,
+    buffered: VecDeque<S::Item>,
+    ended: bool,
+}
+
+impl<S: Stream> RoundRobin<S> {
+    fn new(streams: Vec<S>, prefetch: usize) -> Self {
+        Self {
+            streams: streams
+                .into_iter()
+                .map(|stream| RoundRobinStream {
+                    stream,
+                    buffered: VecDeque::new(),
+                    ended: false,
+                })
+                .collect(),
+            prefetch: prefetch.max(1),
+            next: 0,
+        }
+    }
+}
+
+impl<S> Stream for RoundRobin<S>
+where
+    S: Stream + Unpin,
+    S::Item: Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+        for s in this.streams.iter_mut() {
+            while !s.ended && s.buffered.len() < this.prefetch {
+                match s.stream.poll_next_unpin(cx) {
+                    Poll::Ready(Some(item)) => s.buffered.push_back(item),
+                    Poll::Ready(None) => s.ended = true,
+                    Poll::Pending => break,
+                }
+            }
+        }
+        while !this.streams.is_empty() {
+            this.next %= this.streams.len();
+            let s = &mut this.streams[this.next];
+            if let Some(item) = s.buffered.pop_front() {
+                this.next += 1;
+                return Poll::Ready(Some(item));
+            }
+            if !s.ended {
+                // The stream was polled above and will wake us once it has an item
+                return Poll::Pending;
+            }
+            this.streams.remove(this.next);
+        }
+        Poll::Ready(None)
+    }
+}
+
+// Chunk all inputs into a single stream, so a chunk found in multiple inputs is only compressed
+// and stored once. The inputs are read and chunked concurrently, but their chunks are taken round
+// robin, one chunk of one input at a time, and the hash and compress stages keep that order. The
+// chunk data is then laid out the same regardless of how fast each input is read or how tasks are
+// scheduled.
+//
+// Inputs with a known size (files and block devices) must be read to their end.
 async fn chunk_input<T>(
-    mut input: T,
-    chunker_config: &chunker::Config,
-    compression: Option<Compression>,
-    temp_file_path: &std::path::Path,
-    hash_length: usize,
-    num_chunk_buffers: usize,
-) -> Result<(
-    Vec<u8>,
-    Vec<bitar::chunk_dictionary::ChunkDescriptor>,
-    u64,
-    Vec<usize>,
-)>
+    mut inputs: Vec<T>,
+    input_sizes: &[Option<u64>],
+    opts: &Options,
+    temp_file_path: &Path,
+    seen_chunks: &mut HashSet<HashSum>,
+    progress: &mut Progress,
+) -> Result<Chunked>
 where
     T: AsyncRead + Unpin + Send,
 {
-    let mut source_hasher = Blake2b512::new();
+    let mut source_hashers: Vec<SourceHasher> = inputs
+        .iter()
+        .map(|_| SourceHasher::new(opts.source_checkpoint_interval))
+        .collect();
+    let mut source_sizes: Vec<u64> = vec![0; inputs.len()];
+    let mut chunk_orders: Vec<Vec<usize>> = input_sizes
+        .iter()
+        .map(|size| {
+            // Reserve for the expected number of chunks, but don't trust a huge size blindly
+            let expected = size.map_or(0, |size| expected_chunks(&opts.chunker_config, size));
+            Vec::with_capacity(expected.min(1 << 20))
+        })
+        .collect();
     let mut unique_chunks = HashMap::new();
-    let mut source_size: u64 = 0;
-    let mut chunk_order = Vec::new();
+    let mut processed_size: u64 = 0;
     let mut archive_offset: u64 = 0;
     let mut unique_chunk_index: usize = 0;
     let mut archive_chunks = Vec::new();
+    let mut unique_source_size: u64 = 0;
+    let mut uncompressed_size: u64 = 0;
+    let mut seen_source_size: u64 = 0;
+    let mut dedup_check = opts.dedup_check;
+    let compression = opts.compression;
+    let hash_length = opts.hash_length;
+    let dedup_hash_length = opts.dedup_hash_length.max(hash_length);
+    // Stored (truncated) hash of every unique chunk, distinct chunks must not share one
+    let mut stored_hashes = HashSet::new();
+    let hash_buffers = opts.hash_buffers;
+    let compress_buffers = opts.compress_buffers;
+    let chunk_hasher = opts.chunk_hasher;
+    let chunk_crc = opts.chunk_crc;
+    let max_compress_entropy = opts.max_compress_entropy;
 
     let mut temp_file = OpenOptions::new()
         .write(true)
@@ -51,80 +373,164 @@ where
             temp_file_path.display()
         ))?;
     {
-        let chunker = chunker_config.new_chunker(&mut input);
-        let mut chunk_stream = chunker
-            .map(|result| {
+        let chunkers = inputs.iter_mut().enumerate().map(|(source_index, input)| {
+            let chunker = opts.chunker_config.new_chunker(input);
+            let chunks: Box<dyn Stream<Item = _> + Send + Unpin> = if opts.merge_short_tail {
+                Box::new(merge_short_tail(chunker, &opts.chunker_config))
+            } else {
+                Box::new(chunker)
+            };
+            chunks.map(move |result| (source_index, result))
+        });
+        let mut chunk_stream = RoundRobin::new(chunkers.collect(), hash_buffers)
+            .map(|(source_index, result)| {
                 let (offset, chunk) = result.expect("error while chunking");
-                // Build hash of full source
-                source_hasher.update(chunk.data());
-                source_size += chunk.len() as u64;
-                tokio::task::spawn_blocking(move || (offset, chunk.verify()))
+                // Build hash of full source. Done here, before the chunks are handed to the
+                // concurrent stages, so every source is hashed in order however the tasks
+                // are scheduled.
+                source_hashers[source_index].update(chunk.data());
+                source_sizes[source_index] += chunk.len() as u64;
+                (source_index, offset, chunk)
+            })
+            .map(|(source_index, offset, chunk)| {
+                tokio::task::spawn_blocking(move || {
+                    (source_index, offset, chunk.verify_with(&chunk_hasher))
+                })
             })
-            .buffered(num_chunk_buffers)
+            .buffered(hash_buffers)
             .filter_map(|result| {
                 // Filter unique chunks to be compressed
-                let (offset, verified) = result.expect("error while hashing chunk");
-                let (unique, chunk_index) = if unique_chunks.contains_key(verified.hash()) {
-                    (false, *unique_chunks.get(verified.hash()).unwrap())
-                } else {
-                    let chunk_index = unique_chunk_index;
-                    unique_chunks.insert(verified.hash().clone(), chunk_index);
-                    unique_chunk_index += 1;
-                    (true, chunk_index)
-                };
+                let (source_index, offset, verified) = result.expect("error while hashing chunk");
+                processed_size += verified.len() as u64;
+                progress.set(processed_size);
+                let mut dedup_key = verified.hash().clone();
+                dedup_key.truncate(dedup_hash_length);
+                let (unique, chunk_index) =
+                    if let Some(&chunk_index) = unique_chunks.get(&dedup_key) {
+                        (false, chunk_index)
+                    } else {
+                        let chunk_index = unique_chunk_index;
+                        unique_chunks.insert(dedup_key, chunk_index);
+                        if !seen_chunks.insert(verified.hash().clone()) {
+                            seen_source_size += verified.len() as u64;
+                        }
+                        unique_chunk_index += 1;
+                        (true, chunk_index)
+                    };
                 // Store a pointer (as index) to unique chunk index for each chunk
-                chunk_order.push(chunk_index);
+                chunk_orders[source_index].push(chunk_index);
                 future::ready(if unique {
-                    Some((chunk_index, offset, verified))
+                    Some((chunk_index, offset, processed_size, verified))
                 } else {
                     None
                 })
             })
-            .map(|(chunk_index, offset, verified)| {
+            .map(|(chunk_index, offset, processed, verified)| {
+                // Chunks which are unlikely to compress (media, encrypted data) are stored as is
+                let skip_compression = compression.is_some()
+                    && matches!(
+                        max_compress_entropy,
+                        Some(max) if estimate_entropy(verified.data()) > max
+                    );
                 tokio::task::spawn_blocking(move || {
                     // Compress each chunk
-                    let compressed = verified
-                        .chunk()
-                        .clone()
-                        .compress(compression)
-                        .expect("compress chunk");
-                    (chunk_index, offset, verified, compressed)
+                    let compressed = if skip_compression {
+                        None
+                    } else {
+                        Some(
+                            verified
+                                .chunk()
+                                .clone()
+                                .compress(compression)
+                                .expect("compress chunk"),
+                        )
+                    };
+                    let crc = if chunk_crc {
+                        verified.chunk().crc32c()
+                    } else {
+                        0
+                    };
+                    (chunk_index, offset, processed, verified, compressed, crc)
                 })
             })
-            .buffered(num_chunk_buffers);
+            .buffered(compress_buffers);
 
         while let Some(result) = chunk_stream.next().await {
-            let (index, offset, verified, compressed) = result.context("Error compressing")?;
+            let (index, offset, processed, verified, compressed, crc32c) =
+                result.context("Error compressing")?;
             let chunk_len = verified.len();
-            let use_uncompressed = compressed.len() >= chunk_len;
+            unique_source_size += chunk_len as u64;
+            if let Some(check) = dedup_check {
+                // Chunks arrive in the order they were read, so processed tells how much of
+                // the input has been read when this chunk was found.
+                if processed >= check.check_after {
+                    dedup_check = None;
+                    let ratio = 1.0 - unique_source_size as f64 / processed as f64;
+                    if ratio < check.min_ratio {
+                        warn!(
+                            "Only {:.1}% of the first {} was deduplicated, consider using plain compression instead",
+                            ratio * 100.0,
+                            human_size!(processed)
+                        );
+                        bail!(
+                            "Dedup ratio {:.1}% is below the minimum of {:.1}%",
+                            ratio * 100.0,
+                            check.min_ratio * 100.0
+                        );
+                    }
+                }
+            }
+            // Keep the compressed data only if it's smaller
+            let compressed = compressed.filter(|compressed| compressed.len() < chunk_len);
+            if compressed.is_none() {
+                uncompressed_size += chunk_len as u64;
+            }
             debug!(
                 "Chunk {}, '{}', offset: {}, size: {}, {}",
                 index,
                 verified.hash(),
                 offset,
                 human_size!(chunk_len),
-                if use_uncompressed {
-                    "left uncompressed".to_owned()
-                } else {
-                    format!("compressed to: {}", human_size!(compressed.len()))
+                match &compressed {
+                    None => "left uncompressed".to_owned(),
+                    Some(compressed) => format!("compressed to: {}", human_size!(compressed.len())),
                 },
             );
             let (mut hash, chunk) = verified.into_parts();
-            let use_data = if use_uncompressed {
-                chunk.data()
-            } else {
-                compressed.data()
+            let use_data = match &compressed {
+                None => chunk.data(),
+                Some(compressed) => compressed.data(),
             };
             hash.truncate(hash_length);
+            if !stored_hashes.insert(hash.clone()) {
+                bail!(
+                    "Chunk {} and a different chunk share the stored hash '{}', use a longer hash length",
+                    index,
+                    hash
+                );
+            }
 
             // Store a descriptor which refers to the compressed data
             archive_chunks.push(dict::ChunkDescriptor {
                 checksum: hash.to_vec(),
-                source_size: chunk_len as u32,
+                source_size: size_to_u32(chunk_len, "Chunk size")?,
                 archive_offset,
-                archive_size: use_data.len() as u32,
+                archive_size: size_to_u32(use_data.len(), "Compressed chunk size")?,
+                crc32c,
             });
             archive_offset += use_data.len() as u64;
+            // The probability only grows with more chunks, so fail as soon as it's too high
+            if opts.strict_hash_length {
+                let probability = collision_probability(archive_chunks.len(), hash_length);
+                if probability > MAX_COLLISION_PROBABILITY {
+                    bail!(Warning::HashCollision {
+                        probability,
+                        unique_chunks: archive_chunks.len(),
+                        hash_length,
+                    }
+                    .to_string());
+                }
+            }
 
             // Write the compressed chunk to temp file
             temp_file
@@ -133,133 +539,2584 @@ where
                 .context("Failed to write to temp file")?;
         }
     }
-    Ok((
-        source_hasher.finalize().to_vec(),
+    // Make sure all writes have completed before the temp file is read back
+    temp_file
+        .flush()
+        .await
+        .context("Failed to write to temp file")?;
+    progress.finish();
+    for (source_size, expected_size) in source_sizes.iter().zip(input_sizes) {
+        if let Some(expected_size) = expected_size {
+            if source_size < expected_size {
+                bail!(
+                    "Input ended after {} of expected {}",
+                    human_size!(*source_size),
+                    human_size!(*expected_size)
+                );
+            }
+        }
+    }
+    let mut warnings = Vec::new();
+    let collision_probability = collision_probability(unique_chunk_index, hash_length);
+    if collision_probability > MAX_COLLISION_PROBABILITY {
+        let warning = Warning::HashCollision {
+            probability: collision_probability,
+            unique_chunks: unique_chunk_index,
+            hash_length,
+        };
+        if opts.strict_hash_length {
+            bail!(warning.to_string());
+        }
+        warn!("{}", warning);
+        warnings.push(warning);
+    }
+    // Most of the data not getting smaller makes compressing it a waste of time
+    if compression.is_some() && uncompressed_size > 0 && uncompressed_size * 2 > unique_source_size
+    {
+        let warning = Warning::IncompressibleData {
+            uncompressed_size,
+            unique_source_size,
+        };
+        warn!("{}", warning);
+        warnings.push(warning);
+    }
+    let sources = source_hashers
+        .into_iter()
+        .zip(source_sizes)
+        .zip(chunk_orders)
+        .map(|((source_hasher, source_size), chunk_order)| {
+            let (source_hash, source_checkpoints) = source_hasher.finalize();
+            ChunkedSource {
+                source_hash: source_hash.to_vec(),
+                source_checkpoints,
+                source_size,
+                chunk_order,
+            }
+        })
+        .collect();
+    Ok(Chunked {
+        sources,
         archive_chunks,
-        source_size,
-        chunk_order,
-    ))
+        seen_source_size,
+        collision_probability,
+        warnings,
+    })
+}
+
+/// Summary of a compressed archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    /// Path of the archive.
+    pub output: PathBuf,
+    /// Size of the source.
+    pub source_size: u64,
+    /// Size of the archive, including header.
+    pub archive_size: u64,
+    /// Number of chunks in source.
+    pub total_chunks: usize,
+    /// Number of unique chunks in source.
+    pub unique_chunks: usize,
+    /// Size of the unique chunks which were already found in earlier inputs.
+    pub seen_source_size: u64,
+    /// Estimated probability of a chunk hash collision.
+    pub collision_probability: f64,
+    /// Time spent building the archive.
+    pub elapsed: Duration,
+    /// Warnings found while building the archive, in the order they were logged.
+    pub warnings: Vec<Warning>,
+}
+
+impl Summary {
+    /// Source size relative to archive size.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.archive_size == 0 {
+            return 0.0;
+        }
+        self.source_size as f64 / self.archive_size as f64
+    }
+}
+
+pub fn print_summary(summary: &Summary) {
+    info!(
+        "Compressed {} into {} ({} of {} chunks unique, ratio {:.2}) in {:.1}s",
+        human_size!(summary.source_size),
+        summary.output.display(),
+        summary.unique_chunks,
+        summary.total_chunks,
+        summary.compression_ratio(),
+        summary.elapsed.as_secs_f64()
+    );
+}
+
+/// Where to write the archive(s).
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// Write a single archive to the given file.
+    File(PathBuf),
+    /// Write one archive per input into the given directory, named after the input file.
+    Dir(PathBuf),
+}
+
+/// How to handle an input which is a symbolic link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Compress the file the link points to.
+    Follow,
+    /// Store the link itself, which clone recreates.
+    Store,
+    /// Leave the input out.
+    Skip,
+    /// Fail with an error.
+    Refuse,
 }
 
 #[derive(Debug, Clone)]
 pub struct Options {
     pub force_create: bool,
 
-    // Use stdin if input not given
-    pub input: Option<PathBuf>,
-    pub output: PathBuf,
-    pub temp_file: PathBuf,
+    // Use stdin if no inputs given
+    pub inputs: Vec<PathBuf>,
+    pub symlinks: SymlinkPolicy,
+    pub output: Output,
     pub hash_length: usize,
+    // Length of the chunk hash used to find duplicate chunks in memory, at least hash_length
+    pub dedup_hash_length: usize,
     pub chunker_config: chunker::Config,
     pub compression: Option<Compression>,
-    pub num_chunk_buffers: usize,
+    // Number of chunks hashed simultaneously. Hashing is cheap compared to compression, so
+    // this may well exceed the number of chunks compressed simultaneously.
+    pub hash_buffers: usize,
+    // Number of chunks compressed simultaneously
+    pub compress_buffers: usize,
+    pub dedup_check: Option<DedupCheck>,
+    pub chunk_order: ChunkOrder,
+    pub chunk_hasher: ChunkHasher,
+    // Store a checkpoint of the source checksum every given number of bytes
+    pub source_checkpoint_interval: Option<u64>,
+    // Split the chunk data into part files of at most this size
+    pub split_size: Option<u64>,
+    // Write all chunk data to a single part file, leaving only the dictionary in the output
+    pub detach_chunk_data: bool,
+    // Fail instead of warn if a chunk hash collision is likely
+    pub strict_hash_length: bool,
+    // Compress all inputs into a single archive with one source per input
+    pub combine: bool,
+    // Store a CRC32C of each chunk
+    pub chunk_crc: bool,
+    // Merge a last chunk shorter than the min chunk size into the chunk before it
+    pub merge_short_tail: bool,
+    // Store the rebuild order as runs of the same chunk
+    pub compact_rebuild_order: bool,
+    // Read back and verify the written archive
+    pub verify: bool,
+    // Directory of the temp file, the directory of the output if not given
+    pub temp_dir: Option<PathBuf>,
+    // Store chunks with a higher estimated entropy (bits per byte) without compressing them
+    pub max_compress_entropy: Option<f64>,
+    // Pad the chunk data so that every chunk starts at a multiple of this many bytes
+    pub chunk_data_alignment: Option<u32>,
+    // MIME type or other hint of the content stored along with the input's file name
+    pub content_type: Option<String>,
+    // Encrypt the dictionary of the archive using this key, leaving the chunk data as is
+    pub dictionary_key: Option<DictionaryKey>,
+    pub progress_format: ProgressFormat,
 }
-pub async fn compress_cmd(opts: Options) -> Result<()> {
-    let chunker_config = opts.chunker_config.clone();
-    let compression = opts.compression;
-    let mut output_file = std::fs::OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create(opts.force_create)
-        .truncate(opts.force_create)
-        .create_new(!opts.force_create)
-        .open(&opts.output)
-        .context(format!(
-            "Failed to open output file {}",
-            opts.output.display()
-        ))?;
 
-    let (source_hash, archive_chunks, source_size, chunk_order) =
-        if let Some(input_path) = opts.input {
-            chunk_input(
-                File::open(&input_path).await.context(format!(
-                    "Failed to open input file {}",
-                    input_path.display()
-                ))?,
-                &chunker_config,
-                compression,
-                &opts.temp_file,
-                opts.hash_length,
-                opts.num_chunk_buffers,
-            )
-            .await?
-        } else if !atty::is(atty::Stream::Stdin) {
-            // Read source from stdin
-            chunk_input(
-                tokio::io::stdin(),
-                &chunker_config,
-                compression,
-                &opts.temp_file,
-                opts.hash_length,
-                opts.num_chunk_buffers,
-            )
-            .await?
-        } else {
-            return Err(anyhow!("Missing input"));
-        };
+fn size_to_u32(size: usize, name: &str) -> Result<u32> {
+    u32::try_from(size).map_err(|_| {
+        anyhow!(
+            "{} ({}) exceeds the maximum of {}",
+            name,
+            human_size!(size),
+            human_size!(u32::MAX)
+        )
+    })
+}
 
-    let chunker_params = match opts.chunker_config {
+// Values of the buzhash table to store in the archive, none for the default table.
+fn buzhash_table_values(config: &chunker::FilterConfig) -> Vec<u32> {
+    config
+        .buzhash_table
+        .as_ref()
+        .map(|table| table.values().to_vec())
+        .unwrap_or_default()
+}
+
+pub(crate) fn chunker_parameters(
+    config: &chunker::Config,
+    hash_length: usize,
+) -> Result<dict::ChunkerParameters> {
+    Ok(match config {
         chunker::Config::BuzHash(hash_config) => dict::ChunkerParameters {
             chunk_filter_bits: hash_config.filter_bits.bits(),
-            min_chunk_size: hash_config.min_chunk_size as u32,
-            max_chunk_size: hash_config.max_chunk_size as u32,
-            rolling_hash_window_size: hash_config.window_size as u32,
-            chunk_hash_length: opts.hash_length as u32,
+            min_chunk_size: size_to_u32(hash_config.min_chunk_size, "Min chunk size")?,
+            max_chunk_size: size_to_u32(hash_config.max_chunk_size, "Max chunk size")?,
+            rolling_hash_window_size: size_to_u32(hash_config.window_size, "Window size")?,
+            chunk_hash_length: hash_length as u32,
             chunking_algorithm: dict::chunker_parameters::ChunkingAlgorithm::Buzhash as i32,
+            buzhash_table: buzhash_table_values(hash_config),
         },
         chunker::Config::RollSum(hash_config) => dict::ChunkerParameters {
             chunk_filter_bits: hash_config.filter_bits.bits(),
-            min_chunk_size: hash_config.min_chunk_size as u32,
-            max_chunk_size: hash_config.max_chunk_size as u32,
-            rolling_hash_window_size: hash_config.window_size as u32,
-            chunk_hash_length: opts.hash_length as u32,
+            min_chunk_size: size_to_u32(hash_config.min_chunk_size, "Min chunk size")?,
+            max_chunk_size: size_to_u32(hash_config.max_chunk_size, "Max chunk size")?,
+            rolling_hash_window_size: size_to_u32(hash_config.window_size, "Window size")?,
+            chunk_hash_length: hash_length as u32,
             chunking_algorithm: dict::chunker_parameters::ChunkingAlgorithm::Rollsum as i32,
+            buzhash_table: vec![],
         },
         chunker::Config::FixedSize(chunk_size) => dict::ChunkerParameters {
             min_chunk_size: 0,
             chunk_filter_bits: 0,
             rolling_hash_window_size: 0,
-            max_chunk_size: chunk_size as u32,
-            chunk_hash_length: opts.hash_length as u32,
+            max_chunk_size: size_to_u32(*chunk_size, "Fixed chunk size")?,
+            chunk_hash_length: hash_length as u32,
             chunking_algorithm: dict::chunker_parameters::ChunkingAlgorithm::FixedSize as i32,
+            buzhash_table: vec![],
         },
+        chunker::Config::Custom(id, hash_config) => dict::ChunkerParameters {
+            chunk_filter_bits: hash_config.filter_bits.bits(),
+            min_chunk_size: size_to_u32(hash_config.min_chunk_size, "Min chunk size")?,
+            max_chunk_size: size_to_u32(hash_config.max_chunk_size, "Max chunk size")?,
+            rolling_hash_window_size: size_to_u32(hash_config.window_size, "Window size")?,
+            chunk_hash_length: hash_length as u32,
+            chunking_algorithm: *id as i32,
+            buzhash_table: buzhash_table_values(hash_config),
+        },
+    })
+}
+
+// Entry to store for the input given the symlink policy, none if the input is skipped.
+// A regular file which is a hard link of an earlier input, as found in `files`, is stored as a
+// hard link to it.
+fn input_entry(
+    input_path: &Path,
+    symlinks: SymlinkPolicy,
+    files: &mut HashMap<(u64, u64), String>,
+) -> Result<Option<SourceEntry>> {
+    let metadata = std::fs::symlink_metadata(input_path).context(format!(
+        "Failed to open input file {}",
+        input_path.display()
+    ))?;
+    if metadata.file_type().is_symlink() {
+        match symlinks {
+            SymlinkPolicy::Follow => {}
+            SymlinkPolicy::Store => {
+                let target = std::fs::read_link(input_path).context(format!(
+                    "Failed to read symbolic link {}",
+                    input_path.display()
+                ))?;
+                let target = target.to_str().ok_or_else(|| {
+                    anyhow!(
+                        "Target of symbolic link {} is not valid UTF-8",
+                        input_path.display()
+                    )
+                })?;
+                return Ok(Some(SourceEntry::Symlink(target.to_string())));
+            }
+            SymlinkPolicy::Skip => return Ok(None),
+            SymlinkPolicy::Refuse => bail!(
+                "Input {} is a symbolic link, use --symlinks to follow, store or skip it",
+                input_path.display()
+            ),
+        }
+    }
+    // Directory trees have no representation in the archive format.
+    let metadata = std::fs::metadata(input_path).context(format!(
+        "Failed to open input file {}",
+        input_path.display()
+    ))?;
+    if metadata.is_dir() {
+        bail!(
+            "Input {} is a directory, only files, devices and streams can be compressed",
+            input_path.display()
+        );
+    }
+    if let Some(id) = file_id(&metadata) {
+        if let Some(name) = files.get(&id) {
+            return Ok(Some(SourceEntry::HardLink(name.clone())));
+        }
+        let name = input_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Input {} has no valid file name", input_path.display()))?;
+        files.insert(id, name.to_string());
+    }
+    Ok(Some(SourceEntry::File))
+}
+
+// Device and inode of a regular file with more than one hard link.
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    if metadata.is_file() && metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Sort the chunk descriptors by hash and update their archive offsets and the rebuild order
+// accordingly. Returns the (offset, size) of each chunk in the temp file, in the new order.
+fn sort_chunks_by_hash(chunked: &mut Chunked) -> Vec<(u64, u64)> {
+    let mut new_order: Vec<usize> = (0..chunked.archive_chunks.len()).collect();
+    new_order.sort_by(|&a, &b| {
+        chunked.archive_chunks[a]
+            .checksum
+            .cmp(&chunked.archive_chunks[b].checksum)
+    });
+    let mut new_index = vec![0; new_order.len()];
+    for (index, &old_index) in new_order.iter().enumerate() {
+        new_index[old_index] = index;
+    }
+    let mut temp_file_chunks = Vec::with_capacity(new_order.len());
+    let mut archive_chunks = Vec::with_capacity(new_order.len());
+    let mut archive_offset = 0;
+    for old_index in new_order {
+        let mut descriptor = chunked.archive_chunks[old_index].clone();
+        temp_file_chunks.push((
+            descriptor.archive_offset,
+            u64::from(descriptor.archive_size),
+        ));
+        descriptor.archive_offset = archive_offset;
+        archive_offset += u64::from(descriptor.archive_size);
+        archive_chunks.push(descriptor);
+    }
+    chunked.archive_chunks = archive_chunks;
+    for source in chunked.sources.iter_mut() {
+        for index in source.chunk_order.iter_mut() {
+            *index = new_index[*index];
+        }
+    }
+    temp_file_chunks
+}
+
+// Split the chunk data into parts of at most split size. A part only holds whole chunks, a chunk
+// bigger than the split size gets a part of its own. Any padding before a chunk ends the part
+// before it.
+fn split_into_parts(archive_chunks: &[dict::ChunkDescriptor], split_size: u64) -> Vec<u64> {
+    let mut part_sizes = Vec::new();
+    let mut part_start = 0;
+    let mut part_end = 0;
+    for descriptor in archive_chunks {
+        let chunk_end = descriptor.archive_offset + u64::from(descriptor.archive_size);
+        if part_end > part_start && chunk_end - part_start > split_size {
+            part_sizes.push(descriptor.archive_offset - part_start);
+            part_start = descriptor.archive_offset;
+        }
+        part_end = chunk_end;
+    }
+    if part_end > part_start {
+        part_sizes.push(part_end - part_start);
+    }
+    part_sizes
+}
+
+fn align_up(offset: u64, alignment: u64) -> u64 {
+    (offset + alignment - 1) / alignment * alignment
+}
+
+// Move every chunk up to the next multiple of the alignment, leaving a gap of padding before it.
+fn align_chunks(archive_chunks: &mut [dict::ChunkDescriptor], alignment: u64) {
+    let mut archive_offset = 0;
+    for descriptor in archive_chunks {
+        descriptor.archive_offset = align_up(archive_offset, alignment);
+        archive_offset = descriptor.archive_offset + u64::from(descriptor.archive_size);
+    }
+}
+
+/// Build an archive header with the chunk data starting at a multiple of the alignment. The
+/// returned header is padded with zeros up to the start of the chunk data. The dictionary is
+/// encrypted if a key is given.
+pub fn build_aligned_header(
+    dictionary: &dict::ChunkDictionary,
+    alignment: Option<u64>,
+    key: Option<&DictionaryKey>,
+) -> std::io::Result<Vec<u8>> {
+    let build = |chunk_data_offset| match key {
+        Some(key) => bitar::header::build_encrypted(dictionary, chunk_data_offset, key),
+        None => bitar::header::build(dictionary, chunk_data_offset),
+    };
+    let header = build(None)?;
+    let alignment = match alignment {
+        Some(alignment) => alignment,
+        None => return Ok(header),
     };
+    // The header size doesn't depend on the chunk data offset stored in it
+    let chunk_data_offset = align_up(header.len() as u64, alignment);
+    let mut header = build(Some(chunk_data_offset))?;
+    header.resize(chunk_data_offset as usize, 0);
+    Ok(header)
+}
+
+/// Path of a part file of a split archive.
+pub fn part_path(archive: &Path, index: usize) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(format!(".part{}", index));
+    PathBuf::from(path)
+}
 
-    // Build the final archive
+// Writes chunk data to the part files, moving on to the next part when one is full.
+struct PartWriter {
+    archive: PathBuf,
+    part_sizes: Vec<u64>,
+    force_create: bool,
+    index: usize,
+    written: u64,
+    file: Option<File>,
+}
+
+impl PartWriter {
+    fn new(archive: &Path, part_sizes: Vec<u64>, force_create: bool) -> Self {
+        Self {
+            archive: archive.to_path_buf(),
+            part_sizes,
+            force_create,
+            index: 0,
+            written: 0,
+            file: None,
+        }
+    }
+}
+
+impl AsyncWrite for PartWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        while this.written == this.part_sizes[this.index] {
+            // Flush the full part before moving on to the next
+            if let Some(file) = &mut this.file {
+                ready!(Pin::new(file).poll_flush(cx))?;
+            }
+            this.index += 1;
+            this.written = 0;
+            this.file = None;
+            if this.index >= this.part_sizes.len() {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+        }
+        if this.file.is_none() {
+            this.file = Some(File::from_std(
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(this.force_create)
+                    .truncate(this.force_create)
+                    .create_new(!this.force_create)
+                    .open(part_path(&this.archive, this.index))?,
+            ));
+        }
+        let size = std::cmp::min(buf.len() as u64, this.part_sizes[this.index] - this.written);
+        let written =
+            ready!(Pin::new(this.file.as_mut().unwrap()).poll_write(cx, &buf[..size as usize]))?;
+        this.written += written as u64;
+        Poll::Ready(Ok(written))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().file {
+            Some(file) => Pin::new(file).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().file {
+            Some(file) => Pin::new(file).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+// Sink hashing everything written to it.
+struct HashWriter(Blake2b512);
+
+impl AsyncWrite for HashWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.get_mut().0.update(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// An archive ready to be written, the header followed by the chunk data which is buffered in
+// a temp file since the header can't be built until all chunks are found.
+struct AssembledArchive {
+    header: Vec<u8>,
+    temp_file: PathBuf,
+    // Offset and size in the temp file of each chunk, in archive order. The temp file is
+    // copied as is if not set.
+    temp_file_chunks: Option<Vec<(u64, u64)>>,
+    // Chunks are padded with zeros up to a multiple of the alignment, requires temp file chunks
+    alignment: Option<u64>,
+    part_sizes: Vec<u64>,
+}
+
+impl AssembledArchive {
+    // Write the whole archive to the given sink. Only for archives which aren't split.
+    async fn write_to<W>(&self, sink: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        sink.write_all(&self.header).await?;
+        self.write_chunk_data(sink).await
+    }
+
+    async fn write_chunk_data<W>(&self, sink: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut temp_file = File::open(&self.temp_file).await?;
+        if let Some(temp_file_chunks) = &self.temp_file_chunks {
+            let mut written = 0;
+            for &(offset, size) in temp_file_chunks {
+                if let Some(alignment) = self.alignment {
+                    let padding = align_up(written, alignment) - written;
+                    tokio::io::copy(&mut tokio::io::repeat(0).take(padding), sink).await?;
+                    written += padding;
+                }
+                temp_file.seek(SeekFrom::Start(offset)).await?;
+                tokio::io::copy(&mut (&mut temp_file).take(size), sink).await?;
+                written += size;
+            }
+        } else {
+            tokio::io::copy(&mut temp_file, sink).await?;
+        }
+        sink.flush().await
+    }
+}
+
+// Temp file next to the output by default, to stay on the same file system. A temp file in
+// another directory is named after the output and process to not collide with other runs.
+fn temp_file_path(output: &Path, temp_dir: Option<&Path>) -> PathBuf {
+    match (temp_dir, output.file_name()) {
+        (Some(temp_dir), Some(file_name)) => {
+            let mut temp_name = std::ffi::OsString::from(".");
+            temp_name.push(file_name);
+            temp_name.push(format!(".{}.tmp", std::process::id()));
+            temp_dir.join(temp_name)
+        }
+        _ => Path::with_extension(output, ".tmp"),
+    }
+}
+
+// How the size of an input is found, as told by its metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputKind {
+    // Regular file of the given length
+    File(u64),
+    // Block device, which has no length in its metadata
+    BlockDevice,
+    // Anything else is read as a stream of unknown size
+    Stream,
+}
+
+impl InputKind {
+    fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        if metadata.is_file() {
+            Self::File(metadata.len())
+        } else if is_block_dev(metadata) {
+            Self::BlockDevice
+        } else {
+            Self::Stream
+        }
+    }
+}
+
+// Size of an input, if known before reading it. Block devices have no length in their
+// metadata, their size is found by seeking to the end of the device.
+async fn input_size<R>(kind: InputKind, input: &mut R) -> Result<Option<u64>, std::io::Error>
+where
+    R: tokio::io::AsyncSeek + Unpin,
+{
+    match kind {
+        InputKind::File(size) => Ok(Some(size)),
+        InputKind::BlockDevice => {
+            let size = input.seek(SeekFrom::End(0)).await?;
+            input.seek(SeekFrom::Start(0)).await?;
+            Ok(Some(size))
+        }
+        InputKind::Stream => Ok(None),
+    }
+}
+
+#[cfg(unix)]
+fn is_block_dev(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_block_device()
+}
+
+#[cfg(not(unix))]
+fn is_block_dev(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+// Open an input file and find its size.
+async fn open_input(input_path: &Path) -> Result<(LocalFile, Option<u64>)> {
+    let mut input_file = LocalFile::open(input_path).await?;
+    let size = async {
+        let kind = InputKind::from_metadata(&input_file.metadata().await?);
+        input_size(kind, &mut input_file).await
+    }
+    .await
+    .context(format!(
+        "Failed to get size of input {}",
+        input_path.display()
+    ))?;
+    if let Some(size) = size {
+        info!("Input {} is {}", input_path.display(), human_size!(size));
+    }
+    Ok((input_file, size))
+}
+
+// File system entry of each input, skipped inputs are left out.
+fn input_entries(opts: &Options) -> Result<Vec<(&Path, SourceEntry)>> {
+    let mut files = HashMap::new();
+    let mut entries = Vec::new();
+    for input_path in &opts.inputs {
+        match input_entry(input_path, opts.symlinks, &mut files)? {
+            Some(entry) => entries.push((input_path.as_path(), entry)),
+            None => warn!("Skipping symbolic link {}", input_path.display()),
+        }
+    }
+    Ok(entries)
+}
+
+// Pair each input (None for stdin) with its entry and the archive it should be written to.
+// Skipped inputs are left out.
+fn output_paths(opts: &Options) -> Result<Vec<(Option<&Path>, SourceEntry, PathBuf)>> {
+    let entries = input_entries(opts)?;
+    match &opts.output {
+        Output::File(output) => {
+            if opts.inputs.len() > 1 {
+                bail!("An output directory (or combining the inputs) is required when compressing multiple inputs");
+            }
+            if opts.inputs.is_empty() {
+                return Ok(vec![(None, SourceEntry::File, output.clone())]);
+            }
+            Ok(entries
+                .into_iter()
+                .map(|(input_path, entry)| (Some(input_path), entry, output.clone()))
+                .collect())
+        }
+        Output::Dir(output_dir) => {
+            if opts.inputs.is_empty() {
+                bail!("An input file is required when compressing to an output directory");
+            }
+            let mut outputs: Vec<(Option<&Path>, SourceEntry, PathBuf)> = Vec::new();
+            for (input_path, entry) in entries {
+                let mut file_name = input_path
+                    .file_name()
+                    .ok_or_else(|| anyhow!("Input {} has no file name", input_path.display()))?
+                    .to_os_string();
+                file_name.push(".cba");
+                let output = output_dir.join(file_name);
+                if outputs.iter().any(|(_, _, other)| *other == output) {
+                    bail!("Multiple inputs would be written to {}", output.display());
+                }
+                outputs.push((Some(input_path), entry, output));
+            }
+            std::fs::create_dir_all(output_dir).context(format!(
+                "Failed to create output directory {}",
+                output_dir.display()
+            ))?;
+            Ok(outputs)
+        }
+    }
+}
+
+// Build the header of the archive holding the chunked inputs, with the given source names and entries.
+async fn assemble_archive(
+    opts: &Options,
+    chunker_params: &dict::ChunkerParameters,
+    names: Vec<(String, SourceEntry)>,
+    chunked: &mut Chunked,
+    temp_file: &Path,
+) -> Result<AssembledArchive> {
+    let mut temp_file_chunks = match opts.chunk_order {
+        ChunkOrder::SourceOffset => None,
+        ChunkOrder::Hash => Some(sort_chunks_by_hash(chunked)),
+    };
+    let alignment = opts.chunk_data_alignment.map(u64::from);
+    if let Some(alignment) = alignment {
+        // The chunks are back to back in the temp file, copy them one by one to pad them
+        temp_file_chunks.get_or_insert_with(|| {
+            chunked
+                .archive_chunks
+                .iter()
+                .map(|descriptor| {
+                    (
+                        descriptor.archive_offset,
+                        u64::from(descriptor.archive_size),
+                    )
+                })
+                .collect()
+        });
+        align_chunks(&mut chunked.archive_chunks, alignment);
+    }
+
+    let part_sizes = match opts.split_size {
+        Some(split_size) => split_into_parts(&chunked.archive_chunks, split_size),
+        None if opts.detach_chunk_data => split_into_parts(&chunked.archive_chunks, u64::MAX),
+        None => Vec::new(),
+    };
+
+    // Build the final archive. The first source is described by the top level fields of the
+    // dictionary, so that it's what gets cloned by readers unaware of multiple sources.
+    // The rebuild order is stored either as is or as runs of the same chunk.
+    let rebuild_order = |source: &ChunkedSource| {
+        let order: Vec<u32> = source
+            .chunk_order
+            .iter()
+            .map(|&index| index as u32)
+            .collect();
+        if opts.compact_rebuild_order {
+            (Vec::new(), Some(dict::RebuildOrderRuns::encode(&order)))
+        } else {
+            (order, None)
+        }
+    };
+    let mut sources = chunked.sources.iter_mut().zip(names);
+    let (first_source, (source_name, source_entry)) = sources.next().expect("at least one input");
+    let additional_sources = sources
+        .map(|(source, (name, entry))| {
+            let (rebuild_order, rebuild_order_runs) = rebuild_order(source);
+            dict::Source {
+                name,
+                source_checksum: std::mem::take(&mut source.source_hash),
+                source_total_size: source.source_size,
+                rebuild_order,
+                rebuild_order_runs,
+                source_entry: entry.into(),
+            }
+        })
+        .collect();
+    let (first_rebuild_order, rebuild_order_runs) = rebuild_order(first_source);
+    let mut archive = AssembledArchive {
+        header: Vec::new(),
+        temp_file: temp_file.to_path_buf(),
+        temp_file_chunks,
+        alignment,
+        part_sizes,
+    };
+    // Hash the chunk data as it will be written, an extra pass over the temp file since the
+    // checksum is stored in the header written before the data.
+    let mut chunk_data_hasher = HashWriter(Blake2b512::new());
+    archive
+        .write_chunk_data(&mut chunk_data_hasher)
+        .await
+        .context("Failed to read temp file")?;
+    let chunk_data_checksum = HashSum::from(&chunk_data_hasher.0.finalize()[..]);
     let file_header = dict::ChunkDictionary {
-        rebuild_order: chunk_order.iter().map(|&index| index as u32).collect(),
+        rebuild_order: first_rebuild_order,
         application_version: PKG_VERSION.to_string(),
-        chunk_descriptors: archive_chunks,
-        source_checksum: source_hash,
+        chunk_descriptors: std::mem::take(&mut chunked.archive_chunks),
+        source_checksum: std::mem::take(&mut first_source.source_hash),
         chunk_compression: Some(opts.compression.into()),
-        source_total_size: source_size,
-        chunker_params: Some(chunker_params),
+        source_total_size: first_source.source_size,
+        chunker_params: Some(chunker_params.clone()),
+        source_entry: source_entry.into(),
+        source_checkpoints: first_source.source_checkpoints.take().map(Into::into),
+        chunk_data_part_sizes: archive.part_sizes.clone(),
+        source_name,
+        additional_sources,
+        chunk_crc32c: opts.chunk_crc,
+        rebuild_order_runs,
+        chunk_data_checksum: Some(dict::ChunkDataChecksum::blake2b_512(&chunk_data_checksum)),
+        chunk_data_alignment: opts.chunk_data_alignment.unwrap_or(0),
+        content_type: opts.content_type.clone().unwrap_or_default(),
     };
-    let header_buf = bitar::header::build(&file_header, None)?;
-    output_file.write_all(&header_buf).context(format!(
-        "Failed to write header to output file {}",
-        opts.output.display()
-    ))?;
+    // The header file of a split archive holds no chunk data to align
+    archive.header = build_aligned_header(
+        &file_header,
+        alignment.filter(|_| archive.part_sizes.is_empty()),
+        opts.dictionary_key.as_ref(),
+    )?;
+    Ok(archive)
+}
+
+// Name, entry type, size if known and reader of an input.
+type NamedInput<T> = (String, SourceEntry, Option<u64>, T);
+
+// Compress inputs into an archive. The inputs are only read once from start to end, so any
+// stream works and the source size doesn't have to be known in advance. Each input is stored
+// as a source of the archive with the given name and entry, a single input is stored unnamed. An
+// input with a known size is used as a hint and is required to be read to its end.
+async fn compress_input<T>(
+    opts: &Options,
+    chunker_params: &dict::ChunkerParameters,
+    inputs: Vec<NamedInput<T>>,
+    output: &Path,
+    seen_chunks: &mut HashSet<HashSum>,
+) -> Result<Summary>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let started = Instant::now();
+    let temp_file = temp_file_path(output, opts.temp_dir.as_deref());
+    let mut output_file = OpenOptions::new()
+        .write(true)
+        .create(opts.force_create)
+        .truncate(opts.force_create)
+        .create_new(!opts.force_create)
+        .open(output)
+        .await
+        .context(format!("Failed to open output file {}", output.display()))?;
+
+    let mut names = Vec::with_capacity(inputs.len());
+    let mut input_sizes = Vec::with_capacity(inputs.len());
+    let mut readers = Vec::with_capacity(inputs.len());
+    for (name, entry, size, reader) in inputs {
+        names.push((name, entry));
+        input_sizes.push(size);
+        readers.push(reader);
+    }
+    // Total size is only known if the size of every input is
+    let total_size = input_sizes.iter().copied().sum::<Option<u64>>();
+    let mut warnings = Vec::new();
+    if let Some(warning) =
+        total_size.and_then(|size| chunk_count_warning(&opts.chunker_config, size))
     {
-        let mut temp_file = std::fs::File::open(&opts.temp_file).context(format!(
-            "Failed to open temp file {}",
-            opts.temp_file.display()
-        ))?;
-        std::io::copy(&mut temp_file, &mut output_file).context(format!(
-            "Failed to copy from temp file {} to output file {}",
-            opts.temp_file.display(),
-            opts.output.display()
+        warn!("{}", warning);
+        warnings.push(warning);
+    }
+    let mut progress = Progress::new(opts.progress_format, "compress", total_size);
+    let mut chunked = match chunk_input(
+        readers,
+        &input_sizes,
+        opts,
+        &temp_file,
+        seen_chunks,
+        &mut progress,
+    )
+    .await
+    {
+        Ok(chunked) => chunked,
+        Err(err) => {
+            // Don't leave a partial temp file or an empty output behind
+            drop(output_file);
+            let _ = std::fs::remove_file(&temp_file);
+            let _ = std::fs::remove_file(output);
+            return Err(err);
+        }
+    };
+
+    let total_chunks = chunked
+        .sources
+        .iter()
+        .map(|source| source.chunk_order.len())
+        .sum();
+    let source_size = chunked
+        .sources
+        .iter()
+        .map(|source| source.source_size)
+        .sum();
+    let unique_chunks = chunked.archive_chunks.len();
+    warnings.append(&mut chunked.warnings);
+    let archive = assemble_archive(opts, chunker_params, names, &mut chunked, &temp_file).await?;
+    if archive.part_sizes.is_empty() {
+        archive.write_to(&mut output_file).await.context(format!(
+            "Failed to write archive to output file {}",
+            output.display()
         ))?;
+    } else {
+        output_file
+            .write_all(&archive.header)
+            .await
+            .context(format!(
+                "Failed to write header to output file {}",
+                output.display()
+            ))?;
+        let mut part_writer =
+            PartWriter::new(output, archive.part_sizes.clone(), opts.force_create);
+        archive
+            .write_chunk_data(&mut part_writer)
+            .await
+            .context(format!(
+                "Failed to copy from temp file to output file {}",
+                output.display()
+            ))?;
     }
-    std::fs::remove_file(&opts.temp_file).context(format!(
+    std::fs::remove_file(&temp_file).context(format!(
         "Failed to remove temporary file {}",
-        opts.temp_file.display()
+        temp_file.display()
     ))?;
+    let archive_size = output_file
+        .metadata()
+        .await
+        .context(format!("Failed to read size of {}", output.display()))?
+        .len()
+        + archive.part_sizes.iter().sum::<u64>();
     drop(output_file);
     {
         // Print archive info
-        let reader = IoReader::new(File::open(opts.output).await?);
-        info_cmd::print_archive_reader(reader).await?;
+        let reader = LocalFile::open_archive(output).await?;
+        info_cmd::print_archive_reader(reader, opts.dictionary_key.as_ref()).await?;
+    }
+    if opts.verify {
+        verify_output(output, &opts.chunk_hasher, opts.dictionary_key.as_ref())
+            .await
+            .context(format!("Failed to verify {}", output.display()))?;
+        info!("Verified {}", output.display());
+    }
+    Ok(Summary {
+        output: output.to_path_buf(),
+        source_size,
+        archive_size,
+        total_chunks,
+        unique_chunks,
+        seen_source_size: chunked.seen_source_size,
+        collision_probability: chunked.collision_probability,
+        elapsed: started.elapsed(),
+        warnings,
+    })
+}
+
+// Read back a written archive, including any parts, and verify it.
+async fn verify_output(
+    output: &Path,
+    hasher: &ChunkHasher,
+    key: Option<&DictionaryKey>,
+) -> Result<()> {
+    let archive = info_cmd::init_archive(LocalFile::open_archive(output).await?, key).await?;
+    let parts = LocalFile::open_parts(output, archive.chunk_data_part_sizes().len()).await?;
+    verify_cmd::verify_archive(&mut archive.with_part_readers(parts)?, hasher).await
+}
+
+// Name of the source read from the input, only the file name to not leak the directory.
+// Empty for stdin.
+fn input_name(input_path: Option<&Path>) -> String {
+    input_path
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// Compress all inputs into a single archive holding one source per input.
+async fn compress_combined(
+    opts: &Options,
+    chunker_params: &dict::ChunkerParameters,
+) -> Result<Vec<Summary>> {
+    let output = match &opts.output {
+        Output::File(output) => output,
+        Output::Dir(_) => bail!("Combined inputs are written to a single output file"),
+    };
+    if opts.inputs.is_empty() {
+        bail!("Input files are required when combining inputs");
+    }
+    let entries = input_entries(opts)?;
+    if entries.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut inputs: Vec<NamedInput<Box<dyn AsyncRead + Unpin + Send>>> =
+        Vec::with_capacity(entries.len());
+    for (input_path, entry) in entries {
+        let name = input_name(Some(input_path));
+        if name.is_empty() {
+            bail!("Input {} has no file name", input_path.display());
+        }
+        if inputs.iter().any(|(other, _, _, _)| *other == name) {
+            bail!("Multiple inputs are named {}", name);
+        }
+        if entry != SourceEntry::File {
+            // A link is stored without any chunks
+            inputs.push((name, entry, None, Box::new(tokio::io::empty())));
+            continue;
+        }
+        let (input_file, size) = open_input(input_path).await?;
+        inputs.push((name, entry, size, Box::new(input_file)));
+    }
+    let summary = compress_input(opts, chunker_params, inputs, output, &mut HashSet::new()).await?;
+    Ok(vec![summary])
+}
+
+pub async fn compress_cmd(opts: Options) -> Result<Vec<Summary>> {
+    // Chunk sizes are stored as u32 in the dictionary, validate before doing any work.
+    let chunker_params = chunker_parameters(&opts.chunker_config, opts.hash_length)?;
+    if opts.combine {
+        return compress_combined(&opts, &chunker_params).await;
+    }
+    let outputs = output_paths(&opts)?;
+    // Chunks of all inputs, used to estimate how well the inputs dedup against each other.
+    let mut seen_chunks = HashSet::new();
+    let mut summaries = Vec::with_capacity(outputs.len());
+    for (index, (input, entry, output)) in outputs.into_iter().enumerate() {
+        let summary = if entry != SourceEntry::File {
+            // A link is stored without any chunks
+            compress_input(
+                &opts,
+                &chunker_params,
+                vec![(input_name(input), entry, None, &[][..])],
+                &output,
+                &mut seen_chunks,
+            )
+            .await?
+        } else if let Some(input_path) = input {
+            let (input_file, size) = open_input(input_path).await?;
+            compress_input(
+                &opts,
+                &chunker_params,
+                vec![(input_name(Some(input_path)), entry, size, input_file)],
+                &output,
+                &mut seen_chunks,
+            )
+            .await?
+        } else if !atty::is(atty::Stream::Stdin) {
+            // Read source from stdin
+            compress_input(
+                &opts,
+                &chunker_params,
+                vec![(input_name(None), entry, None, tokio::io::stdin())],
+                &output,
+                &mut seen_chunks,
+            )
+            .await?
+        } else {
+            bail!("Missing input");
+        };
+        if index > 0 {
+            info!(
+                "{} of the chunk data was also found in earlier inputs",
+                human_size!(summary.seen_source_size)
+            );
+        }
+        summaries.push(summary);
+    }
+    Ok(summaries)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::clone_cmd;
+    use crate::progress::tests::SharedBuf;
+    use bitar::{archive_reader::IoReader, Archive, CloneOutput};
+    use blake2::{Blake2b512, Digest};
+
+    // Pseudo random test data, the same for the same seed.
+    pub(crate) fn random_data(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = 0x1234_5678_9abc_def1 ^ seed.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    // Options to compress the inputs using fixed size chunks and no compression.
+    pub(crate) fn test_options(inputs: Vec<PathBuf>, output: Output) -> Options {
+        Options {
+            force_create: false,
+            inputs,
+            symlinks: SymlinkPolicy::Follow,
+            output,
+            hash_length: HashSum::MAX_LEN,
+            dedup_hash_length: HashSum::MAX_LEN,
+            chunker_config: chunker::Config::FixedSize(4096),
+            compression: None,
+            hash_buffers: 2,
+            compress_buffers: 2,
+            dedup_check: None,
+            chunk_order: ChunkOrder::default(),
+            chunk_hasher: ChunkHasher::default(),
+            source_checkpoint_interval: None,
+            split_size: None,
+            detach_chunk_data: false,
+            strict_hash_length: false,
+            combine: false,
+            chunk_crc: false,
+            merge_short_tail: false,
+            compact_rebuild_order: false,
+            verify: false,
+            temp_dir: None,
+            max_compress_entropy: None,
+            chunk_data_alignment: None,
+            content_type: None,
+            dictionary_key: None,
+            progress_format: ProgressFormat::Plain,
+        }
+    }
+
+    async fn chunk_with_dedup_check(input: &[u8]) -> Result<u64> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut opts = test_options(vec![], Output::File(temp_dir.path().join("output.cba")));
+        opts.dedup_check = Some(DedupCheck {
+            check_after: 256 * 1024,
+            min_ratio: 0.1,
+        });
+        let chunked = chunk_input(
+            vec![input],
+            &[None],
+            &opts,
+            &temp_dir.path().join("output.tmp"),
+            &mut HashSet::new(),
+            &mut Progress::new(ProgressFormat::Plain, "compress", None),
+        )
+        .await?;
+        Ok(chunked.sources[0].source_size)
+    }
+
+    async fn unpack(archive_path: &Path) -> Vec<u8> {
+        unpack_reader(LocalFile::open_archive(archive_path).await.unwrap()).await
+    }
+
+    async fn unpack_reader<T>(reader: IoReader<T>) -> Vec<u8>
+    where
+        T: AsyncRead + tokio::io::AsyncSeek + Unpin + Send,
+    {
+        let mut archive = Archive::try_init(reader).await.unwrap();
+        let mut output_buf = vec![];
+        {
+            let mut output = CloneOutput::new(
+                std::io::Cursor::new(&mut output_buf),
+                archive.build_source_index(),
+            );
+            let mut chunk_stream = archive.chunk_stream(output.chunks());
+            while let Some(result) = chunk_stream.next().await {
+                let verified = result.unwrap().decompress().unwrap().verify().unwrap();
+                output.feed(&verified).await.unwrap();
+            }
+        }
+        output_buf
+    }
+
+    #[test]
+    fn chunker_parameters_max_chunk_size_overflow() {
+        let config = chunker::Config::RollSum(chunker::FilterConfig {
+            filter_bits: chunker::FilterBits::from_size(64 * 1024),
+            min_chunk_size: 16 * 1024,
+            max_chunk_size: u32::MAX as usize + 1,
+            window_size: 64,
+            buzhash_table: None,
+        });
+        let err = chunker_parameters(&config, HashSum::MAX_LEN).unwrap_err();
+        assert!(err.to_string().starts_with("Max chunk size"));
+    }
+
+    #[test]
+    fn chunker_parameters_fixed_size_overflow() {
+        let config = chunker::Config::FixedSize(u32::MAX as usize + 1);
+        let err = chunker_parameters(&config, HashSum::MAX_LEN).unwrap_err();
+        assert!(err.to_string().starts_with("Fixed chunk size"), "{}", err);
+        let config = chunker::Config::FixedSize(u32::MAX as usize);
+        let params = chunker_parameters(&config, HashSum::MAX_LEN).unwrap();
+        assert_eq!(params.max_chunk_size, u32::MAX);
+    }
+
+    #[tokio::test]
+    async fn directory_input_rejected() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("output.cba");
+        let err = compress_cmd(test_options(
+            vec![temp_dir.path().to_path_buf()],
+            Output::File(output.clone()),
+        ))
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("is a directory"));
+        assert!(!output.exists());
+    }
+
+    // Compress a symbolic link to a file using the policy, returning the output path.
+    #[cfg(unix)]
+    async fn compress_symlink(dir: &Path, symlinks: SymlinkPolicy) -> Result<PathBuf> {
+        std::fs::write(dir.join("target.img"), random_data(64 * 1024, 0)).unwrap();
+        std::os::unix::fs::symlink("target.img", dir.join("link.img")).unwrap();
+        let output = dir.join("output.cba");
+        let mut opts = test_options(vec![dir.join("link.img")], Output::File(output.clone()));
+        opts.symlinks = symlinks;
+        compress_cmd(opts).await?;
+        Ok(output)
+    }
+
+    #[cfg(unix)]
+    async fn open_archive(path: &Path) -> Archive<bitar::archive_reader::IoReader<LocalFile>> {
+        Archive::try_init(LocalFile::open_archive(path).await.unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[cfg(unix)]
+    async fn clone_archive(archive: &Path, output: &Path) {
+        clone_cmd::clone_cmd(clone_cmd::Options {
+            force_create: false,
+            input_archive: clone_cmd::InputArchive::Local(archive.to_path_buf()),
+            header_checksum: None,
+            output: output.to_path_buf(),
+            seed_stdin: false,
+            stdin_seed_checksum: None,
+            seed_files: vec![],
+            seed_output: false,
+            verify_output: false,
+            num_chunk_buffers: 1,
+            ordered_write_buffer: None,
+            atomic: false,
+            chunk_hasher: ChunkHasher::default(),
+            crc_verify: None,
+            concurrent_seeds: false,
+            seed_archives: vec![],
+            codec_dictionaries: bitar::CodecDictionaries::default(),
+            plan_only: None,
+            chunk_retries: 0,
+            progress_format: crate::progress::ProgressFormat::Plain,
+            write_buffer: None,
+            strict_seeds: false,
+            prefetch_chunks: None,
+            chunk_data: None,
+            allow_size_mismatch: false,
+            sync: false,
+            chunk_store: None,
+            seed_scan_limit: None,
+            fallback: None,
+            cache_fallback: false,
+            dictionary_key: None,
+            contiguous: false,
+        })
+        .await
+        .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlink_input_followed() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = compress_symlink(temp_dir.path(), SymlinkPolicy::Follow)
+            .await
+            .unwrap();
+        let archive = open_archive(&output).await;
+        assert_eq!(archive.source_entry(), &SourceEntry::File);
+        assert_eq!(
+            archive.source_checksum(),
+            &HashSum::from(&Blake2b512::digest(random_data(64 * 1024, 0))[..])
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlink_input_stored_and_recreated_by_clone() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = compress_symlink(temp_dir.path(), SymlinkPolicy::Store)
+            .await
+            .unwrap();
+        let archive = open_archive(&output).await;
+        assert_eq!(
+            archive.source_entry(),
+            &SourceEntry::Symlink("target.img".to_string())
+        );
+        assert_eq!(archive.total_chunks(), 0);
+
+        let cloned = temp_dir.path().join("cloned.img");
+        clone_archive(&output, &cloned).await;
+        assert_eq!(
+            std::fs::read_link(&cloned).unwrap(),
+            PathBuf::from("target.img")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hard_linked_inputs_recreated_by_clone() {
+        use std::os::unix::fs::MetadataExt;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input1 = temp_dir.path().join("a.img");
+        let input2 = temp_dir.path().join("b.img");
+        std::fs::write(&input1, random_data(64 * 1024, 0)).unwrap();
+        std::fs::hard_link(&input1, &input2).unwrap();
+        let output_dir = temp_dir.path().join("out");
+        compress_cmd(test_options(
+            vec![input1, input2],
+            Output::Dir(output_dir.clone()),
+        ))
+        .await
+        .unwrap();
+        let archive = open_archive(&output_dir.join("b.img.cba")).await;
+        assert_eq!(
+            archive.source_entry(),
+            &SourceEntry::HardLink("a.img".to_string())
+        );
+        assert_eq!(archive.total_chunks(), 0);
+
+        let cloned = temp_dir.path().join("cloned");
+        std::fs::create_dir(&cloned).unwrap();
+        clone_archive(&output_dir.join("a.img.cba"), &cloned.join("a.img")).await;
+        clone_archive(&output_dir.join("b.img.cba"), &cloned.join("b.img")).await;
+        let a = std::fs::metadata(cloned.join("a.img")).unwrap();
+        let b = std::fs::metadata(cloned.join("b.img")).unwrap();
+        assert_eq!((a.dev(), a.ino()), (b.dev(), b.ino()));
+        assert_eq!(
+            std::fs::read(cloned.join("b.img")).unwrap(),
+            random_data(64 * 1024, 0)
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlink_input_skipped() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = compress_symlink(temp_dir.path(), SymlinkPolicy::Skip)
+            .await
+            .unwrap();
+        assert!(!output.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlink_input_refused() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let err = compress_symlink(temp_dir.path(), SymlinkPolicy::Refuse)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is a symbolic link"));
+        assert!(!temp_dir.path().join("output.cba").exists());
+    }
+
+    #[tokio::test]
+    async fn multiple_inputs_to_output_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input1 = temp_dir.path().join("input1.img");
+        let input2 = temp_dir.path().join("input2.img");
+        let data1 = random_data(100 * 1024, 0);
+        let mut data2 = data1[..50 * 1024].to_vec();
+        data2.extend(random_data(30 * 1024, 0).iter().rev());
+        std::fs::write(&input1, &data1).unwrap();
+        std::fs::write(&input2, &data2).unwrap();
+        let output_dir = temp_dir.path().join("out");
+        compress_cmd(test_options(
+            vec![input1, input2],
+            Output::Dir(output_dir.clone()),
+        ))
+        .await
+        .unwrap();
+        assert_eq!(unpack(&output_dir.join("input1.img.cba")).await, data1);
+        assert_eq!(unpack(&output_dir.join("input2.img.cba")).await, data2);
+    }
+
+    // Stored chunk checksums in the order of their archive offset.
+    async fn checksums_by_offset(archive_path: &Path) -> Vec<HashSum> {
+        let archive = Archive::try_init(LocalFile::open_archive(archive_path).await.unwrap())
+            .await
+            .unwrap();
+        let mut descriptors = archive.chunk_descriptors().to_vec();
+        descriptors.sort_by_key(|descriptor| descriptor.archive_offset);
+        descriptors
+            .into_iter()
+            .map(|descriptor| descriptor.checksum)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn chunk_orders_unpack_identically() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input.img");
+        let mut data = random_data(64 * 1024, 0);
+        data.extend(random_data(32 * 1024, 0).iter().rev());
+        data.extend(data[..16 * 1024].to_vec());
+        std::fs::write(&input, &data).unwrap();
+
+        let source_order = temp_dir.path().join("source.cba");
+        compress_cmd(test_options(
+            vec![input.clone()],
+            Output::File(source_order.clone()),
+        ))
+        .await
+        .unwrap();
+        let hash_order = temp_dir.path().join("hash.cba");
+        let mut opts = test_options(vec![input], Output::File(hash_order.clone()));
+        opts.chunk_order = ChunkOrder::Hash;
+        compress_cmd(opts).await.unwrap();
+
+        assert_eq!(unpack(&source_order).await, data);
+        assert_eq!(unpack(&hash_order).await, data);
+
+        // Source offset order is the order in which chunks first appear in the source.
+        let mut first_seen = Vec::new();
+        for chunk in data.chunks(4096) {
+            let checksum = HashSum::from(&Blake2b512::digest(chunk)[..]);
+            if !first_seen.contains(&checksum) {
+                first_seen.push(checksum);
+            }
+        }
+        assert_eq!(checksums_by_offset(&source_order).await, first_seen);
+        let mut sorted = first_seen;
+        sorted.sort_by(|a, b| a.slice().cmp(b.slice()));
+        assert_eq!(checksums_by_offset(&hash_order).await, sorted);
+    }
+
+    #[tokio::test]
+    async fn aligned_chunk_data_unpacks() {
+        const ALIGNMENT: u64 = 4096;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input.img");
+        let mut data = random_data(64 * 1024, 0);
+        data.extend(data[..20 * 1024].to_vec());
+        std::fs::write(&input, &data).unwrap();
+
+        for &chunk_order in &[ChunkOrder::SourceOffset, ChunkOrder::Hash] {
+            let output = temp_dir.path().join("aligned.cba");
+            let mut opts = test_options(vec![input.clone()], Output::File(output.clone()));
+            opts.chunker_config = chunker::Config::FixedSize(3000);
+            opts.chunk_order = chunk_order;
+            opts.chunk_data_alignment = Some(ALIGNMENT as u32);
+            opts.force_create = true;
+            compress_cmd(opts).await.unwrap();
+
+            let mut archive = Archive::try_init(LocalFile::open_archive(&output).await.unwrap())
+                .await
+                .unwrap();
+            assert_eq!(archive.chunk_data_alignment(), Some(ALIGNMENT));
+            assert_eq!(archive.chunk_data_offset() % ALIGNMENT, 0);
+            for descriptor in archive.chunk_descriptors() {
+                assert_eq!(descriptor.archive_offset % ALIGNMENT, 0);
+            }
+            assert_eq!(
+                &archive.hash_chunk_data().await.unwrap(),
+                archive.chunk_data_checksum().unwrap()
+            );
+            assert_eq!(unpack(&output).await, data);
+        }
+
+        // Parts of a split archive start on a chunk, so offsets within every part are aligned
+        let output = temp_dir.path().join("split.cba");
+        let mut opts = test_options(vec![input], Output::File(output.clone()));
+        opts.chunker_config = chunker::Config::FixedSize(3000);
+        opts.chunk_data_alignment = Some(ALIGNMENT as u32);
+        opts.split_size = Some(16 * 1024);
+        opts.verify = true;
+        compress_cmd(opts).await.unwrap();
+        let archive = Archive::try_init(LocalFile::open_archive(&output).await.unwrap())
+            .await
+            .unwrap();
+        assert!(archive.chunk_data_part_sizes().len() > 1);
+        let mut part_start = 0;
+        for part_size in archive.chunk_data_part_sizes() {
+            assert_eq!(part_start % ALIGNMENT, 0);
+            part_start += part_size;
+        }
+        for descriptor in archive.chunk_descriptors() {
+            assert_eq!(
+                (descriptor.archive_offset - archive.chunk_data_offset()) % ALIGNMENT,
+                0
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn buffer_counts_give_identical_archives() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input.img");
+        let block = random_data(64 * 1024, 0);
+        let mut data = random_data(256 * 1024, 0);
+        data.extend(&block);
+        data.extend(random_data(128 * 1024, 0));
+        data.extend(&block);
+        std::fs::write(&input, &data).unwrap();
+
+        let mut archives = Vec::new();
+        for &(hash_buffers, compress_buffers) in &[(1, 1), (1, 8), (8, 1), (16, 3)] {
+            let output = temp_dir
+                .path()
+                .join(format!("{}-{}.cba", hash_buffers, compress_buffers));
+            let mut opts = test_options(vec![input.clone()], Output::File(output.clone()));
+            opts.chunker_config = chunker::Config::BuzHash(chunker::FilterConfig {
+                filter_bits: chunker::FilterBits::from_size(8 * 1024),
+                min_chunk_size: 2 * 1024,
+                max_chunk_size: 32 * 1024,
+                window_size: 48,
+                buzhash_table: None,
+            });
+            opts.compression = Some(Compression::brotli(6).unwrap());
+            opts.hash_buffers = hash_buffers;
+            opts.compress_buffers = compress_buffers;
+            compress_cmd(opts).await.unwrap();
+            archives.push(std::fs::read(&output).unwrap());
+        }
+        assert!(archives.windows(2).all(|pair| pair[0] == pair[1]));
+        let output = temp_dir.path().join("1-1.cba");
+        assert_eq!(unpack(&output).await, data);
+    }
+
+    #[tokio::test]
+    async fn round_robin_reads_streams_concurrently() {
+        use futures_util::stream;
+        // The first stream never yields, the second is still read ahead while waiting on it
+        let streams: Vec<Box<dyn Stream<Item = u32> + Unpin>> = vec![
+            Box::new(stream::pending()),
+            Box::new(stream::iter(vec![1, 2, 3])),
+        ];
+        let mut merged = RoundRobin::new(streams, 2);
+        assert!(futures_util::FutureExt::now_or_never(merged.next()).is_none());
+        assert_eq!(merged.streams[1].buffered, vec![1, 2]);
+
+        // Items are taken in turn however the streams are ready
+        let merged = RoundRobin::new(
+            vec![
+                stream::iter(vec![1, 2, 3]).boxed(),
+                stream::iter(vec![10]).boxed(),
+                stream::iter(vec![20, 21]).boxed(),
+            ],
+            4,
+        );
+        assert_eq!(merged.collect::<Vec<_>>().await, vec![1, 10, 20, 2, 21, 3]);
+    }
+
+    // Reader delaying every read, reading a small piece at a time.
+    struct SlowRead<'a> {
+        data: &'a [u8],
+        delay: Option<Pin<Box<tokio::time::Sleep>>>,
+    }
+
+    impl AsyncRead for SlowRead<'_> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            use std::future::Future;
+            let this = self.get_mut();
+            let delay = this
+                .delay
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(Duration::from_millis(1))));
+            ready!(delay.as_mut().poll(cx));
+            this.delay = None;
+            let size = buf.remaining().min(this.data.len()).min(1000);
+            buf.put_slice(&this.data[..size]);
+            this.data = &this.data[size..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Reader returning pieces of random size, often after yielding first.
+    struct JitterRead<'a> {
+        data: &'a [u8],
+        state: u64,
+    }
+
+    impl JitterRead<'_> {
+        fn next_random(&mut self) -> u64 {
+            self.state = self
+                .state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            self.state >> 33
+        }
+    }
+
+    impl AsyncRead for JitterRead<'_> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            if this.next_random() & 1 == 0 {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let size = (1 + this.next_random() as usize % 3000)
+                .min(buf.remaining())
+                .min(this.data.len());
+            buf.put_slice(&this.data[..size]);
+            this.data = &this.data[size..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn source_hash_independent_of_scheduling() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut opts = test_options(vec![], Output::File(temp_dir.path().join("output.cba")));
+        opts.chunker_config = chunker::Config::BuzHash(chunker::FilterConfig {
+            filter_bits: chunker::FilterBits(10),
+            min_chunk_size: 256,
+            max_chunk_size: 4 * 1024,
+            window_size: 32,
+            buzhash_table: None,
+        });
+        opts.hash_buffers = 32;
+        opts.compress_buffers = 32;
+        opts.source_checkpoint_interval = Some(10_000);
+        let data = random_data(256 * 1024, 0);
+        let inputs = [&data[..192 * 1024], &data[64 * 1024..]];
+        let expected: Vec<(Vec<u8>, Option<SourceCheckpoints>)> = inputs
+            .iter()
+            .map(|input| {
+                let mut hasher = SourceHasher::new(opts.source_checkpoint_interval);
+                hasher.update(input);
+                let (hash, checkpoints) = hasher.finalize();
+                (hash.to_vec(), checkpoints)
+            })
+            .collect();
+        for seed in 0..8 {
+            let readers = inputs
+                .iter()
+                .enumerate()
+                .map(|(index, input)| JitterRead {
+                    data: input,
+                    state: seed * 2 + index as u64,
+                })
+                .collect();
+            let chunked = chunk_input(
+                readers,
+                &[None, None],
+                &opts,
+                &temp_dir.path().join("output.tmp"),
+                &mut HashSet::new(),
+                &mut Progress::new(ProgressFormat::Plain, "compress", None),
+            )
+            .await
+            .unwrap();
+            let hashes: Vec<(Vec<u8>, Option<SourceCheckpoints>)> = chunked
+                .sources
+                .into_iter()
+                .map(|source| (source.source_hash, source.source_checkpoints))
+                .collect();
+            assert_eq!(hashes, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn combined_layout_independent_of_read_speed() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data = random_data(144 * 1024, 0);
+        let common = &data[..32 * 1024];
+        let a = [&data[32 * 1024..96 * 1024], common].concat();
+        let b = [common, &data[96 * 1024..]].concat();
+        let mut opts = test_options(vec![], Output::File(temp_dir.path().join("unused.cba")));
+        opts.compression = Some(Compression::brotli(6).unwrap());
+        let chunker_params = chunker_parameters(&opts.chunker_config, opts.hash_length).unwrap();
+
+        let mut archives = Vec::new();
+        for (index, &(slow_input, buffers)) in [(0, 1), (1, 8), (0, 16), (1, 2)].iter().enumerate()
+        {
+            let output = temp_dir.path().join(format!("{}.cba", index));
+            opts.hash_buffers = buffers;
+            opts.compress_buffers = buffers;
+            let inputs: Vec<NamedInput<Box<dyn AsyncRead + Unpin + Send>>> = [&a, &b]
+                .iter()
+                .enumerate()
+                .map(|(input, data)| {
+                    let reader: Box<dyn AsyncRead + Unpin + Send> = if input == slow_input {
+                        Box::new(SlowRead {
+                            data: &data[..],
+                            delay: None,
+                        })
+                    } else {
+                        Box::new(&data[..])
+                    };
+                    (format!("input{}", input), SourceEntry::File, None, reader)
+                })
+                .collect();
+            compress_input(&opts, &chunker_params, inputs, &output, &mut HashSet::new())
+                .await
+                .unwrap();
+            archives.push(std::fs::read(&output).unwrap());
+        }
+        assert!(archives.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[tokio::test]
+    async fn non_default_compression_window() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input.img");
+        let data = random_data(4 * 1024, 0).repeat(16);
+        std::fs::write(&input, &data).unwrap();
+        let output = temp_dir.path().join("window.cba");
+        let mut opts = test_options(vec![input], Output::File(output.clone()));
+        opts.chunker_config = chunker::Config::FixedSize(64 * 1024);
+        opts.compression = Some(Compression::brotli(9).unwrap().with_window_log(14).unwrap());
+        compress_cmd(opts).await.unwrap();
+
+        let archive = Archive::try_init(LocalFile::open_archive(&output).await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(archive.chunk_compression().unwrap().window_log(), Some(14));
+        assert!(archive.compressed_size() < data.len() as u64);
+        assert_eq!(unpack(&output).await, data);
+    }
+
+    #[tokio::test]
+    async fn compress_unbounded_stream() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("stream.cba");
+        let opts = test_options(vec![], Output::File(output.clone()));
+        let chunker_params = chunker_parameters(&opts.chunker_config, opts.hash_length).unwrap();
+        // A pipe can't be seeked and gives no hint of its size
+        let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+        let block = random_data(300 * 1024, 0);
+        let writer_task = tokio::spawn(async move {
+            let mut written = Vec::new();
+            for i in 0..20 {
+                let data = if i % 3 == 0 {
+                    block.clone()
+                } else {
+                    block.iter().map(|b| b.wrapping_add(i as u8)).collect()
+                };
+                writer.write_all(&data).await.unwrap();
+                written.extend(data);
+            }
+            written
+        });
+        let summary = compress_input(
+            &opts,
+            &chunker_params,
+            vec![(String::new(), SourceEntry::File, None, reader)],
+            &output,
+            &mut HashSet::new(),
+        )
+        .await
+        .unwrap();
+        let written = writer_task.await.unwrap();
+        assert_eq!(summary.source_size, written.len() as u64);
+        assert_eq!(unpack(&output).await, written);
+    }
+
+    #[tokio::test]
+    async fn input_of_known_size_is_chunked_to_its_end() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("device.cba");
+        let mut opts = test_options(vec![], Output::File(output.clone()));
+        let chunker_params = chunker_parameters(&opts.chunker_config, opts.hash_length).unwrap();
+        // Stands in for a block device, which is read as a stream of the size given by seeking
+        let device = random_data(2 * 1024 * 1024 + 123, 0);
+        let summary = compress_input(
+            &opts,
+            &chunker_params,
+            vec![(
+                String::new(),
+                SourceEntry::File,
+                Some(device.len() as u64),
+                &device[..],
+            )],
+            &output,
+            &mut HashSet::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.source_size, device.len() as u64);
+        assert_eq!(unpack(&output).await, device);
+
+        // A device which ends early is an error rather than a truncated archive
+        opts.force_create = true;
+        let err = compress_input(
+            &opts,
+            &chunker_params,
+            vec![(
+                String::new(),
+                SourceEntry::File,
+                Some(device.len() as u64 + 1),
+                &device[..],
+            )],
+            &output,
+            &mut HashSet::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("Input ended after"), "{}", err);
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn archive_written_to_async_sink() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let temp_file = temp_dir.path().join("output.tmp");
+        let mut opts = test_options(vec![], Output::File(temp_dir.path().join("output.cba")));
+        opts.chunk_order = ChunkOrder::Hash;
+        let input = [random_data(100 * 1024, 0), vec![0; 64 * 1024]].concat();
+        let chunker_params = chunker_parameters(&opts.chunker_config, opts.hash_length).unwrap();
+        let mut chunked = chunk_input(
+            vec![&input[..]],
+            &[None],
+            &opts,
+            &temp_file,
+            &mut HashSet::new(),
+            &mut Progress::new(ProgressFormat::Plain, "compress", None),
+        )
+        .await
+        .unwrap();
+        let archive = assemble_archive(
+            &opts,
+            &chunker_params,
+            vec![(String::new(), SourceEntry::File)],
+            &mut chunked,
+            &temp_file,
+        )
+        .await
+        .unwrap();
+        let mut sink: Vec<u8> = vec![];
+        archive.write_to(&mut sink).await.unwrap();
+
+        assert_eq!(
+            unpack_reader(IoReader::new(std::io::Cursor::new(sink))).await,
+            input
+        );
+    }
+
+    #[tokio::test]
+    async fn json_progress_ends_at_100_percent() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let opts = test_options(vec![], Output::File(temp_dir.path().join("output.cba")));
+        let input = random_data(1024 * 1024, 0);
+        let events = SharedBuf::default();
+        let mut progress = Progress::json(
+            "compress",
+            Some(input.len() as u64),
+            Box::new(events.clone()),
+        );
+        chunk_input(
+            vec![&input[..]],
+            &[Some(input.len() as u64)],
+            &opts,
+            &temp_dir.path().join("output.tmp"),
+            &mut HashSet::new(),
+            &mut progress,
+        )
+        .await
+        .unwrap();
+        let events = events.events();
+        assert!(!events.is_empty());
+        assert!(events
+            .iter()
+            .all(|event| event["task"] == "compress" && event["total"] == input.len()));
+        let last = events.last().unwrap();
+        assert_eq!(last["finished"], true);
+        assert_eq!(last["done"], input.len());
+        assert_eq!(last["percent"], 100.0);
+    }
+
+    #[tokio::test]
+    async fn short_tail_merged_into_previous_chunk() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut opts = test_options(vec![], Output::File(temp_dir.path().join("output.cba")));
+        opts.chunker_config = chunker::Config::BuzHash(chunker::FilterConfig {
+            filter_bits: chunker::FilterBits(10),
+            min_chunk_size: 512,
+            max_chunk_size: 8 * 1024,
+            window_size: 32,
+            buzhash_table: None,
+        });
+        opts.merge_short_tail = true;
+        // Common part ending at a chunk boundary, followed by tails differing in a few bytes
+        let data = random_data(64 * 1024, 0);
+        let offsets: Vec<u64> = opts
+            .chunker_config
+            .new_chunker(&data[..])
+            .map(|result| result.unwrap().0)
+            .collect()
+            .await;
+        let common = &data[..*offsets.last().unwrap() as usize];
+        let common_chunks = offsets.len() - 1;
+        let first = [common, &[1; 100][..]].concat();
+        let second = [common, &[2; 100][..]].concat();
+
+        let chunked = chunk_input(
+            vec![&first[..], &second[..]],
+            &[None, None],
+            &opts,
+            &temp_dir.path().join("output.tmp"),
+            &mut HashSet::new(),
+            &mut Progress::new(ProgressFormat::Plain, "compress", None),
+        )
+        .await
+        .unwrap();
+        let first_order = &chunked.sources[0].chunk_order;
+        let second_order = &chunked.sources[1].chunk_order;
+        // The tails are merged into the last chunk of the common part
+        assert_eq!(first_order.len(), common_chunks);
+        assert_eq!(second_order.len(), common_chunks);
+        assert_eq!(
+            first_order[..common_chunks - 1],
+            second_order[..common_chunks - 1]
+        );
+        assert_ne!(first_order.last(), second_order.last());
+        assert!(chunked
+            .archive_chunks
+            .iter()
+            .all(|chunk| chunk.source_size >= 512 && chunk.source_size <= 8 * 1024));
+        assert_eq!(
+            chunked.sources[0].source_size + chunked.sources[1].source_size,
+            2 * first.len() as u64
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn input_size_detection() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input.img");
+        std::fs::write(&input, random_data(4567, 0)).unwrap();
+        let (_, size) = open_input(&input).await.unwrap();
+        assert_eq!(size, Some(4567));
+        // A character device has no known size and is read as a stream
+        let (_, size) = open_input(Path::new("/dev/null")).await.unwrap();
+        assert_eq!(size, None);
+    }
+
+    // Input of the golden archive: unique data, a repeated part and zeros.
+    fn golden_input() -> Vec<u8> {
+        let mut input = random_data(48 * 1024, 1);
+        input.extend(input[4096..20 * 1024].to_vec());
+        input.extend(vec![0; 8 * 1024]);
+        input
+    }
+
+    // The archive built from a fixed input using fixed settings is compared to a committed
+    // reference, which pins the header layout and the order of the chunks. The reference is
+    // stored per version since the version is part of the header. Run with
+    // BITA_UPDATE_GOLDEN=1 to write the reference after an intended change.
+    #[tokio::test]
+    async fn golden_archive() {
+        let golden = PathBuf::from(format!(
+            "bitar/tests/resources/golden-{}-buzhash-none.cba",
+            PKG_VERSION.replace('.', "_")
+        ));
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input");
+        std::fs::write(&input, golden_input()).unwrap();
+        let output = temp_dir.path().join("golden.cba");
+        let mut opts = test_options(vec![input], Output::File(output.clone()));
+        opts.chunker_config = chunker::Config::BuzHash(chunker::FilterConfig {
+            filter_bits: chunker::FilterBits::from_size(4096),
+            min_chunk_size: 1024,
+            max_chunk_size: 16 * 1024,
+            window_size: 48,
+            buzhash_table: None,
+        });
+        opts.hash_length = 32;
+        compress_cmd(opts).await.unwrap();
+        if std::env::var_os("BITA_UPDATE_GOLDEN").is_some() {
+            std::fs::copy(&output, &golden).unwrap();
+        }
+        let expected = std::fs::read(&golden).unwrap_or_else(|err| {
+            panic!(
+                "{}: {}, run with BITA_UPDATE_GOLDEN=1 to create it",
+                golden.display(),
+                err
+            )
+        });
+        assert!(
+            std::fs::read(&output).unwrap() == expected,
+            "archive differs from {}, run with BITA_UPDATE_GOLDEN=1 if the change is intended",
+            golden.display()
+        );
+        assert_eq!(unpack(&golden).await, golden_input());
+    }
+
+    #[tokio::test]
+    async fn block_device_size_from_seek() {
+        // A block device reports a length of 0 in its metadata, its size is found by seeking
+        let mut input = std::io::Cursor::new(random_data(4567, 0));
+        input.set_position(123);
+        assert_eq!(
+            input_size(InputKind::BlockDevice, &mut input)
+                .await
+                .unwrap(),
+            Some(4567)
+        );
+        // Rewound to be read from the start
+        assert_eq!(input.position(), 0);
+        assert_eq!(
+            input_size(InputKind::File(0), &mut input).await.unwrap(),
+            Some(0)
+        );
+        assert_eq!(
+            input_size(InputKind::Stream, &mut input).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn input_name_and_content_type_round_trip() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("disk.img");
+        std::fs::write(&input, random_data(10 * 1024, 0)).unwrap();
+        let output = temp_dir.path().join("output.cba");
+        let mut opts = test_options(vec![input], Output::File(output.clone()));
+        opts.content_type = Some("application/x-raw-disk-image".to_string());
+        compress_cmd(opts).await.unwrap();
+        let archive = Archive::try_init(IoReader::new(File::open(&output).await.unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(archive.source_name(), "disk.img");
+        assert_eq!(archive.content_type(), Some("application/x-raw-disk-image"));
+        // Stdin has no name to record
+        assert_eq!(input_name(None), "");
+        let output = temp_dir.path().join("stdin.cba");
+        let opts = test_options(vec![], Output::File(output.clone()));
+        let chunker_params = chunker_parameters(&opts.chunker_config, opts.hash_length).unwrap();
+        compress_input(
+            &opts,
+            &chunker_params,
+            vec![(
+                input_name(None),
+                SourceEntry::File,
+                None,
+                &random_data(1024, 0)[..],
+            )],
+            &output,
+            &mut HashSet::new(),
+        )
+        .await
+        .unwrap();
+        let archive = Archive::try_init(IoReader::new(File::open(&output).await.unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(archive.source_name(), "");
+        assert_eq!(archive.content_type(), None);
+    }
+
+    #[tokio::test]
+    async fn summary_of_known_input() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input.img");
+        let output = temp_dir.path().join("output.cba");
+        let blocks = random_data(5 * 4096, 0);
+        let blocks: Vec<&[u8]> = blocks.chunks(4096).collect();
+        let source: Vec<u8> = [0, 1, 0, 2, 0, 1, 3, 4]
+            .iter()
+            .flat_map(|&index| blocks[index].iter().copied())
+            .collect();
+        std::fs::write(&input, &source).unwrap();
+        let summaries = compress_cmd(test_options(vec![input], Output::File(output.clone())))
+            .await
+            .unwrap();
+        assert_eq!(summaries.len(), 1);
+        let summary = &summaries[0];
+        assert_eq!(summary.output, output);
+        assert_eq!(summary.source_size, 8 * 4096);
+        assert_eq!(summary.total_chunks, 8);
+        assert_eq!(summary.unique_chunks, 5);
+        assert_eq!(
+            summary.archive_size,
+            std::fs::metadata(&output).unwrap().len()
+        );
+        // Uncompressed, the archive holds the 5 unique chunks and a header.
+        assert!(summary.archive_size > 5 * 4096);
+        assert!(summary.compression_ratio() > 1.0);
+    }
+
+    #[tokio::test]
+    async fn compact_rebuild_order_of_repeated_chunk() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // Unique blocks separated by long runs of zeros, like a sparse disk image
+        let mut source = vec![];
+        for block in random_data(4 * 1024, 0).chunks(1024) {
+            source.extend(block);
+            source.extend(vec![0; 3000 * 1024]);
+        }
+        let inputs = vec![
+            temp_dir.path().join("first.img"),
+            temp_dir.path().join("second.img"),
+        ];
+        std::fs::write(&inputs[0], &source).unwrap();
+        std::fs::write(&inputs[1], &source[1024..]).unwrap();
+        let mut archives = vec![];
+        for &compact in &[false, true] {
+            let output = temp_dir.path().join(format!("compact-{}.cba", compact));
+            let mut opts = test_options(inputs.clone(), Output::File(output.clone()));
+            opts.chunker_config = chunker::Config::FixedSize(1024);
+            opts.combine = true;
+            opts.compact_rebuild_order = compact;
+            compress_cmd(opts).await.unwrap();
+            assert_eq!(unpack(&output).await, source);
+            archives.push(
+                Archive::try_init(LocalFile::open_archive(&output).await.unwrap())
+                    .await
+                    .unwrap(),
+            );
+        }
+        let (plain, compact) = (&archives[0], &archives[1]);
+        assert!(compact.header_size() * 10 < plain.header_size());
+        assert_eq!(compact.total_chunks(), 4 * 3001);
+        let source_chunks = |archive: &Archive<_>| -> Vec<(u64, HashSum)> {
+            archive
+                .iter_source_chunks()
+                .map(|(offset, descriptor)| (offset, descriptor.checksum.clone()))
+                .collect()
+        };
+        assert_eq!(source_chunks(compact), source_chunks(plain));
+        // Chunks may be stored in any order, compare the chunks each source is rebuilt from
+        let additional_chunks = |archive: &Archive<_>| -> Vec<HashSum> {
+            archive.additional_sources()[0]
+                .rebuild_order
+                .iter()
+                .map(|&index| archive.chunk_descriptors()[index as usize].checksum.clone())
+                .collect()
+        };
+        assert_eq!(additional_chunks(compact), additional_chunks(plain));
+    }
+
+    #[test]
+    fn small_chunks_of_big_input_warning() {
+        const GIB: u64 = 1024 * 1024 * 1024;
+        let small_chunks = chunker::Config::BuzHash(chunker::FilterConfig {
+            filter_bits: chunker::FilterBits::from_size(1024),
+            min_chunk_size: 256,
+            max_chunk_size: 4096,
+            window_size: 64,
+            buzhash_table: None,
+        });
+        let message = chunk_count_warning(&small_chunks, 100 * GIB)
+            .unwrap()
+            .to_string();
+        assert!(message.contains("104857600 chunks"), "{}", message);
+        assert!(message.contains("128.0 KiB"), "{}", message);
+        assert!(chunk_count_warning(&small_chunks, GIB / 2).is_none());
+        assert!(chunk_count_warning(&chunker::Config::FixedSize(1 << 20), 100 * GIB).is_none());
+    }
+
+    #[tokio::test]
+    async fn short_hash_collision_warning() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input.img");
+        // 200 unique chunks and a 4 byte hash gives a collision probability of about 5e-6.
+        std::fs::write(&input, random_data(200 * 1024, 0)).unwrap();
+        let mut opts = test_options(
+            vec![input.clone()],
+            Output::File(temp_dir.path().join("short.cba")),
+        );
+        opts.chunker_config = chunker::Config::FixedSize(1024);
+        opts.hash_length = 4;
+        let summaries = compress_cmd(opts.clone()).await.unwrap();
+        assert!(summaries[0].collision_probability > MAX_COLLISION_PROBABILITY);
+        assert!(matches!(
+            summaries[0].warnings[..],
+            [Warning::HashCollision { hash_length: 4, .. }]
+        ));
+
+        opts.output = Output::File(temp_dir.path().join("strict.cba"));
+        opts.strict_hash_length = true;
+        let err = compress_cmd(opts).await.unwrap_err();
+        assert!(err.to_string().contains("chunk hash collision"));
+        // Fails on the first chunk making a collision too likely, not after the whole input
+        let limit = (1..)
+            .find(|&chunks| collision_probability(chunks, 4) > MAX_COLLISION_PROBABILITY)
+            .unwrap();
+        assert!(limit < 200);
+        assert!(
+            err.to_string()
+                .contains(&format!("with {} unique chunks", limit)),
+            "{}",
+            err
+        );
+
+        let mut opts = test_options(vec![input], Output::File(temp_dir.path().join("full.cba")));
+        opts.chunker_config = chunker::Config::FixedSize(1024);
+        opts.strict_hash_length = true;
+        let summaries = compress_cmd(opts).await.unwrap();
+        assert!(summaries[0].collision_probability < MAX_COLLISION_PROBABILITY);
+    }
+
+    #[tokio::test]
+    async fn incompressible_data_warning() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (random, repetitive) = (
+            temp_dir.path().join("random"),
+            temp_dir.path().join("zeros"),
+        );
+        std::fs::write(&random, random_data(64 * 1024, 0)).unwrap();
+        std::fs::write(&repetitive, vec![0; 64 * 1024]).unwrap();
+        let mut opts = test_options(
+            vec![random, repetitive],
+            Output::Dir(temp_dir.path().into()),
+        );
+        opts.compression = Some(Compression::brotli(6).unwrap());
+        let summaries = compress_cmd(opts).await.unwrap();
+        assert_eq!(
+            summaries[0].warnings,
+            vec![Warning::IncompressibleData {
+                uncompressed_size: 64 * 1024,
+                unique_source_size: 64 * 1024,
+            }]
+        );
+        assert_eq!(summaries[1].warnings, vec![]);
+    }
+
+    static COUNTING_CODEC_CALLS: std::sync::atomic::AtomicUsize =
+        std::sync::atomic::AtomicUsize::new(0);
+    const COUNTING_CODEC_ID: u32 = 0x636e_7421;
+
+    // Test codec counting the number of chunks compressed.
+    struct CountingCodec;
+
+    impl bitar::ChunkCodec for CountingCodec {
+        fn id(&self) -> u32 {
+            COUNTING_CODEC_ID
+        }
+        fn compress(&self, data: &[u8]) -> Result<Vec<u8>, bitar::CompressionError> {
+            COUNTING_CODEC_CALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            bitar::TestCodec.compress(data)
+        }
+        fn decompress(
+            &self,
+            data: &[u8],
+            size_hint: usize,
+        ) -> Result<Vec<u8>, bitar::CompressionError> {
+            bitar::TestCodec.decompress(data, size_hint)
+        }
+    }
+
+    #[tokio::test]
+    async fn high_entropy_chunks_skip_compression() {
+        use std::sync::atomic::Ordering;
+        bitar::register_codec(std::sync::Arc::new(CountingCodec));
+        let temp_dir = tempfile::tempdir().unwrap();
+        let compress = |data: Vec<u8>, name: &str| {
+            let input = temp_dir.path().join(name);
+            let output = temp_dir.path().join(format!("{}.cba", name));
+            std::fs::write(&input, &data).unwrap();
+            let mut opts = test_options(vec![input], Output::File(output.clone()));
+            opts.chunker_config = chunker::Config::FixedSize(4096);
+            opts.compression = Some(Compression::custom(COUNTING_CODEC_ID).unwrap());
+            opts.max_compress_entropy = Some(7.5);
+            async move {
+                COUNTING_CODEC_CALLS.store(0, Ordering::SeqCst);
+                compress_cmd(opts).await.unwrap();
+                assert_eq!(unpack(&output).await, data);
+                let archive = Archive::try_init(LocalFile::open_archive(&output).await.unwrap())
+                    .await
+                    .unwrap();
+                (
+                    COUNTING_CODEC_CALLS.load(Ordering::SeqCst),
+                    archive.compressed_size(),
+                )
+            }
+        };
+        // Random chunks are stored as is without trying to compress them
+        let random = random_data(64 * 1024, 0);
+        assert!(estimate_entropy(&random[..4096]) > 7.9);
+        assert_eq!(compress(random, "random").await, (0, 64 * 1024));
+
+        // Runs of repeated bytes, different in every chunk, are compressed
+        let repetitive: Vec<u8> = (0..64 * 1024u32)
+            .map(|i| (i / 64 % 7 + i / 4096) as u8)
+            .collect();
+        assert!(estimate_entropy(&repetitive[..4096]) < 3.0);
+        let (calls, compressed_size) = compress(repetitive, "repetitive").await;
+        assert_eq!(calls, 16);
+        assert!(compressed_size < 4 * 1024);
+    }
+
+    #[tokio::test]
+    async fn short_stored_hash_does_not_merge_chunks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let hasher = ChunkHasher::default();
+        let data = random_data(2048, 0);
+        let (a, mut b) = (data[..1024].to_vec(), data[1024..].to_vec());
+        // Distinct chunks are deduplicated on the full hash, not on the 1 byte stored hash
+        let input = temp_dir.path().join("input.img");
+        std::fs::write(&input, [&a[..], &b[..], &a[..]].concat()).unwrap();
+        let mut opts = test_options(
+            vec![input.clone()],
+            Output::File(temp_dir.path().join("a.cba")),
+        );
+        opts.chunker_config = chunker::Config::FixedSize(1024);
+        opts.hash_length = 1;
+        let a_hash = hasher.digest(&a);
+        if hasher.digest(&b).slice()[0] == a_hash.slice()[0] {
+            b[0] ^= 0xff;
+            std::fs::write(&input, [&a[..], &b[..], &a[..]].concat()).unwrap();
+        }
+        let summaries = compress_cmd(opts.clone()).await.unwrap();
+        assert_eq!(summaries[0].unique_chunks, 2);
+
+        // Distinct chunks sharing the stored hash are rejected rather than merged
+        for value in 0..=u16::MAX {
+            b[..2].copy_from_slice(&value.to_le_bytes());
+            if hasher.digest(&b).slice()[0] == a_hash.slice()[0] {
+                break;
+            }
+        }
+        assert_ne!(a, b);
+        std::fs::write(&input, [&a[..], &b[..]].concat()).unwrap();
+        opts.output = Output::File(temp_dir.path().join("b.cba"));
+        let err = compress_cmd(opts).await.unwrap_err();
+        assert!(err.to_string().contains("share the stored hash"));
+    }
+
+    #[tokio::test]
+    async fn combined_inputs_store_shared_chunks_once() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data = random_data(96 * 1024, 0);
+        let (common, unique_a, unique_b) = (
+            &data[..64 * 1024],
+            &data[64 * 1024..80 * 1024],
+            &data[80 * 1024..],
+        );
+        let a = [common, unique_a].concat();
+        let b = [unique_b, common].concat();
+
+        let opts = test_options(vec![], Output::File(temp_dir.path().join("output.cba")));
+        let chunked = chunk_input(
+            vec![&a[..], &b[..]],
+            &[None, None],
+            &opts,
+            &temp_dir.path().join("output.tmp"),
+            &mut HashSet::new(),
+            &mut Progress::new(ProgressFormat::Plain, "compress", None),
+        )
+        .await
+        .unwrap();
+        // 16 common chunks and 4 unique chunks of each input
+        assert_eq!(chunked.archive_chunks.len(), 24);
+        assert_eq!(chunked.sources.len(), 2);
+        assert_eq!(chunked.sources[0].source_size, a.len() as u64);
+        assert_eq!(chunked.sources[1].source_size, b.len() as u64);
+        assert_eq!(
+            chunked.sources[0].chunk_order[..16],
+            chunked.sources[1].chunk_order[4..]
+        );
+        assert_eq!(chunked.sources[1].source_hash, &Blake2b512::digest(&b)[..]);
+
+        let (input_a, input_b) = (temp_dir.path().join("a"), temp_dir.path().join("b"));
+        std::fs::write(&input_a, &a).unwrap();
+        std::fs::write(&input_b, &b).unwrap();
+        let output = temp_dir.path().join("combined.cba");
+        let mut opts = test_options(vec![input_a, input_b], Output::File(output.clone()));
+        opts.combine = true;
+        let summaries = compress_cmd(opts).await.unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].unique_chunks, 24);
+        assert_eq!(summaries[0].total_chunks, 40);
+        assert_eq!(summaries[0].source_size, (a.len() + b.len()) as u64);
+        assert!(summaries[0].archive_size < (a.len() + b.len()) as u64);
+        assert_eq!(unpack(&output).await, a);
+
+        // Every source reads back from the combined archive
+        let mut archive = Archive::try_init(LocalFile::open_archive(&output).await.unwrap())
+            .await
+            .unwrap();
+        let names: Vec<&str> = archive
+            .sources()
+            .iter()
+            .map(|source| source.name.as_str())
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+        for (name, expected) in [("a", &a), ("b", &b)].iter() {
+            let mut unpacked = vec![];
+            archive
+                .unpack_source(
+                    name,
+                    std::io::Cursor::new(&mut unpacked),
+                    Vec::<&[u8]>::new(),
+                    &ChunkHasher::default(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(&unpacked, *expected);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn combined_inputs_store_links_without_chunks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (link, input, hard_link) = (
+            temp_dir.path().join("link.img"),
+            temp_dir.path().join("a.img"),
+            temp_dir.path().join("b.img"),
+        );
+        std::fs::write(&input, random_data(64 * 1024, 0)).unwrap();
+        std::os::unix::fs::symlink("a.img", &link).unwrap();
+        std::fs::hard_link(&input, &hard_link).unwrap();
+        let output = temp_dir.path().join("combined.cba");
+        let mut opts = test_options(vec![link, input, hard_link], Output::File(output.clone()));
+        opts.symlinks = SymlinkPolicy::Store;
+        opts.combine = true;
+        let summaries = compress_cmd(opts).await.unwrap();
+        // Only the regular file has any chunks
+        assert_eq!(summaries[0].total_chunks, 16);
+        assert_eq!(summaries[0].source_size, 64 * 1024);
+        let archive = open_archive(&output).await;
+        assert_eq!(
+            archive.source_entry(),
+            &SourceEntry::Symlink("a.img".to_string())
+        );
+        assert_eq!(archive.total_source_size(), 0);
+    }
+
+    #[tokio::test]
+    async fn multiple_inputs_require_output_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input.img");
+        std::fs::write(&input, random_data(1024, 0)).unwrap();
+        let output = temp_dir.path().join("output.cba");
+        let err = compress_cmd(test_options(
+            vec![input.clone(), input],
+            Output::File(output.clone()),
+        ))
+        .await
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("An output directory (or combining the inputs) is required"));
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn dedup_check_aborts_on_random_data() {
+        let input = random_data(1024 * 1024, 0);
+        let err = chunk_with_dedup_check(&input[..]).await.unwrap_err();
+        assert!(err.to_string().starts_with("Dedup ratio"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn dedup_check_passes_on_repetitive_data() {
+        let input = random_data(4096, 0).repeat(256);
+        assert_eq!(
+            chunk_with_dedup_check(&input[..]).await.unwrap(),
+            input.len() as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn golden_archive_with_test_codec() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input.img");
+        // Runs of increasing length, repeated to get some duplicate chunks
+        let source: Vec<u8> = (0..64u8)
+            .flat_map(|byte| vec![byte; byte as usize * 7 + 1])
+            .collect::<Vec<u8>>()
+            .repeat(3);
+        std::fs::write(&input, &source).unwrap();
+        let output = temp_dir.path().join("output.cba");
+        let mut opts = test_options(vec![input], Output::File(output.clone()));
+        opts.chunker_config = chunker::Config::FixedSize(1024);
+        opts.compression = Some(bitar::TestCodec::compression());
+        opts.chunk_order = ChunkOrder::Hash;
+        compress_cmd(opts).await.unwrap();
+        assert_eq!(unpack(&output).await, source);
+
+        let archive = Archive::try_init(LocalFile::open_archive(&output).await.unwrap())
+            .await
+            .unwrap();
+        let archive_data = std::fs::read(&output).unwrap();
+        // The chunk data is the same on every platform and with every version of bita
+        assert_eq!(archive.unique_chunks(), 42);
+        assert_eq!(archive.compressed_size(), 598);
+        assert_eq!(
+            HashSum::from(&Blake2b512::digest(&archive_data[archive.chunk_data_offset() as usize..])[..])
+                .to_string(),
+            "95d9220b16327457a7f224c2e3113bf37add780bc381dbbc8dc4cf22abe3b3e0634014669a79da8f5521b2988c7fac21a5911912c007de5a48aa12151c4da196"
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_after_compress() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data = random_data(64 * 1024, 0);
+        let (a, b) = (temp_dir.path().join("a"), temp_dir.path().join("b"));
+        std::fs::write(&a, &data[..48 * 1024]).unwrap();
+        std::fs::write(&b, &data[16 * 1024..]).unwrap();
+        let output = temp_dir.path().join("output.cba");
+        let mut opts = test_options(vec![a, b], Output::File(output.clone()));
+        opts.combine = true;
+        opts.split_size = Some(16 * 1024);
+        opts.verify = true;
+        compress_cmd(opts).await.unwrap();
+
+        // Flip a byte of the chunk data
+        let part = part_path(&output, 0);
+        let mut part_data = std::fs::read(&part).unwrap();
+        part_data[100] ^= 0xff;
+        std::fs::write(&part, &part_data).unwrap();
+        assert!(verify_output(&output, &ChunkHasher::default(), None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn seeded_buzhash_table_stored_in_archive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data = random_data(512 * 1024, 0);
+        let input = temp_dir.path().join("input");
+        std::fs::write(&input, &data).unwrap();
+        let filter_config = chunker::FilterConfig {
+            filter_bits: chunker::FilterBits::from_size(8 * 1024),
+            min_chunk_size: 1024,
+            max_chunk_size: 64 * 1024,
+            window_size: 16,
+            buzhash_table: None,
+        };
+        let mut offsets = vec![];
+        for table in vec![None, Some(chunker::BuzHashTable::from_seed(42))] {
+            let output = temp_dir.path().join("output.cba");
+            let mut opts = test_options(vec![input.clone()], Output::File(output.clone()));
+            opts.force_create = true;
+            opts.chunker_config = chunker::Config::BuzHash(chunker::FilterConfig {
+                buzhash_table: table.clone(),
+                ..filter_config.clone()
+            });
+            compress_cmd(opts).await.unwrap();
+
+            let archive = Archive::try_init(LocalFile::open_archive(&output).await.unwrap())
+                .await
+                .unwrap();
+            match archive.chunker_config() {
+                chunker::Config::BuzHash(config) => assert_eq!(config.buzhash_table, table),
+                config => panic!("unexpected chunker {}", config),
+            }
+            offsets.push(
+                archive
+                    .iter_source_chunks()
+                    .map(|(offset, _)| offset)
+                    .collect::<Vec<u64>>(),
+            );
+            assert_eq!(unpack(&output).await, data);
+        }
+        assert_ne!(offsets[0], offsets[1]);
+    }
+
+    #[tokio::test]
+    async fn temp_file_in_temp_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let (input_dir, output_dir, chunk_dir) = (
+            temp_dir.path().join("input"),
+            temp_dir.path().join("output"),
+            temp_dir.path().join("temp"),
+        );
+        for dir in &[&input_dir, &output_dir, &chunk_dir] {
+            std::fs::create_dir(dir).unwrap();
+        }
+        let input = input_dir.join("source");
+        let output = output_dir.join("source.cba");
+        let data = random_data(64 * 1024, 0);
+        std::fs::write(&input, &data).unwrap();
+        assert_eq!(
+            temp_file_path(&output, None).parent(),
+            Some(output_dir.as_path())
+        );
+        let temp_file = temp_file_path(&output, Some(&chunk_dir));
+        assert_eq!(temp_file.parent(), Some(chunk_dir.as_path()));
+
+        let mut opts = test_options(vec![input], Output::File(output.clone()));
+        opts.temp_dir = Some(chunk_dir.clone());
+        compress_cmd(opts).await.unwrap();
+        assert!(!temp_file.exists());
+        assert_eq!(std::fs::read_dir(&chunk_dir).unwrap().count(), 0);
+        assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 1);
+        assert_eq!(unpack(&output).await, data);
     }
-    Ok(())
 }
@@ -3,32 +3,39 @@ use futures_util::StreamExt;
 use log::*;
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use tokio::fs::File;
 
-use crate::{human_size, info_cmd};
+use crate::{
+    human_size,
+    local_file::LocalFile,
+    progress::{Progress, ProgressFormat},
+};
 use bitar::{chunker, Compression, HashSum};
 
 #[derive(Clone, Debug)]
-struct ChunkDescriptor {
-    source_size: usize,
-    compressed_size: Option<usize>,
-    occurrences: Vec<u64>,
+pub(crate) struct ChunkDescriptor {
+    pub(crate) source_size: usize,
+    pub(crate) compressed_size: Option<usize>,
+    // Source offsets of the chunk, in order
+    pub(crate) occurrences: Vec<u64>,
 }
 
 #[derive(Clone, Debug)]
-struct ChunkerResult {
-    chunks: HashSet<HashSum>,
-    descriptors: HashMap<HashSum, ChunkDescriptor>,
-    total_size: u64,
-    total_compressed_size: u64,
-    total_chunks: usize,
+pub(crate) struct ChunkerResult {
+    pub(crate) chunks: HashSet<HashSum>,
+    pub(crate) descriptors: HashMap<HashSum, ChunkDescriptor>,
+    pub(crate) total_size: u64,
+    pub(crate) total_compressed_size: u64,
+    pub(crate) total_chunks: usize,
 }
 
-async fn chunk_file(
+// Chunk the file and compress every unique chunk, reporting progress as the given task.
+pub(crate) async fn chunk_file(
     path: &Path,
     chunker_config: &chunker::Config,
     compression: Option<Compression>,
     num_chunk_buffers: usize,
+    progress_format: ProgressFormat,
+    task: &'static str,
 ) -> Result<ChunkerResult> {
     let mut descriptors: HashMap<HashSum, ChunkDescriptor> = HashMap::new();
     let mut chunks = HashSet::new();
@@ -36,7 +43,9 @@ async fn chunk_file(
     let mut total_compressed_size = 0u64;
     let mut total_chunks = 0;
     {
-        let mut file = File::open(path).await.expect("failed to open output file");
+        let mut file = LocalFile::open(path).await?;
+        let file_size = file.metadata().await.ok().map(|metadata| metadata.len());
+        let mut progress = Progress::new(progress_format, task, file_size);
         let mut unique_chunk = HashSet::new();
         let chunker = chunker_config.new_chunker(&mut file);
         let mut chunk_stream = chunker
@@ -75,6 +84,7 @@ async fn chunk_file(
             let (offset, verified, compressed_size) = result.expect("error compressing chunk");
             total_chunks += 1;
             total_size += verified.len() as u64;
+            progress.set(total_size);
             chunks.insert(verified.hash().clone());
             if let Some(descriptor) = descriptors.get_mut(verified.hash()) {
                 descriptor.occurrences.push(offset);
@@ -94,6 +104,7 @@ async fn chunk_file(
                 );
             }
         }
+        progress.finish();
     }
 
     Ok(ChunkerResult {
@@ -157,14 +168,17 @@ pub struct Options {
     pub chunker_config: chunker::Config,
     pub compression: Option<Compression>,
     pub num_chunk_buffers: usize,
+    pub progress_format: ProgressFormat,
 }
 
 pub async fn diff_cmd(opts: Options) -> Result<()> {
     let chunker_config = &opts.chunker_config;
     let compression = opts.compression;
 
-    info!("Chunker config:");
-    info_cmd::print_chunker_config(chunker_config);
+    info!("Chunker: {}", chunker_config);
+    if let Some(compression) = compression {
+        info!("Compression: {}", compression);
+    }
     println!();
 
     info!("Scanning {} ...", opts.input_a.display());
@@ -173,6 +187,8 @@ pub async fn diff_cmd(opts: Options) -> Result<()> {
         chunker_config,
         compression,
         opts.num_chunk_buffers,
+        opts.progress_format,
+        "diff",
     )
     .await?;
 
@@ -182,6 +198,8 @@ pub async fn diff_cmd(opts: Options) -> Result<()> {
         chunker_config,
         compression,
         opts.num_chunk_buffers,
+        opts.progress_format,
+        "diff",
     )
     .await?;
 
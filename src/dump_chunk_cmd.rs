@@ -0,0 +1,244 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use std::io::Write;
+use std::path::Path;
+
+use crate::{clone_cmd, info_cmd, local_file::LocalFile};
+use bitar::{
+    archive_reader::{ArchiveReader, HttpReader},
+    header::DictionaryKey,
+    Archive, ChunkCodec, ChunkDescriptor, ChunkHasher, HashSum,
+};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub input: String,
+    pub hash_prefix: String,
+    pub decompress: bool,
+    pub chunk_hasher: ChunkHasher,
+    // Key decrypting the archive dictionary
+    pub dictionary_key: Option<DictionaryKey>,
+}
+
+// A dumped chunk.
+#[derive(Debug)]
+struct DumpedChunk {
+    descriptor: ChunkDescriptor,
+    data: Vec<u8>,
+    // Hash of the chunk, recomputed from the (decompressed) chunk data.
+    hash: HashSum,
+}
+
+// Find the single chunk descriptor whose checksum starts with the given hex prefix.
+fn find_descriptor<'a>(
+    descriptors: &'a [ChunkDescriptor],
+    hash_prefix: &str,
+) -> Result<&'a ChunkDescriptor> {
+    let hash_prefix = hash_prefix.to_lowercase();
+    if hash_prefix.is_empty() || !hash_prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid hash prefix '{}'", hash_prefix);
+    }
+    let mut matching = descriptors
+        .iter()
+        .filter(|cd| cd.checksum.to_string().starts_with(&hash_prefix));
+    let descriptor = matching
+        .next()
+        .ok_or_else(|| anyhow!("No chunk with hash prefix {} in archive", hash_prefix))?;
+    if matching.next().is_some() {
+        bail!("Hash prefix {} matches multiple chunks", hash_prefix);
+    }
+    Ok(descriptor)
+}
+
+// Read a chunk from archive. The hasher must be the one the archive was created with.
+async fn dump_chunk<R>(
+    archive: &mut Archive<R>,
+    hasher: &ChunkHasher,
+    hash_prefix: &str,
+    decompress: bool,
+) -> Result<DumpedChunk>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    let descriptor = find_descriptor(archive.chunk_descriptors(), hash_prefix)?.clone();
+    let stored = archive
+        .reader_mut()
+        .read_at(descriptor.archive_offset, descriptor.archive_size)
+        .await
+        .context("Failed to read chunk")?;
+    // Chunks of the same size as in source are stored uncompressed.
+    let source = match archive.chunk_compression() {
+        Some(compression) if stored.len() != descriptor.source_size as usize => compression
+            .decompress(&stored, descriptor.source_size as usize)
+            .context("Failed to decompress chunk")?,
+        _ => stored.to_vec(),
+    };
+    let mut hash = hasher.digest(&source);
+    hash.truncate(archive.chunk_hash_length());
+    Ok(DumpedChunk {
+        descriptor,
+        data: if decompress { source } else { stored.to_vec() },
+        hash,
+    })
+}
+
+pub async fn dump_chunk_cmd(opts: Options) -> Result<()> {
+    let key = opts.dictionary_key.as_ref();
+    // Chunk data of a split archive is read from its parts.
+    let dumped = if let Ok(url) = opts.input.parse::<reqwest::Url>() {
+        let archive = info_cmd::init_archive(HttpReader::from_url(url.clone()), key).await?;
+        let parts = (0..archive.chunk_data_part_sizes().len())
+            .map(|index| HttpReader::from_url(clone_cmd::part_url(&url, index)))
+            .collect();
+        dump_chunk(
+            &mut archive.with_part_readers(parts)?,
+            &opts.chunk_hasher,
+            &opts.hash_prefix,
+            opts.decompress,
+        )
+        .await?
+    } else {
+        let path = Path::new(&opts.input);
+        let archive = info_cmd::init_archive(LocalFile::open_archive(path).await?, key).await?;
+        let parts = LocalFile::open_parts(path, archive.chunk_data_part_sizes().len()).await?;
+        dump_chunk(
+            &mut archive.with_part_readers(parts)?,
+            &opts.chunk_hasher,
+            &opts.hash_prefix,
+            opts.decompress,
+        )
+        .await?
+    };
+    // Log output goes to stderr for this command, stdout is kept clean for the chunk data.
+    info!(
+        "Chunk {} (archive offset: {}, archive size: {}, source size: {})",
+        dumped.descriptor.checksum,
+        dumped.descriptor.archive_offset,
+        dumped.descriptor.archive_size,
+        dumped.descriptor.source_size
+    );
+    info!("Recomputed hash: {}", dumped.hash);
+    std::io::stdout()
+        .write_all(&dumped.data)
+        .context("Failed to write chunk")?;
+    if dumped.hash != dumped.descriptor.checksum {
+        warn!("Was the archive created with a different hash personalization?");
+        bail!("Chunk hash mismatch");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_cmd::{
+        self,
+        tests::{random_data, test_options},
+    };
+    use bitar::{archive_reader::IoReader, Compression};
+
+    // Archive of brotli compressed chunks. The source only uses 16 byte values, so each chunk
+    // compresses and is stored compressed.
+    async fn open_archive() -> (tempfile::TempDir, Vec<u8>, Archive<IoReader<LocalFile>>) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source: Vec<u8> = random_data(64 * 1024, 0)
+            .into_iter()
+            .map(|b| b % 16)
+            .collect();
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("source.cba");
+        let mut opts = test_options(
+            vec![source_path],
+            compress_cmd::Output::File(archive_path.clone()),
+        );
+        opts.compression = Some(Compression::brotli(6).unwrap());
+        compress_cmd::compress_cmd(opts).await.unwrap();
+        let archive = Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+            .await
+            .unwrap();
+        (temp_dir, source, archive)
+    }
+
+    #[tokio::test]
+    async fn dump_known_chunk() {
+        let (_temp_dir, source, mut archive) = open_archive().await;
+        let (source_offset, descriptor) = archive.iter_source_chunks().nth(5).unwrap();
+        let descriptor = descriptor.clone();
+        let source_range =
+            source_offset as usize..source_offset as usize + descriptor.source_size as usize;
+        let prefix = &descriptor.checksum.to_string()[..8];
+
+        let dumped = dump_chunk(&mut archive, &ChunkHasher::default(), prefix, true)
+            .await
+            .unwrap();
+        assert_eq!(dumped.descriptor, descriptor);
+        assert_eq!(&dumped.data[..], &source[source_range]);
+        assert_eq!(dumped.hash, descriptor.checksum);
+
+        let stored = dump_chunk(&mut archive, &ChunkHasher::default(), prefix, false)
+            .await
+            .unwrap();
+        assert_eq!(stored.data.len(), descriptor.archive_size);
+        assert!(stored.data.len() < dumped.data.len());
+        assert_eq!(stored.hash, descriptor.checksum);
+    }
+
+    #[tokio::test]
+    async fn unknown_hash_prefix() {
+        let (_temp_dir, _source, mut archive) = open_archive().await;
+        assert!(
+            dump_chunk(&mut archive, &ChunkHasher::default(), "xyz", true)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn hash_with_personalization() {
+        let (_temp_dir, _source, mut archive) = open_archive().await;
+        let descriptor = archive.chunk_descriptors()[0].clone();
+        let prefix = &descriptor.checksum.to_string()[..8];
+        let hasher = ChunkHasher::with_personalization(b"bita-test").unwrap();
+        let dumped = dump_chunk(&mut archive, &hasher, prefix, true)
+            .await
+            .unwrap();
+        let mut expected = hasher.digest(&dumped.data);
+        expected.truncate(archive.chunk_hash_length());
+        assert_eq!(dumped.hash, expected);
+        assert_ne!(dumped.hash, descriptor.checksum);
+    }
+
+    #[tokio::test]
+    async fn dump_chunk_of_split_archive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = random_data(64 * 1024, 0);
+        let source_path = temp_dir.path().join("source");
+        std::fs::write(&source_path, &source).unwrap();
+        let archive_path = temp_dir.path().join("split.cba");
+        let mut opts = test_options(
+            vec![source_path],
+            compress_cmd::Output::File(archive_path.clone()),
+        );
+        opts.split_size = Some(40 * 1024);
+        compress_cmd::compress_cmd(opts).await.unwrap();
+
+        let archive = Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+            .await
+            .unwrap();
+        let parts = LocalFile::open_parts(&archive_path, archive.chunk_data_part_sizes().len())
+            .await
+            .unwrap();
+        let mut archive = archive.with_part_readers(parts).unwrap();
+        // The last chunk is stored in the second part
+        let (source_offset, descriptor) = archive.iter_source_chunks().last().unwrap();
+        let descriptor = descriptor.clone();
+        let prefix = &descriptor.checksum.to_string()[..16];
+        let dumped = dump_chunk(&mut archive, &ChunkHasher::default(), prefix, true)
+            .await
+            .unwrap();
+        assert_eq!(&dumped.data[..], &source[source_offset as usize..]);
+        assert_eq!(dumped.hash, descriptor.checksum);
+    }
+}
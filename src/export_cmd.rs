@@ -0,0 +1,320 @@
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::StreamExt;
+use log::*;
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+use crate::{human_size, info_cmd, local_file::LocalFile};
+use bitar::{
+    archive_reader::{chunk_store_path, ArchiveReader},
+    header::DictionaryKey,
+    Archive, Chunk, ChunkOffset, Compression,
+};
+
+// The zstd seekable format is a number of independent zstd frames followed by a seek table
+// stored in a skippable frame. See contrib/seekable_format in the zstd repository.
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const SEEK_TABLE_FOOTER_SIZE: usize = 9;
+const SEEK_TABLE_ENTRY_SIZE: usize = 8;
+
+// Name of the archive header in an exported chunk store directory.
+pub const CHUNK_STORE_INDEX: &str = "index.cba";
+// Directory of the chunk objects in an exported chunk store directory.
+pub const CHUNK_STORE_OBJECTS: &str = "store";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // A single file of zstd frames with a seek table
+    ZstdSeekable,
+    // A directory with the archive header as index and every chunk as an object named by hash
+    ChunkStore,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub force_create: bool,
+    pub format: Format,
+    // Key decrypting the archive dictionary
+    pub dictionary_key: Option<DictionaryKey>,
+}
+
+// Get the archive compression if it's zstd.
+fn zstd_compression(compression: Option<Compression>) -> Option<Compression> {
+    match compression {
+        #[cfg(feature = "zstd-compression")]
+        Some(compression) if compression.algorithm() == bitar::CompressionAlgorithm::Zstd => {
+            Some(compression)
+        }
+        _ => None,
+    }
+}
+
+// Build the seek table from the (compressed size, decompressed size) of every frame.
+fn seek_table(frames: &[(u32, u32)]) -> Vec<u8> {
+    let mut table = Vec::new();
+    table.extend(&SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+    table.extend(
+        &((frames.len() * SEEK_TABLE_ENTRY_SIZE + SEEK_TABLE_FOOTER_SIZE) as u32).to_le_bytes(),
+    );
+    for (compressed_size, decompressed_size) in frames {
+        table.extend(&compressed_size.to_le_bytes());
+        table.extend(&decompressed_size.to_le_bytes());
+    }
+    table.extend(&(frames.len() as u32).to_le_bytes());
+    // Seek table descriptor, no frame checksums
+    table.push(0);
+    table.extend(&SEEKABLE_MAGIC.to_le_bytes());
+    table
+}
+
+// Write every unique chunk, as stored in the archive, to OUTPUT/store/<hh>/<hash> and the
+// archive header to OUTPUT/index.cba. An object already in the store is kept if it's the same
+// as the chunk in the archive.
+async fn export_chunk_store<R>(opts: &Options, archive: &mut Archive<R>) -> Result<()>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    let objects_dir = opts.output.join(CHUNK_STORE_OBJECTS);
+    tokio::fs::create_dir_all(&objects_dir)
+        .await
+        .context(format!("Failed to create {}", objects_dir.display()))?;
+
+    // The header is kept verbatim, also any padding before the chunk data, so that the chunk
+    // offsets and the header checksum stay the same as in the input archive.
+    let chunk_data_offset = archive.chunk_data_offset();
+    let header = archive
+        .reader_mut()
+        .read_at(0, chunk_data_offset as usize)
+        .await
+        .context("Failed to read archive")?;
+    let index_path = opts.output.join(CHUNK_STORE_INDEX);
+    let mut index = OpenOptions::new()
+        .write(true)
+        .create(opts.force_create)
+        .truncate(opts.force_create)
+        .create_new(!opts.force_create)
+        .open(&index_path)
+        .await
+        .context(format!("Failed to open {}", index_path.display()))?;
+    index
+        .write_all(&header)
+        .await
+        .context(format!("Failed to write to {}", index_path.display()))?;
+    index.flush().await?;
+
+    let descriptors = archive.chunk_descriptors().to_vec();
+    let (unique_chunks, compressed_size) = (archive.unique_chunks(), archive.compressed_size());
+    let mut chunk_stream = archive.reader_mut().read_chunks(
+        descriptors
+            .iter()
+            .map(|cd| ChunkOffset::new(cd.archive_offset, cd.archive_size))
+            .collect(),
+    );
+    let mut descriptors = descriptors.iter();
+    let mut written = 0;
+    while let Some(data) = chunk_stream.next().await {
+        let data = data.context("Failed to read archive")?;
+        let cd = descriptors.next().expect("descriptor of chunk");
+        let path = objects_dir.join(chunk_store_path(&cd.checksum));
+        // Objects are named by the hash of the uncompressed chunk. An object written from an
+        // archive of another compression would fail to decompress when cloning this archive.
+        match tokio::fs::read(&path).await {
+            Ok(object) if object == data => continue,
+            Ok(_) => bail!(
+                "Chunk {} is already in {} but stored differently, was it exported from an archive with another compression?",
+                cd.checksum,
+                objects_dir.display()
+            ),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).context(format!("Failed to read {}", path.display()));
+            }
+        }
+        write_object(&path, &data).await?;
+        written += 1;
+    }
+    info!(
+        "Exported {} chunks ({} new) of {} to {}",
+        unique_chunks,
+        written,
+        human_size!(compressed_size),
+        opts.output.display()
+    );
+    Ok(())
+}
+
+// Write an object through a temporary file so that a partly written object never appears
+// in the store.
+async fn write_object(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().expect("object directory");
+    tokio::fs::create_dir_all(dir)
+        .await
+        .context(format!("Failed to create {}", dir.display()))?;
+    let temp_path = path.with_extension("tmp");
+    tokio::fs::write(&temp_path, data)
+        .await
+        .context(format!("Failed to write to {}", temp_path.display()))?;
+    tokio::fs::rename(&temp_path, path)
+        .await
+        .context(format!("Failed to write to {}", path.display()))?;
+    Ok(())
+}
+
+pub async fn export_cmd(opts: Options) -> Result<()> {
+    let reader = LocalFile::open_archive(&opts.input).await?;
+    let archive = info_cmd::init_archive(reader, opts.dictionary_key.as_ref())
+        .await
+        .context(format!("Failed to read archive {}", opts.input.display()))?;
+    // Chunk data of a split archive is read from its parts.
+    let parts = LocalFile::open_parts(&opts.input, archive.chunk_data_part_sizes().len()).await?;
+    let mut archive = archive.with_part_readers(parts)?;
+    if opts.format == Format::ChunkStore {
+        return export_chunk_store(&opts, &mut archive).await;
+    }
+    let compression = zstd_compression(archive.chunk_compression()).ok_or_else(|| {
+        anyhow!(
+            "Only archives with zstd compressed chunks can be exported to the zstd seekable format"
+        )
+    })?;
+    // Every chunk is written in source order, also the duplicated ones.
+    let (source_sizes, read_at): (Vec<u32>, Vec<ChunkOffset>) = archive
+        .iter_source_chunks()
+        .map(|(_offset, cd)| {
+            (
+                cd.source_size,
+                ChunkOffset::new(cd.archive_offset, cd.archive_size),
+            )
+        })
+        .unzip();
+
+    let mut output = OpenOptions::new()
+        .write(true)
+        .create(opts.force_create)
+        .truncate(opts.force_create)
+        .create_new(!opts.force_create)
+        .open(&opts.output)
+        .await
+        .context(format!("Failed to open {}", opts.output.display()))?;
+
+    let mut frames = Vec::with_capacity(source_sizes.len());
+    let total_source_size = archive.total_source_size();
+    let mut chunk_stream = archive.reader_mut().read_chunks(read_at);
+    let mut source_sizes = source_sizes.into_iter();
+    while let Some(data) = chunk_stream.next().await {
+        let data = data.context("Failed to read archive")?;
+        let source_size = source_sizes.next().expect("source size of chunk");
+        // Chunks which didn't get any smaller when compressed are stored uncompressed in the
+        // archive. These have to be compressed to become a zstd frame.
+        let frame = if data.len() == source_size as usize {
+            let (_, data) = Chunk::from(data)
+                .compress(Some(compression))
+                .context("Failed to compress chunk")?
+                .into_inner();
+            data
+        } else {
+            data
+        };
+        output
+            .write_all(&frame)
+            .await
+            .context(format!("Failed to write to {}", opts.output.display()))?;
+        frames.push((frame.len() as u32, source_size));
+    }
+    output
+        .write_all(&seek_table(&frames))
+        .await
+        .context(format!("Failed to write to {}", opts.output.display()))?;
+    output.flush().await?;
+    info!(
+        "Exported {} frames of {} to {}",
+        frames.len(),
+        human_size!(total_source_size),
+        opts.output.display()
+    );
+    Ok(())
+}
+
+#[cfg(all(test, feature = "zstd-compression"))]
+mod tests {
+    use super::*;
+    use crate::compress_cmd::{
+        self,
+        tests::{random_data, test_options},
+    };
+    use bitar::ChunkCodec;
+
+    // Read a range of the uncompressed data from a file in the zstd seekable format.
+    fn read_seekable(file: &[u8], offset: usize, size: usize) -> Vec<u8> {
+        let u32_at = |pos: usize| {
+            u32::from_le_bytes([file[pos], file[pos + 1], file[pos + 2], file[pos + 3]])
+        };
+        let footer = file.len() - SEEK_TABLE_FOOTER_SIZE;
+        assert_eq!(u32_at(footer + 5), SEEKABLE_MAGIC);
+        assert_eq!(file[footer + 4], 0);
+        let num_frames = u32_at(footer) as usize;
+        let table_start = footer - num_frames * SEEK_TABLE_ENTRY_SIZE;
+        assert_eq!(u32_at(table_start - 8), SKIPPABLE_FRAME_MAGIC);
+        let mut compressed_offset = 0;
+        let mut decompressed_offset = 0;
+        let mut data = Vec::new();
+        for entry in 0..num_frames {
+            let compressed_size = u32_at(table_start + entry * SEEK_TABLE_ENTRY_SIZE) as usize;
+            let decompressed_size =
+                u32_at(table_start + entry * SEEK_TABLE_ENTRY_SIZE + 4) as usize;
+            if decompressed_offset + decompressed_size > offset
+                && decompressed_offset < offset + size
+            {
+                let frame = &file[compressed_offset..compressed_offset + compressed_size];
+                let frame_data = Compression::zstd(1)
+                    .unwrap()
+                    .decompress(frame, decompressed_size)
+                    .unwrap();
+                assert_eq!(frame_data.len(), decompressed_size);
+                let start = offset.saturating_sub(decompressed_offset);
+                let end = std::cmp::min(decompressed_size, offset + size - decompressed_offset);
+                data.extend(&frame_data[start..end]);
+            }
+            compressed_offset += compressed_size;
+            decompressed_offset += decompressed_size;
+        }
+        assert_eq!(compressed_offset, table_start - 8);
+        data
+    }
+
+    #[tokio::test]
+    async fn exported_file_is_seekable() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // Compressible data with duplicated chunks followed by incompressible data.
+        let mut source: Vec<u8> = (0..64 * 1024).map(|i| (i / 100) as u8).collect();
+        source.extend(&source.clone());
+        source.extend(random_data(16 * 1024, 0));
+        let input = temp_dir.path().join("input");
+        let archive = temp_dir.path().join("input.cba");
+        let exported = temp_dir.path().join("input.zst");
+        std::fs::write(&input, &source).unwrap();
+        let mut opts = test_options(vec![input], compress_cmd::Output::File(archive.clone()));
+        opts.compression = Some(Compression::zstd(3).unwrap());
+        compress_cmd::compress_cmd(opts).await.unwrap();
+        export_cmd(Options {
+            input: archive,
+            output: exported.clone(),
+            force_create: false,
+            format: Format::ZstdSeekable,
+            dictionary_key: None,
+        })
+        .await
+        .unwrap();
+        let file = std::fs::read(&exported).unwrap();
+        for &(offset, size) in &[(0, 100), (70_000, 10_000), (140_000, 4096)] {
+            assert_eq!(
+                read_seekable(&file, offset, size),
+                &source[offset..offset + size]
+            );
+        }
+    }
+}
@@ -0,0 +1,8 @@
+use anyhow::Result;
+use log::*;
+
+pub fn features_cmd() -> Result<()> {
+    info!("Codecs: {}", bitar::compiled_codecs().join(", "));
+    info!("Features: {}", bitar::compiled_features().join(", "));
+    Ok(())
+}
@@ -1,102 +1,341 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::stream::Stream;
 use log::*;
-use tokio::fs::File;
+use std::convert::{TryFrom, TryInto};
+use std::pin::Pin;
 
-use crate::human_size;
+use crate::{human_size, local_file::LocalFile};
 use bitar::{
-    archive_reader::{ArchiveReader, HttpReader, IoReader},
-    chunker, Archive,
+    archive_reader::{ArchiveReader, HttpReader},
+    chunker,
+    header::{self, DictionaryKey},
+    Archive, ArchiveError, ArchiveSummary, ChunkOffset, HashSum, SourceEntry,
 };
 
-pub async fn print_archive_reader<R>(reader: R) -> Result<()>
+// Archive reader serving reads within the prefetched header of the archive from memory.
+struct PrefetchedReader<R> {
+    prefix: Bytes,
+    inner: R,
+}
+
+impl PrefetchedReader<HttpReader> {
+    // Fetch the header of a remote archive up front, sized from the dictionary size in the
+    // pre-header, so that no chunk data is read.
+    async fn prefetch(mut inner: HttpReader) -> Result<Self> {
+        let pre_header = inner.read_at(0, header::PRE_HEADER_SIZE).await?;
+        if !header::has_archive_magic(&pre_header)
+            && !header::has_encrypted_archive_magic(&pre_header)
+        {
+            // Not an archive, which is reported when reading it
+            return Ok(Self {
+                prefix: pre_header,
+                inner,
+            });
+        }
+        let dictionary_size = u64::from_le_bytes(
+            pre_header[header::ARCHIVE_MAGIC.len()..header::PRE_HEADER_SIZE]
+                .try_into()
+                .expect("dictionary size field"),
+        );
+        // Dictionary followed by the chunk data offset and the header checksum
+        let rest_size = usize::try_from(dictionary_size)
+            .ok()
+            .and_then(|size| size.checked_add(8 + 64))
+            .ok_or_else(|| anyhow!("Invalid dictionary size {}", dictionary_size))?;
+        let rest = inner
+            .read_at(header::PRE_HEADER_SIZE as u64, rest_size)
+            .await?;
+        let mut prefix = pre_header.to_vec();
+        prefix.extend_from_slice(&rest);
+        Ok(Self {
+            prefix: prefix.into(),
+            inner,
+        })
+    }
+}
+
+#[async_trait]
+impl<R> ArchiveReader for PrefetchedReader<R>
+where
+    R: ArchiveReader + Send,
+{
+    type Error = R::Error;
+    async fn read_at<'a>(&'a mut self, offset: u64, size: usize) -> Result<Bytes, R::Error> {
+        match offset.checked_add(size as u64) {
+            Some(end) if end <= self.prefix.len() as u64 => {
+                Ok(self.prefix.slice(offset as usize..end as usize))
+            }
+            _ => self.inner.read_at(offset, size).await,
+        }
+    }
+    fn read_chunks<'a>(
+        &'a mut self,
+        chunks: Vec<ChunkOffset>,
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes, R::Error>> + Send + 'a>> {
+        self.inner.read_chunks(chunks)
+    }
+}
+
+/// Initialize an archive, decrypting the dictionary if a key is given.
+pub async fn init_archive<R>(
+    reader: R,
+    key: Option<&DictionaryKey>,
+) -> Result<Archive<R>, ArchiveError<R::Error>>
 where
     R: ArchiveReader,
-    R::Error: std::error::Error + Send + Sync + 'static,
 {
-    let archive = Archive::try_init(reader).await?;
-    print_archive(&archive);
-    Ok(())
+    match key {
+        Some(key) => Archive::try_init_with_key(reader, key).await,
+        None => Archive::try_init(reader).await,
+    }
 }
 
-fn print_rolling_hash_config(hc: &chunker::FilterConfig) {
-    info!(
-        "  Rolling hash window size: {}",
-        human_size!(hc.window_size)
-    );
-    info!("  Chunk minimum size: {}", human_size!(hc.min_chunk_size));
-    info!("  Chunk maximum size: {}", human_size!(hc.max_chunk_size));
-    info!(
-        "  Chunk average target size: {} (mask: {:#b})",
-        human_size!(hc.filter_bits.chunk_target_average()),
-        hc.filter_bits.mask(),
-    );
+// Read the summary of an archive, decrypting the dictionary if a key is given.
+async fn read_summary<R>(reader: R, key: Option<&DictionaryKey>) -> Result<ArchiveSummary>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    Ok(match key {
+        Some(key) => ArchiveSummary::try_init_with_key(reader, key).await?,
+        None => ArchiveSummary::try_init(reader).await?,
+    })
 }
 
-pub fn print_chunker_config(config: &chunker::Config) {
-    info!(
-        "  Chunking algorithm: {}",
-        match config {
-            chunker::Config::BuzHash(_) => "BuzHash",
-            chunker::Config::RollSum(_) => "RollSum",
-            chunker::Config::FixedSize(_) => "Fixed Size",
-        }
-    );
-    match config {
-        chunker::Config::BuzHash(hc) => print_rolling_hash_config(hc),
-        chunker::Config::RollSum(hc) => print_rolling_hash_config(hc),
-        chunker::Config::FixedSize(chunk_size) => {
-            info!("  Fixed chunk size: {}", human_size!(*chunk_size));
-        }
-    }
+pub async fn print_archive_reader<R>(reader: R, key: Option<&DictionaryKey>) -> Result<()>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    let summary = read_summary(reader, key).await?;
+    print_summary(&summary);
+    Ok(())
 }
 
+// Warn if more than this percentage of the chunks were cut at the max chunk size.
+const MAX_SIZE_CHUNKS_WARN_PERCENT: usize = 10;
+
 pub fn print_archive<R>(archive: &Archive<R>) {
+    print_summary(&ArchiveSummary::from(archive));
+}
+
+fn print_summary(archive: &ArchiveSummary) {
     info!("Archive: ");
     info!("  Built with version: {}", archive.built_with_version());
     info!(
         "  Archive size: {}",
         human_size!(archive.compressed_size() + archive.header_size() as u64)
     );
+    if !archive.chunk_data_part_sizes().is_empty() {
+        info!(
+            "  Chunk data parts: {}",
+            archive.chunk_data_part_sizes().len()
+        );
+    }
     info!("  Header checksum: {}", archive.header_checksum());
+    if let Some(checksum) = archive.chunk_data_checksum() {
+        info!("  Chunk data checksum: {}", checksum);
+    }
+    if let Some(alignment) = archive.chunk_data_alignment() {
+        info!("  Chunk data alignment: {}", human_size!(alignment));
+    }
     info!("  Chunk hash length: {} bytes", archive.chunk_hash_length());
+    if archive.has_chunk_crc32c() {
+        info!("  Chunk CRC32C: stored");
+    }
     info!(
         "  Chunk compression: {}",
         match archive.chunk_compression() {
-            None => "None".to_string(),
-            Some(c) => format!("{}", c),
+            None => "none".to_string(),
+            Some(c) => c.to_string(),
         }
     );
 
-    print_chunker_config(archive.chunker_config());
+    info!("  Chunker: {}", archive.chunker_config());
+    match archive.chunker_config() {
+        chunker::Config::BuzHash(hc) | chunker::Config::RollSum(hc) => info!(
+            "  Chunk average target size: {} (mask: {:#b})",
+            human_size!(hc.filter_bits.chunk_target_average()),
+            hc.filter_bits.mask(),
+        ),
+        chunker::Config::FixedSize(_) | chunker::Config::Custom(..) => {}
+    }
 
     info!("Source:");
+    if !archive.source_name().is_empty() {
+        info!("  Source name: {}", archive.source_name());
+    }
+    if let Some(content_type) = archive.content_type() {
+        info!("  Content type: {}", content_type);
+    }
+    match archive.source_entry() {
+        SourceEntry::File => {}
+        SourceEntry::Symlink(target) => info!("  Symbolic link to: {}", target),
+        SourceEntry::HardLink(name) => info!("  Hard link to: {}", name),
+    }
     info!("  Source checksum: {}", archive.source_checksum());
+    if let Some(checkpoints) = archive.source_checkpoints() {
+        info!(
+            "  Source checkpoints: {} (every {})",
+            checkpoints.checksums().len(),
+            human_size!(checkpoints.interval())
+        );
+    }
     info!(
         "  Chunks in source: {} (unique: {})",
         archive.total_chunks(),
         archive.unique_chunks()
     );
-    info!(
-        "  Average chunk size: {}",
-        human_size!(
-            archive
-                .chunk_descriptors()
-                .iter()
-                .map(|cdesc| u64::from(cdesc.source_size))
-                .sum::<u64>()
-                / archive.chunk_descriptors().len() as u64
-        )
-    );
+    if let Some(stats) = archive.chunk_size_stats() {
+        info!("  Average chunk size: {}", human_size!(stats.average()));
+        info!("  Smallest chunk size: {}", human_size!(stats.min));
+        info!("  Largest chunk size: {}", human_size!(stats.max));
+        if stats.at_max_size * 100 > stats.count * MAX_SIZE_CHUNKS_WARN_PERCENT {
+            warn!(
+                "  {} of {} chunks were cut at the maximum chunk size, consider a larger maximum chunk size",
+                stats.at_max_size, stats.count
+            );
+        }
+    }
     info!(
         "  Source size: {}",
         human_size!(archive.total_source_size())
     );
 }
 
-pub async fn info_cmd(input: String) -> Result<()> {
-    if let Ok(url) = input.parse::<reqwest::Url>() {
-        print_archive_reader(HttpReader::from_url(url)).await
+// Read source and header checksum from archive. Only the header is read, no chunk data.
+async fn read_checksums<R>(reader: R, key: Option<&DictionaryKey>) -> Result<(HashSum, HashSum)>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    let summary = read_summary(reader, key).await?;
+    Ok((
+        summary.source_checksum().clone(),
+        summary.header_checksum().clone(),
+    ))
+}
+
+pub async fn info_cmd(
+    input: String,
+    checksum_only: bool,
+    key: Option<DictionaryKey>,
+) -> Result<()> {
+    let key = key.as_ref();
+    if checksum_only {
+        let (source_checksum, header_checksum) = if let Ok(url) = input.parse::<reqwest::Url>() {
+            let reader = PrefetchedReader::prefetch(HttpReader::from_url(url)).await?;
+            read_checksums(reader, key).await?
+        } else {
+            read_checksums(LocalFile::open_archive(&input).await?, key).await?
+        };
+        info!("Source checksum: {}", source_checksum);
+        info!("Header checksum: {}", header_checksum);
+        Ok(())
+    } else if let Ok(url) = input.parse::<reqwest::Url>() {
+        let reader = PrefetchedReader::prefetch(HttpReader::from_url(url)).await?;
+        print_archive_reader(reader, key).await
     } else {
-        print_archive_reader(IoReader::new(File::open(&input).await?)).await
+        print_archive_reader(LocalFile::open_archive(&input).await?, key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::sync::{Arc, Mutex};
+
+    static ARCHIVE_0_7_1_BROTLI: &str = "bitar/tests/resources/zero-0_7_1-brotli.cba";
+    // Checksum of the zero archive source file.
+    static ZERO_B2SUM: &str = "cd4710390f8542c63765f04837134242d906b297733282f2e906e0151d3ea4ec31db43b925ceea90847760f6470eb72a1cf8feaf1cd4b76ddcde96a614b3527a";
+
+    // Serve data while recording which byte ranges were requested.
+    async fn serve_recording_ranges(
+        listener: std::net::TcpListener,
+        data: Vec<u8>,
+        requested: Arc<Mutex<Vec<(u64, u64)>>>,
+    ) {
+        hyper::Server::from_tcp(listener)
+            .unwrap()
+            .serve(make_service_fn(move |_conn| {
+                let data = data.clone();
+                let requested = requested.clone();
+                async move {
+                    Ok::<_, std::convert::Infallible>(service_fn(move |req| {
+                        let range = req
+                            .headers()
+                            .get("range")
+                            .expect("range header")
+                            .to_str()
+                            .unwrap()[6..]
+                            .split('-')
+                            .map(|s| s.parse::<u64>().unwrap())
+                            .collect::<Vec<u64>>();
+                        requested.lock().unwrap().push((range[0], range[1]));
+                        let start = range[0] as usize;
+                        let end = std::cmp::min(range[1] as usize + 1, data.len());
+                        let data = data[start..end].to_vec();
+                        async move {
+                            let mut response = hyper::Response::new(hyper::Body::from(data));
+                            *response.status_mut() = hyper::StatusCode::PARTIAL_CONTENT;
+                            Ok::<_, hyper::Error>(response)
+                        }
+                    }))
+                }
+            }))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn summary_of_fixture_matches_archive() {
+        let archive =
+            Archive::try_init(LocalFile::open_archive(ARCHIVE_0_7_1_BROTLI).await.unwrap())
+                .await
+                .unwrap();
+        let summary =
+            ArchiveSummary::try_init(LocalFile::open_archive(ARCHIVE_0_7_1_BROTLI).await.unwrap())
+                .await
+                .unwrap();
+        let expected = ArchiveSummary::from(&archive);
+        assert_eq!(summary.header_size(), expected.header_size());
+        assert_eq!(summary.header_checksum(), expected.header_checksum());
+        assert_eq!(summary.source_checksum().to_string(), ZERO_B2SUM);
+        assert_eq!(summary.total_chunks(), expected.total_chunks());
+        assert_eq!(summary.unique_chunks(), expected.unique_chunks());
+        assert_eq!(summary.compressed_size(), expected.compressed_size());
+        assert_eq!(summary.chunk_size_stats(), expected.chunk_size_stats());
+        assert_eq!(summary.total_source_size(), expected.total_source_size());
+    }
+
+    #[tokio::test]
+    async fn remote_checksums_only_read_header() {
+        let data = std::fs::read(ARCHIVE_0_7_1_BROTLI).unwrap();
+        let header_size =
+            Archive::try_init(LocalFile::open_archive(ARCHIVE_0_7_1_BROTLI).await.unwrap())
+                .await
+                .unwrap()
+                .header_size() as u64;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let server = serve_recording_ranges(listener, data, requested.clone());
+        let url = reqwest::Url::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let read = async {
+            let reader = PrefetchedReader::prefetch(HttpReader::from_url(url)).await?;
+            read_checksums(reader, None).await
+        };
+        let (source_checksum, _header_checksum) = tokio::select! {
+            _ = server => panic!("server ended"),
+            checksums = read => checksums.unwrap(),
+        };
+        assert_eq!(source_checksum.to_string(), ZERO_B2SUM);
+        // Only the header is read, the pre-header first and then the rest of it
+        let requested = requested.lock().unwrap();
+        assert_eq!(requested.len(), 2);
+        assert!(requested.iter().all(|(_start, end)| *end < header_size));
     }
 }
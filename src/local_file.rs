@@ -0,0 +1,178 @@
+use anyhow::{Context, Result};
+use bitar::archive_reader::IoReader;
+use core::pin::Pin;
+use core::task::{self, Poll};
+use std::io::Cursor;
+use std::io::{self, SeekFrom};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
+
+use crate::transport_cmd::TransportCompression;
+
+/// A local file opened for async reading and seeking.
+///
+/// Used both as input to the chunker and as the reader of local archives, so that all local
+/// reads go through the same async I/O and never block the runtime.
+#[derive(Debug)]
+pub struct LocalFile {
+    inner: Inner,
+}
+
+#[derive(Debug)]
+enum Inner {
+    File(File),
+    // Archive unwrapped from its transport compression
+    Unwrapped(Cursor<Vec<u8>>),
+}
+
+impl LocalFile {
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .await
+            .context(format!("Failed to open {}", path.display()))?;
+        Ok(Self {
+            inner: Inner::File(file),
+        })
+    }
+
+    /// Open the file as an archive reader. An archive wrapped in a transport compression is
+    /// decompressed into memory.
+    pub async fn open_archive(path: impl AsRef<Path>) -> Result<IoReader<Self>> {
+        let path = path.as_ref();
+        let mut file = File::open(path)
+            .await
+            .context(format!("Failed to open {}", path.display()))?;
+        let mut magic = [0u8; TransportCompression::MAGIC_LEN];
+        let mut magic_len = 0;
+        while magic_len < magic.len() {
+            match file
+                .read(&mut magic[magic_len..])
+                .await
+                .context(format!("Failed to read {}", path.display()))?
+            {
+                0 => break,
+                n => magic_len += n,
+            }
+        }
+        if let Some(compression) = TransportCompression::detect(&magic[..magic_len]) {
+            return Ok(IoReader::new(Self {
+                inner: Inner::Unwrapped(Cursor::new(compression.unwrap_file(path).await?)),
+            }));
+        }
+        file.seek(SeekFrom::Start(0))
+            .await
+            .context(format!("Failed to read {}", path.display()))?;
+        Ok(IoReader::new(Self {
+            inner: Inner::File(file),
+        }))
+    }
+
+    /// Open a part file of a split archive. Parts hold plain chunk data and are never wrapped.
+    pub async fn open_part(path: impl AsRef<Path>) -> Result<IoReader<Self>> {
+        Ok(IoReader::new(Self::open(path).await?))
+    }
+
+    /// Open all part files next to a split archive, none if the archive isn't split.
+    pub async fn open_parts(archive: &Path, num_parts: usize) -> Result<Vec<IoReader<Self>>> {
+        let mut parts = Vec::with_capacity(num_parts);
+        for index in 0..num_parts {
+            parts.push(Self::open_part(crate::compress_cmd::part_path(archive, index)).await?);
+        }
+        Ok(parts)
+    }
+
+    pub async fn metadata(&self) -> io::Result<std::fs::Metadata> {
+        match &self.inner {
+            Inner::File(file) => file.metadata().await,
+            Inner::Unwrapped(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "no metadata of an unwrapped archive",
+            )),
+        }
+    }
+}
+
+impl AsyncRead for LocalFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            Inner::File(file) => Pin::new(file).poll_read(cx, buf),
+            Inner::Unwrapped(cursor) => Pin::new(cursor).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncSeek for LocalFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        match &mut self.get_mut().inner {
+            Inner::File(file) => Pin::new(file).start_seek(position),
+            Inner::Unwrapped(cursor) => Pin::new(cursor).start_seek(position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<u64>> {
+        match &mut self.get_mut().inner {
+            Inner::File(file) => Pin::new(file).poll_complete(cx),
+            Inner::Unwrapped(cursor) => Pin::new(cursor).poll_complete(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitar::{archive_reader::ArchiveReader, ChunkOffset};
+    use futures_util::StreamExt;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn read_scattered_offsets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large");
+        let data: Vec<u8> = (0..16 * 1024 * 1024u32)
+            .map(|v| (v ^ (v >> 9)) as u8)
+            .collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let chunks = vec![
+            ChunkOffset::new(12 * 1024 * 1024, 3000),
+            ChunkOffset::new(17, 1),
+            ChunkOffset::new(data.len() as u64 - 100, 100),
+            ChunkOffset::new(5 * 1024 * 1024 + 3, 1024 * 1024),
+            ChunkOffset::new(0, 64),
+        ];
+        let mut reader = LocalFile::open_archive(&path).await.unwrap();
+        for chunk in &chunks {
+            let read = reader.read_at(chunk.offset, chunk.size).await.unwrap();
+            assert_eq!(&read[..], &data[chunk.offset as usize..][..chunk.size]);
+        }
+        let read: Vec<_> = reader
+            .read_chunks(chunks.clone())
+            .map(|result| result.unwrap())
+            .collect()
+            .await;
+        for (read, chunk) in read.iter().zip(&chunks) {
+            assert_eq!(&read[..], &data[chunk.offset as usize..][..chunk.size]);
+        }
+
+        // Reading sequentially, as the chunker does, gives the whole file
+        let mut file = LocalFile::open(&path).await.unwrap();
+        let mut read_back = vec![];
+        file.read_to_end(&mut read_back).await.unwrap();
+        assert_eq!(read_back, data);
+    }
+
+    #[tokio::test]
+    async fn open_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = LocalFile::open(&dir.path().join("missing"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Failed to open "));
+    }
+}
@@ -1,24 +1,45 @@
+mod analyze_cmd;
 mod clone_cmd;
 mod compress_cmd;
 mod diff_cmd;
+mod dump_chunk_cmd;
+mod export_cmd;
+mod features_cmd;
 mod info_cmd;
+mod local_file;
+mod merge_cmd;
+mod progress;
+mod repair_cmd;
+mod stats_cmd;
 mod string_utils;
+mod transport_cmd;
+mod tune_cmd;
+mod verify_cmd;
 
 use anyhow::{anyhow, bail, Context, Result};
 use clap::{App, Arg, SubCommand};
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::convert::TryFrom;
 use std::path::Path;
 use std::time::Duration;
 use url::Url;
 
+use crate::progress::ProgressFormat;
 use crate::string_utils::*;
+use bitar::archive_reader::{HttpReader, RetryJitter};
 use bitar::chunker;
+use bitar::header::DictionaryKey;
+use bitar::ChunkHasher;
+use bitar::CodecDictionaries;
 use bitar::Compression;
 use bitar::HashSum;
 
 pub const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 pub const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Decorrelated retry jitter is capped at this factor of the retry delay.
+const DECORRELATED_JITTER_MAX_FACTOR: u64 = 10;
+
 fn parse_hash_chunker_config(
     matches: &clap::ArgMatches<'_>,
     default_window_size: &str,
@@ -47,10 +68,20 @@ fn parse_hash_chunker_config(
         min_chunk_size,
         max_chunk_size,
         window_size,
+        buzhash_table: None,
     })
 }
 
 fn parse_chunker_config(matches: &clap::ArgMatches<'_>) -> Result<chunker::Config> {
+    if matches.is_present("buzhash-seed")
+        && (matches.is_present("fixed-size")
+            || !matches
+                .value_of("hash-chunking")
+                .unwrap_or("RollSum")
+                .eq_ignore_ascii_case("buzhash"))
+    {
+        return Err(anyhow!("A buzhash seed requires BuzHash chunking"));
+    }
     Ok(
         match (
             matches.value_of("fixed-size"),
@@ -62,7 +93,16 @@ fn parse_chunker_config(matches: &clap::ArgMatches<'_>) -> Result<chunker::Confi
         ) {
             (Some(fixed_size), _) => chunker::Config::FixedSize(parse_size(fixed_size)?),
             (_, "rollsum") => chunker::Config::RollSum(parse_hash_chunker_config(matches, "64B")?),
-            (_, "buzhash") => chunker::Config::BuzHash(parse_hash_chunker_config(matches, "16B")?),
+            (_, "buzhash") => {
+                let mut config = parse_hash_chunker_config(matches, "16B")?;
+                if let Some(seed) = matches.value_of("buzhash-seed") {
+                    let seed = seed
+                        .parse::<u64>()
+                        .context(format!("Invalid buzhash seed '{}'", seed))?;
+                    config.buzhash_table = Some(chunker::BuzHashTable::from_seed(seed));
+                }
+                chunker::Config::BuzHash(config)
+            }
             _ => unreachable!(),
         },
     )
@@ -88,84 +128,201 @@ fn parse_compression(matches: &clap::ArgMatches<'_>) -> Result<Option<Compressio
         .unwrap_or("6")
         .parse()
         .context("Failed to parse compression level")?;
-    Ok(
-        match matches
-            .value_of("compression")
-            .unwrap_or("brotli")
-            .to_lowercase()
-            .as_ref()
-        {
-            #[cfg(feature = "lzma-compression")]
-            "lzma" => Some(Compression::lzma(compression_level)?),
-            #[cfg(feature = "zstd-compression")]
-            "zstd" => Some(Compression::zstd(compression_level)?),
-            "brotli" => Some(Compression::brotli(compression_level)?),
-            "none" => None,
-            name => return Err(anyhow!("Invalid compression ({})", name)),
+    let compression = match matches
+        .value_of("compression")
+        .unwrap_or("brotli")
+        .to_lowercase()
+        .as_ref()
+    {
+        #[cfg(feature = "lzma-compression")]
+        "lzma" => Some(Compression::lzma(compression_level)?),
+        #[cfg(feature = "zstd-compression")]
+        "zstd" => Some(Compression::zstd(compression_level)?),
+        "brotli" => Some(Compression::brotli(compression_level)?),
+        "none" => None,
+        name => return Err(anyhow!("Invalid compression ({})", name)),
+    };
+    let compression = match (compression, matches.value_of("compression-window-log")) {
+        (Some(compression), Some(window_log)) => Some(
+            compression.with_window_log(
+                window_log
+                    .parse()
+                    .context("Failed to parse compression window log")?,
+            )?,
+        ),
+        (None, Some(_)) => return Err(anyhow!("Compression window log set without compression")),
+        (compression, None) => compression,
+    };
+    match (compression, matches.value_of("compression-threads")) {
+        (Some(compression), Some(threads)) => Ok(Some(
+            compression.with_threads(
+                threads
+                    .parse()
+                    .context("Failed to parse compression threads")?,
+            )?,
+        )),
+        (None, Some(_)) => Err(anyhow!("Compression threads set without compression")),
+        (compression, None) => Ok(compression),
+    }
+}
+
+fn parse_dedup_check(matches: &clap::ArgMatches<'_>) -> Result<Option<compress_cmd::DedupCheck>> {
+    let min_ratio = match matches.value_of("min-dedup-ratio") {
+        Some(percent) => percent
+            .parse::<f64>()
+            .context("Failed to parse min dedup ratio")?,
+        None => return Ok(None),
+    };
+    if !(0.0..=100.0).contains(&min_ratio) {
+        bail!("Invalid min dedup ratio (valid range is 0-100)");
+    }
+    let check_after = parse_size(matches.value_of("dedup-check-size").unwrap_or("64MiB"))?;
+    Ok(Some(compress_cmd::DedupCheck {
+        check_after: check_after as u64,
+        min_ratio: min_ratio / 100.0,
+    }))
+}
+
+fn parse_progress_format(matches: &clap::ArgMatches<'_>) -> ProgressFormat {
+    match matches.value_of("progress-format") {
+        Some("bar") => ProgressFormat::Bar,
+        Some("plain") => ProgressFormat::Plain,
+        Some("json") => ProgressFormat::Json,
+        _ => ProgressFormat::detect(),
+    }
+}
+
+fn parse_chunk_hasher(matches: &clap::ArgMatches<'_>) -> Result<ChunkHasher> {
+    match matches.value_of("hash-personalization") {
+        Some(personalization) => ChunkHasher::with_personalization(personalization.as_bytes())
+            .context("Invalid hash personalization"),
+        None => Ok(ChunkHasher::default()),
+    }
+}
+
+fn parse_dictionary_key(
+    matches: &clap::ArgMatches<'_>,
+    name: &str,
+) -> Result<Option<DictionaryKey>> {
+    let path = match matches.value_of(name) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let key = std::fs::read_to_string(path).context(format!("Failed to read key file {}", path))?;
+    hex_str_to_vec(key.trim())
+        .ok()
+        .and_then(|key| DictionaryKey::from_slice(&key))
+        .map(Some)
+        .ok_or_else(|| {
+            anyhow!(
+                "Invalid key in {} (expected {} hex encoded bytes)",
+                path,
+                DictionaryKey::SIZE
+            )
+        })
+}
+
+fn parse_buffer_count(matches: &clap::ArgMatches<'_>, name: &str, default: usize) -> Result<usize> {
+    match matches.value_of(name) {
+        Some(count) => match count.parse::<usize>() {
+            Ok(count) if count > 0 => Ok(count),
+            _ => Err(anyhow!("Invalid {} value", name)),
         },
-    )
+        None => Ok(default),
+    }
 }
 
-fn parse_size(size_str: &str) -> Result<usize> {
-    let size_val: String = size_str.chars().filter(|a| a.is_numeric()).collect();
-    let size_val: usize = size_val.parse().context("Failed to parse")?;
-    let size_unit: String = size_str.chars().filter(|a| !a.is_numeric()).collect();
-    if size_unit.is_empty() {
-        return Ok(size_val);
+fn parse_codec_dictionaries(matches: &clap::ArgMatches<'_>) -> Result<CodecDictionaries> {
+    let mut dictionaries = CodecDictionaries::new();
+    for value in matches.values_of("codec-dictionary").unwrap_or_default() {
+        let mut split = value.splitn(2, '=');
+        let (id, path) = match (split.next(), split.next()) {
+            (Some(id), Some(path)) => (id, path),
+            _ => bail!("Invalid codec dictionary '{}' (expected ID=FILE)", value),
+        };
+        let id = id
+            .parse::<u32>()
+            .context(format!("Invalid codec id '{}'", id))?;
+        let dictionary =
+            std::fs::read(path).context(format!("Failed to read codec dictionary {}", path))?;
+        dictionaries.insert(id, dictionary);
     }
-    Ok(match size_unit.as_str() {
-        "GiB" => 1024 * 1024 * 1024 * size_val,
-        "MiB" => 1024 * 1024 * size_val,
-        "KiB" => 1024 * size_val,
-        "B" => size_val,
-        unit => return Err(anyhow!("Invalid unit ({})", unit)),
+    Ok(dictionaries)
+}
+
+fn parse_retry_jitter(matches: &clap::ArgMatches<'_>) -> Result<RetryJitter> {
+    Ok(match matches.value_of("http-retry-jitter") {
+        Some("full") => RetryJitter::Full,
+        Some("decorrelated") => {
+            let retry_delay = matches
+                .value_of("http-retry-delay")
+                .map(|v| v.parse())
+                .unwrap_or(Ok(0))
+                .context("Failed to parse http-retry-delay")?;
+            RetryJitter::Decorrelated {
+                max_delay: Duration::from_secs(retry_delay * DECORRELATED_JITTER_MAX_FACTOR),
+            }
+        }
+        _ => RetryJitter::None,
+    })
+}
+
+fn parse_remote_input(matches: &clap::ArgMatches<'_>, url: Url) -> Result<clone_cmd::RemoteInput> {
+    Ok(clone_cmd::RemoteInput {
+        url,
+        retries: matches
+            .value_of("http-retry-count")
+            .unwrap_or("0")
+            .parse()
+            .context("Failed to parse http-retry-count")?,
+        retry_delay: Duration::from_secs(
+            matches
+                .value_of("http-retry-delay")
+                .map(|v| v.parse())
+                .unwrap_or(Ok(0))
+                .context("Failed to parse http-retry-delay")?,
+        ),
+        retry_jitter: parse_retry_jitter(matches)?,
+        receive_timeout: if let Some(v) = matches.value_of("http-timeout") {
+            Some(Duration::from_secs(
+                v.parse().context("Failed to parse http-timeout")?,
+            ))
+        } else {
+            None
+        },
+        headers: match matches.values_of("http-header") {
+            Some(values) => {
+                let mut headers = HeaderMap::new();
+                for header in values {
+                    let mut split = header.splitn(2, ':');
+                    let name = split.next().unwrap().trim_end_matches(": ").trim();
+                    let value = split.next().context("Missing header value")?.trim();
+                    headers.insert(
+                        HeaderName::from_bytes(name.as_bytes()).context("Invalid header name")?,
+                        HeaderValue::from_str(value).context("Invalid header value")?,
+                    );
+                }
+                headers
+            }
+            None => HeaderMap::new(),
+        },
+        http2_prior_knowledge: matches.is_present("http2-prior-knowledge"),
+        full_download_limit: matches
+            .value_of("http-full-download-limit")
+            .map(parse_size)
+            .transpose()?
+            .map(|limit| limit as u64)
+            .unwrap_or(HttpReader::DEFAULT_FULL_DOWNLOAD_LIMIT),
     })
 }
 
 fn parse_input_config(matches: &clap::ArgMatches<'_>) -> Result<clone_cmd::InputArchive> {
     let input = matches.value_of("INPUT").unwrap().to_string();
     Ok(match input.parse::<Url>() {
+        Ok(url) if url.scheme() == "sftp" => clone_cmd::InputArchive::Sftp(url),
         Ok(url) => {
             // Use as URL
-            clone_cmd::InputArchive::Remote(Box::new(clone_cmd::RemoteInput {
-                url,
-                retries: matches
-                    .value_of("http-retry-count")
-                    .unwrap_or("0")
-                    .parse()
-                    .context("Failed to parse http-retry-count")?,
-                retry_delay: Duration::from_secs(
-                    matches
-                        .value_of("http-retry-delay")
-                        .map(|v| v.parse())
-                        .unwrap_or(Ok(0))
-                        .context("Failed to parse http-retry-delay")?,
-                ),
-                receive_timeout: if let Some(v) = matches.value_of("http-timeout") {
-                    Some(Duration::from_secs(
-                        v.parse().context("Failed to parse http-timeout")?,
-                    ))
-                } else {
-                    None
-                },
-                headers: match matches.values_of("http-header") {
-                    Some(values) => {
-                        let mut headers = HeaderMap::new();
-                        for header in values {
-                            let mut split = header.splitn(2, ':');
-                            let name = split.next().unwrap().trim_end_matches(": ").trim();
-                            let value = split.next().context("Missing header value")?.trim();
-                            headers.insert(
-                                HeaderName::from_bytes(name.as_bytes())
-                                    .context("Invalid header name")?,
-                                HeaderValue::from_str(value).context("Invalid header value")?,
-                            );
-                        }
-                        headers
-                    }
-                    None => HeaderMap::new(),
-                },
-            }))
+            clone_cmd::InputArchive::Remote(Box::new(parse_remote_input(matches, url)?))
         }
         Err(_) => {
             // Use as path
@@ -174,9 +331,10 @@ fn parse_input_config(matches: &clap::ArgMatches<'_>) -> Result<clone_cmd::Input
     })
 }
 
-fn init_log(level: log::LevelFilter) -> Result<()> {
+// Log to stderr instead of stdout when stdout is used for command output.
+fn init_log(level: log::LevelFilter, to_stderr: bool) -> Result<()> {
     let local_level = level;
-    fern::Dispatch::new()
+    let dispatch = fern::Dispatch::new()
         .format(move |out, message, record| {
             if local_level > log::LevelFilter::Info {
                 // Add some extra info to each message in debug
@@ -191,10 +349,13 @@ fn init_log(level: log::LevelFilter) -> Result<()> {
                 out.finish(format_args!("{}", message))
             }
         })
-        .level(level)
-        .chain(std::io::stdout())
-        .apply()
-        .context("Unable to initialize log")?;
+        .level(level);
+    let dispatch = if to_stderr {
+        dispatch.chain(std::io::stderr())
+    } else {
+        dispatch.chain(std::io::stdout())
+    };
+    dispatch.apply().context("Unable to initialize log")?;
     Ok(())
 }
 
@@ -233,11 +394,17 @@ fn add_chunker_args<'a, 'b>(
                 .value_name("SIZE")
                 .help("Set size of the rolling hash window to use for chunking. [default: 64B for RollSum, 16B for BuzHash]")
         )
+        .arg(
+            Arg::with_name("buzhash-seed")
+                .long("buzhash-seed")
+                .value_name("SEED")
+                .help("Generate the BuzHash table from SEED instead of using the built-in table."),
+        )
         .arg(
             Arg::with_name("fixed-size")
                 .long("fixed-size")
                 .value_name("SIZE")
-                .help("Use fixed size chunking instead of rolling hash.")
+                .help("Use fixed size chunking, with chunks of SIZE (e.g. 64KiB), instead of rolling hash.")
                 .conflicts_with("hash-chunking"),
         )
         .arg(
@@ -251,6 +418,18 @@ fn add_chunker_args<'a, 'b>(
                 .long("compression")
                 .value_name("TYPE")
                 .help(compression_desc),
+        )
+        .arg(
+            Arg::with_name("compression-window-log")
+                .long("compression-window-log")
+                .value_name("BITS")
+                .help("Set the compression window size to 2^BITS bytes [default: algorithm default]"),
+        )
+        .arg(
+            Arg::with_name("compression-threads")
+                .long("compression-threads")
+                .value_name("COUNT")
+                .help("Compress each chunk using COUNT codec threads, useful for large chunks (zstd only)"),
         ).arg(
             Arg::with_name("hash-length")
                 .long("hash-length")
@@ -259,12 +438,19 @@ fn add_chunker_args<'a, 'b>(
         )
 }
 
+fn dictionary_key_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("dictionary-key")
+        .long("dictionary-key")
+        .value_name("KEYFILE")
+        .help("Decrypt the archive dictionary using the key in KEYFILE")
+}
+
 fn add_input_archive_args<'a, 'b>(sub_cmd: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
     sub_cmd
         .arg(
             Arg::with_name("INPUT")
                 .value_name("INPUT")
-                .help("Input file (can be a local archive or a URL)")
+                .help("Input file (can be a local archive, an http(s) URL or an sftp URL)")
                 .required(true),
         )
         .arg(
@@ -279,6 +465,19 @@ fn add_input_archive_args<'a, 'b>(sub_cmd: clap::App<'a, 'b>) -> clap::App<'a, '
                 .value_name("SECONDS")
                 .help("Delay retry for some time on transfer failure [default: 0]"),
         )
+        .arg(
+            Arg::with_name("http-retry-jitter")
+                .long("http-retry-jitter")
+                .value_name("JITTER")
+                .possible_values(&["none", "full", "decorrelated"])
+                .help("Randomize the retry delay, full (0 to delay) or decorrelated (delay to 10 times delay) [default: none]"),
+        )
+        .arg(
+            Arg::with_name("http-full-download-limit")
+                .long("http-full-download-limit")
+                .value_name("SIZE")
+                .help("Download the whole archive if the server doesn't support range requests and the archive is at most SIZE [default: 16MiB]"),
+        )
         .arg(
             Arg::with_name("http-timeout")
                 .long("http-timeout")
@@ -292,6 +491,11 @@ fn add_input_archive_args<'a, 'b>(sub_cmd: clap::App<'a, 'b>) -> clap::App<'a, '
                 .multiple(true)
                 .help("Provide custom http header"),
         )
+        .arg(
+            Arg::with_name("http2-prior-knowledge")
+                .long("http2-prior-knowledge")
+                .help("Use HTTP/2 without negotiation, for plain http servers known to support it"),
+        )
         .arg(
             Arg::with_name("verify-header")
                 .long("verify-header")
@@ -313,20 +517,160 @@ async fn parse_opts() -> Result<()> {
                     .short("i")
                     .long("input")
                     .value_name("FILE")
-                    .help("Input file, if none is given stdin is used")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Input file, if none is given stdin is used. Can be given multiple times together with --output-dir or --combine")
                     .required(false),
             )
             .arg(
                 Arg::with_name("OUTPUT")
                     .value_name("OUTPUT")
                     .help("Output file")
-                    .required(true),
+                    .required_unless("output-dir"),
+            )
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("DIR")
+                    .conflicts_with("OUTPUT")
+                    .help("Write one archive per input file to DIR, named <input file name>.cba"),
+            )
+            .arg(
+                Arg::with_name("combine")
+                    .long("combine")
+                    .conflicts_with("output-dir")
+                    .requires("INPUT")
+                    .help("Compress all input files into a single archive holding one source per input, chunks shared between inputs are stored once"),
+            )
+            .arg(
+                Arg::with_name("chunk-crc")
+                    .long("chunk-crc")
+                    .help("Store a CRC32C of each chunk, allowing a cheap corruption check when cloning"),
+            )
+            .arg(
+                Arg::with_name("merge-short-tail")
+                    .long("merge-short-tail")
+                    .help("Merge a last chunk smaller than the min chunk size into the chunk before it, so inputs differing only in a tiny tail don't end with a tiny unique chunk"),
+            )
+            .arg(
+                Arg::with_name("verify")
+                    .long("verify")
+                    .help("Read back the written archive, verifying every chunk and that the rebuilt source matches the source checksum"),
+            )
+            .arg(
+                Arg::with_name("compact-rebuild-order")
+                    .long("compact-rebuild-order")
+                    .help("Store the rebuild order as runs of the same chunk, shrinking the header of highly repetitive sources. Older versions of bita fail to clone such archives"),
             )
             .arg(
                 Arg::with_name("force-create")
                     .short("f")
                     .long("force-create")
                     .help("Overwrite output files if they exist"),
+            )
+            .arg(
+                Arg::with_name("symlinks")
+                    .long("symlinks")
+                    .value_name("POLICY")
+                    .possible_values(&["follow", "store", "skip", "refuse"])
+                    .help("Compress the file an input symbolic link points to, store the link itself, skip it or refuse it [default: follow]. An input hard linked to an earlier input is stored as a hard link to it."),
+            )
+            .arg(
+                Arg::with_name("chunk-order")
+                    .long("chunk-order")
+                    .value_name("ORDER")
+                    .possible_values(&["source", "hash"])
+                    .help("Order of the chunk data in the archive, by first offset in source or by chunk hash [default: source]"),
+            )
+            .arg(
+                Arg::with_name("min-dedup-ratio")
+                    .long("min-dedup-ratio")
+                    .value_name("PERCENT")
+                    .help("Abort if less than PERCENT of the input has been deduplicated once the dedup check size is reached"),
+            )
+            .arg(
+                Arg::with_name("dedup-check-size")
+                    .long("dedup-check-size")
+                    .value_name("SIZE")
+                    .requires("min-dedup-ratio")
+                    .help("Amount of input to process before checking the dedup ratio [default: 64MiB]"),
+            )
+            .arg(
+                Arg::with_name("source-checkpoints")
+                    .long("source-checkpoints")
+                    .value_name("SIZE")
+                    .help("Store a checksum of the source up to every SIZE bytes, used to verify parts of a source"),
+            )
+            .arg(
+                Arg::with_name("dedup-hash-length")
+                    .long("dedup-hash-length")
+                    .value_name("LENGTH")
+                    .help("Length of the chunk hash used to find duplicate chunks, at least the stored hash length [default: 64]"),
+            )
+            .arg(
+                Arg::with_name("strict-hash-length")
+                    .long("strict-hash-length")
+                    .help("Fail if a chunk hash collision is likely with the given hash length"),
+            )
+            .arg(
+                Arg::with_name("split-size")
+                    .long("split-size")
+                    .value_name("SIZE")
+                    .help("Split the chunk data into part files (OUTPUT.part0, OUTPUT.part1...) of at most SIZE bytes"),
+            )
+            .arg(
+                Arg::with_name("detach-chunk-data")
+                    .long("detach-chunk-data")
+                    .conflicts_with("split-size")
+                    .help("Write all chunk data to OUTPUT.part0, leaving only the dictionary in OUTPUT. The data file can be moved and given to clone with --chunk-data."),
+            )
+            .arg(
+                Arg::with_name("temp-dir")
+                    .long("temp-dir")
+                    .value_name("DIR")
+                    .help("Directory of the temporary chunk data file [default: directory of the output]"),
+            )
+            .arg(
+                Arg::with_name("max-compress-entropy")
+                    .long("max-compress-entropy")
+                    .value_name("BITS")
+                    .help("Store chunks with an estimated entropy above BITS per byte (0-8) without compressing them, typically 7.5 to skip already compressed or encrypted data"),
+            )
+            .arg(
+                Arg::with_name("chunk-alignment")
+                    .long("chunk-alignment")
+                    .value_name("SIZE")
+                    .help("Pad the chunk data so every chunk starts at a multiple of SIZE bytes (a power of two), for reading chunks with direct I/O"),
+            )
+            .arg(
+                Arg::with_name("content-type")
+                    .long("content-type")
+                    .value_name("TYPE")
+                    .help("Store a MIME type or other hint of the content of the input in the archive, printed by info"),
+            )
+            .arg(
+                Arg::with_name("hash-buffers")
+                    .long("hash-buffers")
+                    .value_name("COUNT")
+                    .help("Limit number of chunks hashed simultaneously, may exceed the compression limit since hashing is cheaper [default: buffered-chunks]"),
+            )
+            .arg(
+                Arg::with_name("compress-buffers")
+                    .long("compress-buffers")
+                    .value_name("COUNT")
+                    .help("Limit number of chunks compressed simultaneously [default: buffered-chunks]"),
+            )
+            .arg(
+                Arg::with_name("hash-personalization")
+                    .long("hash-personalization")
+                    .value_name("STRING")
+                    .help("Personalization of the chunk hash (max 16 bytes), must be given when cloning"),
+            )
+            .arg(
+                Arg::with_name("encrypt-dictionary")
+                    .long("encrypt-dictionary")
+                    .value_name("KEYFILE")
+                    .help("Encrypt the archive dictionary using the key (32 hex encoded bytes) in KEYFILE, leaving the chunk data as is. The key must be given to clone and info."),
             ),
         &compression_desc,
     );
@@ -338,7 +682,19 @@ async fn parse_opts() -> Result<()> {
             Arg::with_name("OUTPUT")
                 .value_name("OUTPUT")
                 .help("Output file")
-                .required(true),
+                .required_unless("plan-only"),
+        )
+        .arg(
+            Arg::with_name("plan-only")
+                .long("plan-only")
+                .help("Print the chunks to fetch from the archive and where in the output to write them, without cloning")
+                .conflicts_with_all(&["seed", "seed-archive", "seed-output"]),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .requires("plan-only")
+                .help("Print the plan as JSON"),
         )
         .arg(
             Arg::with_name("seed")
@@ -347,11 +703,96 @@ async fn parse_opts() -> Result<()> {
                 .help("File to use as seed while cloning or '-' to read from stdin")
                 .multiple(true),
         )
+        .arg(
+            Arg::with_name("seed-scan-limit")
+                .long("seed-scan-limit")
+                .value_name("SIZE")
+                .help("Only scan the first SIZE (e.g. 512MiB) of each seed file for chunks, for seeds likely to only match at the start. The rest is fetched from the archive.")
+                .requires("seed"),
+        )
+        .arg(
+            Arg::with_name("seed-archive")
+                .value_name("FILE")
+                .long("seed-archive")
+                .help("Archive to use as seed while cloning, re-chunked if not chunked like the cloned archive")
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("verify-stdin-seed")
+                .long("verify-stdin-seed")
+                .value_name("CHECKSUM")
+                .help("Verify that the seed read from stdin has the checksum given"),
+        )
         .arg(
             Arg::with_name("seed-output")
                 .long("seed-output")
                 .help("Use the output file as seed and update in-place."),
         )
+        .arg(
+            Arg::with_name("codec-dictionary")
+                .long("codec-dictionary")
+                .value_name("ID=FILE")
+                .help("Dictionary to give the custom codec with the id when decompressing")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("chunk-retries")
+                .long("chunk-retries")
+                .value_name("COUNT")
+                .help("Fetch chunks failing verification again, bypassing caches, up to this many times [default: 0]"),
+        )
+        .arg(
+            Arg::with_name("strict-seeds")
+                .long("strict-seeds")
+                .help("Only use chunks from seeds which also match the CRC32C of the archive chunk, guarding against corrupt seed chunks matching a short chunk hash. Requires an archive compressed with --chunk-crc."),
+        )
+        .arg(
+            Arg::with_name("allow-size-mismatch")
+                .long("allow-size-mismatch")
+                .help("Clone to an output block device larger than the source, writing the source to the start of the device and leaving the rest as is"),
+        )
+        .arg(
+            Arg::with_name("no-sync")
+                .long("no-sync")
+                .help("Don't wait for the output to reach stable storage before returning. Has no effect with --atomic, which always syncs the output before replacing the target."),
+        )
+        .arg(
+            Arg::with_name("contiguous")
+                .long("contiguous")
+                .help("Allocate the whole output file before writing to it, so the filesystem can place it in few contiguous extents instead of fragmenting it (Linux only, ignored where not supported)"),
+        )
+        .arg(
+            Arg::with_name("chunk-data")
+                .long("chunk-data")
+                .value_name("FILE")
+                .help("Read the chunk data of an archive compressed with --detach-chunk-data from FILE instead of INPUT.part0. A path for a local archive or a URL, possibly relative to the archive URL, for a remote one."),
+        )
+        .arg(
+            Arg::with_name("chunk-store")
+                .long("chunk-store")
+                .value_name("DIR")
+                .conflicts_with("chunk-data")
+                .help("Read every chunk from its own object in the chunk store DIR, as written by export --format chunk-store. A path for a local archive or a URL, possibly relative to the archive URL, for a remote one. Also used to clone a local casync index (INPUT ending in .caibx) from its .castr chunk store."),
+        )
+        .arg(
+            Arg::with_name("fallback")
+                .long("fallback")
+                .value_name("URL")
+                .conflicts_with_all(&["chunk-data", "chunk-store"])
+                .help("Read every range missing from the local archive INPUT, e.g. an incomplete mirror, from the same archive at URL instead."),
+        )
+        .arg(
+            Arg::with_name("cache-fallback")
+                .long("cache-fallback")
+                .requires("fallback")
+                .help("Write the ranges read from the fallback URL into the local archive INPUT."),
+        )
+        .arg(
+            Arg::with_name("concurrent-seeds")
+                .long("concurrent-seeds")
+                .help("Scan seed files while fetching from the archive. Overlaps disk and network I/O, but chunks may be fetched before they're found in a seed."),
+        )
         .arg(
             Arg::with_name("force-create")
                 .short("f")
@@ -362,7 +803,44 @@ async fn parse_opts() -> Result<()> {
             Arg::with_name("verify-output")
                 .long("verify-output")
                 .help("Vefify that the checksum of the output matches with the archive."),
-        );
+        )
+        .arg(
+            Arg::with_name("crc-verify")
+                .long("crc-verify")
+                .value_name("PERCENT")
+                .help("Check fetched chunks using the CRC32C stored in the archive and only verify the hash of PERCENT of them, or of chunks failing the CRC check"),
+        )
+        .arg(
+            Arg::with_name("atomic")
+                .long("atomic")
+                .conflicts_with("seed-output")
+                .help("Write to a temporary file and rename it to the output when done"),
+        )
+        .arg(
+            Arg::with_name("ordered-write-buffer")
+                .long("ordered-write-buffer")
+                .value_name("SIZE")
+                .help("Buffer up to SIZE of chunks and write them to output in offset order"),
+        )
+        .arg(
+            Arg::with_name("prefetch-chunks")
+                .long("prefetch-chunks")
+                .value_name("COUNT")
+                .help("Keep fetching up to COUNT chunks ahead while earlier chunks are decompressed and written, overlapping network and CPU"),
+        )
+        .arg(
+            Arg::with_name("write-buffer")
+                .long("write-buffer")
+                .value_name("SIZE")
+                .help("Size of the buffer in front of the output, a large buffer speeds up sequential writes to block devices when combined with --ordered-write-buffer [default: 8KiB]"),
+        )
+        .arg(
+            Arg::with_name("hash-personalization")
+                .long("hash-personalization")
+                .value_name("STRING")
+                .help("Personalization of the chunk hash (max 16 bytes) used when the archive was compressed"),
+        )
+        .arg(dictionary_key_arg());
     let diff_subcmd = add_chunker_args(
         SubCommand::with_name("diff")
             .about("Show the differential between two files.")
@@ -393,6 +871,14 @@ async fn parse_opts() -> Result<()> {
             .arg(Arg::with_name("buffered-chunks").long("buffered-chunks").value_name("COUNT").global(true).help(
                 "Limit number of chunks processed simultaneously [default: cores available x 2]",
             ))
+            .arg(
+                Arg::with_name("progress-format")
+                    .long("progress-format")
+                    .value_name("FORMAT")
+                    .possible_values(&["bar", "plain", "json"])
+                    .global(true)
+                    .help("How the progress of compress, clone and diff is reported, json events are printed to stderr [default: bar on a terminal, plain otherwise]"),
+            )
             .subcommand(compress_subcmd)
             .subcommand(clone_subcmd)
             .subcommand(
@@ -403,17 +889,303 @@ async fn parse_opts() -> Result<()> {
                             .value_name("INPUT")
                             .help("Input file (can be a local archive or a URL)")
                             .required(true),
-                    ),
+                    )
+                    .arg(
+                        Arg::with_name("checksum-only")
+                            .long("checksum-only")
+                            .help("Only print the source and header checksum"),
+                    )
+                    .arg(dictionary_key_arg()),
             )
             .subcommand(diff_subcmd)
+            .subcommand(
+                SubCommand::with_name("features")
+                    .about("Print the codecs and optional features this binary was built with."),
+            )
+            .subcommand(
+                SubCommand::with_name("archive-stats")
+                    .about("Print how much of the chunk data of a local archive is referenced by chunks, and any unreferenced holes.")
+                    .arg(
+                        Arg::with_name("INPUT")
+                            .value_name("INPUT")
+                            .help("Input archive")
+                            .required(true),
+                    )
+                    .arg(dictionary_key_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("analyze")
+                    .about("Report how much the archives in a directory would dedup if they shared one chunk store. Only reads the archive dictionaries.")
+                    .arg(
+                        Arg::with_name("DIR")
+                            .value_name("DIR")
+                            .help("Directory of archives")
+                            .required(true),
+                    )
+                    .arg(dictionary_key_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("tune")
+                    .about("Chunk a sample file using several chunker settings and recommend the one giving the smallest archive.")
+                    .arg(
+                        Arg::with_name("INPUT")
+                            .value_name("INPUT")
+                            .help("Sample file")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("avg-chunk-sizes")
+                            .long("avg-chunk-sizes")
+                            .value_name("SIZES")
+                            .use_delimiter(true)
+                            .help("Comma separated average chunk sizes to try, min and max chunk size follow from the average [default: 16KiB,32KiB,64KiB,128KiB,256KiB,1MiB]"),
+                    )
+                    .arg(
+                        Arg::with_name("hash-chunking")
+                            .long("hash-chunking")
+                            .value_name("HASH")
+                            .help("Set hash to use for chunking (RollSum/BuzHash). [default: RollSum]"),
+                    )
+                    .arg(
+                        Arg::with_name("hash-length")
+                            .long("hash-length")
+                            .value_name("LENGTH")
+                            .help("Length of the stored chunk hash used to estimate the header size [default: 64]"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("verify")
+                    .about("Verify a file against the source of an archive, or the archive chunks if no file is given.")
+                    .arg(
+                        Arg::with_name("ARCHIVE")
+                            .value_name("ARCHIVE")
+                            .help("Archive (can be a local archive or a URL)")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("FILE")
+                            .value_name("FILE")
+                            .help("File to verify"),
+                    )
+                    .arg(
+                        Arg::with_name("prefix")
+                            .long("prefix")
+                            .requires("FILE")
+                            .help("Allow the file to be the first part of the source (requires source checkpoints)"),
+                    )
+                    .arg(
+                        Arg::with_name("sample")
+                            .long("sample")
+                            .value_name("PERCENT")
+                            .conflicts_with("FILE")
+                            .help("Verify a pseudo-random PERCENT of the archive chunks [default: 100]"),
+                    )
+                    .arg(
+                        Arg::with_name("sample-seed")
+                            .long("sample-seed")
+                            .value_name("SEED")
+                            .requires("sample")
+                            .help("Seed used to pick the sampled chunks, for a reproducible sample"),
+                    )
+                    .arg(
+                        Arg::with_name("hash-personalization")
+                            .long("hash-personalization")
+                            .value_name("STRING")
+                            .conflicts_with("FILE")
+                            .help("Personalization of the chunk hash given when compressing"),
+                    )
+                    .arg(
+                        Arg::with_name("full")
+                            .long("full")
+                            .conflicts_with_all(&["FILE", "sample"])
+                            .help("Verify the whole chunk data against the archive checksum in one pass"),
+                    )
+                    .arg(dictionary_key_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("dump-chunk")
+                    .about("Write a single chunk of an archive to stdout.")
+                    .arg(
+                        Arg::with_name("INPUT")
+                            .value_name("INPUT")
+                            .help("Input file (can be a local archive or a URL)")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("HASH")
+                            .value_name("HASH")
+                            .help("Hash (or unique prefix of hash) of chunk")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("decompress")
+                            .long("decompress")
+                            .help("Decompress the chunk data"),
+                    )
+                    .arg(
+                        Arg::with_name("hash-personalization")
+                            .long("hash-personalization")
+                            .value_name("STRING")
+                            .help("Personalization of the chunk hash given when compressing"),
+                    )
+                    .arg(dictionary_key_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("export")
+                    .about("Export an archive with zstd compressed chunks to the zstd seekable format, or the chunks of any archive to a chunk store.")
+                    .arg(
+                        Arg::with_name("INPUT")
+                            .value_name("INPUT")
+                            .help("Input archive")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("OUTPUT")
+                            .value_name("OUTPUT")
+                            .help("Output file, or directory of the chunk store")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("force-create")
+                            .short("f")
+                            .long("force-create")
+                            .help("Overwrite output file if it exists"),
+                    )
+                    .arg(
+                        Arg::with_name("format")
+                            .long("format")
+                            .value_name("FORMAT")
+                            .possible_values(&["zstd-seekable", "chunk-store"])
+                            .help("Format to export to. A chunk store is a directory with every chunk in store/<hh>/<hash> and the archive header in index.cba, cloned using --chunk-store [default: zstd-seekable]"),
+                    )
+                    .arg(dictionary_key_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("wrap")
+                    .about("Compress a whole local archive file for transport. A wrapped archive can still be opened locally, it's decompressed into memory.")
+                    .arg(
+                        Arg::with_name("INPUT")
+                            .value_name("INPUT")
+                            .help("Input archive")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("OUTPUT")
+                            .value_name("OUTPUT")
+                            .help("Wrapped archive")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("compression")
+                            .long("compression")
+                            .value_name("TYPE")
+                            .possible_values(&["gzip", "zstd"])
+                            .help("Compression of the whole archive [default: gzip]"),
+                    )
+                    .arg(
+                        Arg::with_name("force-create")
+                            .short("f")
+                            .long("force-create")
+                            .help("Overwrite output file if it exists"),
+                    )
+                    .arg(dictionary_key_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("unwrap")
+                    .about("Decompress a wrapped archive file back into a plain archive.")
+                    .arg(
+                        Arg::with_name("INPUT")
+                            .value_name("INPUT")
+                            .help("Wrapped archive")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("OUTPUT")
+                            .value_name("OUTPUT")
+                            .help("Output archive")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("force-create")
+                            .short("f")
+                            .long("force-create")
+                            .help("Overwrite output file if it exists"),
+                    )
+                    .arg(dictionary_key_arg()),
+            )
+            .subcommand(
+                SubCommand::with_name("repair-order")
+                    .about("Recover a corrupt rebuild order of a local archive, by chunking its source or from the chunk offsets.")
+                    .arg(
+                        Arg::with_name("INPUT")
+                            .value_name("INPUT")
+                            .help("Input archive")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("OUTPUT")
+                            .value_name("OUTPUT")
+                            .help("Repaired archive")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("source")
+                            .long("source")
+                            .value_name("FILE")
+                            .help("Source of the archive to recover the order from [default: use the chunk offsets]"),
+                    )
+                    .arg(
+                        Arg::with_name("hash-personalization")
+                            .long("hash-personalization")
+                            .value_name("STRING")
+                            .help("Personalization of the chunk hash given when compressing"),
+                    )
+                    .arg(
+                        Arg::with_name("force-create")
+                            .short("f")
+                            .long("force-create")
+                            .help("Overwrite output file if it exists"),
+                    )
+                    .arg(dictionary_key_arg().help("Decrypt the archive dictionary using the key in KEYFILE, the repaired dictionary is encrypted using the same key")),
+            )
+            .subcommand(
+                SubCommand::with_name("merge")
+                    .about("Merge archives into one archive holding the sources of all of them.")
+                    .arg(
+                        Arg::with_name("INPUT")
+                            .value_name("INPUT")
+                            .help("Input archives, the main source of the first one stays the main source")
+                            .multiple(true)
+                            .min_values(2)
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("output")
+                            .short("o")
+                            .long("output")
+                            .value_name("OUTPUT")
+                            .help("Output archive")
+                            .required(true),
+                    )
+                    .arg(
+                        Arg::with_name("force-create")
+                            .short("f")
+                            .long("force-create")
+                            .help("Overwrite output file if it exists"),
+                    )
+                    .arg(dictionary_key_arg().help("Decrypt the archive dictionaries using the key in KEYFILE, the merged dictionary is encrypted using the same key")),
+            )
             .get_matches();
 
     // Set log level
-    init_log(match matches.occurrences_of("verbose") {
-        0 => log::LevelFilter::Info,
-        1 => log::LevelFilter::Debug,
-        _ => log::LevelFilter::Trace,
-    })?;
+    init_log(
+        match matches.occurrences_of("verbose") {
+            0 => log::LevelFilter::Info,
+            1 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        },
+        matches.subcommand_name() == Some("dump-chunk"),
+    )?;
 
     let num_chunk_buffers: usize = if let Some(v) = matches.value_of("buffered-chunks") {
         v.parse().context("Invalid buffered-chunks value")?
@@ -425,11 +1197,16 @@ async fn parse_opts() -> Result<()> {
         }
     };
     if let Some(matches) = matches.subcommand_matches("compress") {
-        let output = Path::new(matches.value_of("OUTPUT").unwrap());
-        let input = matches
-            .value_of("INPUT")
-            .map(|input| Path::new(input).to_path_buf());
-        let temp_file = Path::with_extension(output, ".tmp");
+        let output = if let Some(output_dir) = matches.value_of("output-dir") {
+            compress_cmd::Output::Dir(Path::new(output_dir).to_path_buf())
+        } else {
+            compress_cmd::Output::File(Path::new(matches.value_of("OUTPUT").unwrap()).to_path_buf())
+        };
+        let inputs = matches
+            .values_of("INPUT")
+            .unwrap_or_default()
+            .map(|input| Path::new(input).to_path_buf())
+            .collect();
         let hash_length = if let Some(hash_length) = matches.value_of("hash-length") {
             let hash_length = hash_length.parse::<usize>().context("parse hash length")?;
             if !(4..=HashSum::MAX_LEN).contains(&hash_length) {
@@ -442,19 +1219,104 @@ async fn parse_opts() -> Result<()> {
         } else {
             HashSum::MAX_LEN
         };
+        let dedup_hash_length =
+            if let Some(dedup_hash_length) = matches.value_of("dedup-hash-length") {
+                let dedup_hash_length = dedup_hash_length
+                    .parse::<usize>()
+                    .context("parse dedup hash length")?;
+                if !(hash_length..=HashSum::MAX_LEN).contains(&dedup_hash_length) {
+                    bail!(
+                        "Invalid dedup hash length value (valid range is {}-{})",
+                        hash_length,
+                        HashSum::MAX_LEN
+                    );
+                }
+                dedup_hash_length
+            } else {
+                HashSum::MAX_LEN
+            };
         let chunker_config = parse_chunker_config(matches)?;
         let compression = parse_compression(matches)?;
-        compress_cmd::compress_cmd(compress_cmd::Options {
-            input,
-            output: output.to_path_buf(),
+        let dedup_check = parse_dedup_check(matches)?;
+        let chunk_hasher = parse_chunk_hasher(matches)?;
+        let source_checkpoint_interval = matches
+            .value_of("source-checkpoints")
+            .map(parse_size)
+            .transpose()?
+            .map(|interval| interval as u64);
+        if source_checkpoint_interval == Some(0) {
+            bail!("Invalid source checkpoint interval");
+        }
+        let split_size = matches
+            .value_of("split-size")
+            .map(parse_size)
+            .transpose()?
+            .map(|size| size as u64);
+        if split_size == Some(0) {
+            bail!("Invalid split size");
+        }
+        let max_compress_entropy = matches
+            .value_of("max-compress-entropy")
+            .map(|bits| bits.parse::<f64>())
+            .transpose()
+            .context("Failed to parse max compress entropy")?;
+        if matches!(max_compress_entropy, Some(bits) if !(0.0..=8.0).contains(&bits)) {
+            bail!("Invalid max compress entropy (valid range is 0-8)");
+        }
+        let chunk_data_alignment = matches
+            .value_of("chunk-alignment")
+            .map(|size| {
+                u32::try_from(parse_size(size)?)
+                    .ok()
+                    .filter(|alignment| alignment.is_power_of_two())
+                    .ok_or_else(|| anyhow!("Invalid chunk alignment (must be a power of two)"))
+            })
+            .transpose()?;
+        let hash_buffers = parse_buffer_count(matches, "hash-buffers", num_chunk_buffers)?;
+        let compress_buffers = parse_buffer_count(matches, "compress-buffers", num_chunk_buffers)?;
+        let summaries = compress_cmd::compress_cmd(compress_cmd::Options {
+            inputs,
+            symlinks: match matches.value_of("symlinks") {
+                Some("store") => compress_cmd::SymlinkPolicy::Store,
+                Some("skip") => compress_cmd::SymlinkPolicy::Skip,
+                Some("refuse") => compress_cmd::SymlinkPolicy::Refuse,
+                _ => compress_cmd::SymlinkPolicy::Follow,
+            },
+            output,
             hash_length,
+            dedup_hash_length,
             force_create: matches.is_present("force-create"),
-            temp_file,
             chunker_config,
             compression,
-            num_chunk_buffers,
+            hash_buffers,
+            compress_buffers,
+            dedup_check,
+            chunk_order: match matches.value_of("chunk-order") {
+                Some("hash") => compress_cmd::ChunkOrder::Hash,
+                _ => compress_cmd::ChunkOrder::SourceOffset,
+            },
+            chunk_hasher,
+            source_checkpoint_interval,
+            split_size,
+            detach_chunk_data: matches.is_present("detach-chunk-data"),
+            strict_hash_length: matches.is_present("strict-hash-length"),
+            combine: matches.is_present("combine"),
+            chunk_crc: matches.is_present("chunk-crc"),
+            merge_short_tail: matches.is_present("merge-short-tail"),
+            compact_rebuild_order: matches.is_present("compact-rebuild-order"),
+            verify: matches.is_present("verify"),
+            temp_dir: matches
+                .value_of("temp-dir")
+                .map(|dir| Path::new(dir).to_path_buf()),
+            max_compress_entropy,
+            chunk_data_alignment,
+            content_type: matches.value_of("content-type").map(str::to_string),
+            dictionary_key: parse_dictionary_key(matches, "encrypt-dictionary")?,
+            progress_format: parse_progress_format(matches),
         })
-        .await
+        .await?;
+        summaries.iter().for_each(compress_cmd::print_summary);
+        Ok(())
     } else if let Some(matches) = matches.subcommand_matches("clone") {
         let output = matches.value_of("OUTPUT").unwrap_or("");
         let mut seed_stdin = false;
@@ -479,22 +1341,151 @@ async fn parse_opts() -> Result<()> {
         } else {
             None
         };
+        let stdin_seed_checksum = if let Some(c) = matches.value_of("verify-stdin-seed") {
+            if !seed_stdin {
+                bail!("Verifying the stdin seed requires reading a seed from stdin (--seed -)");
+            }
+            Some(HashSum::from(
+                hex_str_to_vec(c).context("Failed to parse seed checksum")?,
+            ))
+        } else {
+            None
+        };
         let input_archive = parse_input_config(matches)?;
-        clone_cmd::clone_cmd(clone_cmd::Options {
+        let ordered_write_buffer = matches
+            .value_of("ordered-write-buffer")
+            .map(parse_size)
+            .transpose()?;
+        let crc_verify = matches
+            .value_of("crc-verify")
+            .map(|percent| percent.trim_end_matches('%').parse::<f64>())
+            .transpose()
+            .context("Failed to parse CRC verify percent")?;
+        if let Some(percent) = crc_verify {
+            if !(0.0..=100.0).contains(&percent) {
+                bail!("Invalid CRC verify percent (valid range is 0-100)");
+            }
+        }
+        let opts = clone_cmd::Options {
             input_archive,
             header_checksum,
             output: Path::new(output).to_path_buf(),
             force_create: matches.is_present("force-create"),
             seed_files,
+            concurrent_seeds: matches.is_present("concurrent-seeds"),
+            seed_scan_limit: matches
+                .value_of("seed-scan-limit")
+                .map(parse_size)
+                .transpose()?
+                .map(|size| size as u64),
+            seed_archives: matches
+                .values_of("seed-archive")
+                .unwrap_or_default()
+                .map(|path| Path::new(path).to_path_buf())
+                .collect(),
             seed_stdin,
+            stdin_seed_checksum,
             verify_output: matches.is_present("verify-output"),
+            atomic: matches.is_present("atomic"),
             seed_output,
             num_chunk_buffers,
-        })
-        .await
+            prefetch_chunks: matches
+                .value_of("prefetch-chunks")
+                .map(|count| count.parse::<usize>())
+                .transpose()
+                .context("Failed to parse prefetch-chunks")?,
+            ordered_write_buffer,
+            write_buffer: matches
+                .value_of("write-buffer")
+                .map(parse_size)
+                .transpose()?,
+            crc_verify,
+            chunk_hasher: parse_chunk_hasher(matches)?,
+            codec_dictionaries: parse_codec_dictionaries(matches)?,
+            chunk_retries: matches
+                .value_of("chunk-retries")
+                .unwrap_or("0")
+                .parse()
+                .context("Failed to parse chunk-retries")?,
+            plan_only: match (matches.is_present("plan-only"), matches.is_present("json")) {
+                (false, _) => None,
+                (true, false) => Some(clone_cmd::PlanFormat::Text),
+                (true, true) => Some(clone_cmd::PlanFormat::Json),
+            },
+            progress_format: parse_progress_format(matches),
+            strict_seeds: matches.is_present("strict-seeds"),
+            chunk_data: matches.value_of("chunk-data").map(str::to_string),
+            chunk_store: matches.value_of("chunk-store").map(str::to_string),
+            fallback: match matches.value_of("fallback") {
+                Some(url) => {
+                    let url = url.parse::<Url>().context("Failed to parse fallback URL")?;
+                    Some(Box::new(parse_remote_input(matches, url)?))
+                }
+                None => None,
+            },
+            cache_fallback: matches.is_present("cache-fallback"),
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
+            allow_size_mismatch: matches.is_present("allow-size-mismatch"),
+            sync: !matches.is_present("no-sync"),
+            contiguous: matches.is_present("contiguous"),
+        };
+        clone_cmd::clone_cmd_cancellable(opts, ctrl_c()).await
     } else if let Some(matches) = matches.subcommand_matches("info") {
         let input = matches.value_of("INPUT").unwrap();
-        info_cmd::info_cmd(input.to_string()).await
+        info_cmd::info_cmd(
+            input.to_string(),
+            matches.is_present("checksum-only"),
+            parse_dictionary_key(matches, "dictionary-key")?,
+        )
+        .await
+    } else if matches.subcommand_matches("features").is_some() {
+        features_cmd::features_cmd()
+    } else if let Some(matches) = matches.subcommand_matches("archive-stats") {
+        stats_cmd::stats_cmd(stats_cmd::Options {
+            input: Path::new(matches.value_of("INPUT").unwrap()).to_path_buf(),
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
+        })
+        .await
+    } else if let Some(matches) = matches.subcommand_matches("analyze") {
+        analyze_cmd::analyze_cmd(analyze_cmd::Options {
+            dir: Path::new(matches.value_of("DIR").unwrap()).to_path_buf(),
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
+        })
+        .await
+    } else if let Some(matches) = matches.subcommand_matches("tune") {
+        let avg_chunk_sizes = match matches.values_of("avg-chunk-sizes") {
+            Some(sizes) => sizes.map(parse_size).collect::<Result<Vec<usize>>>()?,
+            None => tune_cmd::DEFAULT_AVG_CHUNK_SIZES.to_vec(),
+        };
+        if avg_chunk_sizes.contains(&0) {
+            bail!("Invalid average chunk size");
+        }
+        let buzhash = match matches.value_of("hash-chunking") {
+            Some(hash) if hash.eq_ignore_ascii_case("buzhash") => true,
+            Some(hash) if hash.eq_ignore_ascii_case("rollsum") => false,
+            None => false,
+            Some(hash) => bail!("Invalid chunking hash '{}'", hash),
+        };
+        let hash_length = matches
+            .value_of("hash-length")
+            .map(|length| length.parse::<usize>())
+            .transpose()
+            .context("parse hash length")?
+            .unwrap_or(HashSum::MAX_LEN);
+        if !(4..=HashSum::MAX_LEN).contains(&hash_length) {
+            bail!(
+                "Invalid hash length value (valid range is 4-{})",
+                HashSum::MAX_LEN
+            );
+        }
+        tune_cmd::tune_cmd(tune_cmd::Options {
+            input: Path::new(matches.value_of("INPUT").unwrap()).to_path_buf(),
+            candidates: tune_cmd::hash_chunker_candidates(buzhash, &avg_chunk_sizes),
+            hash_length,
+            num_chunk_buffers,
+            progress_format: parse_progress_format(matches),
+        })
+        .await
     } else if let Some(matches) = matches.subcommand_matches("diff") {
         let input_a = Path::new(matches.value_of("A").unwrap());
         let input_b = Path::new(matches.value_of("B").unwrap());
@@ -506,6 +1497,103 @@ async fn parse_opts() -> Result<()> {
             chunker_config,
             compression,
             num_chunk_buffers,
+            progress_format: parse_progress_format(matches),
+        })
+        .await
+    } else if let Some(matches) = matches.subcommand_matches("verify") {
+        let sample = match matches.value_of("sample") {
+            Some(percent) => percent
+                .trim_end_matches('%')
+                .parse::<f64>()
+                .context("Failed to parse sample percent")?,
+            None => 100.0,
+        };
+        if !(sample > 0.0 && sample <= 100.0) {
+            bail!("Invalid sample percent (valid range is 0-100)");
+        }
+        let sample_seed = matches
+            .value_of("sample-seed")
+            .map(|seed| seed.parse::<u64>())
+            .transpose()
+            .context("Failed to parse sample seed")?;
+        verify_cmd::verify_cmd(verify_cmd::Options {
+            input_archive: matches.value_of("ARCHIVE").unwrap().to_string(),
+            source: matches
+                .value_of("FILE")
+                .map(|file| Path::new(file).to_path_buf()),
+            prefix: matches.is_present("prefix"),
+            sample,
+            sample_seed,
+            chunk_hasher: parse_chunk_hasher(matches)?,
+            full: matches.is_present("full"),
+            num_chunk_buffers,
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
+        })
+        .await
+    } else if let Some(matches) = matches.subcommand_matches("dump-chunk") {
+        dump_chunk_cmd::dump_chunk_cmd(dump_chunk_cmd::Options {
+            input: matches.value_of("INPUT").unwrap().to_string(),
+            hash_prefix: matches.value_of("HASH").unwrap().to_string(),
+            decompress: matches.is_present("decompress"),
+            chunk_hasher: parse_chunk_hasher(matches)?,
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
+        })
+        .await
+    } else if let Some(matches) = matches.subcommand_matches("export") {
+        export_cmd::export_cmd(export_cmd::Options {
+            input: Path::new(matches.value_of("INPUT").unwrap()).to_path_buf(),
+            output: Path::new(matches.value_of("OUTPUT").unwrap()).to_path_buf(),
+            force_create: matches.is_present("force-create"),
+            format: match matches.value_of("format") {
+                Some("chunk-store") => export_cmd::Format::ChunkStore,
+                _ => export_cmd::Format::ZstdSeekable,
+            },
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
+        })
+        .await
+    } else if let Some(matches) = matches.subcommand_matches("wrap") {
+        transport_cmd::transport_cmd(transport_cmd::Options {
+            input: Path::new(matches.value_of("INPUT").unwrap()).to_path_buf(),
+            output: Path::new(matches.value_of("OUTPUT").unwrap()).to_path_buf(),
+            compression: Some(match matches.value_of("compression") {
+                Some("zstd") => transport_cmd::TransportCompression::Zstd,
+                _ => transport_cmd::TransportCompression::Gzip,
+            }),
+            force_create: matches.is_present("force-create"),
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
+        })
+        .await
+    } else if let Some(matches) = matches.subcommand_matches("unwrap") {
+        transport_cmd::transport_cmd(transport_cmd::Options {
+            input: Path::new(matches.value_of("INPUT").unwrap()).to_path_buf(),
+            output: Path::new(matches.value_of("OUTPUT").unwrap()).to_path_buf(),
+            compression: None,
+            force_create: matches.is_present("force-create"),
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
+        })
+        .await
+    } else if let Some(matches) = matches.subcommand_matches("repair-order") {
+        repair_cmd::repair_cmd(repair_cmd::Options {
+            input: Path::new(matches.value_of("INPUT").unwrap()).to_path_buf(),
+            output: Path::new(matches.value_of("OUTPUT").unwrap()).to_path_buf(),
+            source: matches
+                .value_of("source")
+                .map(|source| Path::new(source).to_path_buf()),
+            chunk_hasher: parse_chunk_hasher(matches)?,
+            force_create: matches.is_present("force-create"),
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
+        })
+        .await
+    } else if let Some(matches) = matches.subcommand_matches("merge") {
+        merge_cmd::merge_cmd(merge_cmd::Options {
+            inputs: matches
+                .values_of("INPUT")
+                .unwrap_or_default()
+                .map(|input| Path::new(input).to_path_buf())
+                .collect(),
+            output: Path::new(matches.value_of("output").unwrap()).to_path_buf(),
+            force_create: matches.is_present("force-create"),
+            dictionary_key: parse_dictionary_key(matches, "dictionary-key")?,
         })
         .await
     } else {
@@ -513,6 +1601,13 @@ async fn parse_opts() -> Result<()> {
     }
 }
 
+// Resolves on Ctrl-C, never if the signal can't be listened for.
+async fn ctrl_c() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        futures_util::future::pending::<()>().await;
+    }
+}
+
 fn main() -> Result<()> {
     let rt = tokio::runtime::Runtime::new()?;
     rt.block_on(async { parse_opts().await })
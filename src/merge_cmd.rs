@@ -0,0 +1,371 @@
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::StreamExt;
+use log::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+use crate::{compress_cmd, human_size, info_cmd, local_file::LocalFile};
+use bitar::{
+    archive_reader::{ArchiveReader, IoReader},
+    chunk_dictionary as dict,
+    header::{self, DictionaryKey},
+    Archive, ChunkOffset, HashSum,
+};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub inputs: Vec<PathBuf>,
+    pub output: PathBuf,
+    pub force_create: bool,
+    // Key decrypting the input dictionaries, the output dictionary is encrypted using it too
+    pub dictionary_key: Option<DictionaryKey>,
+}
+
+async fn open_archive(
+    path: &Path,
+    key: Option<&DictionaryKey>,
+) -> Result<Archive<IoReader<LocalFile>>> {
+    let archive = info_cmd::init_archive(LocalFile::open_archive(path).await?, key)
+        .await
+        .context(format!("Failed to read archive {}", path.display()))?;
+    if !archive.chunk_data_part_sizes().is_empty() {
+        bail!("Merging split archive {} is not supported", path.display());
+    }
+    Ok(archive)
+}
+
+// Name of the main source of an archive, the archive file name is used if it has no name.
+fn main_source_name(path: &Path, archive: &Archive<IoReader<LocalFile>>) -> String {
+    if !archive.source_name().is_empty() {
+        return archive.source_name().to_string();
+    }
+    path.file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+// Check that the chunks of the archives can be stored together without being re-chunked or
+// re-compressed.
+fn check_compatible(
+    first: &Archive<IoReader<LocalFile>>,
+    first_path: &Path,
+    other: &Archive<IoReader<LocalFile>>,
+    other_path: &Path,
+) -> Result<()> {
+    let first_params =
+        compress_cmd::chunker_parameters(first.chunker_config(), first.chunk_hash_length())?;
+    let other_params =
+        compress_cmd::chunker_parameters(other.chunker_config(), other.chunk_hash_length())?;
+    if first_params != other_params {
+        bail!(
+            "Archives {} and {} are chunked using different parameters ({} and {})",
+            first_path.display(),
+            other_path.display(),
+            first.chunker_config(),
+            other.chunker_config()
+        );
+    }
+    let compression = |archive: &Archive<IoReader<LocalFile>>| {
+        archive
+            .chunk_compression()
+            .map(|compression| (compression.algorithm(), compression.window_log()))
+    };
+    if compression(first) != compression(other) {
+        bail!(
+            "Archives {} and {} use different chunk compression",
+            first_path.display(),
+            other_path.display()
+        );
+    }
+    Ok(())
+}
+
+// Merge archives into a single archive holding the sources of all of them. Chunks are
+// deduplicated by their hash and copied as stored, without being re-chunked or re-compressed.
+pub async fn merge_cmd(opts: Options) -> Result<()> {
+    if opts.inputs.len() < 2 {
+        bail!("At least two archives are required to merge");
+    }
+    let mut archives: Vec<(&Path, Archive<IoReader<LocalFile>>)> =
+        Vec::with_capacity(opts.inputs.len());
+    for path in &opts.inputs {
+        let archive = open_archive(path, opts.dictionary_key.as_ref()).await?;
+        if let Some((first_path, first)) = archives.first() {
+            check_compatible(first, first_path, &archive, path)?;
+        }
+        archives.push((path, archive));
+    }
+
+    // Union of the chunks, in the order they are first found in the archives
+    let mut chunk_indexes: HashMap<HashSum, u32> = HashMap::new();
+    let mut chunk_descriptors: Vec<dict::ChunkDescriptor> = Vec::new();
+    // Where to copy the data of the chunks from, for each archive
+    let mut copy_from: Vec<Vec<ChunkOffset>> = Vec::with_capacity(archives.len());
+    let mut archive_offset = 0;
+    let chunk_crc32c = archives
+        .iter()
+        .all(|(_, archive)| archive.has_chunk_crc32c());
+    for (_, archive) in &archives {
+        let mut copy = Vec::new();
+        for descriptor in archive.chunk_descriptors() {
+            if chunk_indexes.contains_key(&descriptor.checksum) {
+                continue;
+            }
+            let index = u32::try_from(chunk_descriptors.len())
+                .map_err(|_| anyhow!("Too many chunks to merge"))?;
+            chunk_indexes.insert(descriptor.checksum.clone(), index);
+            chunk_descriptors.push(dict::ChunkDescriptor {
+                checksum: descriptor.checksum.to_vec(),
+                archive_size: descriptor.archive_size as u32,
+                archive_offset,
+                source_size: descriptor.source_size,
+                crc32c: if chunk_crc32c {
+                    descriptor.crc32c.unwrap_or(0)
+                } else {
+                    0
+                },
+            });
+            archive_offset += descriptor.archive_size as u64;
+            copy.push(ChunkOffset::new(
+                descriptor.archive_offset,
+                descriptor.archive_size,
+            ));
+        }
+        copy_from.push(copy);
+    }
+
+    // Every source of every archive is kept, with its rebuild order pointing into the union
+    let mut sources: Vec<dict::Source> = Vec::new();
+    for (path, archive) in &archives {
+        let rebuild_order = |order: &mut dyn Iterator<Item = &HashSum>| -> Vec<u32> {
+            order.map(|checksum| chunk_indexes[checksum]).collect()
+        };
+        sources.push(dict::Source {
+            name: main_source_name(path, archive),
+            source_checksum: archive.source_checksum().to_vec(),
+            source_total_size: archive.total_source_size(),
+            rebuild_order: rebuild_order(
+                &mut archive
+                    .iter_source_chunks()
+                    .map(|(_offset, descriptor)| &descriptor.checksum),
+            ),
+            source_entry: archive.source_entry().clone().into(),
+            rebuild_order_runs: None,
+        });
+        for source in archive.additional_sources() {
+            let descriptors = archive.chunk_descriptors();
+            sources.push(dict::Source {
+                name: source.name.clone(),
+                source_checksum: source.source_checksum.clone(),
+                source_total_size: source.source_total_size,
+                rebuild_order: rebuild_order(
+                    &mut source
+                        .rebuild_order
+                        .iter()
+                        .map(|&index| &descriptors[index as usize].checksum),
+                ),
+                source_entry: source.source_entry.clone(),
+                rebuild_order_runs: None,
+            });
+        }
+    }
+    for (index, source) in sources.iter().enumerate() {
+        if sources[..index]
+            .iter()
+            .any(|other| other.name == source.name)
+        {
+            bail!("Multiple sources are named {}", source.name);
+        }
+    }
+
+    // The main source of the first archive stays the main source, so that it's what gets
+    // cloned by readers unaware of multiple sources.
+    let (_, first) = &archives[0];
+    let mut sources = sources.into_iter();
+    let main_source = sources.next().expect("main source");
+    let dictionary = dict::ChunkDictionary {
+        rebuild_order: main_source.rebuild_order,
+        application_version: compress_cmd::PKG_VERSION.to_string(),
+        chunk_descriptors,
+        source_checksum: main_source.source_checksum,
+        chunk_compression: Some(first.chunk_compression().into()),
+        source_total_size: main_source.source_total_size,
+        chunker_params: Some(compress_cmd::chunker_parameters(
+            first.chunker_config(),
+            first.chunk_hash_length(),
+        )?),
+        source_entry: main_source.source_entry,
+        source_checkpoints: first.source_checkpoints().cloned().map(Into::into),
+        chunk_data_part_sizes: vec![],
+        source_name: main_source.name,
+        additional_sources: sources.collect(),
+        chunk_crc32c,
+        rebuild_order_runs: None,
+        chunk_data_checksum: None,
+        chunk_data_alignment: 0,
+        content_type: first.content_type().unwrap_or_default().to_string(),
+    };
+    let header_buf = match &opts.dictionary_key {
+        Some(key) => header::build_encrypted(&dictionary, None, key)?,
+        None => header::build(&dictionary, None)?,
+    };
+
+    let mut output = OpenOptions::new()
+        .write(true)
+        .create(opts.force_create)
+        .truncate(opts.force_create)
+        .create_new(!opts.force_create)
+        .open(&opts.output)
+        .await
+        .context(format!("Failed to open {}", opts.output.display()))?;
+    output
+        .write_all(&header_buf)
+        .await
+        .context(format!("Failed to write to {}", opts.output.display()))?;
+    for ((path, _), copy) in archives.iter().zip(copy_from) {
+        let mut reader = LocalFile::open_archive(path).await?;
+        let mut chunk_stream = reader.read_chunks(copy);
+        while let Some(data) = chunk_stream.next().await {
+            let data = data.context(format!("Failed to read archive {}", path.display()))?;
+            output
+                .write_all(&data)
+                .await
+                .context(format!("Failed to write to {}", opts.output.display()))?;
+        }
+    }
+    output
+        .flush()
+        .await
+        .context(format!("Failed to write to {}", opts.output.display()))?;
+    drop(output);
+    info!(
+        "Merged {} archives into {} ({} of chunk data)",
+        archives.len(),
+        opts.output.display(),
+        human_size!(archive_offset)
+    );
+    let reader = LocalFile::open_archive(&opts.output).await?;
+    info_cmd::print_archive_reader(reader, opts.dictionary_key.as_ref()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_cmd::tests::{random_data, test_options};
+    use bitar::{ChunkHasher, Compression};
+
+    async fn compress(input: &Path, output: &Path) {
+        let mut opts = test_options(
+            vec![input.to_path_buf()],
+            compress_cmd::Output::File(output.to_path_buf()),
+        );
+        opts.compression = Some(Compression::brotli(6).unwrap());
+        compress_cmd::compress_cmd(opts).await.unwrap();
+    }
+
+    // Unpack the source of the given name from an archive.
+    async fn unpack_source(archive_path: &Path, name: &str) -> Vec<u8> {
+        let mut archive = open_archive(archive_path, None).await.unwrap();
+        let mut output = std::io::Cursor::new(Vec::new());
+        archive
+            .unpack_source(
+                name,
+                &mut output,
+                Vec::<&[u8]>::new(),
+                &ChunkHasher::default(),
+            )
+            .await
+            .unwrap();
+        output.into_inner()
+    }
+
+    #[tokio::test]
+    async fn merged_archive_unpacks_both_sources() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data = random_data(96 * 1024, 0);
+        let (common, unique_a, unique_b) = (
+            &data[..64 * 1024],
+            &data[64 * 1024..80 * 1024],
+            &data[80 * 1024..],
+        );
+        let a = [common, unique_a].concat();
+        let b = [unique_b, common].concat();
+        let (input_a, input_b) = (temp_dir.path().join("a"), temp_dir.path().join("b"));
+        std::fs::write(&input_a, &a).unwrap();
+        std::fs::write(&input_b, &b).unwrap();
+        let (archive_a, archive_b) = (temp_dir.path().join("a.cba"), temp_dir.path().join("b.cba"));
+        compress(&input_a, &archive_a).await;
+        compress(&input_b, &archive_b).await;
+
+        let merged = temp_dir.path().join("merged.cba");
+        merge_cmd(Options {
+            inputs: vec![archive_a.clone(), archive_b.clone()],
+            output: merged.clone(),
+            force_create: false,
+            dictionary_key: None,
+        })
+        .await
+        .unwrap();
+
+        let archive = open_archive(&merged, None).await.unwrap();
+        // 16 common chunks and 4 unique chunks of each input
+        assert_eq!(archive.unique_chunks(), 24);
+        assert_eq!(archive.source_name(), "a");
+        assert_eq!(archive.additional_sources().len(), 1);
+        assert_eq!(archive.additional_sources()[0].name, "b");
+        // Chunks are copied as stored
+        let stored_size = |path: &Path| std::fs::metadata(path).unwrap().len();
+        assert!(
+            stored_size(&merged) < stored_size(&archive_a) + stored_size(&archive_b) - 32 * 1024
+        );
+        assert_eq!(unpack_source(&merged, "a").await, a);
+        assert_eq!(unpack_source(&merged, "b").await, b);
+
+        // Merging the merged archive again would give two sources of the same name
+        let err = merge_cmd(Options {
+            inputs: vec![merged, archive_b],
+            output: temp_dir.path().join("again.cba"),
+            force_create: false,
+            dictionary_key: None,
+        })
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("Multiple sources are named b"));
+    }
+
+    #[tokio::test]
+    async fn merged_dictionary_stays_encrypted() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let key = DictionaryKey::from_slice(&[7; DictionaryKey::SIZE]).unwrap();
+        let mut inputs = Vec::new();
+        for (index, name) in ["a", "b"].iter().enumerate() {
+            let input = temp_dir.path().join(name);
+            let archive = temp_dir.path().join(format!("{}.cba", name));
+            std::fs::write(&input, random_data(32 * 1024, index as u64)).unwrap();
+            let mut opts = test_options(vec![input], compress_cmd::Output::File(archive.clone()));
+            opts.dictionary_key = Some(key.clone());
+            compress_cmd::compress_cmd(opts).await.unwrap();
+            inputs.push(archive);
+        }
+
+        let merged = temp_dir.path().join("merged.cba");
+        let mut opts = Options {
+            inputs,
+            output: merged.clone(),
+            force_create: false,
+            dictionary_key: None,
+        };
+        assert!(merge_cmd(opts.clone()).await.is_err());
+        opts.dictionary_key = Some(key.clone());
+        merge_cmd(opts).await.unwrap();
+
+        assert!(open_archive(&merged, None).await.is_err());
+        let archive = open_archive(&merged, Some(&key)).await.unwrap();
+        assert_eq!(archive.source_name(), "a");
+        assert_eq!(archive.additional_sources()[0].name, "b");
+    }
+}
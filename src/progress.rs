@@ -0,0 +1,282 @@
+use core::pin::Pin;
+use core::task::{self, Poll};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::*;
+use std::io::{self, SeekFrom, Write};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+use crate::human_size;
+
+/// How the progress of a long running command is reported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressFormat {
+    /// Interactive progress bar on stderr.
+    Bar,
+    /// Log line every few seconds.
+    Plain,
+    /// JSON event per line on stderr.
+    Json,
+}
+
+impl ProgressFormat {
+    /// Progress bar if stderr is a terminal, log lines otherwise.
+    pub fn detect() -> Self {
+        if atty::is(atty::Stream::Stderr) {
+            Self::Bar
+        } else {
+            Self::Plain
+        }
+    }
+}
+
+const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+const JSON_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Reporter {
+    Bar(ProgressBar),
+    Plain,
+    Json(Box<dyn Write + Send>),
+}
+
+/// Progress of a task processing a number of bytes, reported in the given format.
+pub struct Progress {
+    reporter: Reporter,
+    task: &'static str,
+    total: Option<u64>,
+    done: u64,
+    last_report: Option<Instant>,
+}
+
+impl Progress {
+    /// Progress of the named task, processing `total` bytes if known.
+    pub fn new(format: ProgressFormat, task: &'static str, total: Option<u64>) -> Self {
+        let reporter = match format {
+            ProgressFormat::Bar => {
+                let bar = match total {
+                    Some(total) => ProgressBar::new(total).with_style(
+                        ProgressStyle::default_bar()
+                            .template("{msg} [{bar:40}] {bytes}/{total_bytes} ({eta})")
+                            .progress_chars("=> "),
+                    ),
+                    None => ProgressBar::new_spinner().with_style(
+                        ProgressStyle::default_spinner().template("{msg} {spinner} {bytes}"),
+                    ),
+                };
+                bar.set_message(task);
+                Reporter::Bar(bar)
+            }
+            ProgressFormat::Plain => Reporter::Plain,
+            ProgressFormat::Json => return Self::json(task, total, Box::new(std::io::stderr())),
+        };
+        Self::with_reporter(reporter, task, total)
+    }
+
+    /// Progress reported as JSON events written to the given writer.
+    pub fn json(task: &'static str, total: Option<u64>, writer: Box<dyn Write + Send>) -> Self {
+        Self::with_reporter(Reporter::Json(writer), task, total)
+    }
+
+    fn with_reporter(reporter: Reporter, task: &'static str, total: Option<u64>) -> Self {
+        Self {
+            reporter,
+            task,
+            total,
+            done: 0,
+            last_report: None,
+        }
+    }
+
+    /// Set the number of bytes processed so far.
+    pub fn set(&mut self, done: u64) {
+        self.done = done;
+        let interval = match &self.reporter {
+            Reporter::Bar(bar) => {
+                bar.set_position(done);
+                return;
+            }
+            Reporter::Plain => PLAIN_INTERVAL,
+            Reporter::Json(_) => JSON_INTERVAL,
+        };
+        if matches!(self.last_report, Some(last) if last.elapsed() < interval) {
+            return;
+        }
+        self.last_report = Some(Instant::now());
+        self.report(false);
+    }
+
+    /// Add to the number of bytes processed so far.
+    pub fn add(&mut self, done: u64) {
+        self.set(self.done + done);
+    }
+
+    /// Report the task as done.
+    pub fn finish(&mut self) {
+        if let Reporter::Bar(bar) = &self.reporter {
+            bar.finish_and_clear();
+            return;
+        }
+        self.report(true);
+    }
+
+    fn percent(&self) -> Option<f64> {
+        self.total.map(|total| {
+            if total == 0 {
+                100.0
+            } else {
+                (self.done as f64 * 100.0 / total as f64).min(100.0)
+            }
+        })
+    }
+
+    fn report(&mut self, finished: bool) {
+        let percent = if finished {
+            Some(100.0)
+        } else {
+            self.percent()
+        };
+        match &mut self.reporter {
+            Reporter::Bar(_) => {}
+            Reporter::Plain => {
+                // The command logs its own summary when done
+                if finished {
+                    return;
+                }
+                match (self.total, percent) {
+                    (Some(total), Some(percent)) => info!(
+                        "{}: {} of {} ({:.1}%)",
+                        self.task,
+                        human_size!(self.done),
+                        human_size!(total),
+                        percent
+                    ),
+                    _ => info!("{}: {}", self.task, human_size!(self.done)),
+                }
+            }
+            Reporter::Json(writer) => {
+                let event = serde_json::json!({
+                    "task": self.task,
+                    "done": self.done,
+                    "total": self.total,
+                    "percent": percent,
+                    "finished": finished,
+                });
+                // Progress is informational, failing to report it doesn't fail the command
+                let _ = writeln!(writer, "{}", event);
+            }
+        }
+    }
+}
+
+/// Writer reporting the number of bytes written to it as progress.
+pub struct ProgressWriter<W> {
+    inner: W,
+    progress: Progress,
+}
+
+impl<W> ProgressWriter<W> {
+    pub fn new(inner: W, progress: Progress) -> Self {
+        Self { inner, progress }
+    }
+
+    pub fn into_inner(self) -> (W, Progress) {
+        (self.inner, self.progress)
+    }
+}
+
+impl<W> AsyncWrite for ProgressWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.progress.add(written as u64);
+        }
+        result
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl<W> AsyncRead for ProgressWriter<W>
+where
+    W: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<W> AsyncSeek for ProgressWriter<W>
+where
+    W: AsyncSeek + Unpin,
+{
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut self.get_mut().inner).start_seek(position)
+    }
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.get_mut().inner).poll_complete(cx)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Buffer shared with a progress reporter, to inspect the reported events.
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuf(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuf {
+        pub(crate) fn events(&self) -> Vec<serde_json::Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn json_events_are_throttled() {
+        let buf = SharedBuf::default();
+        let mut progress = Progress::json("test", Some(1000), Box::new(buf.clone()));
+        for _ in 0..10 {
+            progress.add(100);
+        }
+        progress.finish();
+        let events = buf.events();
+        // The first update is reported right away, the rest within the interval are not
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["done"], 100);
+        assert_eq!(events[0]["percent"], 10.0);
+        assert_eq!(events[1]["done"], 1000);
+        assert_eq!(events[1]["percent"], 100.0);
+        assert_eq!(events[1]["finished"], true);
+    }
+}
@@ -0,0 +1,362 @@
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::StreamExt;
+use log::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::{compress_cmd, human_size, info_cmd, local_file::LocalFile};
+use bitar::{
+    archive_reader::IoReader, chunk_dictionary as dict, header::DictionaryKey, Archive,
+    ChunkHasher, HashSum, SourceHasher,
+};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    // Source of the archive to recover the order from, the chunk offsets are used if not given
+    pub source: Option<PathBuf>,
+    pub chunk_hasher: ChunkHasher,
+    pub force_create: bool,
+    // Key decrypting the archive dictionary, the repaired dictionary is encrypted using it too
+    pub dictionary_key: Option<DictionaryKey>,
+}
+
+async fn open_archive(
+    path: &Path,
+    key: Option<&DictionaryKey>,
+) -> Result<Archive<IoReader<LocalFile>>> {
+    info_cmd::init_archive(LocalFile::open_archive(path).await?, key)
+        .await
+        .context(format!("Failed to read archive {}", path.display()))
+}
+
+// Chunks are written to the archive in the order they are first found in the source. Unless
+// some chunk is repeated in the source that is also the source order.
+fn order_from_offsets(archive: &Archive<IoReader<LocalFile>>) -> Result<Vec<u32>> {
+    let descriptors = archive.chunk_descriptors();
+    let mut order: Vec<u32> = (0..u32::try_from(descriptors.len())?).collect();
+    order.sort_by_key(|&index| descriptors[index as usize].archive_offset);
+    let size: u64 = descriptors.iter().map(|cd| cd.source_size as u64).sum();
+    if size != archive.total_source_size() {
+        bail!(
+            "Chunks add up to {} but the source is {}, the order can't be recovered from chunk offsets (try giving the source)",
+            human_size!(size),
+            human_size!(archive.total_source_size())
+        );
+    }
+    Ok(order)
+}
+
+// Chunk the source the same way as when compressing and look up every chunk in the archive.
+async fn order_from_source(
+    archive: &Archive<IoReader<LocalFile>>,
+    hasher: &ChunkHasher,
+    path: &Path,
+) -> Result<Vec<u32>> {
+    let chunk_indexes: HashMap<&HashSum, u32> = archive
+        .chunk_descriptors()
+        .iter()
+        .enumerate()
+        .map(|(index, cd)| Ok((&cd.checksum, u32::try_from(index)?)))
+        .collect::<Result<_>>()?;
+    let mut file = File::open(path)
+        .await
+        .context(format!("Failed to open {}", path.display()))?;
+    let mut chunk_stream = archive.chunker_config().new_chunker(&mut file);
+    let mut source_hasher = SourceHasher::default();
+    let mut order = Vec::new();
+    while let Some(result) = chunk_stream.next().await {
+        let (offset, chunk) = result.context(format!("Failed to read {}", path.display()))?;
+        source_hasher.update(chunk.data());
+        let (mut hash, _chunk) = chunk.verify_with(hasher).into_parts();
+        hash.truncate(archive.chunk_hash_length());
+        let index = chunk_indexes.get(&hash).ok_or_else(|| {
+            anyhow!(
+                "Chunk {} at offset {} of {} is not in the archive",
+                hash,
+                offset,
+                path.display()
+            )
+        })?;
+        order.push(*index);
+    }
+    if source_hasher.size() != archive.total_source_size()
+        || &source_hasher.finalize().0 != archive.source_checksum()
+    {
+        bail!("{} is not the source of the archive", path.display());
+    }
+    Ok(order)
+}
+
+// Write a copy of the archive with the main source rebuilt in the given order. The chunk data
+// is copied as is.
+async fn write_archive(
+    archive: &Archive<IoReader<LocalFile>>,
+    input: &Path,
+    output: &Path,
+    force_create: bool,
+    rebuild_order: Vec<u32>,
+    key: Option<&DictionaryKey>,
+) -> Result<()> {
+    let chunk_data_offset = archive.chunk_data_offset();
+    let dictionary = dict::ChunkDictionary {
+        rebuild_order,
+        application_version: compress_cmd::PKG_VERSION.to_string(),
+        chunk_descriptors: archive
+            .chunk_descriptors()
+            .iter()
+            .map(|cd| dict::ChunkDescriptor {
+                checksum: cd.checksum.to_vec(),
+                archive_size: cd.archive_size as u32,
+                archive_offset: cd.archive_offset - chunk_data_offset,
+                source_size: cd.source_size,
+                crc32c: cd.crc32c.unwrap_or(0),
+            })
+            .collect(),
+        source_checksum: archive.source_checksum().to_vec(),
+        chunk_compression: Some(archive.chunk_compression().into()),
+        source_total_size: archive.total_source_size(),
+        chunker_params: Some(compress_cmd::chunker_parameters(
+            archive.chunker_config(),
+            archive.chunk_hash_length(),
+        )?),
+        source_entry: archive.source_entry().clone().into(),
+        source_checkpoints: archive.source_checkpoints().cloned().map(Into::into),
+        chunk_data_part_sizes: vec![],
+        source_name: archive.source_name().to_string(),
+        additional_sources: archive.additional_sources().to_vec(),
+        chunk_crc32c: archive.has_chunk_crc32c(),
+        rebuild_order_runs: None,
+        // The chunk data is copied as is
+        chunk_data_checksum: archive
+            .chunk_data_checksum()
+            .map(dict::ChunkDataChecksum::blake2b_512),
+        chunk_data_alignment: archive.chunk_data_alignment().unwrap_or(0) as u32,
+        content_type: archive.content_type().unwrap_or_default().to_string(),
+    };
+    let header_buf =
+        compress_cmd::build_aligned_header(&dictionary, archive.chunk_data_alignment(), key)?;
+
+    let mut output_file = OpenOptions::new()
+        .write(true)
+        .create(force_create)
+        .truncate(force_create)
+        .create_new(!force_create)
+        .open(output)
+        .await
+        .context(format!("Failed to open {}", output.display()))?;
+    output_file
+        .write_all(&header_buf)
+        .await
+        .context(format!("Failed to write to {}", output.display()))?;
+    let mut input_file = File::open(input)
+        .await
+        .context(format!("Failed to open {}", input.display()))?;
+    input_file
+        .seek(SeekFrom::Start(chunk_data_offset))
+        .await
+        .context(format!("Failed to read archive {}", input.display()))?;
+    tokio::io::copy(&mut input_file, &mut output_file)
+        .await
+        .context(format!("Failed to write to {}", output.display()))?;
+    output_file
+        .flush()
+        .await
+        .context(format!("Failed to write to {}", output.display()))?;
+    Ok(())
+}
+
+// Checksum of the source as rebuilt from the archive.
+async fn rebuilt_source_checksum(
+    archive: &mut Archive<IoReader<LocalFile>>,
+    hasher: &ChunkHasher,
+) -> Result<HashSum> {
+    let mut source_hasher = SourceHasher::default();
+    let mut chunk_stream = archive.source_chunk_stream(0);
+    while let Some(result) = chunk_stream.next().await {
+        let (_offset, chunk) = result.context("Failed to read chunk")?;
+        let verified = chunk
+            .decompress()
+            .context("Failed to decompress chunk")?
+            .verify_with(hasher)
+            .context("Failed to verify chunk")?;
+        source_hasher.update(verified.data());
+    }
+    Ok(source_hasher.finalize().0)
+}
+
+// Rebuild a corrupt rebuild order of the main source of an archive, either by chunking the
+// source or from the chunk offsets. The repaired archive is checked against the source checksum.
+pub async fn repair_cmd(opts: Options) -> Result<()> {
+    let key = opts.dictionary_key.as_ref();
+    let archive = open_archive(&opts.input, key).await?;
+    if !archive.chunk_data_part_sizes().is_empty() {
+        bail!(
+            "Repairing split archive {} is not supported",
+            opts.input.display()
+        );
+    }
+    let order = match &opts.source {
+        Some(source) => {
+            info!("Recovering order by chunking {}", source.display());
+            order_from_source(&archive, &opts.chunk_hasher, source).await?
+        }
+        None => {
+            info!("Recovering order from chunk offsets");
+            order_from_offsets(&archive)?
+        }
+    };
+    let total_chunks = order.len();
+    write_archive(
+        &archive,
+        &opts.input,
+        &opts.output,
+        opts.force_create,
+        order,
+        key,
+    )
+    .await?;
+
+    let mut repaired = open_archive(&opts.output, key).await?;
+    let checksum = rebuilt_source_checksum(&mut repaired, &opts.chunk_hasher).await?;
+    if &checksum != repaired.source_checksum() {
+        let _ = std::fs::remove_file(&opts.output);
+        bail!(
+            "Source rebuilt from the recovered order doesn't match the source checksum (got {}, expected {})",
+            checksum,
+            repaired.source_checksum()
+        );
+    }
+    info!(
+        "Recovered order of {} chunks into {}",
+        total_chunks,
+        opts.output.display()
+    );
+    info_cmd::print_archive_reader(LocalFile::open_archive(&opts.output).await?, key).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_cmd::tests::{random_data, test_options};
+    use bitar::Compression;
+    use blake2::{Blake2b512, Digest};
+
+    async fn compress(input: &Path, output: &Path) {
+        let mut opts = test_options(
+            vec![input.to_path_buf()],
+            compress_cmd::Output::File(output.to_path_buf()),
+        );
+        opts.compression = Some(Compression::brotli(6).unwrap());
+        compress_cmd::compress_cmd(opts).await.unwrap();
+    }
+
+    // Compress the source into an archive with every index of the rebuild order zeroed.
+    async fn corrupt_archive(source: &Path, dir: &Path) -> PathBuf {
+        let archive_path = dir.join("archive.cba");
+        compress(source, &archive_path).await;
+        let archive = open_archive(&archive_path, None).await.unwrap();
+        let corrupt_path = dir.join("corrupt.cba");
+        write_archive(
+            &archive,
+            &archive_path,
+            &corrupt_path,
+            false,
+            vec![0; archive.total_chunks()],
+            None,
+        )
+        .await
+        .unwrap();
+        corrupt_path
+    }
+
+    fn repair_options(input: PathBuf, output: PathBuf, source: Option<PathBuf>) -> Options {
+        Options {
+            input,
+            output,
+            source,
+            chunk_hasher: ChunkHasher::default(),
+            force_create: false,
+            dictionary_key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn recover_order_from_source() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let chunk = random_data(4096, 1);
+        let data = [
+            random_data(16 * 4096, 1),
+            chunk.clone(),
+            chunk.clone(),
+            chunk,
+        ]
+        .concat();
+        let source = temp_dir.path().join("source");
+        std::fs::write(&source, &data).unwrap();
+        let corrupt = corrupt_archive(&source, temp_dir.path()).await;
+        let output = temp_dir.path().join("repaired.cba");
+
+        // A repeated chunk makes the chunks add up to less than the source
+        assert!(
+            repair_cmd(repair_options(corrupt.clone(), output.clone(), None))
+                .await
+                .is_err()
+        );
+        assert!(!output.exists());
+
+        repair_cmd(repair_options(corrupt, output.clone(), Some(source)))
+            .await
+            .unwrap();
+        let mut repaired = open_archive(&output, None).await.unwrap();
+        let checksum = rebuilt_source_checksum(&mut repaired, &ChunkHasher::default())
+            .await
+            .unwrap();
+        assert_eq!(checksum, HashSum::from(&Blake2b512::digest(&data)[..]));
+    }
+
+    #[tokio::test]
+    async fn recover_order_from_offsets() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let data = random_data(16 * 4096 + 100, 1);
+        let source = temp_dir.path().join("source");
+        std::fs::write(&source, &data).unwrap();
+        let corrupt = corrupt_archive(&source, temp_dir.path()).await;
+        let output = temp_dir.path().join("repaired.cba");
+        repair_cmd(repair_options(corrupt, output.clone(), None))
+            .await
+            .unwrap();
+        let repaired = open_archive(&output, None).await.unwrap();
+        let offsets: Vec<u64> = repaired
+            .iter_source_chunks()
+            .map(|(_offset, cd)| cd.archive_offset)
+            .collect();
+        assert_eq!(offsets.len(), 17);
+        assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[tokio::test]
+    async fn wrong_source_is_rejected() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("source");
+        std::fs::write(&source, random_data(8 * 4096, 1)).unwrap();
+        let corrupt = corrupt_archive(&source, temp_dir.path()).await;
+        let mut other = random_data(8 * 4096, 1);
+        other.truncate(7 * 4096);
+        let other_path = temp_dir.path().join("other");
+        std::fs::write(&other_path, &other).unwrap();
+        assert!(repair_cmd(repair_options(
+            corrupt,
+            temp_dir.path().join("repaired.cba"),
+            Some(other_path)
+        ))
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,220 @@
+use anyhow::{bail, Context, Result};
+use log::*;
+use std::path::PathBuf;
+
+use crate::{human_size, info_cmd, local_file::LocalFile};
+use bitar::{header::DictionaryKey, ChunkDescriptor};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub input: PathBuf,
+    // Key decrypting the archive dictionary
+    pub dictionary_key: Option<DictionaryKey>,
+}
+
+// Bytes of the chunk data section referenced by the chunk descriptors.
+#[derive(Debug, Clone, PartialEq)]
+struct ChunkDataStats {
+    // Size of the chunk data section
+    total: u64,
+    // Bytes of the section referenced by any chunk
+    covered: u64,
+    // Offset and size of every range of bytes not referenced by any chunk
+    holes: Vec<(u64, u64)>,
+}
+
+// Find the bytes between start and end of the chunk data section not referenced by any chunk.
+fn chunk_data_stats(descriptors: &[ChunkDescriptor], start: u64, end: u64) -> ChunkDataStats {
+    let mut ranges: Vec<(u64, u64)> = descriptors
+        .iter()
+        .map(|cd| (cd.archive_offset, cd.archive_end_offset()))
+        .collect();
+    ranges.sort_unstable();
+    let mut holes = Vec::new();
+    let mut covered = 0;
+    let mut position = start;
+    for (range_start, range_end) in ranges {
+        let (range_start, range_end) = (range_start.max(position), range_end.min(end));
+        if range_end <= range_start {
+            continue;
+        }
+        if range_start > position {
+            holes.push((position, range_start - position));
+        }
+        covered += range_end - range_start;
+        position = range_end;
+    }
+    if end > position {
+        holes.push((position, end - position));
+    }
+    ChunkDataStats {
+        total: end.saturating_sub(start),
+        covered,
+        holes,
+    }
+}
+
+pub async fn stats_cmd(opts: Options) -> Result<()> {
+    let reader = LocalFile::open_archive(&opts.input).await?;
+    let archive = info_cmd::init_archive(reader, opts.dictionary_key.as_ref())
+        .await
+        .context(format!("Failed to read archive {}", opts.input.display()))?;
+    if !archive.chunk_data_part_sizes().is_empty() {
+        bail!(
+            "Stats of split archive {} is not supported",
+            opts.input.display()
+        );
+    }
+    let file_size = std::fs::metadata(&opts.input)
+        .context(format!("Failed to read size of {}", opts.input.display()))?
+        .len();
+    let mut stats = chunk_data_stats(
+        archive.chunk_descriptors(),
+        archive.chunk_data_offset(),
+        file_size,
+    );
+    // Holes padding a chunk up to the alignment are expected in an aligned archive
+    let mut padding = 0;
+    if let Some(alignment) = archive.chunk_data_alignment() {
+        stats.holes.retain(|&(offset, size)| {
+            let hole_end = offset + size;
+            let is_padding = size < alignment && hole_end % alignment == 0 && hole_end < file_size;
+            if is_padding {
+                padding += size;
+            }
+            !is_padding
+        });
+    }
+    info!("Chunk data: {}", human_size!(stats.total));
+    info!(
+        "  Referenced by chunks: {} ({:.1}%)",
+        human_size!(stats.covered),
+        if stats.total == 0 {
+            100.0
+        } else {
+            stats.covered as f64 * 100.0 / stats.total as f64
+        }
+    );
+    if padding > 0 {
+        info!("  Alignment padding: {}", human_size!(padding));
+    }
+    if stats.holes.is_empty() {
+        info!("  No unreferenced bytes");
+        return Ok(());
+    }
+    let unreferenced: u64 = stats.holes.iter().map(|(_, size)| size).sum();
+    warn!(
+        "  Unreferenced: {} in {} holes, recompress the archive to reclaim the space",
+        human_size!(unreferenced),
+        stats.holes.len()
+    );
+    for (offset, size) in &stats.holes {
+        info!("    {} at offset {}", human_size!(*size), offset);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitar::{chunk_dictionary as dict, Archive, HashSum};
+
+    #[test]
+    fn overlapping_chunks_are_covered_once() {
+        let descriptor = |archive_offset, archive_size| ChunkDescriptor {
+            checksum: HashSum::from(&[0u8; 4][..]),
+            archive_size,
+            archive_offset,
+            source_size: archive_size as u32,
+            crc32c: None,
+        };
+        let descriptors = [descriptor(30, 20), descriptor(10, 10), descriptor(15, 10)];
+        assert_eq!(
+            chunk_data_stats(&descriptors, 10, 60),
+            ChunkDataStats {
+                total: 50,
+                covered: 35,
+                holes: vec![(25, 5), (50, 10)],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn holey_archive() {
+        let chunks: Vec<Vec<u8>> = (1..=3u8).map(|n| vec![n; 100]).collect();
+        // Chunks stored at 0, 150 and 250 of the chunk data, followed by 30 trailing bytes
+        let offsets = [0, 150, 250];
+        let mut chunk_data = vec![0xff; 380];
+        for (chunk, &offset) in chunks.iter().zip(&offsets) {
+            chunk_data[offset..offset + chunk.len()].copy_from_slice(chunk);
+        }
+        let source: Vec<u8> = chunks.concat();
+        let dictionary = dict::ChunkDictionary {
+            rebuild_order: vec![0, 1, 2],
+            application_version: "test".to_string(),
+            chunk_descriptors: chunks
+                .iter()
+                .zip(&offsets)
+                .map(|(chunk, &offset)| dict::ChunkDescriptor {
+                    checksum: bitar::Chunk::from(chunk.clone()).verify().hash().to_vec(),
+                    archive_size: chunk.len() as u32,
+                    archive_offset: offset as u64,
+                    source_size: chunk.len() as u32,
+                    crc32c: 0,
+                })
+                .collect(),
+            source_checksum: bitar::Chunk::from(source.clone()).verify().hash().to_vec(),
+            chunk_compression: Some(None.into()),
+            source_total_size: source.len() as u64,
+            chunker_params: Some(dict::ChunkerParameters {
+                chunk_filter_bits: 0,
+                min_chunk_size: 0,
+                max_chunk_size: 100,
+                rolling_hash_window_size: 0,
+                chunk_hash_length: HashSum::MAX_LEN as u32,
+                chunking_algorithm: dict::chunker_parameters::ChunkingAlgorithm::FixedSize as i32,
+                buzhash_table: vec![],
+            }),
+            source_checkpoints: None,
+            chunk_data_part_sizes: vec![],
+            source_name: String::new(),
+            additional_sources: vec![],
+            chunk_crc32c: false,
+            rebuild_order_runs: None,
+            source_entry: None,
+            chunk_data_checksum: None,
+            chunk_data_alignment: 0,
+            content_type: String::new(),
+        };
+        let mut archive_data = bitar::header::build(&dictionary, None).unwrap();
+        let data_offset = archive_data.len() as u64;
+        archive_data.extend(&chunk_data);
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("holey.cba");
+        std::fs::write(&path, &archive_data).unwrap();
+
+        let archive = Archive::try_init(LocalFile::open_archive(&path).await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(archive.chunk_data_offset(), data_offset);
+        let stats = chunk_data_stats(
+            archive.chunk_descriptors(),
+            archive.chunk_data_offset(),
+            archive_data.len() as u64,
+        );
+        assert_eq!(
+            stats,
+            ChunkDataStats {
+                total: 380,
+                covered: 300,
+                holes: vec![(data_offset + 100, 50), (data_offset + 350, 30)],
+            }
+        );
+        stats_cmd(Options {
+            input: path,
+            dictionary_key: None,
+        })
+        .await
+        .unwrap();
+    }
+}
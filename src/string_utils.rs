@@ -1,3 +1,4 @@
+use anyhow::{anyhow, Context, Result};
 use std::num::ParseIntError;
 
 #[macro_export]
@@ -25,6 +26,28 @@ pub fn hex_str_to_vec(hex_str: &str) -> Result<Vec<u8>, ParseIntError> {
         .collect()
 }
 
+/// Parse a size given in bytes, optionally followed by a unit (B, KiB, MiB or GiB).
+pub fn parse_size(size_str: &str) -> Result<usize> {
+    let size_str = size_str.trim();
+    let unit_start = size_str
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size_str.len());
+    let (size_val, size_unit) = size_str.split_at(unit_start);
+    let size_val: usize = size_val
+        .parse()
+        .context(format!("Failed to parse size ({})", size_str))?;
+    let multiplier: usize = match size_unit.trim_start().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "kib" => 1024,
+        "mib" => 1024 * 1024,
+        "gib" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("Invalid unit of size ({})", size_str)),
+    };
+    size_val
+        .checked_mul(multiplier)
+        .ok_or_else(|| anyhow!("Size too large ({})", size_str))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -38,6 +61,33 @@ mod tests {
         hex_str_to_vec("1234efy1").unwrap_err();
     }
     #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert_eq!(parse_size("64B").unwrap(), 64);
+        assert_eq!(parse_size("16KiB").unwrap(), 16 * 1024);
+        assert_eq!(parse_size("1MiB").unwrap(), 1024 * 1024);
+        assert_eq!(parse_size("2 gib").unwrap(), 2 * 1024 * 1024 * 1024);
+    }
+    #[test]
+    fn parse_size_invalid() {
+        for size in &["", "KiB", "-16KiB"] {
+            let err = parse_size(size).unwrap_err();
+            assert!(
+                err.to_string().starts_with("Failed to parse size"),
+                "{}",
+                err
+            );
+        }
+        for size in &["16KB", "1K6iB", "1.5MiB"] {
+            let err = parse_size(size).unwrap_err();
+            assert!(
+                err.to_string().starts_with("Invalid unit of size"),
+                "{}",
+                err
+            );
+        }
+    }
+    #[test]
     fn human_size_small() {
         assert_eq!(human_size!(100).as_str(), "100 bytes");
     }
@@ -0,0 +1,231 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::*;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{human_size, info_cmd, local_file::LocalFile};
+use bitar::header::DictionaryKey;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression of a whole archive file for transport, on top of any chunk compression.
+///
+/// A wrapped archive is detected by its magic and decompressed into memory when opened, as
+/// the archive is read at random offsets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportCompression {
+    Gzip,
+    Zstd,
+}
+
+impl TransportCompression {
+    /// Number of bytes needed to detect a wrapped archive.
+    pub const MAGIC_LEN: usize = 4;
+
+    /// Get the compression of a file starting with the given bytes, if any.
+    pub fn detect(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    fn compress<R: Read, W: Write>(self, mut reader: R, writer: W) -> Result<()> {
+        match self {
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(writer, flate2::Compression::best());
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            }
+            #[cfg(feature = "zstd-compression")]
+            Self::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(writer, 19)?;
+                io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            }
+            #[cfg(not(feature = "zstd-compression"))]
+            Self::Zstd => bail!("zstd transport compression requires the zstd-compression feature"),
+        }
+        Ok(())
+    }
+
+    fn decoder<'a, R: Read + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        match self {
+            Self::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(reader))),
+            #[cfg(feature = "zstd-compression")]
+            Self::Zstd => Ok(Box::new(zstd::stream::read::Decoder::new(reader)?)),
+            #[cfg(not(feature = "zstd-compression"))]
+            Self::Zstd => bail!("zstd transport compression requires the zstd-compression feature"),
+        }
+    }
+
+    /// Decompress a whole wrapped file into memory.
+    pub async fn unwrap_file(self, path: &Path) -> Result<Vec<u8>> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let file = File::open(&path).context(format!("Failed to open {}", path.display()))?;
+            let mut data = Vec::new();
+            self.decoder(BufReader::new(file))?
+                .read_to_end(&mut data)
+                .context(format!("Failed to decompress {}", path.display()))?;
+            Ok(data)
+        })
+        .await?
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    // Compression to wrap the archive in, unwrap the archive if not given
+    pub compression: Option<TransportCompression>,
+    pub force_create: bool,
+    // Key decrypting the archive dictionary
+    pub dictionary_key: Option<DictionaryKey>,
+}
+
+fn create_output(opts: &Options) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(opts.force_create)
+        .truncate(opts.force_create)
+        .create_new(!opts.force_create)
+        .open(&opts.output)
+        .context(format!("Failed to open {}", opts.output.display()))
+}
+
+// Compress or decompress the whole archive file, as is.
+fn transcode(opts: &Options, wrapped: Option<TransportCompression>) -> Result<()> {
+    let input = BufReader::new(
+        File::open(&opts.input).context(format!("Failed to open {}", opts.input.display()))?,
+    );
+    let mut output = BufWriter::new(create_output(opts)?);
+    match (wrapped, opts.compression) {
+        (None, Some(compression)) => compression.compress(input, output),
+        (Some(compression), _) => {
+            io::copy(&mut compression.decoder(input)?, &mut output)?;
+            Ok(output.flush()?)
+        }
+        (None, None) => unreachable!(),
+    }
+    .context(format!("Failed to write {}", opts.output.display()))
+}
+
+// Wrap an archive in an outer stream compression for transport, or unwrap it again. The
+// archive is checked to open before being transcoded.
+pub async fn transport_cmd(opts: Options) -> Result<()> {
+    let mut magic = [0u8; TransportCompression::MAGIC_LEN];
+    let magic_len = File::open(&opts.input)
+        .and_then(|mut file| file.read(&mut magic))
+        .context(format!("Failed to read {}", opts.input.display()))?;
+    let wrapped = TransportCompression::detect(&magic[..magic_len]);
+    match (wrapped, opts.compression) {
+        (Some(compression), Some(_)) => bail!(
+            "{} is already wrapped in {:?}",
+            opts.input.display(),
+            compression
+        ),
+        (None, None) => bail!("{} is not wrapped", opts.input.display()),
+        _ => {}
+    }
+    let reader = LocalFile::open_archive(&opts.input).await?;
+    let archive = info_cmd::init_archive(reader, opts.dictionary_key.as_ref())
+        .await
+        .context(format!("Failed to read archive {}", opts.input.display()))?;
+    if !archive.chunk_data_part_sizes().is_empty() {
+        bail!(
+            "Wrapping split archive {} is not supported",
+            opts.input.display()
+        );
+    }
+    drop(archive);
+    {
+        let opts = opts.clone();
+        tokio::task::spawn_blocking(move || transcode(&opts, wrapped))
+            .await
+            .map_err(|err| anyhow!(err))??;
+    }
+    let size = |path: &Path| -> Result<u64> {
+        Ok(std::fs::metadata(path)
+            .context(format!("Failed to read size of {}", path.display()))?
+            .len())
+    };
+    info!(
+        "{} {} ({}) into {} ({})",
+        if wrapped.is_some() {
+            "Unwrapped"
+        } else {
+            "Wrapped"
+        },
+        opts.input.display(),
+        human_size!(size(&opts.input)?),
+        opts.output.display(),
+        human_size!(size(&opts.output)?)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_cmd, compress_cmd::tests::test_options, verify_cmd};
+    use bitar::{chunker, Archive, ChunkHasher};
+
+    #[tokio::test]
+    async fn open_gzip_wrapped_archive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source: Vec<u8> = (0..64 * 1024)
+            .map(|i| b"lorem ipsum dolor sit amet "[i % 27])
+            .collect();
+        let input = temp_dir.path().join("input.txt");
+        let archive = temp_dir.path().join("input.cba");
+        std::fs::write(&input, &source).unwrap();
+        let mut opts = test_options(vec![input], compress_cmd::Output::File(archive.clone()));
+        opts.chunker_config = chunker::Config::FixedSize(1000);
+        compress_cmd::compress_cmd(opts).await.unwrap();
+
+        let wrapped = temp_dir.path().join("input.cba.gz");
+        transport_cmd(Options {
+            input: archive.clone(),
+            output: wrapped.clone(),
+            compression: Some(TransportCompression::Gzip),
+            force_create: false,
+            dictionary_key: None,
+        })
+        .await
+        .unwrap();
+        assert!(
+            std::fs::metadata(&wrapped).unwrap().len() < std::fs::metadata(&archive).unwrap().len()
+        );
+
+        // The wrapped archive opens directly
+        let mut opened = Archive::try_init(LocalFile::open_archive(&wrapped).await.unwrap())
+            .await
+            .unwrap();
+        verify_cmd::verify_archive(&mut opened, &ChunkHasher::default())
+            .await
+            .unwrap();
+
+        let unwrapped = temp_dir.path().join("unwrapped.cba");
+        transport_cmd(Options {
+            input: wrapped,
+            output: unwrapped.clone(),
+            compression: None,
+            force_create: false,
+            dictionary_key: None,
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read(&unwrapped).unwrap(),
+            std::fs::read(&archive).unwrap()
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use anyhow::{bail, Result};
+use log::*;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::{compress_cmd, diff_cmd, human_size, progress::ProgressFormat};
+use bitar::chunk_dictionary as dict;
+use bitar::{chunker, HashSum};
+
+/// Average chunk sizes tried if none are given.
+pub const DEFAULT_AVG_CHUNK_SIZES: [usize; 6] = [
+    16 * 1024,
+    32 * 1024,
+    64 * 1024,
+    128 * 1024,
+    256 * 1024,
+    1024 * 1024,
+];
+
+/// Hash chunker configs with the given average chunk sizes. The min and max chunk size are
+/// picked relative to the average, keeping the ratios of the compress defaults.
+pub fn hash_chunker_candidates(buzhash: bool, avg_chunk_sizes: &[usize]) -> Vec<chunker::Config> {
+    avg_chunk_sizes
+        .iter()
+        .map(|&avg_chunk_size| {
+            let config = chunker::FilterConfig {
+                filter_bits: chunker::FilterBits::from_size(avg_chunk_size as u32),
+                min_chunk_size: avg_chunk_size / 4,
+                max_chunk_size: avg_chunk_size * 256,
+                window_size: if buzhash { 16 } else { 64 },
+                buzhash_table: None,
+            };
+            if buzhash {
+                chunker::Config::BuzHash(config)
+            } else {
+                chunker::Config::RollSum(config)
+            }
+        })
+        .collect()
+}
+
+// Compress arguments giving the config.
+fn compress_args(config: &chunker::Config) -> String {
+    match config {
+        chunker::Config::BuzHash(filter_config) | chunker::Config::RollSum(filter_config) => {
+            format!(
+                "{}--avg-chunk-size {} --min-chunk-size {} --max-chunk-size {}",
+                if matches!(config, chunker::Config::BuzHash(_)) {
+                    "--hash-chunking BuzHash "
+                } else {
+                    ""
+                },
+                filter_config.filter_bits.chunk_target_average(),
+                filter_config.min_chunk_size,
+                filter_config.max_chunk_size
+            )
+        }
+        chunker::Config::FixedSize(size) => format!("--fixed-size {}", size),
+        chunker::Config::Custom(..) => config.to_string(),
+    }
+}
+
+// Result of chunking the input using one of the candidate configs.
+#[derive(Debug, Clone)]
+struct Candidate {
+    config: chunker::Config,
+    source_size: u64,
+    total_chunks: usize,
+    unique_chunks: usize,
+    // Size of the unique chunks, what the archive stores without compression
+    unique_size: u64,
+    header_size: u64,
+}
+
+impl Candidate {
+    fn unique_percent(&self) -> f64 {
+        if self.source_size == 0 {
+            100.0
+        } else {
+            self.unique_size as f64 * 100.0 / self.source_size as f64
+        }
+    }
+
+    fn avg_chunk_size(&self) -> u64 {
+        self.unique_size / self.unique_chunks.max(1) as u64
+    }
+
+    // Size of an archive of the input using the config, without compression.
+    fn archive_size(&self) -> u64 {
+        self.unique_size + self.header_size
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:", self.config)?;
+        writeln!(
+            f,
+            "  Chunks: {} (unique: {}, {:.1}% of source)",
+            self.total_chunks,
+            self.unique_chunks,
+            self.unique_percent()
+        )?;
+        writeln!(
+            f,
+            "  Average chunk size: {}",
+            human_size!(self.avg_chunk_size())
+        )?;
+        writeln!(f, "  Header size: {}", human_size!(self.header_size))?;
+        write!(
+            f,
+            "  Archive size (uncompressed): {}",
+            human_size!(self.archive_size())
+        )
+    }
+}
+
+// Size of the header of an archive holding the chunks, built the same way as by compress.
+fn estimate_header_size(
+    result: &diff_cmd::ChunkerResult,
+    config: &chunker::Config,
+    hash_length: usize,
+) -> Result<u64> {
+    let mut descriptors: Vec<_> = result.descriptors.iter().collect();
+    descriptors.sort_by_key(|(_, descriptor)| descriptor.occurrences[0]);
+    let mut rebuild_order: Vec<(u64, u32)> = Vec::with_capacity(result.total_chunks);
+    let mut archive_offset = 0;
+    let chunk_descriptors = descriptors
+        .iter()
+        .enumerate()
+        .map(|(index, (hash, descriptor))| {
+            rebuild_order.extend(
+                descriptor
+                    .occurrences
+                    .iter()
+                    .map(|&offset| (offset, index as u32)),
+            );
+            let chunk = dict::ChunkDescriptor {
+                checksum: hash.slice()[..hash_length.min(hash.len())].to_vec(),
+                archive_size: descriptor.source_size as u32,
+                archive_offset,
+                source_size: descriptor.source_size as u32,
+                crc32c: 0,
+            };
+            archive_offset += descriptor.source_size as u64;
+            chunk
+        })
+        .collect();
+    rebuild_order.sort_unstable();
+    let dictionary = dict::ChunkDictionary {
+        rebuild_order: rebuild_order.into_iter().map(|(_, index)| index).collect(),
+        application_version: crate::PKG_VERSION.to_string(),
+        chunk_descriptors,
+        source_checksum: vec![0; 64],
+        chunk_compression: Some(None.into()),
+        source_total_size: result.total_size,
+        chunker_params: Some(compress_cmd::chunker_parameters(config, hash_length)?),
+        source_checkpoints: None,
+        chunk_data_part_sizes: vec![],
+        source_name: String::new(),
+        additional_sources: vec![],
+        chunk_crc32c: false,
+        rebuild_order_runs: None,
+        chunk_data_checksum: Some(dict::ChunkDataChecksum::blake2b_512(&HashSum::from(vec![
+            0;
+            64
+        ]))),
+        chunk_data_alignment: 0,
+        content_type: String::new(),
+        source_entry: None,
+    };
+    Ok(bitar::header::build(&dictionary, None)?.len() as u64)
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub input: PathBuf,
+    pub candidates: Vec<chunker::Config>,
+    pub hash_length: usize,
+    pub num_chunk_buffers: usize,
+    pub progress_format: ProgressFormat,
+}
+
+// Chunk the input using every candidate config. Returns the candidates and the index of the
+// recommended one, which gives the smallest archive.
+async fn tune(opts: &Options) -> Result<(Vec<Candidate>, usize)> {
+    if opts.candidates.is_empty() {
+        bail!("No chunker config to try");
+    }
+    let mut candidates = Vec::with_capacity(opts.candidates.len());
+    for config in &opts.candidates {
+        info!("Scanning {} using {} ...", opts.input.display(), config);
+        let result = diff_cmd::chunk_file(
+            &opts.input,
+            config,
+            None,
+            opts.num_chunk_buffers,
+            opts.progress_format,
+            "tune",
+        )
+        .await?;
+        candidates.push(Candidate {
+            config: config.clone(),
+            source_size: result.total_size,
+            total_chunks: result.total_chunks,
+            unique_chunks: result.descriptors.len(),
+            unique_size: result
+                .descriptors
+                .values()
+                .map(|descriptor| descriptor.source_size as u64)
+                .sum(),
+            header_size: estimate_header_size(&result, config, opts.hash_length)?,
+        });
+    }
+    let recommended = candidates
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, candidate)| candidate.archive_size())
+        .map(|(index, _)| index)
+        .expect("at least one candidate");
+    Ok((candidates, recommended))
+}
+
+pub async fn tune_cmd(opts: Options) -> Result<()> {
+    let (candidates, recommended) = tune(&opts).await?;
+    for candidate in &candidates {
+        for line in candidate.to_string().lines() {
+            info!("{}", line);
+        }
+    }
+    let recommended = &candidates[recommended];
+    info!("Recommended chunker: {}", recommended.config);
+    info!("  compress {}", compress_args(&recommended.config));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_cmd::tests::random_data;
+
+    #[tokio::test]
+    async fn recommends_one_of_the_candidates() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        // Two versions of the same data, the second with a few bytes changed
+        let mut source = random_data(512 * 1024, 0);
+        let mut modified = source.clone();
+        for offset in (0..modified.len()).step_by(200 * 1024) {
+            modified[offset] ^= 0xff;
+        }
+        source.extend(modified);
+        let input = temp_dir.path().join("input");
+        std::fs::write(&input, &source).unwrap();
+
+        let candidates = hash_chunker_candidates(false, &[4 * 1024, 16 * 1024, 64 * 1024]);
+        let (results, recommended) = tune(&Options {
+            input,
+            candidates: candidates.clone(),
+            hash_length: HashSum::MAX_LEN,
+            num_chunk_buffers: 2,
+            progress_format: ProgressFormat::Plain,
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(results.len(), candidates.len());
+        assert!(recommended < candidates.len());
+        assert!(results
+            .iter()
+            .all(|result| result.archive_size() >= results[recommended].archive_size()));
+        for (result, config) in results.iter().zip(&candidates) {
+            assert_eq!(result.config.to_string(), config.to_string());
+            assert_eq!(result.source_size, source.len() as u64);
+            assert!(result.unique_size < result.source_size);
+            assert!(result.header_size > 0);
+            let report = result.to_string();
+            for metric in &[
+                "Chunks:",
+                "Average chunk size:",
+                "Header size:",
+                "Archive size",
+            ] {
+                assert!(report.contains(metric), "{} missing in {}", metric, report);
+            }
+        }
+        // Smaller chunks dedup better but give a bigger header
+        assert!(results[0].unique_size < results[2].unique_size);
+        assert!(results[0].header_size > results[2].header_size);
+    }
+}
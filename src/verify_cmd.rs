@@ -0,0 +1,630 @@
+use anyhow::{bail, Context, Result};
+use futures_util::StreamExt;
+use log::*;
+use std::collections::HashSet;
+use std::hash::{BuildHasher, Hasher};
+use std::path::{Path, PathBuf};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    task::spawn_blocking,
+};
+
+use crate::{clone_cmd, human_size, info_cmd, local_file::LocalFile};
+use bitar::{
+    archive_reader::{ArchiveReader, HttpReader},
+    header::DictionaryKey,
+    Archive, ChunkHasher, ChunkIndex, HashSum, SourceHasher,
+};
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub input_archive: String,
+    // Verify the archive chunks if no source is given
+    pub source: Option<PathBuf>,
+    // Allow the source to be a prefix of the archive source
+    pub prefix: bool,
+    // Percent of the archive chunks to verify
+    pub sample: f64,
+    pub sample_seed: Option<u64>,
+    pub chunk_hasher: ChunkHasher,
+    // Verify the whole chunk data section against its checksum instead of the chunks
+    pub full: bool,
+    // Number of chunks decompressed and hashed simultaneously
+    pub num_chunk_buffers: usize,
+    // Key decrypting the archive dictionary
+    pub dictionary_key: Option<DictionaryKey>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Verification {
+    // Whole source matches.
+    Match,
+    // Source is shorter than the archive source, first bytes matches.
+    Prefix { verified: u64 },
+    // First difference is somewhere within the region.
+    Mismatch { start: u64, end: u64 },
+}
+
+async fn verify_source<R, T>(archive: &Archive<R>, mut source: T) -> Result<Verification>
+where
+    T: AsyncRead + Unpin,
+{
+    let checkpoints = archive.source_checkpoints();
+    let interval = checkpoints.map(|checkpoints| checkpoints.interval());
+    let expected = checkpoints
+        .map(|checkpoints| checkpoints.checksums())
+        .unwrap_or_default();
+    let source_size = archive.total_source_size();
+    let mut hasher = SourceHasher::new(interval);
+    let mut verified_checkpoints = 0;
+    let mut extra_size = 0u64;
+    let mut buf = vec![0u8; 1024 * 1024];
+    loop {
+        let rc = source
+            .read(&mut buf)
+            .await
+            .context("Failed to read source")?;
+        if rc == 0 {
+            break;
+        }
+        let hash_size = std::cmp::min(rc as u64, source_size - hasher.size()) as usize;
+        extra_size += (rc - hash_size) as u64;
+        hasher.update(&buf[..hash_size]);
+        for (index, checksum) in hasher
+            .checkpoints()
+            .iter()
+            .enumerate()
+            .skip(verified_checkpoints)
+        {
+            if Some(checksum) != expected.get(index) {
+                let interval = interval.unwrap();
+                return Ok(Verification::Mismatch {
+                    start: index as u64 * interval,
+                    end: std::cmp::min((index as u64 + 1) * interval, source_size),
+                });
+            }
+            verified_checkpoints += 1;
+        }
+    }
+    let verified = verified_checkpoints as u64 * interval.unwrap_or(0);
+    if hasher.size() < source_size {
+        return Ok(Verification::Prefix { verified });
+    }
+    let (checksum, _) = hasher.finalize();
+    if checksum != *archive.source_checksum() {
+        return Ok(Verification::Mismatch {
+            start: verified,
+            end: source_size,
+        });
+    }
+    if extra_size > 0 {
+        return Ok(Verification::Mismatch {
+            start: source_size,
+            end: source_size + extra_size,
+        });
+    }
+    Ok(Verification::Match)
+}
+
+// Pick percent of the indices 0..count, at least one if percent is non-zero. The same seed
+// always gives the same sample.
+pub(crate) fn sample_chunks(count: usize, percent: f64, seed: u64) -> Vec<usize> {
+    let mut sample_size = ((count as f64 * percent / 100.0).ceil() as usize).min(count);
+    if percent > 0.0 && count > 0 {
+        sample_size = sample_size.max(1);
+    }
+    let mut state = seed;
+    let mut next_random = move || {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    // Partial Fisher-Yates shuffle
+    let mut indices: Vec<usize> = (0..count).collect();
+    for i in 0..sample_size {
+        let j = i + (next_random() % (count - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(sample_size);
+    indices.sort_unstable();
+    indices
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ChunkVerification {
+    verified: usize,
+    // Hash of every chunk which failed to verify.
+    mismatches: Vec<HashSum>,
+}
+
+// Chunks are decompressed and hashed by up to max_buffered_chunks blocking tasks at a time,
+// while the next chunks are being read.
+async fn verify_chunks<R>(
+    archive: &mut Archive<R>,
+    indices: &[usize],
+    hasher: &ChunkHasher,
+    max_buffered_chunks: usize,
+) -> Result<ChunkVerification>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut chunks = ChunkIndex::new_empty(archive.chunk_hash_length());
+    // Chunks are streamed in descriptor order, same as the sorted indices.
+    let mut hashes = Vec::with_capacity(indices.len());
+    for &index in indices {
+        let descriptor = &archive.chunk_descriptors()[index];
+        chunks.add_chunk(
+            descriptor.checksum.clone(),
+            descriptor.source_size as usize,
+            &[],
+        );
+        hashes.push(descriptor.checksum.clone());
+    }
+    let mut result = ChunkVerification {
+        verified: 0,
+        mismatches: Vec::new(),
+    };
+    let hasher = *hasher;
+    let mut chunk_stream = archive
+        .chunk_stream(&chunks)
+        .zip(futures_util::stream::iter(hashes))
+        .map(|(chunk, expected_hash)| async move {
+            let chunk = chunk.context("Failed to read chunk")?;
+            let verified = spawn_blocking(move || -> Result<(), String> {
+                chunk
+                    .decompress()
+                    .map_err(|err| err.to_string())?
+                    .verify_with(&hasher)
+                    .map_err(|err| err.to_string())?;
+                Ok(())
+            })
+            .await?;
+            Ok::<_, anyhow::Error>((verified, expected_hash))
+        })
+        .buffered(max_buffered_chunks);
+    while let Some(verified) = chunk_stream.next().await {
+        match verified? {
+            (Ok(()), _) => result.verified += 1,
+            (Err(err), expected_hash) => {
+                warn!("Chunk {}: {}", expected_hash, err);
+                result.mismatches.push(expected_hash);
+            }
+        }
+    }
+    Ok(result)
+}
+
+// Verify every chunk of the archive, and that the main source rebuilt by the rebuild order
+// matches the source checksum.
+pub(crate) async fn verify_archive<R>(archive: &mut Archive<R>, hasher: &ChunkHasher) -> Result<()>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    let in_source: HashSet<&HashSum> = archive
+        .iter_source_chunks()
+        .map(|(_offset, descriptor)| &descriptor.checksum)
+        .collect();
+    let other_chunks: Vec<usize> = archive
+        .chunk_descriptors()
+        .iter()
+        .enumerate()
+        .filter(|(_index, descriptor)| !in_source.contains(&descriptor.checksum))
+        .map(|(index, _descriptor)| index)
+        .collect();
+    for source in archive.additional_sources() {
+        let size: u64 = source
+            .rebuild_order
+            .iter()
+            .map(|&index| archive.chunk_descriptors()[index as usize].source_size as u64)
+            .sum();
+        if size != source.source_total_size {
+            bail!(
+                "Chunks of source {} add up to {} but the source is {}",
+                source.name,
+                size,
+                source.source_total_size
+            );
+        }
+    }
+
+    let mut source_hasher = SourceHasher::default();
+    let mut chunk_stream = archive.source_chunk_stream(0);
+    while let Some(result) = chunk_stream.next().await {
+        let (offset, chunk) = result.context("Failed to read chunk")?;
+        let expected_hash = chunk.expected_hash().clone();
+        let verified = chunk
+            .decompress()
+            .context(format!("Failed to decompress chunk {}", expected_hash))?
+            .verify_with(hasher)
+            .context(format!(
+                "Chunk {} at offset {} is corrupt",
+                expected_hash, offset
+            ))?;
+        source_hasher.update(verified.data());
+    }
+    drop(chunk_stream);
+    let (checksum, _) = source_hasher.finalize();
+    if checksum != *archive.source_checksum() {
+        bail!(
+            "Source rebuilt from the archive has checksum {} but expected {}",
+            checksum,
+            archive.source_checksum()
+        );
+    }
+
+    // Chunks only used by additional sources
+    let result = verify_chunks(archive, &other_chunks, hasher, 1).await?;
+    if !result.mismatches.is_empty() {
+        bail!(
+            "{} of {} chunks not in the main source are corrupt",
+            result.mismatches.len(),
+            other_chunks.len()
+        );
+    }
+    Ok(())
+}
+
+async fn verify_source_file<R>(archive: &Archive<R>, source: &Path, prefix: bool) -> Result<()> {
+    if prefix && archive.source_checkpoints().is_none() {
+        bail!(
+            "Archive has no source checkpoints, recompress it with --source-checkpoints to verify a prefix"
+        );
+    }
+    let file = LocalFile::open(source).await?;
+    match verify_source(archive, file).await? {
+        Verification::Match => {
+            info!("{} matches the archive source", source.display());
+            Ok(())
+        }
+        Verification::Prefix { verified } if prefix => {
+            info!(
+                "First {} of {} matches the archive source",
+                human_size!(verified),
+                source.display()
+            );
+            Ok(())
+        }
+        Verification::Prefix { verified } => bail!(
+            "{} is shorter than the archive source (first {} matches)",
+            source.display(),
+            human_size!(verified)
+        ),
+        Verification::Mismatch { start, end } => bail!(
+            "{} differs from the archive source, first difference within bytes {}-{}",
+            source.display(),
+            start,
+            end
+        ),
+    }
+}
+
+// Verify the whole chunk data section against the checksum stored by compress, in one pass.
+async fn verify_chunk_data<R>(archive: &mut Archive<R>) -> Result<()>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    let expected = match archive.chunk_data_checksum() {
+        Some(checksum) => checksum.clone(),
+        None => bail!("Archive has no chunk data checksum, recompress it to verify in full"),
+    };
+    let checksum = archive
+        .hash_chunk_data()
+        .await
+        .context("Failed to read chunk data")?;
+    if checksum != expected {
+        bail!(
+            "Chunk data checksum mismatch (expected {}, got {}), the archive is corrupt",
+            expected,
+            checksum
+        );
+    }
+    info!(
+        "All {} of chunk data matches the archive checksum",
+        human_size!(archive.chunk_data_size())
+    );
+    Ok(())
+}
+
+async fn verify_opened_archive<R>(mut archive: Archive<R>, opts: &Options) -> Result<()>
+where
+    R: ArchiveReader,
+    R::Error: std::error::Error + Send + Sync + 'static,
+{
+    if let Some(source) = &opts.source {
+        return verify_source_file(&archive, source, opts.prefix).await;
+    }
+    if opts.full {
+        return verify_chunk_data(&mut archive).await;
+    }
+    let seed = opts.sample_seed.unwrap_or_else(|| {
+        std::collections::hash_map::RandomState::new()
+            .build_hasher()
+            .finish()
+    });
+    let indices = sample_chunks(archive.unique_chunks(), opts.sample, seed);
+    if indices.len() < archive.unique_chunks() {
+        info!(
+            "Verifying {} of {} chunks (sample seed {})",
+            indices.len(),
+            archive.unique_chunks(),
+            seed
+        );
+    } else {
+        info!("Verifying all {} chunks", indices.len());
+    }
+    let result = verify_chunks(
+        &mut archive,
+        &indices,
+        &opts.chunk_hasher,
+        opts.num_chunk_buffers,
+    )
+    .await?;
+    if !result.mismatches.is_empty() {
+        bail!(
+            "{} of {} verified chunks are corrupt",
+            result.mismatches.len(),
+            indices.len()
+        );
+    }
+    info!("All {} verified chunks are valid", result.verified);
+    Ok(())
+}
+
+pub async fn verify_cmd(opts: Options) -> Result<()> {
+    let key = opts.dictionary_key.as_ref();
+    // Chunk data of a split archive is read from its parts.
+    if let Ok(url) = opts.input_archive.parse::<reqwest::Url>() {
+        let archive = info_cmd::init_archive(HttpReader::from_url(url.clone()), key).await?;
+        let parts = (0..archive.chunk_data_part_sizes().len())
+            .map(|index| HttpReader::from_url(clone_cmd::part_url(&url, index)))
+            .collect();
+        verify_opened_archive(archive.with_part_readers(parts)?, &opts).await
+    } else {
+        let path = Path::new(&opts.input_archive);
+        let archive = info_cmd::init_archive(LocalFile::open_archive(path).await?, key).await?;
+        let parts = LocalFile::open_parts(path, archive.chunk_data_part_sizes().len()).await?;
+        verify_opened_archive(archive.with_part_readers(parts)?, &opts).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_cmd::{
+        self,
+        tests::{random_data, test_options},
+    };
+    use bitar::{archive_reader::IoReader, ChunkHasher};
+
+    const CHECKPOINT_INTERVAL: u64 = 64 * 1024;
+
+    async fn archive_with_checkpoints(
+        source: &[u8],
+    ) -> (tempfile::TempDir, Archive<IoReader<LocalFile>>) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input");
+        let output = temp_dir.path().join("input.cba");
+        std::fs::write(&input, source).unwrap();
+        let mut opts = test_options(vec![input], compress_cmd::Output::File(output.clone()));
+        opts.source_checkpoint_interval = Some(CHECKPOINT_INTERVAL);
+        compress_cmd::compress_cmd(opts).await.unwrap();
+        let archive = Archive::try_init(LocalFile::open_archive(&output).await.unwrap())
+            .await
+            .unwrap();
+        (temp_dir, archive)
+    }
+
+    #[tokio::test]
+    async fn corruption_is_localized_to_checkpoint_interval() {
+        let source: Vec<u8> = (0..500_000u32).map(|v| (v % 251) as u8).collect();
+        let (_temp_dir, archive) = archive_with_checkpoints(&source).await;
+        assert_eq!(
+            archive.source_checkpoints().unwrap().checksums().len(),
+            source.len() / CHECKPOINT_INTERVAL as usize
+        );
+        assert_eq!(
+            verify_source(&archive, &source[..]).await.unwrap(),
+            Verification::Match
+        );
+        for &offset in &[0, 200_000, 262_143, 499_999] {
+            let mut corrupt = source.clone();
+            corrupt[offset] ^= 0xff;
+            let start = offset as u64 / CHECKPOINT_INTERVAL * CHECKPOINT_INTERVAL;
+            assert_eq!(
+                verify_source(&archive, &corrupt[..]).await.unwrap(),
+                Verification::Mismatch {
+                    start,
+                    end: std::cmp::min(start + CHECKPOINT_INTERVAL, source.len() as u64),
+                }
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_prefix() {
+        let source: Vec<u8> = (0..500_000u32).map(|v| (v % 251) as u8).collect();
+        let (_temp_dir, archive) = archive_with_checkpoints(&source).await;
+        assert_eq!(
+            verify_source(&archive, &source[..200_000]).await.unwrap(),
+            Verification::Prefix {
+                verified: 3 * CHECKPOINT_INTERVAL
+            }
+        );
+        let mut longer = source.clone();
+        longer.extend(&[1, 2, 3]);
+        assert_eq!(
+            verify_source(&archive, &longer[..]).await.unwrap(),
+            Verification::Mismatch {
+                start: 500_000,
+                end: 500_003
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_prefix_without_checkpoints() {
+        let archive = Archive::try_init(
+            LocalFile::open_archive("bitar/tests/resources/zero-0_7_1-brotli.cba")
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(archive.source_checkpoints().is_none());
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("source");
+        std::fs::write(&source, [0u8; 1024]).unwrap();
+        let err = verify_source_file(&archive, &source, true)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Archive has no source checkpoints"));
+    }
+
+    #[tokio::test]
+    async fn full_sample_equals_full_verify() {
+        let source: Vec<u8> = (0..500_000u32).map(|v| (v % 251) as u8).collect();
+        let (_temp_dir, mut archive) = archive_with_checkpoints(&source).await;
+        let count = archive.unique_chunks();
+        let all: Vec<usize> = (0..count).collect();
+        assert_eq!(sample_chunks(count, 100.0, 7), all);
+        assert_eq!(sample_chunks(count, 10.0, 7), sample_chunks(count, 10.0, 7));
+        assert_eq!(sample_chunks(count, 10.0, 7).len(), (count + 9) / 10);
+        assert_eq!(
+            verify_chunks(&mut archive, &all, &ChunkHasher::default(), 4)
+                .await
+                .unwrap(),
+            ChunkVerification {
+                verified: count,
+                mismatches: vec![],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn full_verify_detects_any_flipped_byte() {
+        let source: Vec<u8> = (0..20_000u32).map(|v| (v % 251) as u8).collect();
+        let (temp_dir, mut archive) = archive_with_checkpoints(&source).await;
+        verify_chunk_data(&mut archive).await.unwrap();
+        let data_offset = archive.chunk_data_offset() as usize;
+        let data = std::fs::read(temp_dir.path().join("input.cba")).unwrap();
+        assert_eq!((data.len() - data_offset) as u64, archive.chunk_data_size());
+        // Every chunk, its boundaries and the last byte of the section
+        let offsets = (data_offset..data.len())
+            .step_by(509)
+            .chain(archive.chunk_descriptors().iter().flat_map(|cd| {
+                let offset = cd.archive_offset as usize;
+                vec![offset, offset + cd.archive_size - 1]
+            }))
+            .chain(std::iter::once(data.len() - 1));
+        for offset in offsets {
+            let mut corrupt = data.clone();
+            corrupt[offset] ^= 0x01;
+            let mut archive = Archive::try_init(IoReader::new(std::io::Cursor::new(corrupt)))
+                .await
+                .unwrap();
+            let err = verify_chunk_data(&mut archive).await.unwrap_err();
+            assert!(err.to_string().contains("checksum mismatch"), "{}", offset);
+        }
+    }
+
+    #[tokio::test]
+    async fn sample_catches_corrupt_chunk() {
+        let source: Vec<u8> = (0..500_000u32).map(|v| (v % 251) as u8).collect();
+        let (temp_dir, archive) = archive_with_checkpoints(&source).await;
+        let sample = sample_chunks(archive.unique_chunks(), 10.0, 7);
+        let corrupt = archive.chunk_descriptors()[sample[0]].clone();
+        let archive_path = temp_dir.path().join("input.cba");
+        let mut data = std::fs::read(&archive_path).unwrap();
+        data[corrupt.archive_offset as usize] ^= 0xff;
+        std::fs::write(&archive_path, data).unwrap();
+
+        let mut archive = Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            verify_chunks(&mut archive, &sample, &ChunkHasher::default(), 4)
+                .await
+                .unwrap(),
+            ChunkVerification {
+                verified: sample.len() - 1,
+                mismatches: vec![corrupt.checksum],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_verify_equals_sequential() {
+        let source: Vec<u8> = (0..500_000u32).map(|v| (v % 251) as u8).collect();
+        let (temp_dir, archive) = archive_with_checkpoints(&source).await;
+        let all: Vec<usize> = (0..archive.unique_chunks()).collect();
+        let archive_path = temp_dir.path().join("input.cba");
+        let mut data = std::fs::read(&archive_path).unwrap();
+        for &index in &[1, all.len() / 2, all.len() - 1] {
+            data[archive.chunk_descriptors()[index].archive_offset as usize] ^= 0xff;
+        }
+        std::fs::write(&archive_path, data).unwrap();
+
+        let mut results = Vec::new();
+        for &max_buffered_chunks in &[1, 8] {
+            let mut archive =
+                Archive::try_init(LocalFile::open_archive(&archive_path).await.unwrap())
+                    .await
+                    .unwrap();
+            results.push(
+                verify_chunks(
+                    &mut archive,
+                    &all,
+                    &ChunkHasher::default(),
+                    max_buffered_chunks,
+                )
+                .await
+                .unwrap(),
+            );
+        }
+        assert_eq!(results[0].mismatches.len(), 3);
+        assert_eq!(results[0].verified, all.len() - 3);
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[tokio::test]
+    async fn verify_archive_with_encrypted_dictionary() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("input");
+        let archive_path = temp_dir.path().join("input.cba");
+        std::fs::write(&input, random_data(64 * 1024, 0)).unwrap();
+        let key = DictionaryKey::from_slice(&[7; DictionaryKey::SIZE]).unwrap();
+        let mut compress_opts = test_options(
+            vec![input],
+            compress_cmd::Output::File(archive_path.clone()),
+        );
+        compress_opts.dictionary_key = Some(key.clone());
+        compress_cmd::compress_cmd(compress_opts).await.unwrap();
+
+        let mut opts = Options {
+            input_archive: archive_path.to_str().unwrap().to_string(),
+            source: None,
+            prefix: false,
+            sample: 100.0,
+            sample_seed: None,
+            chunk_hasher: ChunkHasher::default(),
+            full: false,
+            num_chunk_buffers: 1,
+            dictionary_key: None,
+        };
+        let err = verify_cmd(opts.clone()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<bitar::ArchiveError<std::io::Error>>(),
+            Some(bitar::ArchiveError::EncryptedDictionary)
+        ));
+        opts.dictionary_key = Some(key);
+        verify_cmd(opts).await.unwrap();
+    }
+}